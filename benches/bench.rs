@@ -0,0 +1,181 @@
+#![feature(test)]
+
+extern crate mesh;
+extern crate test;
+
+use mesh::clock::{ManualClock, Nanos};
+use mesh::dispatch::Dispatcher;
+use mesh::fastpath;
+use mesh::members::NodeId;
+use mesh::message::{Message, AckedMessage, Health, PingBody, Responder,
+                    WireAddr, PROTOCOL_VERSION};
+use mesh::scheduler::Timer;
+use mesh::transport::SimTransport;
+use std::net::SocketAddr;
+use test::{black_box, Bencher};
+
+fn bench_recode(b: &mut Bencher, m: Message) {
+    b.iter(|| {
+        let bytes = m.encode();
+        black_box(Message::decode(&bytes))
+    });
+}
+
+#[bench]
+fn recode_join(b: &mut Bencher) {
+    bench_recode(b, Message::Acked(42, AckedMessage::Join(NodeId(42),
+                                                          PROTOCOL_VERSION)));
+}
+
+#[bench]
+fn recode_ack(b: &mut Bencher) {
+    bench_recode(b, ack());
+}
+
+#[bench]
+fn recode_ping(b: &mut Bencher) {
+    bench_recode(b, Message::Ping(ping_body()));
+}
+
+#[bench]
+fn recode_pong(b: &mut Bencher) {
+    bench_recode(b, pong());
+}
+
+// The same two messages, read by the fast path and by the general
+// decoder it stands in for.
+fn bench_parse(b: &mut Bencher, m: Message, fast: bool) {
+    let bytes = m.encode();
+    b.iter(|| {
+        if fast {
+            black_box(fastpath::parse(&bytes))
+        } else {
+            black_box(mesh::parse_datagram(&bytes).ok())
+        }
+    });
+}
+
+fn ack() -> Message {
+    Message::Ack(42, wire_addr(), Some(Responder {
+        id: NodeId(42),
+        incarnation: 7,
+    }))
+}
+
+fn pong() -> Message {
+    let health = Health { queued: 12, dropped: 3, alive: 40 };
+    Message::Pong(ping_body(), wire_addr(), Some(health))
+}
+
+#[bench]
+fn parse_ack_fast(b: &mut Bencher) {
+    bench_parse(b, ack(), true);
+}
+
+#[bench]
+fn parse_ack_general(b: &mut Bencher) {
+    bench_parse(b, ack(), false);
+}
+
+#[bench]
+fn parse_pong_fast(b: &mut Bencher) {
+    bench_parse(b, pong(), true);
+}
+
+#[bench]
+fn parse_pong_general(b: &mut Bencher) {
+    bench_parse(b, pong(), false);
+}
+
+fn ping_body() -> PingBody {
+    PingBody { nonce: 0x0123456789abcdef, sent_at_micros: 1000000, pad: vec![] }
+}
+
+fn wire_addr() -> WireAddr {
+    WireAddr("127.0.0.1:9000".parse().unwrap())
+}
+
+#[bench]
+fn timer_add_advance_10k_pending(b: &mut Bencher) {
+    let mut t = Timer::new();
+    // Far enough out that nothing here expires however long we run.
+    for i in 0..10000 {
+        t.add(Nanos::from_nanos((1 << 40) + (i * 7919) % 10000), ());
+    }
+
+    b.iter(|| {
+        t.add(Nanos::from_nanos(1), ());
+        black_box(t.advance(Nanos::from_nanos(1)))
+    });
+}
+
+// Drive a timer the way the scheduler's thread does, sleeping as long as
+// `wakeup` allows, with 1000 events spread over one second. Returns how
+// many wakeups that took.
+fn wakeups_for_1000_events(tolerance: Nanos) -> u32 {
+    let mut t = Timer::new();
+    for i in 0..1000u64 {
+        t.add(Nanos::from_micros(i * 7919 % 1000000), ());
+    }
+
+    let mut wakeups = 0;
+    while let Some(ns) = t.wakeup(tolerance) {
+        t.advance(ns);
+        wakeups += 1;
+    }
+    wakeups
+}
+
+fn bench_wakeups(b: &mut Bencher, tolerance: Nanos) {
+    println!("{} wakeups with {} tolerance",
+             wakeups_for_1000_events(tolerance), tolerance);
+    b.iter(|| black_box(wakeups_for_1000_events(tolerance)));
+}
+
+#[bench]
+fn timer_wakeups_1000_events_no_coalescing(b: &mut Bencher) {
+    bench_wakeups(b, Nanos::zero());
+}
+
+#[bench]
+fn timer_wakeups_1000_events_5ms_tolerance(b: &mut Bencher) {
+    bench_wakeups(b, Nanos::from_millis(5));
+}
+
+#[bench]
+fn dispatch_ping(b: &mut Bencher) {
+    let peer: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    let ping = Message::Ping(ping_body()).encode();
+    let mut d = Dispatcher::new(SimTransport::new(), ManualClock::new());
+
+    b.iter(|| {
+        d.transport().deliver(ping.clone(), peer);
+        d.poll();
+        d.transport().sent.borrow_mut().clear();
+    });
+}
+
+// A peer's Pongs, each answering a Ping of ours: the dispatcher's half of
+// a probe.
+#[bench]
+fn dispatch_pong(b: &mut Bencher) {
+    let peer: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    let mut d = Dispatcher::new(SimTransport::new(), ManualClock::new());
+    let join = Message::Acked(1, AckedMessage::Join(NodeId(1),
+                                                    PROTOCOL_VERSION));
+    d.transport().deliver(join.encode(), peer);
+    d.poll();
+
+    b.iter(|| {
+        d.transport().sent.borrow_mut().clear();
+        d.ping(&peer);
+        d.poll();
+        let pong = match Message::decode(&d.transport().sent.borrow()[0].0) {
+            Message::Ping(body) =>
+                Message::Pong(body.echo(), wire_addr(), None).encode(),
+            other => panic!("expected Ping, got {:?}", other),
+        };
+        d.transport().deliver(pong, peer);
+        d.poll();
+    });
+}
@@ -0,0 +1,14 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate mesh;
+
+// Run with `cargo fuzz run parse_datagram` from the crate root. Any input
+// that makes this panic should be added to the bad inputs in
+// src/message.rs. Whatever the fast path reads, the general decoder must
+// read the same.
+fuzz_target!(|data: &[u8]| {
+    let parsed = mesh::parse_datagram(data);
+    if let Some(fast) = mesh::fastpath::parse(data) {
+        assert_eq!(parsed, Ok(fast));
+    }
+});
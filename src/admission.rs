@@ -0,0 +1,173 @@
+use members::NodeId;
+use message::Seq;
+use protocol::limits::ADMISSION_TIMEOUT;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+#[cfg(test)] use std::time::Duration;
+
+// A newcomer asking to join, as an embedder deciding whether to have it
+// sees it: the id and protocol version its Join gave, and where it came
+// from. See Dispatcher::require_admission.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JoinCandidate {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub version: u8,
+}
+
+// What an embedder decides about a JoinCandidate: let it in, turn it away
+// saying why, or decide later, by Dispatcher::resolve_join.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Admission {
+    Accept,
+    Reject(String),
+    Defer,
+}
+
+// What became of a Join given to Admissions::hold.
+#[derive(Debug, PartialEq)]
+pub enum Held {
+    // It's new, and waits for the embedder.
+    New,
+    // It was waiting already: a retransmission.
+    Again,
+    // Its sender was turned away lately, for this reason.
+    Refused(String),
+    // There's no room to hold it.
+    Full,
+}
+
+struct Waiting {
+    candidate: JoinCandidate,
+    seq: Seq,
+    since: Instant,
+}
+
+// Newcomers' Joins held, unacked, until the embedder decides on them,
+// at most `capacity` at once and each for ADMISSION_TIMEOUT at most,
+// and those turned away, remembered as long, so as to tell them again
+// should a retransmission of their Join come. Held Joins go by the
+// joiner's id: a retransmission takes the place of the Join it repeats,
+// from wherever it comes.
+pub struct Admissions {
+    capacity: usize,
+    waiting: HashMap<NodeId, Waiting>,
+    refused: HashMap<NodeId, (String, Instant)>,
+}
+
+impl Admissions {
+    pub fn new(capacity: usize) -> Admissions {
+        Admissions {
+            capacity: capacity,
+            waiting: HashMap::new(),
+            refused: HashMap::new(),
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    pub fn hold(&mut self, candidate: JoinCandidate, seq: Seq, now: Instant)
+            -> Held {
+        if let Some(&(ref reason, _)) = self.refused.get(&candidate.id) {
+            return Held::Refused(reason.clone());
+        }
+        if let Some(w) = self.waiting.get_mut(&candidate.id) {
+            w.candidate = candidate;
+            w.seq = seq;
+            return Held::Again;
+        }
+        if self.waiting.len() >= self.capacity {
+            return Held::Full;
+        }
+        self.waiting.insert(candidate.id, Waiting {
+            candidate: candidate,
+            seq: seq,
+            since: now,
+        });
+        Held::New
+    }
+
+    // The Join `id` is waiting with, if it is, no longer held.
+    pub fn take(&mut self, id: NodeId) -> Option<(JoinCandidate, Seq)> {
+        self.waiting.remove(&id).map(|w| (w.candidate, w.seq))
+    }
+
+    pub fn is_held(&self, id: NodeId) -> bool {
+        self.waiting.contains_key(&id)
+    }
+
+    pub fn refuse(&mut self, id: NodeId, reason: String, now: Instant) {
+        self.refused.insert(id, (reason, now));
+    }
+
+    // Forget those held or refused ADMISSION_TIMEOUT or longer ago,
+    // and return those that were held.
+    pub fn expire(&mut self, now: Instant) -> Vec<JoinCandidate> {
+        self.refused.retain(|_, &mut (_, at)| now - at < ADMISSION_TIMEOUT);
+        let expired: Vec<NodeId> = self.waiting.iter()
+            .filter(|&(_, w)| now - w.since >= ADMISSION_TIMEOUT)
+            .map(|(&id, _)| id)
+            .collect();
+        expired.into_iter()
+            .filter_map(|id| self.waiting.remove(&id))
+            .map(|w| w.candidate)
+            .collect()
+    }
+
+    // How many Joins are held.
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+#[cfg(test)]
+fn candidate(id: u64, port: u16) -> JoinCandidate {
+    JoinCandidate {
+        id: NodeId(id),
+        addr: SocketAddr::new("127.0.0.1".parse().unwrap(), port),
+        version: 15,
+    }
+}
+
+#[test]
+fn retransmissions_take_the_place_of_the_join_they_repeat() {
+    let mut a = Admissions::new(2);
+    let now = Instant::now();
+    assert_eq!(a.hold(candidate(1, 9001), Seq::new(1, 1), now), Held::New);
+    assert_eq!(a.hold(candidate(1, 9002), Seq::new(1, 2), now), Held::Again);
+    assert_eq!(a.hold(candidate(2, 9003), Seq::new(2, 1), now), Held::New);
+    assert_eq!(a.hold(candidate(3, 9004), Seq::new(3, 1), now), Held::Full);
+    assert_eq!(a.len(), 2);
+
+    assert_eq!(a.take(NodeId(1)), Some((candidate(1, 9002), Seq::new(1, 2))));
+    assert_eq!(a.take(NodeId(1)), None);
+    assert_eq!(a.hold(candidate(3, 9004), Seq::new(3, 1), now), Held::New);
+}
+
+#[test]
+fn the_held_and_the_refused_are_forgotten_in_time() {
+    let mut a = Admissions::new(4);
+    let start = Instant::now();
+    a.hold(candidate(1, 9001), Seq::new(1, 1), start);
+    a.take(NodeId(1));
+    a.refuse(NodeId(1), "not in the inventory".to_string(), start);
+    let later = start + ADMISSION_TIMEOUT / 2;
+    a.hold(candidate(2, 9002), Seq::new(2, 1), later);
+    assert_eq!(a.hold(candidate(1, 9001), Seq::new(1, 2), later),
+               Held::Refused("not in the inventory".to_string()));
+
+    let timeout = ADMISSION_TIMEOUT;
+    assert!(a.expire(start + timeout - Duration::from_millis(1)).is_empty());
+    assert!(a.expire(start + timeout).is_empty());
+    assert_eq!(a.hold(candidate(1, 9001), Seq::new(1, 3), start + timeout),
+               Held::New);
+    assert_eq!(a.expire(later + timeout), vec![candidate(2, 9002)]);
+    assert_eq!(a.len(), 1);
+}
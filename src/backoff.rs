@@ -0,0 +1,162 @@
+use clock::Nanos;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// When to stop hammering an address that isn't answering. After `after`
+// failures in a row (sends the kernel refused, or probes that went
+// unanswered until the next), everything to the address is suppressed
+// except a probe now and then: `base` after the last failure, doubling
+// with each further one up to `max`. Anything heard from the address
+// ends the backoff at once.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackoffConfig {
+    pub after: u32,
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> BackoffConfig {
+        BackoffConfig {
+            after: 3,
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+struct PeerBackoff {
+    failures: u32,
+    // Whether the last probe is still unanswered.
+    awaiting: bool,
+    next_probe: Instant,
+}
+
+// The failures of every address that has any.
+pub struct Backoff {
+    config: BackoffConfig,
+    peers: HashMap<SocketAddr, PeerBackoff>,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Backoff {
+        Backoff { config: config, peers: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &BackoffConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: BackoffConfig) {
+        self.config = config;
+    }
+
+    // Whether to hold back everything to `addr` but the occasional probe.
+    pub fn suppressed(&self, addr: &SocketAddr) -> bool {
+        self.peers.get(addr)
+            .map_or(false, |p| p.failures >= self.config.after)
+    }
+
+    // Whether `addr` is due a probe, backing off or not.
+    pub fn probe_due(&self, addr: &SocketAddr, now: Instant) -> bool {
+        !self.suppressed(addr) || now >= self.peers[addr].next_probe
+    }
+
+    // We've just probed `addr`. If it never answered the last probe, that
+    // counts as a failure.
+    pub fn probed(&mut self, addr: &SocketAddr, now: Instant) {
+        let unanswered = self.peers.get(addr).map_or(false, |p| p.awaiting);
+        if unanswered {
+            self.failed(addr, now);
+        }
+        self.entry(addr, now).awaiting = true;
+    }
+
+    // Sending to `addr` failed.
+    pub fn failed(&mut self, addr: &SocketAddr, now: Instant) {
+        let config = self.config.clone();
+        let p = self.entry(addr, now);
+        p.failures += 1;
+        if p.failures >= config.after {
+            p.next_probe = now + delay(&config, p.failures - config.after);
+        }
+    }
+
+    // We've heard from `addr`, so it's reachable after all.
+    pub fn heard(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    // Forget `addr`'s failures without having heard from it, for a
+    // caller with a backoff of its own to try it afresh.
+    pub fn reset(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    fn entry(&mut self, addr: &SocketAddr, now: Instant) -> &mut PeerBackoff {
+        self.peers.entry(*addr).or_insert(PeerBackoff {
+            failures: 0,
+            awaiting: false,
+            next_probe: now,
+        })
+    }
+}
+
+// How long to wait for the next probe after `extra` failures beyond the
+// ones that started the backoff.
+fn delay(config: &BackoffConfig, extra: u32) -> Duration {
+    let base = Nanos::from(config.base);
+    let delay = if extra >= 32 { Nanos::from_nanos(u64::max_value()) } else {
+        base * (1 << extra)
+    };
+    if delay > Nanos::from(config.max) {
+        config.max
+    } else {
+        Duration::from(delay)
+    }
+}
+
+#[cfg(test)]
+fn addr() -> SocketAddr {
+    "127.0.0.1:9000".parse().unwrap()
+}
+
+#[test]
+fn backoff_delay_doubles_up_to_max() {
+    let config = BackoffConfig {
+        after: 2,
+        base: Duration::from_secs(1),
+        max: Duration::from_secs(5),
+    };
+    let delays: Vec<u64> = (0..5).map(|n| delay(&config, n).as_secs())
+        .collect();
+    assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+    assert_eq!(delay(&config, 100), Duration::from_secs(5));
+}
+
+#[test]
+fn backoff_starts_after_enough_failures_and_ends_when_heard() {
+    let mut b = Backoff::new(BackoffConfig {
+        after: 2,
+        base: Duration::from_secs(1),
+        max: Duration::from_secs(5),
+    });
+    let now = Instant::now();
+    b.probed(&addr(), now);
+    b.failed(&addr(), now);
+    assert!(!b.suppressed(&addr()));
+    // The first probe went unanswered.
+    b.probed(&addr(), now);
+    assert!(b.suppressed(&addr()));
+    assert!(!b.probe_due(&addr(), now + Duration::from_millis(999)));
+    assert!(b.probe_due(&addr(), now + Duration::from_secs(1)));
+
+    b.heard(&addr());
+    assert!(!b.suppressed(&addr()));
+    // A probe answered resets the count too.
+    b.probed(&addr(), now);
+    b.heard(&addr());
+    b.probed(&addr(), now);
+    assert!(!b.suppressed(&addr()));
+}
@@ -0,0 +1,289 @@
+use outbound::Band;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// What a datagram takes up on the link besides its payload: the UDP and
+// IP headers, and Ethernet's header (14 bytes) and frame check sequence
+// (4), with the preamble (8) and interframe gap (12) that the link spends
+// on every frame all the same.
+pub const UDP_HEADER: usize = 8;
+pub const IPV4_HEADER: usize = 20;
+pub const IPV6_HEADER: usize = 40;
+pub const ETHERNET_OVERHEAD: usize = 38;
+
+// The bytes a datagram of `payload` bytes to `target` takes up on the
+// link, which is what a cap on a metered link has to count.
+pub fn wire_bytes(payload: usize, target: &SocketAddr) -> usize {
+    let ip = match *target {
+        SocketAddr::V4(_) => IPV4_HEADER,
+        SocketAddr::V6(_) => IPV6_HEADER,
+    };
+    payload + UDP_HEADER + ip + ETHERNET_OVERHEAD
+}
+
+// How much a node may send in all, to every peer together: at most `cap`
+// bytes a second (see wire_bytes), or as much as it likes if 0. Of that,
+// `reserved` percent is kept for control and probe traffic (see
+// outbound::Band), so that however much bulk is waiting, Acks, Joins and
+// probes still get out, and peers don't judge us dead for want of them.
+// After a quiet spell up to `burst` at the cap may go at once. Datagrams
+// held back wait in the outbound queue, and if that goes on for
+// `warn_after`, we warn.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BandwidthConfig {
+    pub cap: u64,
+    pub reserved: u32,
+    pub burst: Duration,
+    pub warn_after: Duration,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> BandwidthConfig {
+        BandwidthConfig {
+            cap: 0,
+            reserved: 20,
+            burst: Duration::from_millis(100),
+            warn_after: Duration::from_secs(5),
+        }
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+// The node's buckets: the reserved one, which only control and probe
+// traffic may draw on, and the general one, which anything may. The
+// reserved one fills first, and what it can't hold spills into the
+// general one, so that between them they never fill faster than the cap.
+pub struct Bandwidth {
+    config: BandwidthConfig,
+    general: f64,
+    reserved: f64,
+    refilled: Option<Instant>,
+    // Since when every flush has had to hold something back, and whether
+    // we've warned about it yet.
+    queuing: Option<Instant>,
+    warned: bool,
+    // Bytes sent in the second starting at `window`, and in the whole
+    // second before it.
+    window: Option<Instant>,
+    window_bytes: u64,
+    last_second: u64,
+}
+
+impl Bandwidth {
+    pub fn new(config: BandwidthConfig) -> Bandwidth {
+        Bandwidth {
+            config: config,
+            general: 0.0,
+            reserved: 0.0,
+            refilled: None,
+            queuing: None,
+            warned: false,
+            window: None,
+            window_bytes: 0,
+            last_second: 0,
+        }
+    }
+
+    pub fn config(&self) -> &BandwidthConfig {
+        &self.config
+    }
+
+    // Switch to `config`, starting its buckets over, full.
+    pub fn set_config(&mut self, config: BandwidthConfig) {
+        self.config = config;
+        self.refilled = None;
+    }
+
+    // The most each bucket holds.
+    fn capacities(&self) -> (f64, f64) {
+        let burst = self.config.cap as f64 * secs(self.config.burst);
+        let reserved = burst * self.config.reserved.min(100) as f64 / 100.0;
+        (burst - reserved, reserved)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let (general_max, reserved_max) = self.capacities();
+        let added = match self.refilled {
+            Some(refilled) => secs(now - refilled) * self.config.cap as f64,
+            None => {
+                self.general = general_max;
+                self.reserved = reserved_max;
+                0.0
+            },
+        };
+        self.refilled = Some(now);
+        let reserved = self.reserved + added;
+        let spilled = (reserved - reserved_max).max(0.0);
+        self.reserved = reserved.min(reserved_max);
+        self.general = (self.general + spilled).min(general_max);
+    }
+
+    // Whether a datagram in `band` that takes up `bytes` on the link may
+    // go now, paying for it if so. One too big for the bucket goes once
+    // the bucket is full, and leaves it owing the rest.
+    pub fn admit(&mut self, band: Band, bytes: usize, now: Instant) -> bool {
+        if self.config.cap == 0 {
+            self.count(bytes, now);
+            return true;
+        }
+        self.refill(now);
+        let (general_max, reserved_max) = self.capacities();
+        let bytes = bytes as f64;
+        let (available, most) = if band == Band::Bulk {
+            (self.general, general_max)
+        } else {
+            (self.general + self.reserved, general_max + reserved_max)
+        };
+        if available < bytes.min(most) {
+            return false;
+        }
+        let from_general = if band == Band::Bulk {
+            bytes
+        } else {
+            bytes.min(self.general.max(0.0))
+        };
+        self.general -= from_general;
+        self.reserved -= bytes - from_general;
+        self.count(bytes as usize, now);
+        true
+    }
+
+    // Give back what a datagram that `admit` let through cost, the
+    // transport having pushed back before it went.
+    pub fn refund(&mut self, bytes: usize) {
+        self.general += bytes as f64;
+        self.window_bytes = self.window_bytes.saturating_sub(bytes as u64);
+    }
+
+    fn count(&mut self, bytes: usize, now: Instant) {
+        self.roll(now);
+        self.window_bytes += bytes as u64;
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let second = Duration::from_secs(1);
+        match self.window {
+            Some(start) if now - start < second => (),
+            Some(start) => {
+                // A second or more with nothing sent counts as nothing.
+                self.last_second = if now - start < second * 2 {
+                    self.window_bytes
+                } else {
+                    0
+                };
+                self.window = Some(now);
+                self.window_bytes = 0;
+            },
+            None => self.window = Some(now),
+        }
+    }
+
+    // Note that a flush at `now` did (`held`) or didn't have to hold
+    // something back. True the once it has had to every time for
+    // `warn_after`, until one doesn't.
+    pub fn flushed(&mut self, held: bool, now: Instant) -> bool {
+        self.roll(now);
+        if !held {
+            self.queuing = None;
+            self.warned = false;
+            return false;
+        }
+        let since = self.queuing.unwrap_or(now);
+        self.queuing = Some(since);
+        if !self.warned && now - since >= self.config.warn_after {
+            self.warned = true;
+            return true;
+        }
+        false
+    }
+
+    // How much of the cap the last whole second used, in percent; 0 with
+    // no cap.
+    pub fn utilization(&self) -> u32 {
+        if self.config.cap == 0 {
+            return 0;
+        }
+        (self.last_second * 100 / self.config.cap) as u32
+    }
+}
+
+#[cfg(test)]
+fn test_config() -> BandwidthConfig {
+    BandwidthConfig {
+        cap: 1000,
+        reserved: 20,
+        burst: Duration::from_secs(1),
+        warn_after: Duration::from_secs(2),
+    }
+}
+
+#[test]
+fn overhead_is_counted() {
+    let v4: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    let v6: SocketAddr = "[::1]:9000".parse().unwrap();
+    assert_eq!(wire_bytes(100, &v4), 166);
+    assert_eq!(wire_bytes(100, &v6), 186);
+}
+
+#[test]
+fn bulk_leaves_the_reserve_to_control() {
+    let mut bandwidth = Bandwidth::new(test_config());
+    let now = Instant::now();
+    // 800 general, 200 reserved.
+    assert!(bandwidth.admit(Band::Bulk, 500, now));
+    assert!(bandwidth.admit(Band::Bulk, 300, now));
+    assert!(!bandwidth.admit(Band::Bulk, 1, now));
+    assert!(bandwidth.admit(Band::Probe, 150, now));
+    assert!(bandwidth.admit(Band::Control, 50, now));
+    assert!(!bandwidth.admit(Band::Control, 1, now));
+
+    // The reserve fills first: a tenth of a second is a hundred bytes.
+    let later = now + Duration::from_millis(100);
+    assert!(!bandwidth.admit(Band::Bulk, 1, later));
+    assert!(bandwidth.admit(Band::Control, 90, later));
+    // And then spills over.
+    let later = later + Duration::from_millis(300);
+    assert!(bandwidth.admit(Band::Bulk, 100, later));
+    assert!(!bandwidth.admit(Band::Bulk, 20, later));
+}
+
+#[test]
+fn a_datagram_bigger_than_the_bucket_goes_once_it_is_full() {
+    let mut bandwidth = Bandwidth::new(BandwidthConfig {
+        burst: Duration::from_millis(100),
+        .. test_config()
+    });
+    let now = Instant::now();
+    assert!(bandwidth.admit(Band::Control, 300, now));
+    // Owing 200, it takes 300ms to be full again.
+    let later = now + Duration::from_millis(250);
+    assert!(!bandwidth.admit(Band::Control, 300, later));
+    let later = now + Duration::from_millis(350);
+    assert!(bandwidth.admit(Band::Control, 300, later));
+}
+
+#[test]
+fn sustained_queuing_warns_once_and_utilization_is_per_second() {
+    let mut bandwidth = Bandwidth::new(test_config());
+    let start = Instant::now();
+    let at = |ms: u64| start + Duration::from_millis(ms);
+    assert!(bandwidth.admit(Band::Bulk, 500, at(0)));
+    assert!(!bandwidth.flushed(true, at(0)));
+    assert!(!bandwidth.flushed(true, at(1500)));
+    assert_eq!(bandwidth.utilization(), 50);
+    assert!(bandwidth.flushed(true, at(2000)));
+    assert!(!bandwidth.flushed(true, at(2500)));
+
+    // It has to stop before it counts as starting again.
+    assert!(!bandwidth.flushed(false, at(3000)));
+    assert!(!bandwidth.flushed(true, at(3100)));
+    assert!(bandwidth.flushed(true, at(5100)));
+    assert_eq!(bandwidth.utilization(), 0);
+
+    let mut uncapped = Bandwidth::new(BandwidthConfig::default());
+    assert!(uncapped.admit(Band::Bulk, 1 << 20, start));
+    assert_eq!(uncapped.utilization(), 0);
+}
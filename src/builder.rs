@@ -0,0 +1,879 @@
+use admission::{Admission, JoinCandidate};
+use chaos::Chaos;
+use clock::{Nanos, SystemClock};
+use config::Config;
+use dispatch::Dispatcher;
+use dump::DebugDump;
+use error::MeshError;
+use event::{EventQueue, MeshEvent, Overflow};
+use history::{Change, History};
+use members::{NodeId, Peer, PeerMatch, PeerState, Snapshot};
+use node;
+use size::SizeEstimate;
+use sockopts::SocketInfo;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub type EventHandler = Fn(MeshEvent) + Send;
+
+pub type JoinRequestHandler = Fn(&JoinCandidate) -> Admission + Send;
+
+// A subscriber's queue, shared with the node's thread, which signals the
+// condvar on each event it adds. Like the node's history, it's locked
+// only to add or take events, and never along with another lock; an
+// on_event handler is called with no lock held at all.
+type Subscriber = Arc<(Mutex<EventQueue>, Condvar)>;
+
+// Where the node's thread sends its socket's details, when asked
+// through Mesh::socket_info.
+type SocketReply = mpsc::Sender<Option<SocketInfo>>;
+
+// And everything else, through Mesh::debug_dump.
+type DumpReply = mpsc::Sender<DebugDump>;
+
+// And how big the mesh is, through Mesh::estimated_size.
+type SizeReply = mpsc::Sender<SizeEstimate>;
+
+// And what its timer has pending, through Mesh::timers.
+type TimersReply = mpsc::Sender<Vec<(String, Nanos)>>;
+
+// What an operator asks of a peer through Mesh::evict or
+// Mesh::probe_now, of the node through Mesh::set_tag or Mesh::reload, or
+// of a newcomer through Mesh::resolve_join (as the admission thread does
+// too), and where the node's thread sends what came of it.
+enum Operation {
+    Evict(NodeId),
+    ProbeNow(NodeId),
+    SetTag(String, String),
+    ResolveJoin(NodeId, Admission),
+    Reload(Config),
+}
+type OperationReply = (Operation, mpsc::Sender<Result<(), MeshError>>);
+
+// How many events Mesh::wait_for_member's subscription holds while it
+// looks through them.
+const WAIT_QUEUE: usize = 256;
+
+// Sets up a Mesh: a node running on a thread of its own. Starts from
+// Config::default, the same defaults the binary has.
+pub struct MeshBuilder {
+    config: Config,
+    join: Vec<String>,
+    on_event: Option<Box<EventHandler>>,
+    on_join_request: Option<Box<JoinRequestHandler>>,
+    // Called on the node's thread with the bound address, before anything
+    // else is set up, so tests can send to a node that isn't ready yet.
+    #[cfg(test)]
+    starting: Option<Box<Fn(SocketAddr) + Send>>,
+}
+
+impl MeshBuilder {
+    pub fn new() -> MeshBuilder {
+        MeshBuilder {
+            config: Config::default(),
+            join: Vec::new(),
+            on_event: None,
+            on_join_request: None,
+            #[cfg(test)]
+            starting: None,
+        }
+    }
+
+    pub fn host(mut self, host: &str) -> MeshBuilder {
+        self.config.host = host.to_string();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> MeshBuilder {
+        self.config.port = port;
+        self
+    }
+
+    pub fn cluster(mut self, cluster: &str) -> MeshBuilder {
+        self.config.cluster = cluster.to_string();
+        self
+    }
+
+    // Bounds on the probe interval; see probe::probe_interval.
+    pub fn probe_interval(mut self, min: Duration, max: Duration)
+            -> MeshBuilder {
+        self.config.probe.min_interval = min;
+        self.config.probe.max_interval = max;
+        self
+    }
+
+    // Everything else.
+    pub fn config(mut self, config: Config) -> MeshBuilder {
+        self.config = config;
+        self
+    }
+
+    // Join `target` once running. May be given more than once.
+    pub fn join(mut self, target: &str) -> MeshBuilder {
+        self.join.push(target.to_string());
+        self
+    }
+
+    // Have `handler` called with each event, on the node's thread.
+    // Without one, events are dropped.
+    pub fn on_event<F>(mut self, handler: F) -> MeshBuilder
+            where F: Fn(MeshEvent) + Send + 'static {
+        self.on_event = Some(Box::new(handler));
+        self
+    }
+
+    // Have every newcomer wait to join until `handler` lets it in, turns
+    // it away, or defers it to Mesh::resolve_join; see
+    // Dispatcher::require_admission. The handler is called on a thread of
+    // its own, one newcomer at a time, so that it may take its time (to
+    // ask a deployment database, say) without holding the node up.
+    pub fn on_join_request<F>(mut self, handler: F) -> MeshBuilder
+            where F: Fn(&JoinCandidate) -> Admission + Send + 'static {
+        self.on_join_request = Some(Box::new(handler));
+        self
+    }
+
+    // Check the settings as the binary does, bind the socket and start
+    // the node's thread. Once this returns, the node is receiving.
+    //
+    // The socket is bound first, so that its errors are ours to return,
+    // but nothing reads it until the dispatcher is whole and its joins
+    // are queued: whatever arrives in between, a seed's quick Ack
+    // included, waits in the socket's receive buffer (see
+    // Config::recv_buffer_size) and is handled once, by the first poll.
+    // Past the buffer's size the kernel drops it.
+    pub fn build(self) -> Result<Mesh, MeshError> {
+        try!(self.config.validate());
+        let mut targets = Vec::new();
+        for target in self.join.iter() {
+            targets.push(try!(node::resolve(target)
+                .map_err(|e| MeshError::InvalidTarget(e.to_string()))));
+        }
+        let config = self.config;
+        let socket = try!(config.socket_opts()
+            .bind((&config.host[..], config.port))
+            .map_err(|e| MeshError::Bind(format!("{}:{}: {}", config.host,
+                                                 config.port, e))));
+        let addr = try!(socket.local_addr()
+            .map_err(|e| MeshError::Bind(e.to_string())));
+
+        let on_event = self.on_event;
+        #[cfg(test)]
+        let starting = self.starting;
+        #[cfg(not(test))]
+        let starting: Option<Box<Fn(SocketAddr) + Send>> = None;
+        let (tx, rx) = mpsc::channel();
+        let (subscribe, subscriptions) = mpsc::channel::<Subscriber>();
+        let (ask_socket, socket_asked) = mpsc::channel::<SocketReply>();
+        let (operate, operations) = mpsc::channel::<OperationReply>();
+        let (ask_dump, dump_asked) = mpsc::channel::<DumpReply>();
+        let (ask_size, size_asked) = mpsc::channel::<SizeReply>();
+        let (ask_timers, timers_asked) = mpsc::channel::<TimersReply>();
+        // Newcomers for the admission thread to decide on, which runs
+        // until the node's thread, and so this sender, is gone.
+        let requests = self.on_join_request.map(|handler| {
+            let (request, requested) = mpsc::channel::<JoinCandidate>();
+            let admit = operate.clone();
+            thread::spawn(move || {
+                for candidate in requested.iter() {
+                    let decision = handler(&candidate);
+                    if decision == Admission::Defer {
+                        continue;
+                    }
+                    let (tx, _) = mpsc::channel();
+                    let resolve = Operation::ResolveJoin(candidate.id,
+                                                         decision);
+                    if admit.send((resolve, tx)).is_err() {
+                        break;
+                    }
+                }
+            });
+            request
+        });
+        let thread = thread::spawn(move || {
+            let mut config = config;
+            if let Some(starting) = starting {
+                starting(addr);
+            }
+            let mut subscribers = Vec::new();
+            let socket = Chaos::new(socket, config.chaos.clone());
+            let mut node = Dispatcher::with_config(socket, SystemClock,
+                                                   &config);
+            if requests.is_some() {
+                node.require_admission();
+            }
+            let shutdown = node.shutdown_handle();
+            tx.send((node.node_id(), shutdown.clone(), node.history_handle(),
+                     node.converged_handle(), node.snapshot_handle()))
+                .unwrap();
+            for target in targets.iter() {
+                node.join(target);
+            }
+            while !shutdown.load(Ordering::SeqCst) {
+                node.poll();
+                // Taken on after polling, so a subscriber sees everything
+                // that happened after it subscribed.
+                while let Ok(s) = subscriptions.try_recv() {
+                    subscribers.push(s);
+                }
+                while let Ok(reply) = socket_asked.try_recv() {
+                    let _ = reply.send(node.socket_info().ok());
+                }
+                while let Ok(reply) = dump_asked.try_recv() {
+                    let _ = reply.send(node.debug_dump(&config));
+                }
+                while let Ok(reply) = size_asked.try_recv() {
+                    let _ = reply.send(node.estimated_size());
+                }
+                while let Ok(reply) = timers_asked.try_recv() {
+                    let _ = reply.send(node.timers());
+                }
+                while let Ok((operation, reply)) = operations.try_recv() {
+                    let _ = reply.send(match operation {
+                        Operation::Evict(id) => node.evict(id),
+                        Operation::ProbeNow(id) => node.probe_now(id),
+                        Operation::SetTag(key, value) =>
+                            node.set_tag(&key, &value),
+                        Operation::ResolveJoin(id, decision) =>
+                            node.resolve_join(id, decision),
+                        Operation::Reload(new) => {
+                            node::reload(&mut node, &config, &new);
+                            config = config.reloaded(&new);
+                            Ok(())
+                        },
+                    });
+                }
+                // Nobody is listening to a queue only we hold.
+                subscribers.retain(|s| Arc::strong_count(s) > 1);
+                while let Some(event) = node.next_event() {
+                    if let MeshEvent::JoinRequested(ref candidate) = event {
+                        if let Some(ref requests) = requests {
+                            let _ = requests.send(candidate.clone());
+                        }
+                    }
+                    for s in subscribers.iter() {
+                        let &(ref queue, ref ready) = &**s;
+                        queue.lock().unwrap().push(event.clone());
+                        ready.notify_one();
+                    }
+                    if let Some(ref handler) = on_event {
+                        handler(event);
+                    }
+                }
+            }
+        });
+        let (id, shutdown, history, converged, snapshot) = rx.recv().unwrap();
+        Ok(Mesh {
+            id: id,
+            addr: addr,
+            shutdown: shutdown,
+            history: history,
+            converged: converged,
+            snapshot: snapshot,
+            subscriptions: subscribe,
+            ask_socket: ask_socket,
+            operate: operate,
+            ask_dump: ask_dump,
+            ask_size: ask_size,
+            ask_timers: ask_timers,
+            thread: Some(thread),
+        })
+    }
+}
+
+// A running node, as started by MeshBuilder. Dropping it shuts the node
+// down and waits for its thread to finish.
+pub struct Mesh {
+    id: NodeId,
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    history: Arc<Mutex<History>>,
+    converged: Arc<AtomicBool>,
+    snapshot: Arc<Mutex<Arc<Snapshot>>>,
+    // Where new subscribers are sent for the node's thread to take on.
+    subscriptions: mpsc::Sender<Subscriber>,
+    ask_socket: mpsc::Sender<SocketReply>,
+    operate: mpsc::Sender<OperationReply>,
+    ask_dump: mpsc::Sender<DumpReply>,
+    ask_size: mpsc::Sender<SizeReply>,
+    ask_timers: mpsc::Sender<TimersReply>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Mesh {
+    pub fn node_id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // The node's shutdown flag; see Dispatcher::shutdown_handle. Once the
+    // node has stopped this is the only reference left to it.
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    // The node's latest membership changes, newest first, only `peer`'s
+    // if given; see Dispatcher::history.
+    pub fn history(&self, peer: Option<NodeId>) -> Vec<Change> {
+        self.history.lock().unwrap().changes(peer)
+    }
+
+    // Whether the node has caught up with the mesh since joining it; see
+    // MeshEvent::Converged.
+    pub fn is_converged(&self) -> bool {
+        self.converged.load(Ordering::SeqCst)
+    }
+
+    // The node's peers, as of the end of its last poll, or as it stopped;
+    // see Dispatcher::snapshot_handle. Asking again while nothing has
+    // changed gets the same snapshot, without copying a thing, and never
+    // waits on the node.
+    pub fn peers(&self) -> Arc<Snapshot> {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    // What the node's socket is bound to and how it's set up, as of its
+    // next poll; see sockopts::SocketInfo. None once the node has
+    // stopped, or where the platform can't say.
+    pub fn socket_info(&self) -> Option<SocketInfo> {
+        let (tx, rx) = mpsc::channel();
+        if self.ask_socket.send(tx).is_err() {
+            return None;
+        }
+        rx.recv().ok().and_then(|info| info)
+    }
+
+    // Everything about the node worth attaching to a bug report, as of
+    // its next poll; see Dispatcher::debug_dump. None once the node has
+    // stopped.
+    pub fn debug_dump(&self) -> Option<DebugDump> {
+        self.request_dump().and_then(|rx| rx.recv().ok())
+    }
+
+    // As debug_dump, without waiting for it: it comes through what's
+    // returned, unless the node stops first.
+    pub fn request_dump(&self) -> Option<mpsc::Receiver<DebugDump>> {
+        let (tx, rx) = mpsc::channel();
+        if self.ask_dump.send(tx).is_err() {
+            return None;
+        }
+        Some(rx)
+    }
+
+    // How big the mesh is by its nodes' own counts, and how far apart
+    // those are, as of the node's next poll; see
+    // Dispatcher::estimated_size. None once the node has stopped.
+    pub fn estimated_size(&self) -> Option<SizeEstimate> {
+        let (tx, rx) = mpsc::channel();
+        if self.ask_size.send(tx).is_err() {
+            return None;
+        }
+        rx.recv().ok()
+    }
+
+    // What the node's timer has pending, each event's label and time
+    // remaining, soonest first, as of its next poll; see
+    // Dispatcher::timers. None once the node has stopped.
+    pub fn timers(&self) -> Option<Vec<(String, Nanos)>> {
+        let (tx, rx) = mpsc::channel();
+        if self.ask_timers.send(tx).is_err() {
+            return None;
+        }
+        rx.recv().ok()
+    }
+
+    // Have the node declare peer `id` Dead, and keep it so; see
+    // Dispatcher::evict.
+    pub fn evict(&self, id: NodeId) -> Result<(), MeshError> {
+        self.operate_on(Operation::Evict(id))
+    }
+
+    // Have the node probe peer `id` at once, reviving it if it answers;
+    // see Dispatcher::probe_now.
+    pub fn probe_now(&self, id: NodeId) -> Result<(), MeshError> {
+        self.operate_on(Operation::ProbeNow(id))
+    }
+
+    // Have the node set its tag `key` to `value`, and tell its peers; see
+    // Dispatcher::set_tag.
+    pub fn set_tag(&self, key: &str, value: &str) -> Result<(), MeshError> {
+        self.operate_on(Operation::SetTag(key.to_string(), value.to_string()))
+    }
+
+    // Let newcomer `id`, whose Join waits for a decision, in, or turn it
+    // away, or leave it waiting; see Dispatcher::resolve_join. For a
+    // handler given to MeshBuilder::on_join_request that deferred it.
+    pub fn resolve_join(&self, id: NodeId, decision: Admission)
+            -> Result<(), MeshError> {
+        self.operate_on(Operation::ResolveJoin(id, decision))
+    }
+
+    // Have the node switch over to `config` as far as it can without a
+    // restart, warning about each field that can't; see node::reload.
+    pub fn reload(&self, config: &Config) -> Result<(), MeshError> {
+        self.operate_on(Operation::Reload(config.clone()))
+    }
+
+    fn operate_on(&self, operation: Operation) -> Result<(), MeshError> {
+        let (tx, rx) = mpsc::channel();
+        if self.operate.send((operation, tx)).is_err() {
+            return Err(MeshError::Stopped);
+        }
+        rx.recv().unwrap_or(Err(MeshError::Stopped))
+    }
+
+    // Wait up to `timeout` for the node to have a peer `wanted` matches,
+    // not Dead, and return it as it was then: a peer that joined and has
+    // died since is still returned. A peer already there is returned at
+    // once. Membership events are subscribed to before the table is
+    // looked at, so a peer that turns up in between is caught by one or
+    // the other, and the table is looked at again should the queue ever
+    // lose an event. A peer whose event is queued by the deadline wins
+    // over the deadline, however late it's looked at: every queued event
+    // is gone through before giving up. Fails with Timeout, or Stopped if
+    // the node has stopped.
+    pub fn wait_for_member(&self, wanted: PeerMatch, timeout: Duration)
+            -> Result<Peer, MeshError> {
+        let found = |p: &Peer| {
+            p.state() != PeerState::Dead && wanted.matches(p)
+        };
+        let events = self.subscribe(WAIT_QUEUE, Overflow::DropNewest);
+        let deadline = Instant::now() + timeout;
+        let mut dropped = None;
+        loop {
+            if dropped != Some(events.dropped()) {
+                dropped = Some(events.dropped());
+                if let Some(p) = self.peers().peers.iter().find(|p| found(p)) {
+                    return Ok(p.clone());
+                }
+            }
+            let now = Instant::now();
+            let event = if now < deadline {
+                events.next_timeout(deadline - now)
+            } else {
+                events.try_next()
+            };
+            match event {
+                Some(MeshEvent::PeerJoined(p)) |
+                Some(MeshEvent::PeerStateChanged(p)) |
+                Some(MeshEvent::PeerTagsChanged(p)) => if found(&p) {
+                    return Ok(p);
+                },
+                // Carrying no Peer, it's looked for in the table, which
+                // already has it.
+                Some(MeshEvent::PeerRestarted { .. }) => dropped = None,
+                Some(_) => (),
+                // Woken early, or the deadline has only just passed.
+                None if Instant::now() < deadline => (),
+                None if self.shutdown.load(Ordering::SeqCst) =>
+                    return Err(MeshError::Stopped),
+                None => return Err(MeshError::Timeout),
+            }
+        }
+    }
+
+    // Receive every event from now on, as well as any on_event handler
+    // does, through a queue of `capacity` events. Unlike a handler, a
+    // subscriber that falls behind doesn't hold up the node: once its
+    // queue is full, `overflow` decides which events it loses.
+    pub fn subscribe(&self, capacity: usize, overflow: Overflow)
+            -> Subscription {
+        let shared = Arc::new((Mutex::new(EventQueue::new(capacity, overflow)),
+                               Condvar::new()));
+        // Only fails once the node has stopped, when there'd be nothing
+        // to receive anyway.
+        let _ = self.subscriptions.send(shared.clone());
+        Subscription { shared: shared }
+    }
+}
+
+// Events for one subscriber, from Mesh::subscribe. Dropping it
+// unsubscribes.
+pub struct Subscription {
+    shared: Subscriber,
+}
+
+impl Subscription {
+    // The next event, if there's one waiting.
+    pub fn try_next(&self) -> Option<MeshEvent> {
+        self.shared.0.lock().unwrap().pop()
+    }
+
+    // The next event, waiting up to `timeout` for one.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<MeshEvent> {
+        let &(ref queue, ref ready) = &*self.shared;
+        let mut queue = queue.lock().unwrap();
+        if queue.is_empty() {
+            queue = ready.wait_timeout(queue, timeout).unwrap().0;
+        }
+        queue.pop()
+    }
+
+    // Events this subscriber has lost to its Overflow policy.
+    pub fn dropped(&self) -> u64 {
+        self.shared.0.lock().unwrap().dropped()
+    }
+
+    // Membership events this subscriber had replaced by later ones; see
+    // Overflow::Coalesce.
+    pub fn coalesced(&self) -> u64 {
+        self.shared.0.lock().unwrap().coalesced()
+    }
+}
+
+impl Drop for Mesh {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // A node whose thread panicked, say in an on_event handler, has
+        // stopped already. It's no reason to panic again here, possibly
+        // while unwinding from some other panic.
+        if let Some(Err(e)) = self.thread.take().map(|t| t.join()) {
+            let why = e.downcast_ref::<&'static str>().map(|s| s.to_string())
+                .or_else(|| e.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            println!("WARNING: node {}'s thread panicked: {}", self.id, why);
+        }
+    }
+}
+
+#[test]
+fn builder_defaults_match_the_binary() {
+    let builder = MeshBuilder::new();
+    let cli = ::cli::parse(vec!["mesh"]).unwrap().config();
+    assert_eq!(builder.config.host, cli.host);
+    assert_eq!(builder.config.port, cli.port);
+    assert_eq!(builder.config.cluster, cli.cluster);
+    assert_eq!(builder.config.json, cli.json);
+    assert_eq!(builder.config.probe, cli.probe);
+}
+
+#[test]
+fn build_rejects_bad_settings() {
+    fn error(builder: MeshBuilder) -> MeshError {
+        match builder.build() {
+            Err(e) => e,
+            Ok(_) => panic!("expected build to fail"),
+        }
+    }
+
+    assert_eq!(error(MeshBuilder::new().cluster("")),
+               MeshError::InvalidCluster("".to_string()));
+    assert_eq!(error(MeshBuilder::new().probe_interval(
+                   Duration::from_secs(5), Duration::from_secs(1))),
+               MeshError::InvalidProbeInterval);
+    match error(MeshBuilder::new().join("255.255.255.255:9000")) {
+        MeshError::InvalidTarget(..) => (),
+        other => panic!("expected InvalidTarget, got {:?}", other),
+    }
+
+    let taken = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    match error(MeshBuilder::new().port(taken.local_addr().unwrap().port())) {
+        MeshError::Bind(..) => (),
+        other => panic!("expected Bind, got {:?}", other),
+    }
+}
+
+#[test]
+fn two_built_meshes_join_and_shut_down_on_drop() {
+    let seed = MeshBuilder::new().build().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let joiner = MeshBuilder::new()
+        .join(&seed.local_addr().to_string())
+        .on_event(move |event| { let _ = tx.send(event); })
+        .build().unwrap();
+
+    let mut joined = None;
+    while joined.is_none() {
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        if let MeshEvent::JoinCompleted { status, .. } = event {
+            joined = Some(status);
+        }
+    }
+    match joined.unwrap() {
+        ::join::JoinStatus::Joined(peer) => {
+            assert_eq!(peer.id(), seed.node_id());
+            assert_eq!(peer.addr(), seed.local_addr());
+        },
+        other => panic!("expected Joined, got {:?}", other),
+    }
+    let peers = &joiner.peers().peers;
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].id(), seed.node_id());
+    let history = joiner.history(Some(seed.node_id()));
+    assert_eq!(history[0].to, ::members::PeerState::Alive);
+    assert_eq!(history[0].cause, ::history::Cause::Join);
+
+    let handles = vec![seed.shutdown_handle(), joiner.shutdown_handle()];
+    drop(seed);
+    drop(joiner);
+    for handle in handles {
+        assert_eq!(Arc::strong_count(&handle), 1);
+    }
+}
+
+#[test]
+fn dropping_a_mesh_whose_thread_panicked_leaves_it_be() {
+    let seed = MeshBuilder::new().build().unwrap();
+    let joiner = MeshBuilder::new()
+        .join(&seed.local_addr().to_string())
+        .on_event(|event| panic!("the handler choked on {:?}", event))
+        .build().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while joiner.debug_dump().is_some() {
+        assert!(Instant::now() < deadline, "still running after 5s");
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(joiner);
+}
+
+#[test]
+fn datagrams_arriving_during_startup_are_handled_once() {
+    use message::{AckedMessage, Message, Seq, PROTOCOL_VERSION};
+    use std::net::UdpSocket;
+    use std::time::Instant;
+
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let sender = peer.try_clone().unwrap();
+    let mut builder = MeshBuilder::new();
+    // A Join sent the moment the socket is bound, and the node slow to
+    // start after it.
+    builder.starting = Some(Box::new(move |addr| {
+        let join = AckedMessage::Join(NodeId(7), PROTOCOL_VERSION);
+        let join = Message::Acked(Seq::new(1, 1), join);
+        sender.send_to(&join.encode(), addr).unwrap();
+        thread::sleep(Duration::from_millis(200));
+    }));
+    let mesh = builder.build().unwrap();
+
+    let mut acks = 0;
+    let mut buf = [0; 1500];
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while Instant::now() < deadline {
+        if let Ok((len, _)) = peer.recv_from(&mut buf) {
+            match ::message::parse_datagram(&buf[..len]) {
+                Ok(Message::Ack(seq, ..)) if seq == Seq::new(1, 1) =>
+                    acks += 1,
+                _ => (),
+            }
+        }
+    }
+    assert_eq!(acks, 1);
+    assert_eq!(mesh.history(Some(NodeId(7))).len(), 1);
+}
+
+#[test]
+fn subscribers_see_peers_join() {
+    let seed = MeshBuilder::new().build().unwrap();
+    let events = seed.subscribe(16, Overflow::Coalesce);
+    let joiner = MeshBuilder::new()
+        .join(&seed.local_addr().to_string())
+        .build().unwrap();
+
+    loop {
+        match events.next_timeout(Duration::from_secs(5)) {
+            Some(MeshEvent::PeerJoined(peer)) => {
+                assert_eq!(peer.id(), joiner.node_id());
+                break;
+            },
+            Some(_) => (),
+            None => panic!("no PeerJoined within 5s"),
+        }
+    }
+    assert_eq!(events.dropped(), 0);
+}
+
+#[test]
+fn socket_info_describes_the_bound_socket() {
+    use protocol::limits::TICK;
+    use sockopts::Family;
+
+    let mesh = MeshBuilder::new().build().unwrap();
+    let info = mesh.socket_info().unwrap();
+    assert_eq!(info.local_addr, mesh.local_addr());
+    assert_eq!(info.family, Family::V4);
+    assert!(info.recv_buffer_bytes > 0);
+    // Give or take the kernel rounding it to its own ticks.
+    let read_timeout = info.read_timeout.unwrap();
+    assert!(read_timeout >= TICK);
+    assert!(read_timeout < TICK + Duration::from_millis(20));
+}
+
+#[test]
+fn two_nodes_agree_on_the_size_of_the_mesh() {
+    use std::time::Instant;
+
+    let fast = |b: MeshBuilder| {
+        b.probe_interval(Duration::from_millis(10), Duration::from_millis(20))
+    };
+    let seed = fast(MeshBuilder::new()).build().unwrap();
+    assert_eq!(seed.estimated_size(),
+               Some(SizeEstimate { size: 1, min: 1, max: 1, reports: 1 }));
+    let joiner = fast(MeshBuilder::new())
+        .join(&seed.local_addr().to_string())
+        .build().unwrap();
+    // Once each has had a Pong from the other.
+    let both = Some(SizeEstimate { size: 2, min: 2, max: 2, reports: 2 });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while seed.estimated_size() != both || joiner.estimated_size() != both {
+        assert!(Instant::now() < deadline, "{:?} and {:?} after 5s",
+                seed.estimated_size(), joiner.estimated_size());
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn a_busy_node_answers_while_subscribers_drain() {
+    use std::time::Instant;
+
+    let fast = |b: MeshBuilder| {
+        b.probe_interval(Duration::from_millis(10), Duration::from_millis(20))
+    };
+    let seed = fast(MeshBuilder::new()).build().unwrap();
+    let subscriptions: Vec<Subscription> = (0..3)
+        .map(|_| seed.subscribe(4, Overflow::DropOldest)).collect();
+    let joiner = fast(MeshBuilder::new())
+        .join(&seed.local_addr().to_string())
+        .build().unwrap();
+    let joined = Instant::now() + Duration::from_secs(5);
+    while seed.peers().peers.is_empty() {
+        assert!(Instant::now() < joined, "not joined within 5s");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // The node probing and answering probes on its own thread, three
+    // subscribers draining it on theirs, and this one asking it things.
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let drains: Vec<_> = subscriptions.into_iter().map(|events| {
+        thread::spawn(move || {
+            while Instant::now() < deadline {
+                events.next_timeout(Duration::from_millis(10));
+            }
+        })
+    }).collect();
+    while Instant::now() < deadline {
+        seed.peers();
+        seed.history(None);
+        seed.debug_dump().unwrap();
+        seed.probe_now(joiner.node_id()).unwrap();
+    }
+    for d in drains {
+        d.join().unwrap();
+    }
+    let peers = &seed.peers().peers;
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].state(), ::members::PeerState::Alive);
+}
+
+#[test]
+fn waiting_for_a_member_already_there_returns_at_once() {
+    let seed = MeshBuilder::new().build().unwrap();
+    let joiner = MeshBuilder::new()
+        .join(&seed.local_addr().to_string())
+        .build().unwrap();
+    let joined = Instant::now() + Duration::from_secs(5);
+    while seed.peers().peers.is_empty() {
+        assert!(Instant::now() < joined, "not joined within 5s");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let wanted = PeerMatch::Addr(joiner.local_addr());
+    let peer = seed.wait_for_member(wanted, Duration::from_millis(0))
+        .unwrap();
+    assert_eq!(peer.id(), joiner.node_id());
+}
+
+#[test]
+fn waiting_for_a_member_returns_once_it_turns_up() {
+    let seed = MeshBuilder::new().build().unwrap();
+    let seed_id = seed.node_id();
+    let joiner = MeshBuilder::new()
+        .join(&seed.local_addr().to_string())
+        .build().unwrap();
+    let wait = Duration::from_secs(5);
+    // Most likely before the seed has answered the Join.
+    let peer = joiner.wait_for_member(PeerMatch::Id(seed_id), wait).unwrap();
+    assert_eq!(peer.id(), seed_id);
+
+    // And a peer there all along that comes to match later.
+    let setting = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        seed.set_tag("role", "active").unwrap();
+        seed
+    });
+    let active = PeerMatch::Tag("role".to_string(), "active".to_string());
+    let peer = joiner.wait_for_member(active, wait).unwrap();
+    assert_eq!(peer.id(), seed_id);
+    assert_eq!(peer.tags().get("role").map(|v| &v[..]), Some("active"));
+    setting.join().unwrap();
+}
+
+#[test]
+fn waiting_for_a_member_that_never_comes_times_out() {
+    let mesh = MeshBuilder::new().build().unwrap();
+    let start = Instant::now();
+    let wait = Duration::from_millis(50);
+    match mesh.wait_for_member(PeerMatch::Id(NodeId(7)), wait) {
+        Err(MeshError::Timeout) => (),
+        other => panic!("expected Timeout, got {:?}", other),
+    }
+    assert!(start.elapsed() >= wait);
+
+    let stopped = mesh.shutdown_handle();
+    stopped.store(true, Ordering::SeqCst);
+    match mesh.wait_for_member(PeerMatch::Id(NodeId(7)), wait) {
+        Err(MeshError::Stopped) => (),
+        other => panic!("expected Stopped, got {:?}", other),
+    }
+}
+
+#[test]
+fn newcomers_wait_on_the_admission_handler() {
+    let (tx, requests) = mpsc::channel();
+    let asked = ::std::sync::atomic::AtomicUsize::new(0);
+    let seed = MeshBuilder::new()
+        .on_join_request(move |candidate| {
+            let _ = tx.send(candidate.clone());
+            if asked.fetch_add(1, Ordering::SeqCst) == 0 {
+                Admission::Defer
+            } else {
+                Admission::Reject("not in the inventory".to_string())
+            }
+        })
+        .build().unwrap();
+    let wait = Duration::from_secs(5);
+
+    // Deferred, and let in later.
+    let first = MeshBuilder::new()
+        .join(&seed.local_addr().to_string())
+        .build().unwrap();
+    let candidate: JoinCandidate = requests.recv_timeout(wait).unwrap();
+    assert_eq!(candidate.id, first.node_id());
+    assert_eq!(seed.resolve_join(candidate.id, Admission::Accept), Ok(()));
+    let peer = seed.wait_for_member(PeerMatch::Id(first.node_id()), wait)
+        .unwrap();
+    assert_eq!(peer.addr(), first.local_addr());
+
+    // Turned away.
+    let (tx, rx) = mpsc::channel();
+    let _second = MeshBuilder::new()
+        .join(&seed.local_addr().to_string())
+        .on_event(move |event| { let _ = tx.send(event); })
+        .build().unwrap();
+    loop {
+        let event = rx.recv_timeout(wait).unwrap();
+        if let MeshEvent::JoinCompleted { status, .. } = event {
+            let reason = "not in the inventory".to_string();
+            assert_eq!(status, ::join::JoinStatus::Failed(
+                MeshError::NotAdmitted(reason)));
+            break;
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use clock::Nanos;
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+use rustc_serialize::{Decodable, Decoder};
+use scheduler::Timer;
+use sockopts::SocketInfo;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use transport::{Connected, Transport};
+
+// Failures to inject into a node's traffic, for soak testing. Every
+// datagram, sent or received, is lost with probability `drop`; otherwise
+// it has a bit flipped with probability `corrupt`, arrives twice with
+// `dup`, and each copy is held back for a time drawn from `delay`, if
+// given. Written as `drop=0.1,dup=0.02,delay=5..50ms,corrupt=0.01,seed=7`
+// (see `parse`), leaving out whatever shouldn't happen. With no seed, one
+// is picked at random; see Chaos::new.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChaosConfig {
+    pub drop: f64,
+    pub dup: f64,
+    pub corrupt: f64,
+    pub delay: Option<(Duration, Duration)>,
+    pub seed: Option<u64>,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> ChaosConfig {
+        ChaosConfig {
+            drop: 0.0,
+            dup: 0.0,
+            corrupt: 0.0,
+            delay: None,
+            seed: None,
+        }
+    }
+}
+
+impl ChaosConfig {
+    pub fn parse(spec: &str) -> Result<ChaosConfig, String> {
+        let mut config = ChaosConfig::default();
+        for field in spec.split(',') {
+            let mut kv = field.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => return Err(format!("expected key=value, got {:?}",
+                                        field)),
+            };
+            match key {
+                "drop" => config.drop = try!(probability(key, value)),
+                "dup" => config.dup = try!(probability(key, value)),
+                "corrupt" => config.corrupt = try!(probability(key, value)),
+                "delay" => config.delay = Some(try!(delay(value))),
+                "seed" => config.seed = Some(try!(value.parse().map_err(
+                    |_| format!("seed {:?} isn't a number", value)))),
+                _ => return Err(format!("unknown chaos {:?}", key)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn probability(key: &str, value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(p) if p >= 0.0 && p <= 1.0 => Ok(p),
+        _ => Err(format!("{} {:?} isn't a probability from 0 to 1", key,
+                         value)),
+    }
+}
+
+// `5..50ms`, or `20ms` for always the same.
+fn delay(value: &str) -> Result<(Duration, Duration), String> {
+    let bad = || format!("delay {:?} isn't like 5..50ms", value);
+    if !value.ends_with("ms") {
+        return Err(bad());
+    }
+    let range = &value[..value.len() - 2];
+    let mut bounds = range.splitn(2, "..");
+    let low = try!(bounds.next().unwrap().parse::<u64>().map_err(|_| bad()));
+    let high = match bounds.next() {
+        Some(high) => try!(high.parse::<u64>().map_err(|_| bad())),
+        None => low,
+    };
+    if low > high {
+        return Err(bad());
+    }
+    Ok((Duration::from_millis(low), Duration::from_millis(high)))
+}
+
+// Written back as `parse` reads it, so that a run's printed config (seed
+// and all) reproduces it.
+impl fmt::Display for ChaosConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut fields = Vec::new();
+        if self.drop > 0.0 {
+            fields.push(format!("drop={}", self.drop));
+        }
+        if self.dup > 0.0 {
+            fields.push(format!("dup={}", self.dup));
+        }
+        if let Some((low, high)) = self.delay {
+            let (low, high) = (millis(low), millis(high));
+            fields.push(if low == high { format!("delay={}ms", low) }
+                        else { format!("delay={}..{}ms", low, high) });
+        }
+        if self.corrupt > 0.0 {
+            fields.push(format!("corrupt={}", self.corrupt));
+        }
+        if let Some(seed) = self.seed {
+            fields.push(format!("seed={}", seed));
+        }
+        write!(f, "{}", fields.join(","))
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    Nanos::from(d).as_millis()
+}
+
+// So that a spec on the command line is checked along with the rest.
+impl Decodable for ChaosConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<ChaosConfig, D::Error> {
+        let spec = try!(d.read_str());
+        ChaosConfig::parse(&spec).map_err(|e| d.error(&e))
+    }
+}
+
+// A datagram held back, on its way out or in.
+enum Held {
+    Out(Vec<u8>, SocketAddr),
+    In(Vec<u8>, SocketAddr),
+}
+
+// A transport that does to whatever goes through `inner` what its
+// ChaosConfig says, or nothing if it has none. Held back datagrams go on
+// their way from the next send or receive after their time is up, so
+// while the node is idle they may wait up to a tick longer. Sockets from
+// `connect` are left alone.
+pub struct Chaos<T> {
+    inner: T,
+    config: Option<ChaosConfig>,
+    rng: RefCell<XorShiftRng>,
+    held: RefCell<Timer<Held>>,
+    // When `held` was last advanced.
+    last: Cell<Instant>,
+    // Received datagrams to hand out before anything new: duplicates,
+    // and those no longer held back.
+    ready: RefCell<VecDeque<(Vec<u8>, SocketAddr)>>,
+}
+
+impl<T: Transport> Chaos<T> {
+    // Wrap `inner`, picking a seed if `config` doesn't give one.
+    pub fn new(inner: T, config: Option<ChaosConfig>) -> Chaos<T> {
+        let config = config.map(|c| ChaosConfig {
+            seed: Some(c.seed.unwrap_or_else(rand::random)),
+            .. c
+        });
+        let seed = config.as_ref().and_then(|c| c.seed).unwrap_or(0);
+        Chaos {
+            inner: inner,
+            config: config,
+            rng: RefCell::new(seeded_rng(seed)),
+            held: RefCell::new(Timer::new()),
+            last: Cell::new(Instant::now()),
+            ready: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    // What's being done to the traffic, seed included, if anything is.
+    pub fn config(&self) -> Option<&ChaosConfig> {
+        self.config.as_ref()
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    // Send or make ready whatever has been held back long enough.
+    fn release(&self) {
+        let now = Instant::now();
+        let elapsed = Nanos::from(now - self.last.get());
+        self.last.set(now);
+        let due = self.held.borrow_mut().advance(elapsed);
+        for held in due {
+            match held {
+                // Lost, if it can't go now.
+                Held::Out(bytes, addr) => {
+                    let _ = self.inner.send_to(&bytes, &addr);
+                },
+                Held::In(bytes, from) =>
+                    self.ready.borrow_mut().push_back((bytes, from)),
+            }
+        }
+    }
+
+    // Decide what becomes of a datagram: returns the copies of it to pass
+    // on now, having held back those to go later as `hold` makes them.
+    fn inflict<F>(&self, config: &ChaosConfig, mut bytes: Vec<u8>, hold: F)
+            -> Vec<Vec<u8>>
+            where F: Fn(Vec<u8>) -> Held {
+        let mut rng = self.rng.borrow_mut();
+        if rng.gen::<f64>() < config.drop {
+            return Vec::new();
+        }
+        if rng.gen::<f64>() < config.corrupt && !bytes.is_empty() {
+            let bit = rng.gen_range(0, bytes.len() * 8);
+            bytes[bit / 8] ^= 1 << (bit % 8);
+        }
+        let copies = if rng.gen::<f64>() < config.dup { 2 } else { 1 };
+        let mut now = Vec::new();
+        for _ in 0..copies {
+            match config.delay {
+                Some((low, high)) => {
+                    let (low, high) = (Nanos::from(low).as_nanos(),
+                                       Nanos::from(high).as_nanos());
+                    let delay = Nanos::from_nanos(rng.gen_range(low, high + 1));
+                    self.held.borrow_mut().add(delay, hold(bytes.clone()));
+                },
+                None => now.push(bytes.clone()),
+            }
+        }
+        now
+    }
+}
+
+// A generator whose every number follows from `seed`.
+pub fn seeded_rng(seed: u64) -> XorShiftRng {
+    // XorShift needs some bit set, whatever the seed.
+    SeedableRng::from_seed([seed as u32, (seed >> 32) as u32,
+                            0x9e3779b9, 0x7f4a7c15])
+}
+
+impl<T: Transport> Transport for Chaos<T> {
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        let config = match self.config {
+            Some(ref config) => config,
+            None => return self.inner.send_to(buf, addr),
+        };
+        self.release();
+        let target = *addr;
+        let copies = self.inflict(config, buf.to_vec(),
+                                  |bytes| Held::Out(bytes, target));
+        for bytes in copies {
+            try!(self.inner.send_to(&bytes, addr));
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let config = match self.config {
+            Some(ref config) => config,
+            None => return self.inner.recv_from(buf),
+        };
+        self.release();
+        if self.ready.borrow().is_empty() {
+            let (amt, from) = try!(self.inner.recv_from(buf));
+            let copies = self.inflict(config, buf[..amt].to_vec(),
+                                      |bytes| Held::In(bytes, from));
+            for bytes in copies {
+                self.ready.borrow_mut().push_back((bytes, from));
+            }
+        }
+        match self.ready.borrow_mut().pop_front() {
+            Some((bytes, from)) => {
+                let amt = cmp::min(buf.len(), bytes.len());
+                buf[..amt].clone_from_slice(&bytes[..amt]);
+                Ok((amt, from))
+            },
+            // As if it had never come.
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                       "nothing ready")),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Connected>> {
+        self.inner.connect(addr)
+    }
+
+    fn socket_info(&self) -> io::Result<SocketInfo> {
+        self.inner.socket_info()
+    }
+}
+
+#[cfg(test)]
+fn chaos(spec: &str) -> Chaos<::transport::SimTransport> {
+    let config = ChaosConfig::parse(spec).unwrap();
+    Chaos::new(::transport::SimTransport::new(), Some(config))
+}
+
+#[cfg(test)]
+fn peer() -> SocketAddr {
+    "127.0.0.1:9000".parse().unwrap()
+}
+
+#[test]
+fn specs_parse_and_print_back() {
+    let config = ChaosConfig::parse("drop=0.1,dup=0.02,delay=5..50ms,\
+                                     corrupt=0.01,seed=7").unwrap();
+    assert_eq!(config, ChaosConfig {
+        drop: 0.1,
+        dup: 0.02,
+        corrupt: 0.01,
+        delay: Some((Duration::from_millis(5), Duration::from_millis(50))),
+        seed: Some(7),
+    });
+    assert_eq!(config.to_string(),
+               "drop=0.1,dup=0.02,delay=5..50ms,corrupt=0.01,seed=7");
+
+    let config = ChaosConfig::parse("delay=20ms").unwrap();
+    assert_eq!(config.delay, Some((Duration::from_millis(20),
+                                   Duration::from_millis(20))));
+    assert_eq!(config.to_string(), "delay=20ms");
+}
+
+#[test]
+fn bad_specs_are_refused() {
+    for spec in &["", "drop", "drop=2", "drop=-0.1", "dup=often",
+                  "delay=50..5ms", "delay=5..50", "seed=x", "jitter=1"] {
+        assert!(ChaosConfig::parse(spec).is_err(), "{:?} was accepted", spec);
+    }
+}
+
+#[test]
+fn a_seed_is_picked_and_kept() {
+    let c = chaos("drop=0.5");
+    let seed = c.config().unwrap().seed.unwrap();
+    // The same seed, the same fate for every datagram.
+    let again = chaos(&c.config().unwrap().to_string());
+    for n in 0..100u8 {
+        c.send_to(&[n], &peer()).unwrap();
+        again.send_to(&[n], &peer()).unwrap();
+    }
+    assert_eq!(*c.inner().sent.borrow(), *again.inner().sent.borrow());
+    assert_eq!(again.config().unwrap().seed, Some(seed));
+}
+
+#[test]
+fn no_config_passes_everything_through() {
+    let c = Chaos::new(::transport::SimTransport::new(), None);
+    c.send_to(&[1, 2, 3], &peer()).unwrap();
+    assert_eq!(*c.inner().sent.borrow(), vec![(vec![1, 2, 3], peer())]);
+    assert!(c.config().is_none());
+}
+
+#[test]
+fn drops_duplicates_and_corrupts_both_ways() {
+    let c = chaos("drop=1");
+    c.send_to(&[1], &peer()).unwrap();
+    c.inner().deliver(vec![1], peer());
+    assert!(c.inner().sent.borrow().is_empty());
+    assert!(c.recv_from(&mut [0; 16]).is_err());
+
+    let c = chaos("dup=1");
+    c.send_to(&[1], &peer()).unwrap();
+    assert_eq!(c.inner().sent.borrow().len(), 2);
+    c.inner().deliver(vec![2], peer());
+    let mut buf = [0; 16];
+    assert_eq!(c.recv_from(&mut buf).unwrap(), (1, peer()));
+    assert_eq!(c.recv_from(&mut buf).unwrap(), (1, peer()));
+    assert_eq!(buf[0], 2);
+    assert!(c.recv_from(&mut buf).is_err());
+
+    let c = chaos("corrupt=1");
+    c.send_to(&[0; 8], &peer()).unwrap();
+    let flipped: u32 = c.inner().sent.borrow()[0].0.iter()
+        .map(|b| b.count_ones()).sum();
+    assert_eq!(flipped, 1);
+}
+
+#[test]
+fn delayed_datagrams_wait_their_turn() {
+    use std::thread;
+
+    let c = chaos("delay=20ms");
+    c.send_to(&[1], &peer()).unwrap();
+    c.inner().deliver(vec![2], peer());
+    let mut buf = [0; 16];
+    assert!(c.recv_from(&mut buf).is_err());
+    assert!(c.inner().sent.borrow().is_empty());
+
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(c.recv_from(&mut buf).unwrap(), (1, peer()));
+    assert_eq!(buf[0], 2);
+    assert_eq!(*c.inner().sent.borrow(), vec![(vec![1], peer())]);
+}
@@ -0,0 +1,408 @@
+use bandwidth::BandwidthConfig;
+use chaos::ChaosConfig;
+use config::Config;
+use docopt::{self, Docopt};
+use isolation::OnIsolation;
+use mtu::MtuConfig;
+use observed::ObservedConfig;
+use probe::ProbeConfig;
+use scheduler::WatchdogConfig;
+use std::time::Duration;
+
+pub const USAGE: &'static str = "
+Usage:
+    mesh [options] doctor [TARGET]
+    mesh [options] swarm N
+    mesh [options] replay FILE
+    mesh [options]
+    mesh [options] TARGET
+
+Options:
+    -h, --host HOST        Host to listen on. [default: 127.0.0.1]
+    -p, --port PORT        Local port to bind to, 0 for any. [default: 0]
+    --cluster NAME         Name of the mesh, for display. [default: mesh]
+    --json                 Print status lines as JSON.
+    --status-interval MS   How often to print a status line.
+                           [default: 30000]
+    --recv-buffer BYTES    Socket receive buffer size to request.
+    --send-buffer BYTES    Socket send buffer size to request.
+    --reuse-addr           Set SO_REUSEADDR on the socket.
+    --fast-fail            Probe from connected sockets, to hear sooner
+                           when a peer's process has died.
+    --adopt-observed-addr  Advertise the address peers agree they see us
+                           at, if it isn't the one we're bound to.
+    --probe-mtu            Find out how large a datagram gets through to
+                           each peer, with Don't Fragment set.
+    --explain-rejects      Tell nodes whose datagrams we drop why, at
+                           most a few times a minute each.
+    --no-ping-reply        Answer only peers' Pings, so as not to be used
+                           to reflect Pongs at others.
+    --abort-on-stall       Abort if the timer thread stalls, for a
+                           supervisor to restart the node.
+    --bandwidth-cap BYTES  Most bytes a second to send to all peers
+                           together, headers and all; 0 for no cap.
+                           [default: 0]
+    --nat-refresh MS       Send a peer a two-byte keepalive once we've
+                           sent it nothing else for MS milliseconds, so
+                           that NATs in between keep the way open; 0 for
+                           never. [default: 0]
+    --on-isolation POLICY  What to do once every peer is dead: carry on
+                           (standalone), join TARGET again (rejoin), or
+                           exit with status 3 (exit).
+                           [default: standalone]
+    --restart-window MS    Take a node joining from the address of a
+                           peer heard from within MS milliseconds for
+                           that peer restarted, replacing it; 0 for
+                           never. [default: 10000]
+    --chaos SPEC           Inject failures into this node's traffic, for
+                           soak testing. SPEC is like
+                           drop=0.1,dup=0.02,delay=5..50ms,corrupt=0.01
+                           and may add seed=N to repeat a run.
+    --dump-packets FILE    Write down every datagram sent and received,
+                           for `replay`.
+    --fast                 Replay without keeping to the dump's timing.
+
+When run with TARGET, attempt to join the specified target mesh.
+Otherwise, begin listening on the specified host and port.
+
+`doctor` checks that a node could start with the given options, and
+join TARGET if given, and exits non-zero if not.
+
+`swarm` runs N nodes in one process, on loopback and ports of the OS's
+choosing, each joining the first. Their events are printed prefixed
+with [i] for the i-th node, and lines typed are commands for them: addr,
+peers, history, timers (what each node has scheduled, and when), evict
+ID (declare a peer dead and keep it so), probe ID (probe a peer now,
+reviving it if it answers), reload [OPTIONS] (switch to OPTIONS, as far
+as can be done without a restart; those not given go back to their
+defaults), dump [FILE] (write everything the nodes know, as JSON, for a
+bug report) or graph [FILE] (write who the nodes know and how well, in
+Graphviz DOT) for every node, or prefixed with @i for node i alone. ID
+is a peer's id, or enough of it to tell it apart. quit stops them all.
+
+`replay` plays a dump from --dump-packets back through a node that
+takes on the recorded node's id, and prints what it receives, what it
+sends (which goes nowhere), what happens and who it ends up knowing.
+Give it the options the recorded node was run with.
+";
+
+#[allow(non_snake_case)]
+#[derive(Debug, RustcDecodable)]
+pub struct Args {
+    pub flag_host: String,
+    pub flag_port: u16,
+    pub flag_cluster: String,
+    pub flag_json: bool,
+    pub flag_status_interval: u64,
+    pub flag_recv_buffer: Option<usize>,
+    pub flag_send_buffer: Option<usize>,
+    pub flag_reuse_addr: bool,
+    pub flag_fast_fail: bool,
+    pub flag_adopt_observed_addr: bool,
+    pub flag_probe_mtu: bool,
+    pub flag_explain_rejects: bool,
+    pub flag_no_ping_reply: bool,
+    pub flag_abort_on_stall: bool,
+    pub flag_bandwidth_cap: u64,
+    pub flag_nat_refresh: u64,
+    pub flag_on_isolation: OnIsolation,
+    pub flag_restart_window: u64,
+    pub flag_chaos: Option<ChaosConfig>,
+    pub flag_dump_packets: Option<String>,
+    pub flag_fast: bool,
+    pub cmd_doctor: bool,
+    pub cmd_swarm: bool,
+    pub cmd_replay: bool,
+    pub arg_N: Option<usize>,
+    pub arg_FILE: String,
+    pub arg_TARGET: String,
+}
+
+// Parse a full argv, program name first. On error, `e.exit()` prints the
+// usage (or help) and exits as appropriate.
+pub fn parse<I, S>(argv: I) -> Result<Args, docopt::Error>
+        where I: IntoIterator<Item=S>, S: Into<String> {
+    let args: Args = try!(Docopt::new(USAGE)
+        .and_then(|d| d.argv(argv.into_iter()).decode()));
+    // A subcommand missing what it needs, like a bare `mesh swarm`, would
+    // otherwise be taken for a TARGET of that name.
+    if subcommands().contains(&&args.arg_TARGET[..]) {
+        let why = format!("`{}` is missing its arguments", args.arg_TARGET);
+        return Err(docopt::Error::WithProgramUsage(
+            Box::new(docopt::Error::Argv(why)), USAGE.trim().to_string()));
+    }
+    Ok(args)
+}
+
+// The subcommands in USAGE, such as doctor and swarm.
+fn subcommands() -> Vec<&'static str> {
+    USAGE.lines()
+        .map(|l| l.trim())
+        .filter(|l| l.starts_with("mesh "))
+        .filter_map(|l| l.split_whitespace().nth(2))
+        .filter(|w| w.chars().all(|c| c.is_lowercase()))
+        .collect()
+}
+
+impl Args {
+    pub fn config(&self) -> Config {
+        Config {
+            host: self.flag_host.clone(),
+            port: self.flag_port,
+            cluster: self.flag_cluster.clone(),
+            json: self.flag_json,
+            recv_buffer_size: self.flag_recv_buffer,
+            send_buffer_size: self.flag_send_buffer,
+            reuse_addr: self.flag_reuse_addr,
+            probe: ProbeConfig {
+                fast_fail: self.flag_fast_fail,
+                .. ProbeConfig::default()
+            },
+            observed: ObservedConfig {
+                adopt: self.flag_adopt_observed_addr,
+                .. ObservedConfig::default()
+            },
+            mtu: MtuConfig {
+                enabled: self.flag_probe_mtu,
+                .. MtuConfig::default()
+            },
+            explain_rejects: self.flag_explain_rejects,
+            ping_strangers: !self.flag_no_ping_reply,
+            watchdog: WatchdogConfig {
+                abort: self.flag_abort_on_stall,
+                .. WatchdogConfig::default()
+            },
+            bandwidth: BandwidthConfig {
+                cap: self.flag_bandwidth_cap,
+                .. BandwidthConfig::default()
+            },
+            status_interval: Duration::from_millis(self.flag_status_interval),
+            nat_refresh: match self.flag_nat_refresh {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            on_isolation: self.flag_on_isolation,
+            restart_window: match self.flag_restart_window {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            },
+            chaos: self.flag_chaos.clone(),
+            dump_packets: self.flag_dump_packets.clone(),
+            .. Config::default()
+        }
+    }
+
+    // The mesh to join, if one was given.
+    pub fn target(&self) -> Option<&str> {
+        if self.arg_TARGET.is_empty() { None } else { Some(&self.arg_TARGET) }
+    }
+}
+
+#[test]
+fn no_arguments_listens_on_defaults() {
+    let args = parse(vec!["mesh"]).unwrap();
+    let config = args.config();
+    assert_eq!(config.host, "127.0.0.1");
+    assert_eq!(config.port, 0);
+    assert_eq!(config.cluster, "mesh");
+    assert!(!config.json);
+    assert_eq!(config.recv_buffer_size, None);
+    assert_eq!(config.send_buffer_size, None);
+    assert!(!config.reuse_addr);
+    assert!(!config.probe.fast_fail);
+    assert!(!config.observed.adopt);
+    assert!(!config.mtu.enabled);
+    assert!(!config.explain_rejects);
+    assert!(config.ping_strangers);
+    assert!(!config.watchdog.abort);
+    assert_eq!(config.status_interval, Config::default().status_interval);
+    assert_eq!(config.on_isolation, OnIsolation::Standalone);
+    assert_eq!(config.chaos, None);
+    assert_eq!(config.dump_packets, None);
+    assert_eq!(args.target(), None);
+}
+
+#[test]
+fn target_is_positional() {
+    let args = parse(vec!["mesh", "10.0.0.1:5000"]).unwrap();
+    assert_eq!(args.target(), Some("10.0.0.1:5000"));
+}
+
+#[test]
+fn short_and_long_flags() {
+    for argv in vec![vec!["mesh", "-h", "0.0.0.0", "-p", "4000", "peer:1"],
+                     vec!["mesh", "--host", "0.0.0.0", "--port", "4000",
+                          "peer:1"],
+                     vec!["mesh", "--host=0.0.0.0", "--port=4000", "peer:1"]] {
+        let args = parse(argv).unwrap();
+        let config = args.config();
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.port, 4000);
+        assert_eq!(args.target(), Some("peer:1"));
+    }
+}
+
+#[test]
+fn output_flags() {
+    let config = parse(vec!["mesh", "--cluster", "prod", "--json",
+                            "--status-interval", "500"]).unwrap()
+        .config();
+    assert_eq!(config.cluster, "prod");
+    assert!(config.json);
+    assert_eq!(config.status_interval, Duration::from_millis(500));
+}
+
+#[test]
+fn socket_flags() {
+    let args = parse(vec!["mesh", "--recv-buffer", "1048576",
+                          "--send-buffer", "65536", "--reuse-addr"]).unwrap();
+    let config = args.config();
+    assert_eq!(config.recv_buffer_size, Some(1048576));
+    assert_eq!(config.send_buffer_size, Some(65536));
+    assert!(config.reuse_addr);
+}
+
+#[test]
+fn fast_fail_flag() {
+    let args = parse(vec!["mesh", "--fast-fail"]).unwrap();
+    assert!(args.config().probe.fast_fail);
+}
+
+#[test]
+fn adopt_observed_addr_flag() {
+    let args = parse(vec!["mesh", "--adopt-observed-addr"]).unwrap();
+    assert!(args.config().observed.adopt);
+}
+
+#[test]
+fn probe_mtu_flag() {
+    let config = parse(vec!["mesh", "--probe-mtu"]).unwrap().config();
+    assert!(config.mtu.enabled);
+    assert!(config.socket_opts().dont_fragment);
+}
+
+#[test]
+fn explain_rejects_flag() {
+    let config = parse(vec!["mesh", "--explain-rejects"]).unwrap().config();
+    assert!(config.explain_rejects);
+}
+
+#[test]
+fn no_ping_reply_flag() {
+    let config = parse(vec!["mesh", "--no-ping-reply"]).unwrap().config();
+    assert!(!config.ping_strangers);
+}
+
+#[test]
+fn abort_on_stall_flag() {
+    let config = parse(vec!["mesh", "--abort-on-stall"]).unwrap().config();
+    assert!(config.watchdog.abort);
+}
+
+#[test]
+fn bandwidth_cap_flag() {
+    assert_eq!(parse(vec!["mesh"]).unwrap().config().bandwidth.cap, 0);
+    let config = parse(vec!["mesh", "--bandwidth-cap", "50000"]).unwrap()
+        .config();
+    assert_eq!(config.bandwidth.cap, 50000);
+}
+
+#[test]
+fn nat_refresh_flag() {
+    assert_eq!(parse(vec!["mesh"]).unwrap().config().nat_refresh, None);
+    let config = parse(vec!["mesh", "--nat-refresh", "20000"]).unwrap()
+        .config();
+    assert_eq!(config.nat_refresh, Some(Duration::from_secs(20)));
+}
+
+#[test]
+fn restart_window_flag() {
+    assert_eq!(parse(vec!["mesh"]).unwrap().config().restart_window,
+               Some(Duration::from_secs(10)));
+    let config = parse(vec!["mesh", "--restart-window", "0"]).unwrap()
+        .config();
+    assert_eq!(config.restart_window, None);
+}
+
+#[test]
+fn on_isolation_flag() {
+    let config = parse(vec!["mesh", "--on-isolation", "exit"]).unwrap()
+        .config();
+    assert_eq!(config.on_isolation, OnIsolation::Exit);
+    assert!(parse(vec!["mesh", "--on-isolation", "restart"]).is_err());
+}
+
+#[test]
+fn chaos_flag() {
+    let config = parse(vec!["mesh", "--chaos", "drop=0.1,delay=5..50ms"])
+        .unwrap().config();
+    let chaos = config.chaos.unwrap();
+    assert_eq!(chaos.drop, 0.1);
+    assert_eq!(chaos.delay, Some((Duration::from_millis(5),
+                                  Duration::from_millis(50))));
+    assert_eq!(chaos.seed, None);
+
+    assert!(parse(vec!["mesh", "--chaos", "drop=lots"]).is_err());
+}
+
+#[test]
+fn doctor_takes_an_optional_target() {
+    let args = parse(vec!["mesh", "-p", "4000", "doctor"]).unwrap();
+    assert!(args.cmd_doctor);
+    assert_eq!(args.config().port, 4000);
+    assert_eq!(args.target(), None);
+
+    let args = parse(vec!["mesh", "doctor", "10.0.0.1:5000"]).unwrap();
+    assert!(args.cmd_doctor);
+    assert_eq!(args.target(), Some("10.0.0.1:5000"));
+
+    assert!(!parse(vec!["mesh", "10.0.0.1:5000"]).unwrap().cmd_doctor);
+}
+
+#[test]
+fn swarm_takes_a_count() {
+    let args = parse(vec!["mesh", "--json", "swarm", "10"]).unwrap();
+    assert!(args.cmd_swarm);
+    assert_eq!(args.arg_N, Some(10));
+    assert!(args.config().json);
+
+    assert!(parse(vec!["mesh", "swarm"]).is_err());
+    assert!(parse(vec!["mesh", "doctor", "swarm"]).is_err());
+    assert!(parse(vec!["mesh", "swarm", "many"]).is_err());
+    assert!(!parse(vec!["mesh"]).unwrap().cmd_swarm);
+}
+
+#[test]
+fn dump_packets_flag() {
+    let config = parse(vec!["mesh", "--dump-packets", "node.packets"])
+        .unwrap().config();
+    assert_eq!(config.dump_packets, Some("node.packets".to_string()));
+}
+
+#[test]
+fn replay_takes_a_file() {
+    let args = parse(vec!["mesh", "--fast", "replay", "node.packets"])
+        .unwrap();
+    assert!(args.cmd_replay);
+    assert_eq!(args.arg_FILE, "node.packets");
+    assert!(args.flag_fast);
+    assert_eq!(args.target(), None);
+
+    assert!(parse(vec!["mesh", "replay"]).is_err());
+    assert!(!parse(vec!["mesh"]).unwrap().flag_fast);
+}
+
+#[test]
+fn bad_arguments_are_usage_errors() {
+    for argv in vec![vec!["mesh", "--bogus"],
+                     vec!["mesh", "a", "b"],
+                     vec!["mesh", "-p"]] {
+        match parse(argv) {
+            Err(docopt::Error::WithProgramUsage(..)) => (),
+            other => panic!("expected a usage error, got {:?}", other),
+        }
+    }
+
+    // Well-formed but not a port.
+    assert!(parse(vec!["mesh", "-p", "http"]).is_err());
+}
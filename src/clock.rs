@@ -0,0 +1,176 @@
+use std::cell::Cell;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Sub};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// A source of monotonic time. The dispatcher asks its clock rather than
+// calling Instant::now() directly so tests can drive time by hand.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// A clock that only moves when told to.
+pub struct ManualClock {
+    now: Cell<Instant>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock { now: Cell::new(Instant::now()) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+// So that a test can keep hold of the clock it gives a dispatcher.
+impl<C: Clock> Clock for Rc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+// A span of time in the nanoseconds a Timer counts in. Timers, and the
+// Schedulers and dispatcher that drive them, take and give nothing else,
+// so that a count of milliseconds can't be passed off as one: a count
+// becomes a Nanos only by naming its unit, and a Duration becomes one,
+// and back, only by the From impls below. Arithmetic on it saturates,
+// at zero and at about 584 years, rather than wrapping or panicking.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Nanos(u64);
+
+impl Nanos {
+    pub fn zero() -> Nanos {
+        Nanos(0)
+    }
+
+    pub fn from_secs(secs: u64) -> Nanos {
+        Nanos(secs.saturating_mul(1000000000))
+    }
+
+    pub fn from_millis(millis: u64) -> Nanos {
+        Nanos(millis.saturating_mul(1000000))
+    }
+
+    pub fn from_micros(micros: u64) -> Nanos {
+        Nanos(micros.saturating_mul(1000))
+    }
+
+    pub fn from_nanos(nanos: u64) -> Nanos {
+        Nanos(nanos)
+    }
+
+    // Each rounded down.
+    pub fn as_millis(self) -> u64 {
+        self.0 / 1000000
+    }
+
+    pub fn as_micros(self) -> u64 {
+        self.0 / 1000
+    }
+
+    pub fn as_nanos(self) -> u64 {
+        self.0
+    }
+}
+
+impl Add for Nanos {
+    type Output = Nanos;
+
+    fn add(self, other: Nanos) -> Nanos {
+        Nanos(self.0.saturating_add(other.0))
+    }
+}
+
+impl Sub for Nanos {
+    type Output = Nanos;
+
+    fn sub(self, other: Nanos) -> Nanos {
+        Nanos(self.0.saturating_sub(other.0))
+    }
+}
+
+impl Mul<u64> for Nanos {
+    type Output = Nanos;
+
+    fn mul(self, k: u64) -> Nanos {
+        Nanos(self.0.saturating_mul(k))
+    }
+}
+
+impl Div<u64> for Nanos {
+    type Output = Nanos;
+
+    fn div(self, k: u64) -> Nanos {
+        Nanos(self.0 / k)
+    }
+}
+
+// How many of `other` fit in this.
+impl Div for Nanos {
+    type Output = u64;
+
+    fn div(self, other: Nanos) -> u64 {
+        self.0 / other.0
+    }
+}
+
+impl fmt::Display for Nanos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}ns", self.0)
+    }
+}
+
+impl From<Duration> for Nanos {
+    fn from(d: Duration) -> Nanos {
+        Nanos::from_secs(d.as_secs()) + Nanos(d.subsec_nanos() as u64)
+    }
+}
+
+impl From<Nanos> for Duration {
+    fn from(ns: Nanos) -> Duration {
+        Duration::new(ns.0 / 1000000000, (ns.0 % 1000000000) as u32)
+    }
+}
+
+#[test]
+fn manual_clock_advances() {
+    let clock = ManualClock::new();
+    let start = clock.now();
+    clock.advance(Duration::from_millis(1500));
+    assert_eq!(Nanos::from(clock.now() - start), Nanos::from_millis(1500));
+}
+
+#[test]
+fn nanos_round_trip() {
+    let ns = Nanos::from_nanos(12345678901);
+    assert_eq!(Nanos::from(Duration::from(ns)), ns);
+    assert_eq!(Duration::from(ns), Duration::new(12, 345678901));
+    assert_eq!(ns.as_millis(), 12345);
+    assert_eq!(ns.as_micros(), 12345678);
+}
+
+#[test]
+fn nanos_saturate() {
+    let max = Nanos::from_nanos(u64::max_value());
+    assert_eq!(Nanos::from_millis(u64::max_value()), max);
+    assert_eq!(max + Nanos::from_nanos(1), max);
+    assert_eq!(Nanos::from_millis(1) - Nanos::from_millis(2), Nanos::zero());
+    assert_eq!(Nanos::from_secs(1) / Nanos::from_millis(300), 3);
+}
@@ -0,0 +1,90 @@
+use decoder;
+use message::{self, DecodeError, Message};
+#[cfg(test)] use members::{MetaVersion, NodeId};
+#[cfg(test)] use message::{parse_datagram, AckedMessage, Health, PingBody,
+                           RejectCode, Responder, Seq, WireAddr,
+                           VERSION_FLAG};
+#[cfg(test)] use protocol::limits::{CHECKSUM_LEN, FRAME_HEADER_LEN};
+#[cfg(test)] use std::collections::BTreeMap;
+
+// Reading frames from the versions before ours that we still understand
+// (see message::MIN_PROTOCOL_VERSION), each by way of that version's own
+// message types, which are then turned into today's. Only decoding lives
+// here; we always send the current version.
+
+// How many message types version 14 knew.
+const V14_MESSAGE_TYPES: u8 = 12;
+
+// Decode a frame of `version`, the whole of it, version byte and all.
+// parse_datagram has already checked the version is one we read.
+pub fn parse_frame(version: u8, bytes: &[u8]) -> Result<Message, DecodeError> {
+    match version {
+        // Version 14's messages are today's, but for there being no
+        // JoinRejected, which comes last, so its frames need no types of
+        // their own.
+        14 => {
+            let contents = try!(message::checked_contents(bytes));
+            match bytes[1] {
+                t if t >= V14_MESSAGE_TYPES => Err(DecodeError::UnknownType(t)),
+                t => decoder::decode_contents(t, contents),
+            }
+        },
+        v => Err(DecodeError::UnsupportedVersion(v)),
+    }
+}
+
+// A frame as version 14 would have sent `m`.
+#[cfg(test)]
+fn encode_v14(m: &Message) -> Vec<u8> {
+    let bytes = m.encode();
+    let header = [VERSION_FLAG | 14, bytes[1]];
+    let contents = if bytes.len() > FRAME_HEADER_LEN {
+        &bytes[FRAME_HEADER_LEN + CHECKSUM_LEN..]
+    } else {
+        &[]
+    };
+    message::frame(&header, contents)
+}
+
+#[cfg(test)]
+fn ping_body() -> PingBody {
+    PingBody { nonce: 7, sent_at_micros: 1000, pad: vec![] }
+}
+
+#[test]
+fn v14_frames_decode_to_the_same_messages() {
+    let addr = WireAddr("10.0.0.1:4000".parse().unwrap());
+    let responder = Responder { id: NodeId(42), incarnation: 1 };
+    let health = Health { queued: 3, dropped: 1, alive: 4 };
+    let seq = |n| Seq::new(7, n);
+    let mut tags = BTreeMap::new();
+    tags.insert("role".to_string(), "active".to_string());
+    let messages = vec![
+        Message::Acked(seq(1), AckedMessage::Join(NodeId(42), 14)),
+        Message::Acked(seq(2), AckedMessage::Ordered(0, vec![1, 2])),
+        Message::Acked(seq(3), AckedMessage::Suspected(NodeId(42), 1)),
+        Message::Acked(seq(4), AckedMessage::Tags(
+            NodeId(42), MetaVersion::new(1, 2), tags)),
+        Message::Ack(seq(3), addr, Some(responder)),
+        Message::Ping(ping_body()),
+        Message::Pong(ping_body(), addr, Some(health)),
+        Message::AckMulti(vec![seq(4), seq(5)]),
+        Message::VersionMismatch(14),
+        Message::SyncRequest(2),
+        Message::Rejected(RejectCode::Blocked),
+        Message::AckSack(7, 3, 0b1011),
+        Message::JoinForward(addr, seq(1), NodeId(42), 14),
+        Message::Keepalive,
+        Message::JoinRetryAfter(500),
+    ];
+    for m in messages {
+        assert_eq!(parse_datagram(&encode_v14(&m)), Ok(m));
+    }
+}
+
+#[test]
+fn v14_frames_know_only_v14_messages() {
+    let rejected = encode_v14(&Message::JoinRejected("no".to_string()));
+    assert_eq!(parse_datagram(&rejected),
+               Err(DecodeError::UnknownType(V14_MESSAGE_TYPES)));
+}
@@ -0,0 +1,368 @@
+use backoff::BackoffConfig;
+use bandwidth::BandwidthConfig;
+use chaos::ChaosConfig;
+use detector::DetectorConfig;
+use error::MeshError;
+use health::HealthConfig;
+use isolation::OnIsolation;
+use mtu::MtuConfig;
+use observed::ObservedConfig;
+use probe::ProbeConfig;
+use sockopts::SocketOpts;
+use scheduler::WatchdogConfig;
+use throttle::ThrottleConfig;
+use timing::TimingConfig;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// Everything a node needs to know to start up. The binary fills this in
+// from the command line, and MeshBuilder from its setters; fields they
+// don't set keep their defaults.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+
+    // The name of the mesh this node belongs to, for operators' benefit.
+    pub cluster: String,
+
+    // Print status lines (like READY) as JSON rather than key=value.
+    pub json: bool,
+
+    // Socket buffer sizes to request, in bytes. None leaves the
+    // kernel's default alone.
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+
+    // Set SO_REUSEADDR so a restarted node can rebind its port at once.
+    pub reuse_addr: bool,
+
+    // How peers are judged alive, suspect or dead.
+    pub failure_detector: DetectorConfig,
+
+    // How often peers are probed.
+    pub probe: ProbeConfig,
+
+    // How hard to try getting each kind of acked message through.
+    pub join_retransmit: RetransmitPolicy,
+    pub data_retransmit: RetransmitPolicy,
+
+    // Caps on state that other nodes can make us keep.
+    pub limits: Limits,
+
+    // When to stop sending to peers that aren't answering.
+    pub backoff: BackoffConfig,
+
+    // How much any one address may send us.
+    pub throttle: ThrottleConfig,
+
+    // How much we may send in all; see bandwidth::Bandwidth.
+    pub bandwidth: BandwidthConfig,
+
+    // Log each Pong or Ack that answers nothing we sent, as well as
+    // dropping and counting it.
+    pub log_unsolicited: bool,
+
+    // Tell whoever sent a frame we drop why, with a Rejected, so that a
+    // misconfigured node learns what's wrong instead of seeing loss. Off
+    // by default: a frame's source is easily forged, and this makes us
+    // send to it unasked. Replies are few regardless; see
+    // rejects::RejectLimiter.
+    pub explain_rejects: bool,
+
+    // Answer Pings from addresses that aren't peers. Turned off, a node
+    // can't be used to reflect Pongs at whoever a Ping's forged source
+    // names, while peers' Pings, and so their round trip times, are
+    // answered as ever. Those who aren't peers yet, though, such as a
+    // node checking it can reach us before joining (see doctor), hear
+    // nothing back.
+    pub ping_strangers: bool,
+
+    // When to believe what peers say our address is, and whether to
+    // advertise it.
+    pub observed: ObservedConfig,
+
+    // Whether to find out how large a datagram gets through to each peer.
+    pub mtu: MtuConfig,
+
+    // Whether our Pongs say how we're doing, and how much longer to give
+    // peers whose Pongs say they're overloaded.
+    pub health: HealthConfig,
+
+    // How many membership changes to remember; see history::History.
+    pub history: usize,
+
+    // How closely to watch the scheduler's timer thread, and whether a
+    // stall is fatal; see scheduler::Watchdog.
+    pub watchdog: WatchdogConfig,
+
+    // Whether to time how long each kind of message takes to handle, and
+    // what's too long; see timing::TimingConfig.
+    pub timing: TimingConfig,
+
+    // How long after joining a mesh to give up on catching up with it;
+    // see converge::Convergence.
+    pub convergence_deadline: Duration,
+
+    // How often a running node prints a status line; see node::run.
+    pub status_interval: Duration,
+
+    // A peer to hand every newcomer's Join to, for it to answer instead
+    // (see Dispatcher::forward_join), so that one node can let everyone
+    // in whichever node they're told to join through. None, as usual,
+    // and a node hands Joins on only while it's overloaded.
+    pub coordinator: Option<SocketAddr>,
+
+    // How long a peer may go without our sending it anything before it's
+    // sent a Keepalive, to keep a NAT or firewall between us from
+    // forgetting the way; see keepalive::Keepalives. None sends none.
+    pub nat_refresh: Option<Duration>,
+
+    // What to do once every peer is dead; see isolation::Isolation.
+    pub on_isolation: OnIsolation,
+
+    // How lately a peer must have been heard from for a node we don't
+    // know, Joining from its address, to be taken for it restarted, and
+    // to replace it with a single MeshEvent::PeerRestarted; see
+    // merge::restarted. None takes every such node for a newcomer: it
+    // joins beside the old peer, with a PeerJoined, and the old one is
+    // left to be found dead.
+    pub restart_window: Option<Duration>,
+
+    // Failures to inject into the node's own traffic, for soak testing;
+    // see chaos::Chaos. None for a node that behaves.
+    pub chaos: Option<ChaosConfig>,
+
+    // Where a node run from the command line writes down every datagram
+    // it sends and receives, for replaying later; see node::start and
+    // trace. None to write nothing.
+    pub dump_packets: Option<String>,
+}
+
+// An acked message is resent until it's acknowledged, it has been resent
+// `attempts` times, or `budget` has passed since it was first sent,
+// whichever comes first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetransmitPolicy {
+    pub attempts: u32,
+    pub budget: Duration,
+}
+
+// How much of each kind of per-peer state we'll keep, in all and for any
+// one peer, so that no peer, however hostile or broken, can run us out of
+// memory. What happens at a cap depends on what's being kept: see each
+// field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    // Acked messages awaiting an ack. Sending another past either cap
+    // fails its delivery at once with MeshError::Overloaded.
+    pub pending: usize,
+    pub pending_per_peer: usize,
+    // Handled acked messages remembered so as to ignore retransmissions;
+    // see dedup::DedupCache. Past either cap the oldest is forgotten.
+    pub dedup_peers: usize,
+    pub dedup_per_peer: usize,
+    // Joins in progress. Starting another past the cap fails it at once
+    // with MeshError::Overloaded.
+    pub joins: usize,
+    // Joins in progress (as `joins`) past which a newcomer's Join isn't
+    // answered with our own, but told to try again later, so that a seed
+    // everyone joins through at once lets them in a few at a time. See
+    // Dispatcher::defer_join.
+    pub join_handshakes: usize,
+    // Newcomers' Joins held for the embedder to decide on (see
+    // Dispatcher::require_admission). Past the cap, a newcomer is told to
+    // try again later, as with `join_handshakes`.
+    pub admissions: usize,
+    // Fast-fail probe sockets open (see ProbeConfig::fast_fail). Past the
+    // cap, probes go out on the main socket.
+    pub probe_sockets: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            pending: 4096,
+            pending_per_peer: 256,
+            dedup_peers: 1024,
+            dedup_per_peer: 64,
+            joins: 64,
+            join_handshakes: 32,
+            admissions: 64,
+            probe_sockets: 16,
+        }
+    }
+}
+
+impl Config {
+    pub fn socket_opts(&self) -> SocketOpts {
+        SocketOpts {
+            recv_buffer_size: self.recv_buffer_size,
+            send_buffer_size: self.send_buffer_size,
+            reuse_addr: self.reuse_addr,
+            // So that probes too large for the path are lost, rather than
+            // fragmented and answered.
+            dont_fragment: self.mtu.enabled,
+        }
+    }
+
+    // Whether a node could run with these settings, as far as can be told
+    // without trying.
+    pub fn validate(&self) -> Result<(), MeshError> {
+        try!(check_cluster(&self.cluster));
+        let zero = Duration::from_secs(0);
+        if self.probe.min_interval == zero
+                || self.probe.min_interval > self.probe.max_interval {
+            return Err(MeshError::InvalidProbeInterval);
+        }
+        Ok(())
+    }
+
+    // The fields that differ in `new` but can't be changed without a
+    // restart: the socket's, and those fixed when the dispatcher starts.
+    // Dispatcher::reload changes the rest.
+    pub fn restart_needed(&self, new: &Config) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.host != new.host { fields.push("host"); }
+        if self.port != new.port { fields.push("port"); }
+        if self.cluster != new.cluster { fields.push("cluster"); }
+        if self.json != new.json { fields.push("json"); }
+        if self.recv_buffer_size != new.recv_buffer_size {
+            fields.push("recv_buffer_size");
+        }
+        if self.send_buffer_size != new.send_buffer_size {
+            fields.push("send_buffer_size");
+        }
+        if self.reuse_addr != new.reuse_addr { fields.push("reuse_addr"); }
+        if self.status_interval != new.status_interval {
+            fields.push("status_interval");
+        }
+        if self.history != new.history { fields.push("history"); }
+        if self.chaos != new.chaos { fields.push("chaos"); }
+        if self.dump_packets != new.dump_packets {
+            fields.push("dump_packets");
+        }
+        // Don't Fragment is set as the socket is bound, and probing
+        // without it finds that everything up to the max gets through.
+        if self.mtu.enabled != new.mtu.enabled {
+            fields.push("mtu.enabled");
+        }
+        // A new detector would forget everything the old one knew.
+        if self.failure_detector != new.failure_detector {
+            fields.push("failure_detector");
+        }
+        fields
+    }
+
+    // What a node running on this runs on once reloaded with `new`: `new`,
+    // but for the fields restart_needed lists, which stay as they are.
+    pub fn reloaded(&self, new: &Config) -> Config {
+        Config {
+            host: self.host.clone(),
+            port: self.port,
+            cluster: self.cluster.clone(),
+            json: self.json,
+            recv_buffer_size: self.recv_buffer_size,
+            send_buffer_size: self.send_buffer_size,
+            reuse_addr: self.reuse_addr,
+            status_interval: self.status_interval,
+            history: self.history,
+            chaos: self.chaos.clone(),
+            dump_packets: self.dump_packets.clone(),
+            failure_detector: self.failure_detector.clone(),
+            mtu: MtuConfig { enabled: self.mtu.enabled, .. new.mtu.clone() },
+            .. new.clone()
+        }
+    }
+}
+
+// A cluster name has to fit on a READY line: not empty, and without
+// spaces or control characters to break it up.
+pub fn check_cluster(name: &str) -> Result<(), MeshError> {
+    if name.is_empty() || name.chars().any(|c| c.is_control() || c == ' ') {
+        Err(MeshError::InvalidCluster(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            cluster: "mesh".to_string(),
+            json: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            reuse_addr: false,
+            failure_detector: DetectorConfig::default(),
+            probe: ProbeConfig::default(),
+            // Getting into the mesh at all matters more than any one
+            // message.
+            join_retransmit: RetransmitPolicy {
+                attempts: 8,
+                budget: Duration::from_secs(10),
+            },
+            data_retransmit: RetransmitPolicy {
+                attempts: 5,
+                budget: Duration::from_secs(5),
+            },
+            limits: Limits::default(),
+            backoff: BackoffConfig::default(),
+            throttle: ThrottleConfig::default(),
+            bandwidth: BandwidthConfig::default(),
+            log_unsolicited: false,
+            explain_rejects: false,
+            ping_strangers: true,
+            observed: ObservedConfig::default(),
+            mtu: MtuConfig::default(),
+            health: HealthConfig::default(),
+            history: 1024,
+            watchdog: WatchdogConfig::default(),
+            timing: TimingConfig::default(),
+            convergence_deadline: Duration::from_secs(30),
+            status_interval: Duration::from_secs(30),
+            coordinator: None,
+            nat_refresh: None,
+            on_isolation: OnIsolation::default(),
+            restart_window: Some(Duration::from_secs(10)),
+            chaos: None,
+            dump_packets: None,
+        }
+    }
+}
+
+#[test]
+fn restart_needed_lists_fixed_fields() {
+    let running = Config::default();
+    let mut new = Config::default();
+    new.probe.fast_fail = true;
+    new.limits.joins = 1;
+    new.explain_rejects = true;
+    new.watchdog.abort = true;
+    new.health.report = false;
+    new.timing.slow = None;
+    new.ping_strangers = false;
+    new.convergence_deadline = Duration::from_secs(1);
+    new.on_isolation = OnIsolation::Exit;
+    new.bandwidth.cap = 50000;
+    new.nat_refresh = Some(Duration::from_secs(20));
+    new.restart_window = None;
+    assert!(running.restart_needed(&new).is_empty());
+
+    new.port = 4000;
+    new.cluster = "prod".to_string();
+    new.reuse_addr = true;
+    new.mtu.enabled = true;
+    new.mtu.max = 4000;
+    assert_eq!(running.restart_needed(&new),
+               vec!["port", "cluster", "reuse_addr", "mtu.enabled"]);
+
+    let reloaded = running.reloaded(&new);
+    assert!(running.restart_needed(&reloaded).is_empty());
+    assert_eq!(reloaded.limits.joins, 1);
+    assert_eq!(reloaded.restart_window, None);
+    assert!(!reloaded.mtu.enabled);
+    assert_eq!(reloaded.mtu.max, 4000);
+}
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+
+// What came of watching for convergence after a join.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    // Caught up this long after joining.
+    Converged(Duration),
+    // Not caught up by the deadline.
+    TimedOut,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // Not joined anything yet.
+    Idle,
+    Watching {
+        joined: Instant,
+        // When we last learned of a member, or joined if we haven't since.
+        learned: Instant,
+        synced: bool,
+    },
+    Converged,
+    TimedOut,
+}
+
+// Whether a node that has just joined a mesh has caught up with it, by a
+// heuristic: it has, once it has synced with the mesh at least once and
+// then gone a whole `quiet` period (as long as the mesh could take to
+// tell it of anyone it's missing) without learning of anyone new. Each
+// join after the first changes nothing; the question is settled once,
+// one way or the other.
+pub struct Convergence {
+    state: State,
+}
+
+impl Convergence {
+    pub fn new() -> Convergence {
+        Convergence { state: State::Idle }
+    }
+
+    // We joined a mesh at `now`.
+    pub fn joined(&mut self, now: Instant) {
+        if self.state == State::Idle {
+            self.state = State::Watching {
+                joined: now,
+                learned: now,
+                synced: false,
+            };
+        }
+    }
+
+    // We learned of a member we didn't know.
+    pub fn learned(&mut self, now: Instant) {
+        if let State::Watching { ref mut learned, .. } = self.state {
+            *learned = now;
+        }
+    }
+
+    // We've had what a member knows of the mesh.
+    pub fn synced(&mut self) {
+        if let State::Watching { ref mut synced, .. } = self.state {
+            *synced = true;
+        }
+    }
+
+    // The clock jumped, and `lost` of the time it says passed never
+    // really did: count from that much later.
+    pub fn shift(&mut self, lost: Duration) {
+        if let State::Watching { ref mut joined, ref mut learned, .. } =
+                self.state {
+            *joined += lost;
+            *learned += lost;
+        }
+    }
+
+    pub fn converged(&self) -> bool {
+        self.state == State::Converged
+    }
+
+    // What's come of it by `now`, the once it comes to something: either
+    // we've caught up, or `deadline` has passed since we joined.
+    pub fn poll(&mut self, now: Instant, quiet: Duration, deadline: Duration)
+            -> Option<Outcome> {
+        let (joined, learned, synced) = match self.state {
+            State::Watching { joined, learned, synced } =>
+                (joined, learned, synced),
+            _ => return None,
+        };
+        if synced && now - learned >= quiet {
+            self.state = State::Converged;
+            Some(Outcome::Converged(now - joined))
+        } else if now - joined >= deadline {
+            self.state = State::TimedOut;
+            Some(Outcome::TimedOut)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+fn secs(n: u64) -> Duration {
+    Duration::from_secs(n)
+}
+
+#[test]
+fn converges_once_synced_and_quiet() {
+    let start = Instant::now();
+    let mut c = Convergence::new();
+    // Nothing to converge on before joining.
+    c.synced();
+    assert_eq!(c.poll(start + secs(60), secs(1), secs(30)), None);
+
+    c.joined(start);
+    c.learned(start + secs(1));
+    assert_eq!(c.poll(start + secs(3), secs(1), secs(30)), None);
+    c.synced();
+    c.learned(start + secs(3));
+    assert_eq!(c.poll(start + secs(3), secs(1), secs(30)), None);
+    assert!(!c.converged());
+    assert_eq!(c.poll(start + secs(4), secs(1), secs(30)),
+               Some(Outcome::Converged(secs(4))));
+    assert!(c.converged());
+
+    // Said once, and for good.
+    c.joined(start + secs(5));
+    c.learned(start + secs(5));
+    assert_eq!(c.poll(start + secs(60), secs(1), secs(30)), None);
+    assert!(c.converged());
+}
+
+#[test]
+fn gives_up_at_the_deadline() {
+    let start = Instant::now();
+    let mut c = Convergence::new();
+    c.joined(start);
+    c.synced();
+    for n in 0..10 {
+        c.learned(start + secs(n * 3));
+        assert_eq!(c.poll(start + secs(n * 3), secs(5), secs(30)), None);
+    }
+    assert_eq!(c.poll(start + secs(30), secs(5), secs(30)),
+               Some(Outcome::TimedOut));
+    assert_eq!(c.poll(start + secs(40), secs(5), secs(30)), None);
+    assert!(!c.converged());
+}
@@ -0,0 +1,360 @@
+use message::{DecodeError, LengthError};
+use rustc_serialize::{Decodable, Decoder};
+use std::str;
+
+// A bincode-compatible decoder over a byte slice that treats its input as
+// hostile: every length is checked against the bytes actually remaining
+// before anything is read or allocated, so no input can make it panic,
+// overflow, or allocate more than the input's own size.
+//
+// Layout (as bincode writes it): integers and floats big-endian, usize
+// and lengths as u64, bools and option tags as a u8 of 0 or 1, enum
+// variants as a u32 index, strings and sequences as a length followed by
+// their contents, chars as bare UTF-8. A frame (see `decode_frame`)
+// differs only in its outermost enum variant, which is a single byte, and
+// may be kept apart from the rest (see `decode_contents`). A length or
+// count that claims more than the bytes left, and anything left over once
+// the value is whole, are told apart from the rest of what's malformed;
+// see message::LengthError.
+pub struct SliceDecoder<'a> {
+    buf: &'a [u8],
+    // The next enum variant, if it's a frame's type byte, read already.
+    variant: Option<u8>,
+}
+
+impl<'a> SliceDecoder<'a> {
+    pub fn new(buf: &'a [u8]) -> SliceDecoder<'a> {
+        SliceDecoder { buf: buf, variant: None }
+    }
+
+    // How many bytes have not been consumed yet.
+    pub fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if n > self.buf.len() {
+            return Err(ran_out());
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn read_be(&mut self, n: usize) -> Result<u64, DecodeError> {
+        let bytes = try!(self.take(n));
+        Ok(bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    // A length prefix, which can't be more than the bytes left to read
+    // given each counted item takes at least `min_item` of them.
+    fn read_len(&mut self, min_item: usize) -> Result<usize, DecodeError> {
+        let len = try!(self.read_u64());
+        if len.saturating_mul(min_item as u64) > self.buf.len() as u64 {
+            return Err(DecodeError::BadLength(LengthError::LengthMismatch));
+        }
+        Ok(len as usize)
+    }
+
+    // `value`, unless it was decoded from less than all of the input.
+    fn whole<T>(&self, value: T) -> Result<T, DecodeError> {
+        if self.buf.is_empty() {
+            Ok(value)
+        } else {
+            Err(DecodeError::BadLength(LengthError::TrailingBytes))
+        }
+    }
+}
+
+// Decode a complete value from `bytes`, and nothing more.
+pub fn decode<T: Decodable>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let mut d = SliceDecoder::new(bytes);
+    let value = try!(T::decode(&mut d));
+    d.whole(value)
+}
+
+// Decode an enum from a frame, which leads with its variant as one byte.
+pub fn decode_frame<T: Decodable>(bytes: &[u8]) -> Result<T, DecodeError> {
+    match bytes.split_first() {
+        Some((&variant, contents)) => decode_contents(variant, contents),
+        None => Err(malformed("unexpected end of datagram")),
+    }
+}
+
+// The same, given the type byte apart from the contents it's of, as where
+// a frame's checksum comes between them.
+pub fn decode_contents<T: Decodable>(variant: u8, contents: &[u8])
+        -> Result<T, DecodeError> {
+    let mut d = SliceDecoder { buf: contents, variant: Some(variant) };
+    let value = try!(T::decode(&mut d));
+    d.whole(value)
+}
+
+fn malformed(why: &str) -> DecodeError {
+    DecodeError::Malformed(why.to_string())
+}
+
+fn ran_out() -> DecodeError {
+    malformed("unexpected end of datagram")
+}
+
+// A sequence or map whose items run out before its count does claimed
+// more than the bytes left, as much as one whose count was too big for
+// them to start with.
+fn counted<T>(result: Result<T, DecodeError>) -> Result<T, DecodeError> {
+    match result {
+        Err(ref e) if *e == ran_out() =>
+            Err(DecodeError::BadLength(LengthError::LengthMismatch)),
+        other => other,
+    }
+}
+
+impl<'a> Decoder for SliceDecoder<'a> {
+    type Error = DecodeError;
+
+    fn read_nil(&mut self) -> Result<(), DecodeError> { Ok(()) }
+
+    fn read_usize(&mut self) -> Result<usize, DecodeError> {
+        let n = try!(self.read_u64());
+        if n > usize::max_value() as u64 {
+            return Err(malformed("usize out of range"));
+        }
+        Ok(n as usize)
+    }
+    fn read_u64(&mut self) -> Result<u64, DecodeError> { self.read_be(8) }
+    fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        self.read_be(4).map(|n| n as u32)
+    }
+    fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        self.read_be(2).map(|n| n as u16)
+    }
+    fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        self.read_be(1).map(|n| n as u8)
+    }
+
+    fn read_isize(&mut self) -> Result<isize, DecodeError> {
+        let n = try!(self.read_i64());
+        if n > isize::max_value() as i64 || n < isize::min_value() as i64 {
+            return Err(malformed("isize out of range"));
+        }
+        Ok(n as isize)
+    }
+    fn read_i64(&mut self) -> Result<i64, DecodeError> {
+        self.read_be(8).map(|n| n as i64)
+    }
+    fn read_i32(&mut self) -> Result<i32, DecodeError> {
+        self.read_be(4).map(|n| n as u32 as i32)
+    }
+    fn read_i16(&mut self) -> Result<i16, DecodeError> {
+        self.read_be(2).map(|n| n as u16 as i16)
+    }
+    fn read_i8(&mut self) -> Result<i8, DecodeError> {
+        self.read_be(1).map(|n| n as u8 as i8)
+    }
+
+    fn read_bool(&mut self) -> Result<bool, DecodeError> {
+        match try!(self.read_u8()) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(malformed("invalid bool")),
+        }
+    }
+
+    fn read_f64(&mut self) -> Result<f64, DecodeError> {
+        self.read_be(8).map(|n| f64::from_bits(n))
+    }
+    fn read_f32(&mut self) -> Result<f32, DecodeError> {
+        self.read_be(4).map(|n| f32::from_bits(n as u32))
+    }
+
+    fn read_char(&mut self) -> Result<char, DecodeError> {
+        let width = match self.buf.first() {
+            None => return Err(ran_out()),
+            Some(&b) if b < 0x80 => 1,
+            Some(&b) if b & 0xe0 == 0xc0 => 2,
+            Some(&b) if b & 0xf0 == 0xe0 => 3,
+            Some(&b) if b & 0xf8 == 0xf0 => 4,
+            Some(_) => return Err(malformed("invalid char")),
+        };
+        let bytes = try!(self.take(width));
+        match str::from_utf8(bytes) {
+            Ok(s) => Ok(s.chars().next().unwrap()),
+            Err(_) => Err(malformed("invalid char")),
+        }
+    }
+
+    fn read_str(&mut self) -> Result<String, DecodeError> {
+        let len = try!(self.read_len(1));
+        let bytes = try!(self.take(len));
+        match str::from_utf8(bytes) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => Err(malformed("string is not UTF-8")),
+        }
+    }
+
+    fn read_enum<T, F>(&mut self, _: &str, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_enum_variant<T, F>(&mut self, names: &[&str], mut f: F)
+            -> Result<T, DecodeError>
+            where F: FnMut(&mut Self, usize) -> Result<T, DecodeError> {
+        let id = match self.variant.take() {
+            Some(variant) => variant as usize,
+            None => try!(self.read_u32()) as usize,
+        };
+        if id >= names.len() {
+            return Err(malformed("unknown enum variant"));
+        }
+        f(self, id)
+    }
+
+    fn read_enum_variant_arg<T, F>(&mut self, _: usize, f: F)
+            -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_enum_struct_variant<T, F>(&mut self, names: &[&str], f: F)
+            -> Result<T, DecodeError>
+            where F: FnMut(&mut Self, usize) -> Result<T, DecodeError> {
+        self.read_enum_variant(names, f)
+    }
+
+    fn read_enum_struct_variant_field<T, F>(&mut self, _: &str, _: usize, f: F)
+            -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_struct<T, F>(&mut self, _: &str, _: usize, f: F)
+            -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_struct_field<T, F>(&mut self, _: &str, _: usize, f: F)
+            -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_tuple<T, F>(&mut self, _: usize, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_tuple_arg<T, F>(&mut self, _: usize, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_tuple_struct<T, F>(&mut self, _: &str, _: usize, f: F)
+            -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_tuple_struct_arg<T, F>(&mut self, _: usize, f: F)
+            -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_option<T, F>(&mut self, mut f: F) -> Result<T, DecodeError>
+            where F: FnMut(&mut Self, bool) -> Result<T, DecodeError> {
+        match try!(self.read_u8()) {
+            0 => f(self, false),
+            1 => f(self, true),
+            _ => Err(malformed("invalid option tag")),
+        }
+    }
+
+    // Sequence and map elements are at least a byte each on the wire (or
+    // so we insist), which bounds how many can be claimed.
+    fn read_seq<T, F>(&mut self, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self, usize) -> Result<T, DecodeError> {
+        let len = try!(self.read_len(1));
+        counted(f(self, len))
+    }
+
+    fn read_seq_elt<T, F>(&mut self, _: usize, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_map<T, F>(&mut self, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self, usize) -> Result<T, DecodeError> {
+        let len = try!(self.read_len(2));
+        counted(f(self, len))
+    }
+
+    fn read_map_elt_key<T, F>(&mut self, _: usize, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn read_map_elt_val<T, F>(&mut self, _: usize, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        f(self)
+    }
+
+    fn error(&mut self, err: &str) -> DecodeError {
+        malformed(err)
+    }
+}
+
+#[test]
+fn decodes_what_bincode_encodes() {
+    use bincode;
+
+    let values: (u8, i16, u32, i64, bool, f64, char, String, Option<u32>, Vec<u16>)
+        = (7, -2, 0xdeadbeef, -1, true, 0.5, 'é', "hi".to_string(),
+           Some(3), vec![1, 2, 3]);
+    let bytes = bincode::encode(&values, bincode::SizeLimit::Infinite).unwrap();
+
+    let decoded: (u8, i16, u32, i64, bool, f64, char, String, Option<u32>, Vec<u16>)
+        = decode(&bytes).unwrap();
+    assert!(decoded == values);
+}
+
+#[test]
+fn frame_variant_is_one_byte() {
+    #[derive(Debug, PartialEq, RustcDecodable)]
+    enum Outer { A, B(Inner) }
+    #[derive(Debug, PartialEq, RustcDecodable)]
+    enum Inner { C, D }
+
+    assert_eq!(decode_frame::<Outer>(&[1, 0, 0, 0, 1]), Ok(Outer::B(Inner::D)));
+    assert_eq!(decode::<Outer>(&[0, 0, 0, 0]), Ok(Outer::A));
+    assert!(decode_frame::<Outer>(&[1, 1]).is_err());
+    assert!(decode_frame::<Outer>(&[]).is_err());
+    assert_eq!(decode_contents::<Outer>(1, &[0, 0, 0, 1]),
+               Ok(Outer::B(Inner::D)));
+    assert!(decode_contents::<Outer>(2, &[]).is_err());
+}
+
+#[test]
+fn rejects_lengths_beyond_input() {
+    // A Vec<u8> claiming a billion elements, with none following.
+    let bytes = [0, 0, 0, 0, 0x40, 0, 0, 0];
+    let mismatch = Err(DecodeError::BadLength(LengthError::LengthMismatch));
+    assert_eq!(decode::<Vec<u8>>(&bytes).map(|_| ()), mismatch);
+    assert_eq!(decode::<String>(&bytes).map(|_| ()), mismatch);
+    // Two u32s claimed, one and a half there.
+    let bytes = [0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0];
+    assert_eq!(decode::<Vec<u32>>(&bytes).map(|_| ()), mismatch);
+    // A u16 cut short is only malformed, there being no length to blame.
+    assert_eq!(decode::<u16>(&[1]), Err(ran_out()));
+}
+
+#[test]
+fn rejects_bytes_after_the_value() {
+    let trailing = Err(DecodeError::BadLength(LengthError::TrailingBytes));
+    assert_eq!(decode::<u16>(&[0, 1]), Ok(1));
+    assert_eq!(decode::<u16>(&[0, 1, 0]).map(|_| ()), trailing);
+    assert_eq!(decode::<Vec<u8>>(&[0, 0, 0, 0, 0, 0, 0, 1, 9, 9])
+               .map(|_| ()), trailing);
+    assert_eq!(decode_contents::<u16>(0, &[0, 1, 0]).map(|_| ()), trailing);
+}
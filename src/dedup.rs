@@ -0,0 +1,184 @@
+use message::Seq;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+// The sequence numbers of acked messages recently handled, by who sent
+// them, so that a retransmission whose ack got lost is acked again but
+// not handled twice. A seq is its session and count together, so a peer
+// that has restarted, or whose count has wrapped, isn't taken to be
+// repeating itself. Both dimensions are bounded, and both drop the
+// oldest to make room: a peer's oldest seq once it has `per_peer`, and
+// the peer remembered longest once there are `peers`. Forgetting costs
+// at worst a duplicate, which acked messages can't rule out anyway, but
+// it's warned of and counted (see `evicted`), as a sign the caps are
+// too small.
+pub struct DedupCache {
+    per_peer: usize,
+    peers: usize,
+    seen: HashMap<SocketAddr, VecDeque<Seq>>,
+    // Peers in the order we started remembering them.
+    order: VecDeque<SocketAddr>,
+    // Seqs forgotten to make room; see `evicted`.
+    evicted: u64,
+}
+
+impl DedupCache {
+    pub fn new(per_peer: usize, peers: usize) -> DedupCache {
+        DedupCache {
+            per_peer: per_peer,
+            peers: peers,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+            evicted: 0,
+        }
+    }
+
+    // Whether this is the first we've seen (as far as we remember) of
+    // `src`'s message `seq`, which is remembered from now on.
+    pub fn first_time(&mut self, src: &SocketAddr, seq: Seq) -> bool {
+        if !self.seen.contains_key(src) {
+            if self.order.len() >= self.peers {
+                if let Some(oldest) = self.order.pop_front() {
+                    println!("WARNING: dedup cache full ({} peers), \
+                              forgetting {} for {}", self.peers, oldest, src);
+                    self.forget_peer(&oldest);
+                }
+            }
+            if self.peers == 0 {
+                return true;
+            }
+            self.order.push_back(*src);
+        }
+
+        let seen = self.seen.entry(*src).or_insert(VecDeque::new());
+        if seen.contains(&seq) {
+            return false;
+        }
+        if seen.len() >= self.per_peer {
+            if let Some(oldest) = seen.pop_front() {
+                println!("WARNING: dedup cache full for {} ({} seqs), \
+                          forgetting {}", src, self.per_peer, oldest);
+                self.evicted += 1;
+            }
+        }
+        if self.per_peer > 0 {
+            seen.push_back(seq);
+        }
+        true
+    }
+
+    // Change the caps, forgetting whatever no longer fits at once.
+    pub fn set_limits(&mut self, per_peer: usize, peers: usize) {
+        self.per_peer = per_peer;
+        self.peers = peers;
+        while self.order.len() > peers {
+            let oldest = self.order.pop_front().unwrap();
+            self.forget_peer(&oldest);
+        }
+        for seen in self.seen.values_mut() {
+            while seen.len() > per_peer {
+                seen.pop_front();
+                self.evicted += 1;
+            }
+        }
+    }
+
+    // Forget `oldest`, the peer remembered longest, to make room.
+    fn forget_peer(&mut self, oldest: &SocketAddr) {
+        if let Some(seen) = self.seen.remove(oldest) {
+            self.evicted += seen.len() as u64;
+        }
+    }
+
+    // Forget everything from `src`, whose sequence numbers are starting
+    // over.
+    pub fn forget(&mut self, src: &SocketAddr) {
+        if self.seen.remove(src).is_some() {
+            self.order.retain(|a| a != src);
+        }
+    }
+
+    // How many peers, and seqs in all, are remembered.
+    pub fn occupancy(&self) -> (usize, usize) {
+        (self.seen.len(), self.seen.values().map(|s| s.len()).sum())
+    }
+
+    // Seqs forgotten to make room for others, whether a peer's oldest or
+    // all of the peer remembered longest at once.
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
+
+#[cfg(test)]
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+}
+
+#[cfg(test)]
+fn seq(n: u32) -> Seq {
+    Seq::new(1, n)
+}
+
+#[test]
+fn dedup_drops_a_peers_oldest_seq() {
+    let mut cache = DedupCache::new(3, 10);
+    for n in 1..5 {
+        assert!(cache.first_time(&addr(1), seq(n)));
+    }
+    assert!(!cache.first_time(&addr(1), seq(4)));
+    assert!(!cache.first_time(&addr(1), seq(2)));
+    // 1 was dropped for 4, so looks new again.
+    assert!(cache.first_time(&addr(1), seq(1)));
+    assert_eq!(cache.occupancy(), (1, 3));
+    assert_eq!(cache.evicted(), 2);
+}
+
+#[test]
+fn dedup_drops_the_oldest_peer() {
+    let mut cache = DedupCache::new(3, 2);
+    assert!(cache.first_time(&addr(1), seq(1)));
+    assert!(cache.first_time(&addr(2), seq(1)));
+    assert!(cache.first_time(&addr(3), seq(1)));
+    assert_eq!(cache.evicted(), 1);
+    assert_eq!(cache.occupancy(), (2, 2));
+    assert!(!cache.first_time(&addr(2), seq(1)));
+    assert!(!cache.first_time(&addr(3), seq(1)));
+    assert!(cache.first_time(&addr(1), seq(1)));
+}
+
+#[test]
+fn dedup_shrinks_to_new_limits() {
+    let mut cache = DedupCache::new(3, 3);
+    for port in 1..4 {
+        for n in 1..4 {
+            cache.first_time(&addr(port), seq(n));
+        }
+    }
+    cache.set_limits(2, 2);
+    assert_eq!(cache.occupancy(), (2, 4));
+    // All three of the first peer's, and one of each other's.
+    assert_eq!(cache.evicted(), 5);
+    assert!(cache.first_time(&addr(1), seq(3)));
+    assert!(!cache.first_time(&addr(3), seq(3)));
+    assert!(cache.first_time(&addr(3), seq(1)));
+}
+
+#[test]
+fn dedup_forgets_on_request() {
+    let mut cache = DedupCache::new(3, 2);
+    cache.first_time(&addr(1), seq(1));
+    cache.forget(&addr(1));
+    assert_eq!(cache.occupancy(), (0, 0));
+    assert!(cache.first_time(&addr(1), seq(1)));
+}
+
+#[test]
+fn dedup_tells_sessions_apart() {
+    let mut cache = DedupCache::new(3, 2);
+    assert!(cache.first_time(&addr(1), Seq::new(1, 5)));
+    assert!(!cache.first_time(&addr(1), Seq::new(1, 5)));
+    // The same count from the peer restarted, or wrapped round.
+    assert!(cache.first_time(&addr(1), Seq::new(2, 5)));
+    assert!(!cache.first_time(&addr(1), Seq::new(2, 5)));
+}
@@ -0,0 +1,283 @@
+use bincode;
+use members::{MetaVersion, NodeId};
+use message::WireAddr;
+use protocol::limits::{DIGEST_BUCKETS, FRAME_OVERHEAD};
+use rustc_serialize::Encodable;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// Syncing membership tables in deltas. The requester sends a Digest of
+// what it knows; the responder works out which of its entries the
+// requester may lack (`differing`) and sends them in Pages, each small
+// enough for a datagram of its own, so a large mesh needs no more than
+// some datagrams and the requester can ask again for just the pages that
+// went missing. Applying a page is idempotent. Everything here is pure;
+// there are no membership messages on the wire yet to carry it.
+
+// What one member is, as far as syncing goes: its id, where it is, its
+// incarnation (see message::Responder), and its tags as of their version
+// (see MetaVersion). Both incarnation and version only go up, each apart
+// from the other.
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
+pub struct Entry {
+    pub id: NodeId,
+    pub addr: WireAddr,
+    pub incarnation: u64,
+    pub meta: MetaVersion,
+    pub tags: BTreeMap<String, String>,
+}
+
+// A summary of a table: for each bucket, a hash of the id, incarnation
+// and version of tags of every entry in it, so that a requester that
+// missed a change of tags differs as much as one that missed a new
+// incarnation. Two tables with the same entries have the same digest,
+// whatever order they were built in.
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
+pub struct Digest(pub Vec<u64>);
+
+// One datagram's worth of a delta, page `page` of `pages`, answering the
+// request numbered `sync`. Each stands alone: which it is and how many
+// there are is all a requester needs to tell what's still missing.
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
+pub struct Page {
+    pub sync: u64,
+    pub page: u32,
+    pub pages: u32,
+    pub entries: Vec<Entry>,
+}
+
+fn bucket(id: NodeId) -> usize {
+    // DIGEST_BUCKETS is 2^6.
+    (id.0 >> 58) as usize
+}
+
+// FNV-1a, over the big-endian bytes of `words`.
+fn fnv(hash: u64, words: &[u64]) -> u64 {
+    let mut hash = hash;
+    for w in words {
+        for i in (0..8).rev() {
+            hash ^= (w >> (i * 8)) & 0xff;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+pub fn digest(entries: &[Entry]) -> Digest {
+    let mut buckets: Vec<Vec<(NodeId, u64, MetaVersion)>> =
+        vec![Vec::new(); DIGEST_BUCKETS];
+    for e in entries {
+        buckets[bucket(e.id)].push((e.id, e.incarnation, e.meta));
+    }
+    Digest(buckets.into_iter().map(|mut b| {
+        b.sort();
+        b.iter().fold(0xcbf29ce484222325, |h, &(id, inc, meta)| {
+            fnv(h, &[id.0, inc, meta.incarnation, meta.counter])
+        })
+    }).collect())
+}
+
+// Our entries in every bucket where `theirs` differs from ours: all the
+// requester may be missing or have out of date, and then some. Nothing
+// when the tables are the same.
+pub fn differing(ours: &[Entry], theirs: &Digest) -> Vec<Entry> {
+    let mine = digest(ours);
+    let mut delta: Vec<Entry> = ours.iter()
+        .filter(|e| {
+            let b = bucket(e.id);
+            theirs.0.get(b) != Some(&mine.0[b])
+        })
+        .cloned().collect();
+    delta.sort_by_key(|e| e.id);
+    delta
+}
+
+fn encoded_len<T: Encodable>(t: &T) -> usize {
+    bincode::encode(t, bincode::SizeLimit::Infinite).unwrap().len()
+}
+
+// `entries` split into pages answering request `sync`, each of which
+// frames to no more than `budget` bytes, though a page always has at
+// least one entry. No entries, no pages.
+pub fn paginate(sync: u64, entries: &[Entry], budget: usize) -> Vec<Page> {
+    let empty = Page { sync: sync, page: 0, pages: 0, entries: Vec::new() };
+    let overhead = FRAME_OVERHEAD + encoded_len(&empty);
+    let mut chunks: Vec<Vec<Entry>> = Vec::new();
+    let mut size = 0;
+    for e in entries {
+        let len = encoded_len(e);
+        if !chunks.is_empty() && overhead + size + len <= budget {
+            chunks.last_mut().unwrap().push(e.clone());
+            size += len;
+        } else {
+            chunks.push(vec![e.clone()]);
+            size = len;
+        }
+    }
+    let pages = chunks.len() as u32;
+    chunks.into_iter().enumerate().map(|(i, entries)| Page {
+        sync: sync,
+        page: i as u32,
+        pages: pages,
+        entries: entries,
+    }).collect()
+}
+
+// Take in what `page` says, keeping whichever incarnation of each entry,
+// and whichever version of its tags, is newer. Applying a page again
+// changes nothing.
+pub fn apply(table: &mut HashMap<NodeId, Entry>, page: &Page) {
+    for e in page.entries.iter() {
+        let known = table.entry(e.id).or_insert_with(|| e.clone());
+        if e.incarnation > known.incarnation {
+            known.addr = e.addr;
+            known.incarnation = e.incarnation;
+        }
+        if e.meta > known.meta {
+            known.meta = e.meta;
+            known.tags = e.tags.clone();
+        }
+    }
+}
+
+// A requester's record of which pages of a sync it has, so as to ask
+// again for the rest when they don't come.
+pub struct Pages {
+    sync: u64,
+    // How many pages there are, once any has said.
+    pages: Option<u32>,
+    received: BTreeSet<u32>,
+}
+
+impl Pages {
+    pub fn new(sync: u64) -> Pages {
+        Pages { sync: sync, pages: None, received: BTreeSet::new() }
+    }
+
+    // Note `page` as received. Returns false if it isn't one of this
+    // sync's, or was already had.
+    pub fn received(&mut self, page: &Page) -> bool {
+        if page.sync != self.sync || page.page >= page.pages {
+            return false;
+        }
+        self.pages = Some(page.pages);
+        self.received.insert(page.page)
+    }
+
+    // The pages still to come. Until one has arrived there's no knowing,
+    // so that's all of them: asked for again as page 0.
+    pub fn missing(&self) -> Vec<u32> {
+        match self.pages {
+            Some(pages) =>
+                (0..pages).filter(|p| !self.received.contains(p)).collect(),
+            None => vec![0],
+        }
+    }
+
+    pub fn complete(&self) -> bool {
+        self.pages.map_or(false, |pages| self.received.len() == pages as usize)
+    }
+}
+
+// A table of `n` members with ids from `seed`.
+#[cfg(test)]
+fn table(n: usize, seed: u32) -> Vec<Entry> {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+    let mut rng = XorShiftRng::from_seed([seed, 2, 3, 4]);
+    (0..n).map(|i| {
+        let changes = rng.gen_range(0, 3);
+        Entry {
+            id: NodeId(rng.gen()),
+            addr: WireAddr(format!("10.0.{}.{}:4000", i / 250, i % 250)
+                           .parse().unwrap()),
+            incarnation: rng.gen_range(0, 5),
+            meta: MetaVersion::new(0, changes),
+            tags: (0..changes).map(|c| (format!("tag{}", c), "x".to_string()))
+                .collect(),
+        }
+    }).collect()
+}
+
+#[test]
+fn the_same_tables_differ_in_nothing() {
+    for &n in &[1, 50, 1000] {
+        let ours = table(n, 1);
+        let mut shuffled = ours.clone();
+        shuffled.reverse();
+        assert_eq!(digest(&shuffled), digest(&ours));
+        let delta = differing(&ours, &digest(&shuffled));
+        assert!(delta.is_empty(), "{} members", n);
+        assert!(paginate(1, &delta, 1400).is_empty());
+    }
+}
+
+#[test]
+fn deltas_bring_a_requester_up_to_date() {
+    for &n in &[1, 50, 1000] {
+        let ours = table(n, 1);
+        // The requester has missed every third member, is behind on
+        // every fifth, and on every seventh's tags.
+        let theirs: Vec<Entry> = ours.iter().enumerate()
+            .filter(|&(i, _)| i % 3 != 0)
+            .map(|(i, e)| if i % 5 == 0 {
+                Entry {
+                    incarnation: e.incarnation.saturating_sub(1),
+                    .. e.clone()
+                }
+            } else if i % 7 == 0 {
+                Entry {
+                    meta: MetaVersion::default(),
+                    tags: BTreeMap::new(),
+                    .. e.clone()
+                }
+            } else {
+                e.clone()
+            })
+            .collect();
+
+        let delta = differing(&ours, &digest(&theirs));
+        assert!(!delta.is_empty() && delta.len() <= n, "{} members", n);
+        let pages = paginate(7, &delta, 1400);
+        let mut known: HashMap<NodeId, Entry> = theirs.iter()
+            .map(|e| (e.id, e.clone())).collect();
+        for page in pages.iter() {
+            assert!(FRAME_OVERHEAD + encoded_len(page) <= 1400);
+            apply(&mut known, page);
+        }
+        let synced: Vec<Entry> = known.values().cloned().collect();
+        assert_eq!(digest(&synced), digest(&ours), "{} members", n);
+        for e in ours.iter() {
+            assert_eq!(known[&e.id].tags, e.tags, "{} members", n);
+        }
+    }
+}
+
+#[test]
+fn pages_stand_alone_and_apply_idempotently() {
+    let ours = table(1000, 1);
+    let pages = paginate(7, &ours, 1400);
+    assert!(pages.len() > 1);
+    let mut known = HashMap::new();
+    let mut had = Pages::new(7);
+    assert_eq!(had.missing(), vec![0]);
+
+    // Every other page arrives, one twice.
+    for page in pages.iter().filter(|p| p.page % 2 == 0) {
+        assert!(had.received(page));
+        apply(&mut known, page);
+    }
+    assert!(!had.received(&pages[0]));
+    apply(&mut known, &pages[0]);
+    assert!(!had.complete());
+    let missing = had.missing();
+    assert_eq!(missing, (0..pages.len() as u32).filter(|p| p % 2 == 1)
+               .collect::<Vec<u32>>());
+
+    // Then, asked for again, the rest.
+    for &p in missing.iter() {
+        assert!(had.received(&pages[p as usize]));
+        apply(&mut known, &pages[p as usize]);
+    }
+    assert!(had.complete());
+    assert_eq!(known.len(), ours.len());
+    assert!(!had.received(&Page { sync: 8, .. pages[0].clone() }));
+}
@@ -0,0 +1,560 @@
+use clock::Nanos;
+use members::NodeId;
+use protocol::limits::PHI_HISTORY;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+// What a failure detector has concluded about a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Verdict {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+// Decides which peers are alive from what we hear of them. The dispatcher
+// feeds it everything it learns and applies the verdicts from `poll` to
+// the membership table.
+pub trait FailureDetector {
+    // A probe of `peer` came back after `rtt`, or (None) timed out.
+    fn on_probe_result(&mut self, peer: NodeId, rtt: Option<Duration>,
+                       now: Instant);
+    // We received something from `peer`.
+    fn on_message(&mut self, peer: NodeId, now: Instant);
+    // Verdicts that have changed since the last poll. A peer declared
+    // Dead is forgotten until it's heard from again.
+    fn poll(&mut self, now: Instant) -> Vec<(NodeId, Verdict)>;
+    // Time jumped, so whatever we've measured up to now is meaningless:
+    // start afresh as if every peer had just been heard from.
+    fn reset(&mut self, now: Instant);
+    // `peer` says it's too busy to answer promptly (see health): give it
+    // `grace` longer than usual before suspecting it, until told
+    // otherwise. A zero `grace` takes it back.
+    fn set_grace(&mut self, peer: NodeId, grace: Duration);
+    // `peer`, suspected, didn't ack our telling it so (see
+    // AckedMessage::Suspected), which is more evidence that it has
+    // failed: declare it Dead halfway from where it's suspected to where
+    // it otherwise would be, until it's heard from again.
+    fn on_suspicion_unanswered(&mut self, peer: NodeId);
+    // `peer` has been declared Dead by other means than us (see
+    // Dispatcher::evict): forget it as though we had, until it's heard
+    // from again.
+    fn forget(&mut self, peer: NodeId);
+}
+
+// Which detector to use, and how it's tuned.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DetectorConfig {
+    Timeout {
+        suspect_after: Duration,
+        dead_after: Duration,
+    },
+    PhiAccrual {
+        suspect_phi: f64,
+        dead_phi: f64,
+        initial_interval: Duration,
+    },
+}
+
+impl DetectorConfig {
+    pub fn build(&self) -> Box<FailureDetector> {
+        match *self {
+            DetectorConfig::Timeout { suspect_after, dead_after } =>
+                Box::new(TimeoutDetector::new(suspect_after, dead_after)),
+            DetectorConfig::PhiAccrual { suspect_phi, dead_phi, initial_interval } =>
+                Box::new(PhiAccrualDetector::new(suspect_phi, dead_phi,
+                                                 initial_interval)),
+        }
+    }
+
+    // How long a peer can be silent before it's suspected: for phi
+    // accrual, while its interval is still the initial one.
+    pub fn suspicion_period(&self) -> Duration {
+        match *self {
+            DetectorConfig::Timeout { suspect_after, .. } => suspect_after,
+            DetectorConfig::PhiAccrual { suspect_phi, initial_interval,
+                                         .. } => {
+                let nanos = Nanos::from(initial_interval).as_nanos() as f64
+                    * suspect_phi / ::std::f64::consts::LOG10_E;
+                Duration::from(Nanos::from_nanos(nanos as u64))
+            },
+        }
+    }
+}
+
+impl Default for DetectorConfig {
+    fn default() -> DetectorConfig {
+        DetectorConfig::Timeout {
+            suspect_after: Duration::from_secs(5),
+            dead_after: Duration::from_secs(30),
+        }
+    }
+}
+
+// Keep the last verdict given for each peer, returning only changes.
+struct Verdicts {
+    last: HashMap<NodeId, Verdict>,
+}
+
+impl Verdicts {
+    fn new() -> Verdicts {
+        Verdicts { last: HashMap::new() }
+    }
+
+    fn update(&mut self, changed: &mut Vec<(NodeId, Verdict)>, peer: NodeId,
+              verdict: Verdict) {
+        if self.last.insert(peer, verdict) != Some(verdict) {
+            changed.push((peer, verdict));
+        }
+    }
+}
+
+// The simplest policy: a peer is suspect once we haven't heard from it
+// for `suspect_after` (or as soon as a probe of it fails), and dead after
+// `dead_after`.
+pub struct TimeoutDetector {
+    suspect_after: Duration,
+    dead_after: Duration,
+    last_heard: HashMap<NodeId, Instant>,
+    failed_probe: HashMap<NodeId, bool>,
+    grace: HashMap<NodeId, Duration>,
+    unanswered: HashSet<NodeId>,
+    verdicts: Verdicts,
+}
+
+impl TimeoutDetector {
+    pub fn new(suspect_after: Duration, dead_after: Duration) -> TimeoutDetector {
+        TimeoutDetector {
+            suspect_after: suspect_after,
+            dead_after: dead_after,
+            last_heard: HashMap::new(),
+            failed_probe: HashMap::new(),
+            grace: HashMap::new(),
+            unanswered: HashSet::new(),
+            verdicts: Verdicts::new(),
+        }
+    }
+}
+
+impl FailureDetector for TimeoutDetector {
+    fn on_probe_result(&mut self, peer: NodeId, rtt: Option<Duration>,
+                       now: Instant) {
+        match rtt {
+            Some(_) => self.on_message(peer, now),
+            None => { self.failed_probe.insert(peer, true); },
+        }
+    }
+
+    fn on_message(&mut self, peer: NodeId, now: Instant) {
+        self.last_heard.insert(peer, now);
+        self.failed_probe.insert(peer, false);
+        self.unanswered.remove(&peer);
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<(NodeId, Verdict)> {
+        let mut changed = Vec::new();
+        let mut dead = Vec::new();
+        for (&peer, &heard) in self.last_heard.iter() {
+            let silent = now - heard;
+            let grace = self.grace.get(&peer).cloned()
+                .unwrap_or(Duration::from_secs(0));
+            let dead_after = if self.unanswered.contains(&peer) {
+                self.suspect_after + (self.dead_after
+                    .checked_sub(self.suspect_after)
+                    .unwrap_or(Duration::from_secs(0))) / 2
+            } else {
+                self.dead_after
+            };
+            let verdict = if silent >= dead_after + grace {
+                dead.push(peer);
+                Verdict::Dead
+            } else if silent >= self.suspect_after + grace
+                    || self.failed_probe[&peer] {
+                Verdict::Suspect
+            } else {
+                Verdict::Alive
+            };
+            self.verdicts.update(&mut changed, peer, verdict);
+        }
+        for peer in dead {
+            self.forget(peer);
+        }
+        changed
+    }
+
+    fn reset(&mut self, now: Instant) {
+        for heard in self.last_heard.values_mut() {
+            *heard = now;
+        }
+        for failed in self.failed_probe.values_mut() {
+            *failed = false;
+        }
+        self.unanswered.clear();
+    }
+
+    fn set_grace(&mut self, peer: NodeId, grace: Duration) {
+        if grace == Duration::from_secs(0) {
+            self.grace.remove(&peer);
+        } else {
+            self.grace.insert(peer, grace);
+        }
+    }
+
+    fn on_suspicion_unanswered(&mut self, peer: NodeId) {
+        if self.last_heard.contains_key(&peer) {
+            self.unanswered.insert(peer);
+        }
+    }
+
+    fn forget(&mut self, peer: NodeId) {
+        self.last_heard.remove(&peer);
+        self.failed_probe.remove(&peer);
+        self.grace.remove(&peer);
+        self.unanswered.remove(&peer);
+        self.verdicts.last.remove(&peer);
+    }
+}
+
+// The phi-accrual detector (Hayashibara et al.): rather than a fixed
+// timeout, it learns how often each peer is normally heard from, and
+// grows more suspicious the less likely the current silence is. phi is
+// -log10 of the probability of a silence at least this long, modelling
+// inter-arrival times as exponentially distributed, so a peer heard from
+// every second is suspected about ten times sooner than one heard from
+// every ten. Until two arrivals have been seen, `initial_interval` stands
+// in for the mean.
+pub struct PhiAccrualDetector {
+    suspect_phi: f64,
+    dead_phi: f64,
+    initial_interval: Duration,
+    peers: HashMap<NodeId, Arrivals>,
+    verdicts: Verdicts,
+}
+
+struct Arrivals {
+    last: Instant,
+    // How much of any silence not to count; see set_grace.
+    grace: Duration,
+    // Whether it has since left a Suspected unacked; see
+    // on_suspicion_unanswered.
+    unanswered: bool,
+    intervals: VecDeque<Nanos>,
+    total: Nanos,
+}
+
+impl Arrivals {
+    fn mean(&self) -> Option<f64> {
+        if self.intervals.is_empty() {
+            None
+        } else {
+            Some(self.total.as_nanos() as f64 / self.intervals.len() as f64)
+        }
+    }
+}
+
+impl PhiAccrualDetector {
+    pub fn new(suspect_phi: f64, dead_phi: f64, initial_interval: Duration)
+            -> PhiAccrualDetector {
+        PhiAccrualDetector {
+            suspect_phi: suspect_phi,
+            dead_phi: dead_phi,
+            initial_interval: initial_interval,
+            peers: HashMap::new(),
+            verdicts: Verdicts::new(),
+        }
+    }
+
+    // The current suspicion level for `peer`, if we've heard from it.
+    pub fn phi(&self, peer: NodeId, now: Instant) -> Option<f64> {
+        self.peers.get(&peer).map(|a| {
+            let mean = a.mean()
+                .unwrap_or(Nanos::from(self.initial_interval).as_nanos()
+                           as f64);
+            let silent = (now - a.last).checked_sub(a.grace)
+                .map_or(0.0, |s| Nanos::from(s).as_nanos() as f64);
+            silent / mean * ::std::f64::consts::LOG10_E
+        })
+    }
+}
+
+impl FailureDetector for PhiAccrualDetector {
+    // A failed probe adds nothing: silence already raises phi.
+    fn on_probe_result(&mut self, peer: NodeId, rtt: Option<Duration>,
+                       now: Instant) {
+        if rtt.is_some() {
+            self.on_message(peer, now);
+        }
+    }
+
+    fn on_message(&mut self, peer: NodeId, now: Instant) {
+        let a = self.peers.entry(peer).or_insert(Arrivals {
+            last: now,
+            grace: Duration::from_secs(0),
+            unanswered: false,
+            intervals: VecDeque::new(),
+            total: Nanos::zero(),
+        });
+        a.unanswered = false;
+        let interval = Nanos::from(now - a.last);
+        a.last = now;
+        if interval == Nanos::zero() {
+            return;
+        }
+        if a.intervals.len() == PHI_HISTORY {
+            a.total = a.total - a.intervals.pop_front().unwrap();
+        }
+        a.intervals.push_back(interval);
+        a.total = a.total + interval;
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<(NodeId, Verdict)> {
+        let mut changed = Vec::new();
+        let peers: Vec<NodeId> = self.peers.keys().cloned().collect();
+        for peer in peers {
+            let phi = self.phi(peer, now).unwrap();
+            let dead_phi = if self.peers[&peer].unanswered {
+                (self.suspect_phi + self.dead_phi) / 2.0
+            } else {
+                self.dead_phi
+            };
+            let verdict = if phi >= dead_phi {
+                self.peers.remove(&peer);
+                Verdict::Dead
+            } else if phi >= self.suspect_phi {
+                Verdict::Suspect
+            } else {
+                Verdict::Alive
+            };
+            self.verdicts.update(&mut changed, peer, verdict);
+            if verdict == Verdict::Dead {
+                self.verdicts.last.remove(&peer);
+            }
+        }
+        changed
+    }
+
+    fn reset(&mut self, now: Instant) {
+        for a in self.peers.values_mut() {
+            a.last = now;
+            a.intervals.clear();
+            a.total = Nanos::zero();
+            a.unanswered = false;
+        }
+    }
+
+    // Silence within the grace doesn't raise phi. What's learned of the
+    // peer's intervals is left alone: they're what it's like when it
+    // isn't busy.
+    fn set_grace(&mut self, peer: NodeId, grace: Duration) {
+        if let Some(a) = self.peers.get_mut(&peer) {
+            a.grace = grace;
+        }
+    }
+
+    fn on_suspicion_unanswered(&mut self, peer: NodeId) {
+        if let Some(a) = self.peers.get_mut(&peer) {
+            a.unanswered = true;
+        }
+    }
+
+    fn forget(&mut self, peer: NodeId) {
+        self.peers.remove(&peer);
+        self.verdicts.last.remove(&peer);
+    }
+}
+
+// A synthetic trace: peer 1 is heard from every 100ms and peer 2 every
+// second, both for ten seconds, after which both go silent. Returns when
+// each was first judged Suspect and Dead, in ms after going silent.
+#[cfg(test)]
+fn run_trace(d: &mut FailureDetector) -> HashMap<(NodeId, Verdict), u64> {
+    let start = Instant::now();
+    let at = |ms: u64| start + Duration::from_millis(ms);
+    let mut first = HashMap::new();
+
+    for ms in 0..30000 {
+        if ms <= 10000 {
+            if ms % 100 == 0 { d.on_message(NodeId(1), at(ms)); }
+            if ms % 1000 == 0 { d.on_message(NodeId(2), at(ms)); }
+        }
+        for (peer, verdict) in d.poll(at(ms)) {
+            if ms > 10000 {
+                first.entry((peer, verdict)).or_insert(ms - 10000);
+            } else {
+                assert_eq!(verdict, Verdict::Alive, "{:?} at {}ms", peer, ms);
+            }
+        }
+    }
+    first
+}
+
+#[test]
+fn timeout_detector_goes_by_silence_alone() {
+    let mut d = TimeoutDetector::new(Duration::from_millis(1500),
+                                     Duration::from_secs(5));
+    let first = run_trace(&mut d);
+    for &peer in [NodeId(1), NodeId(2)].iter() {
+        assert_eq!(first[&(peer, Verdict::Suspect)], 1500);
+        assert_eq!(first[&(peer, Verdict::Dead)], 5000);
+    }
+}
+
+#[test]
+fn timeout_detector_suspects_on_failed_probe() {
+    let mut d = TimeoutDetector::new(Duration::from_secs(5),
+                                     Duration::from_secs(30));
+    let now = Instant::now();
+    d.on_message(NodeId(1), now);
+    assert_eq!(d.poll(now), vec![(NodeId(1), Verdict::Alive)]);
+    d.on_probe_result(NodeId(1), None, now);
+    assert_eq!(d.poll(now), vec![(NodeId(1), Verdict::Suspect)]);
+    d.on_probe_result(NodeId(1), Some(Duration::from_millis(3)), now);
+    assert_eq!(d.poll(now), vec![(NodeId(1), Verdict::Alive)]);
+    assert!(d.poll(now).is_empty());
+}
+
+#[test]
+fn reset_forgives_silence_and_failed_probes() {
+    let start = Instant::now();
+    let later = start + Duration::from_secs(600);
+    let mut detectors: Vec<Box<FailureDetector>> = vec![
+        Box::new(TimeoutDetector::new(Duration::from_secs(5),
+                                      Duration::from_secs(30))),
+        Box::new(PhiAccrualDetector::new(8.0, 16.0, Duration::from_secs(1))),
+    ];
+    for d in detectors.iter_mut() {
+        d.on_message(NodeId(1), start);
+        d.on_probe_result(NodeId(1), None, start);
+        let silent = start + Duration::from_secs(20);
+        assert_eq!(d.poll(silent), vec![(NodeId(1), Verdict::Suspect)]);
+        d.reset(later);
+        assert_eq!(d.poll(later), vec![(NodeId(1), Verdict::Alive)]);
+    }
+}
+
+#[test]
+fn overloaded_peers_get_their_grace() {
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+    let grace = Duration::from_secs(10);
+    let mut detectors: Vec<Box<FailureDetector>> = vec![
+        Box::new(TimeoutDetector::new(Duration::from_secs(5),
+                                      Duration::from_secs(30))),
+        Box::new(PhiAccrualDetector::new(2.0, 16.0, Duration::from_secs(1))),
+    ];
+    for d in detectors.iter_mut() {
+        d.on_message(NodeId(1), at(0));
+        d.on_message(NodeId(2), at(0));
+        d.set_grace(NodeId(1), grace);
+        let mut verdicts = d.poll(at(14));
+        verdicts.sort_by_key(|&(peer, _)| peer.0);
+        assert_eq!(verdicts, vec![(NodeId(1), Verdict::Alive),
+                                  (NodeId(2), Verdict::Suspect)]);
+        // Taking the grace back counts the whole silence again.
+        d.set_grace(NodeId(1), Duration::from_secs(0));
+        assert_eq!(d.poll(at(14)), vec![(NodeId(1), Verdict::Suspect)]);
+        d.set_grace(NodeId(1), grace);
+        assert_eq!(d.poll(at(14)), vec![(NodeId(1), Verdict::Alive)]);
+        // And the grace runs out.
+        assert_eq!(d.poll(at(16)), vec![(NodeId(1), Verdict::Suspect)]);
+    }
+}
+
+#[test]
+fn unanswered_suspicions_bring_death_forward() {
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+    let mut detectors: Vec<Box<FailureDetector>> = vec![
+        // Dead at 17.5s rather than 30s.
+        Box::new(TimeoutDetector::new(Duration::from_secs(5),
+                                      Duration::from_secs(30))),
+        // At phi 9 rather than 16: after 20.7s rather than 36.8s.
+        Box::new(PhiAccrualDetector::new(2.0, 16.0, Duration::from_secs(1))),
+    ];
+    for d in detectors.iter_mut() {
+        for &peer in &[NodeId(1), NodeId(2), NodeId(3)] {
+            d.on_message(peer, at(0));
+        }
+        d.on_suspicion_unanswered(NodeId(1));
+        d.on_suspicion_unanswered(NodeId(3));
+        let mut verdicts = d.poll(at(17));
+        verdicts.sort_by_key(|&(peer, _)| peer.0);
+        assert_eq!(verdicts, vec![(NodeId(1), Verdict::Suspect),
+                                  (NodeId(2), Verdict::Suspect),
+                                  (NodeId(3), Verdict::Suspect)]);
+        // Hearing from a peer takes it back.
+        d.on_message(NodeId(3), at(18));
+        let mut verdicts = d.poll(at(21));
+        verdicts.sort_by_key(|&(peer, _)| peer.0);
+        assert_eq!(verdicts, vec![(NodeId(1), Verdict::Dead),
+                                  (NodeId(3), Verdict::Alive)]);
+        assert!(!d.poll(at(38)).contains(&(NodeId(3), Verdict::Dead)));
+    }
+}
+
+#[test]
+fn forgotten_peers_are_judged_afresh_once_heard_from() {
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+    let mut detectors: Vec<Box<FailureDetector>> = vec![
+        Box::new(TimeoutDetector::new(Duration::from_secs(5),
+                                      Duration::from_secs(30))),
+        Box::new(PhiAccrualDetector::new(8.0, 16.0, Duration::from_secs(1))),
+    ];
+    for d in detectors.iter_mut() {
+        d.on_message(NodeId(1), at(0));
+        assert_eq!(d.poll(at(0)), vec![(NodeId(1), Verdict::Alive)]);
+        d.forget(NodeId(1));
+        assert!(d.poll(at(60)).is_empty());
+        d.on_message(NodeId(1), at(60));
+        assert_eq!(d.poll(at(60)), vec![(NodeId(1), Verdict::Alive)]);
+    }
+}
+
+#[test]
+fn phi_accrual_detector_adapts_to_each_peer() {
+    let mut d = PhiAccrualDetector::new(8.0, 16.0, Duration::from_secs(1));
+    let first = run_trace(&mut d);
+
+    // phi reaches 8 after 8 / log10(e) ~= 18.4 mean intervals of silence.
+    let fast = first[&(NodeId(1), Verdict::Suspect)];
+    let slow = first[&(NodeId(2), Verdict::Suspect)];
+    assert!(fast >= 1800 && fast <= 1900, "fast peer suspected at {}", fast);
+    assert!(slow >= 18000 && slow <= 19000, "slow peer suspected at {}", slow);
+    let dead = first[&(NodeId(1), Verdict::Dead)];
+    assert!(dead >= 3600 && dead <= 3700, "fast peer dead at {}", dead);
+    assert!(!first.contains_key(&(NodeId(2), Verdict::Dead)));
+}
+
+#[test]
+fn detectors_are_chosen_by_config() {
+    let now = Instant::now();
+    let later = now + Duration::from_secs(6);
+    let configs = vec![DetectorConfig::default(),
+                       DetectorConfig::PhiAccrual {
+                           suspect_phi: 1.0,
+                           dead_phi: 100.0,
+                           initial_interval: Duration::from_secs(1),
+                       }];
+    for config in configs {
+        let mut d = config.build();
+        d.on_message(NodeId(1), now);
+        d.poll(now);
+        assert_eq!(d.poll(later), vec![(NodeId(1), Verdict::Suspect)]);
+    }
+}
+
+#[test]
+fn suspicion_period_is_how_long_until_suspect() {
+    assert_eq!(DetectorConfig::default().suspicion_period(),
+               Duration::from_secs(5));
+    // phi reaches 1 after 1 / log10(e) ~= 2.3 initial intervals.
+    let phi = DetectorConfig::PhiAccrual {
+        suspect_phi: 1.0,
+        dead_phi: 100.0,
+        initial_interval: Duration::from_secs(1),
+    };
+    let period = phi.suspicion_period();
+    assert!(period > Duration::from_millis(2300)
+            && period < Duration::from_millis(2310), "{:?}", period);
+}
@@ -0,0 +1,6746 @@
+use admission::{Admission, Admissions, Held, JoinCandidate};
+use backoff::Backoff;
+use bandwidth::{self, Bandwidth};
+use bincode;
+use chaos;
+#[cfg(test)] use clock;
+use clock::{Clock, Nanos};
+use config::{Config, Limits, RetransmitPolicy};
+use converge::{Convergence, Outcome};
+use dedup::DedupCache;
+use dump::{self, ChangeDump, DebugDump, PeerDump, PendingDump, TimerDump};
+use error::MeshError;
+use detector::{FailureDetector, Verdict};
+use event::MeshEvent;
+use fastpath;
+use handlers::{self, DispatchCtx, Handlers};
+use health::{HealthConfig, RecentDrops};
+use history::{Cause, Change, History};
+use hooks::{HookAction, Hooks};
+use isolation::{self, Isolation, OnIsolation};
+use join::{JoinStatus, JoinTicket};
+use keepalive::Keepalives;
+use members::{Members, MetaVersion, NodeId, Peer, PeerState, Snapshot,
+              Suspicion};
+#[cfg(test)] use members::SuspicionReason;
+use merge::{self, MemberEntry, MemberUpdate, MergeOutcome, Us};
+use message::{self, AckedMessage, DecodeError, Health, LengthError, Message,
+              MessageKind, PingBody, RejectCode, Responder, Seq, WireAddr};
+use mtu::{MtuConfig, MtuProber};
+use observed::Observations;
+use ordered::{self, Arrival, Streams};
+#[cfg(test)] use observed::ObservedConfig;
+use outbound::{Band, BANDS, OutboundQueue};
+use probe::{self, Feedback, ProbeConfig, ProbeOrder};
+use protocol::limits::{ACK_BATCH, ACK_DELAY, CLOCK_JUMP_FACTOR,
+                       JOIN_RETRY_AFTER, JOIN_RETRY_AFTER_MAX,
+                       MALFORMED_KEPT, MAX_MESSAGE_SIZE, MAX_PING_PAD,
+                       MAX_REJECT_REASON, MAX_TAGS_SIZE, MIN_MTU,
+                       OUTSTANDING_PINGS, RECV_BUFFER_SIZE, RETRANSMIT,
+                       SIZE_REPORT_CYCLES, SYNC_DELAY, TICK};
+#[cfg(test)] use protocol::limits::ADMISSION_TIMEOUT;
+use rand::{self, Rng, XorShiftRng};
+use rejects::RejectLimiter;
+use score::{self, Scores};
+use scheduler::{SchedulerHandle, Timer, Watchdog, WatchdogConfig};
+use size::{SizeEstimate, SizeReports};
+use sockopts::SocketInfo;
+use throttle::{self, Throttle};
+use timing::TimingConfig;
+use stats::{MemoryStats, Stats};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::process;
+use std::net::SocketAddr;
+#[cfg(test)] use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+#[cfg(test)] use testing::{Latency, LatencyModel};
+use transport::{self, Connected, Transport};
+
+// The first protocol version with AckSack. Ordered messages from peers
+// on this or later are acked with one, as often as other acks go out;
+// from anyone else, with plain Acks.
+const SACK_VERSION: u8 = 6;
+
+// The first protocol version with AckedMessage::Suspected. Only peers on
+// this or later are told we suspect them.
+const SUSPECTED_VERSION: u8 = 8;
+
+// The first protocol version with JoinForward. Only a Join on this or
+// later, whose sender takes an Ack from whoever it comes, is handed on,
+// and only to a peer on this or later.
+const JOIN_FORWARD_VERSION: u8 = 9;
+
+// The first protocol version with Keepalive. Only peers on this or later
+// are sent one.
+const KEEPALIVE_VERSION: u8 = 11;
+
+// The first protocol version with JoinRetryAfter. A newcomer's Join on
+// an earlier one that we turn away gets no answer at all.
+const JOIN_RETRY_VERSION: u8 = 12;
+
+// The first protocol version with AckedMessage::Tags. Only peers on this
+// or later are sent our tags.
+const TAGS_VERSION: u8 = 14;
+
+// The first protocol version with JoinRejected. A newcomer's Join on an
+// earlier one that we turn away, on the embedder's say-so, gets no answer
+// at all.
+const ADMISSION_VERSION: u8 = 15;
+
+// Things the dispatcher's own timer can fire.
+enum Timeout {
+    Retransmit(Seq),
+    // Probe a peer, unless the generation is out of date because the
+    // probe has since been rescheduled.
+    Probe(u32),
+    // Send whatever acks are waiting for this peer.
+    FlushAcks(SocketAddr),
+    // Send this peer an AckSack, if it has sent us ordered messages since
+    // the last.
+    FlushSack(SocketAddr),
+    // Ask this peer to announce itself afresh; see check_incarnation.
+    Sync(SocketAddr),
+    // Lift the block on an address that flooded us; see Throttle.
+    Unblock(SocketAddr),
+}
+
+// An acked message we've sent and not yet heard back about.
+struct Pending {
+    kind: MessageKind,
+    band: Band,
+    bytes: Vec<u8>,
+    target: SocketAddr,
+    // Its place in the target's ordered stream, if it's an Ordered
+    // message, by which an AckSack acks it.
+    stream: Option<u32>,
+    first_sent: Instant,
+    retransmits: u32,
+    // Held back until then, for a Join whose target told us to try again
+    // later; see Dispatcher::retry_join_after.
+    retry_at: Option<Instant>,
+}
+
+// A connected socket a probe went out on (see ProbeConfig::fast_fail),
+// watched until the next probe is due for the Pong or for word that
+// nothing is listening.
+struct ProbeSocket {
+    target: SocketAddr,
+    socket: Box<Connected>,
+    until: Instant,
+}
+
+// A Join we've sent, until its JoinTicket is resolved. That takes both
+// an Ack and the target's own Join, which tells us who it is.
+struct JoinAttempt {
+    ticket: JoinTicket,
+    acked: bool,
+    started: Instant,
+    // Who acked it, if not the target: a peer the target handed it on to
+    // (see Dispatcher::forward_join), whose Join we wait for instead.
+    contact: Option<SocketAddr>,
+}
+
+// Owns the socket and all protocol state, handling received messages and
+// periodic maintenance on a single thread. recv_from is given a short
+// timeout, so even when nothing arrives we regularly get the chance to
+// advance the timer and check for shutdown.
+//
+// Being owned by one thread, the protocol state takes no locks: the
+// membership table, pending acks and the rest change together, in one
+// poll, and other threads see them only as copies they ask for (see
+// builder::Mesh). What is shared is only ever read elsewhere: the
+// shutdown and converged flags, which are atomics, and `history`, which
+// is the one lock here. It's held only to record or copy a change, with
+// no other lock held, no I/O done and no callback called.
+pub struct Dispatcher<T, C> {
+    id: NodeId,
+    // Everything we pick at random comes from `rng`, seeded with `seed`;
+    // see `with_seed`.
+    seed: u64,
+    rng: XorShiftRng,
+    // Raised whenever what peers know of us goes out of date; our Acks
+    // carry it. See message::Responder.
+    incarnation: u64,
+    // When we last refuted a Join sent as us; see `impersonated`.
+    refuted_at: Option<Instant>,
+    // Our tags, and which version of them this is; see `set_tag`.
+    tags: BTreeMap<String, String>,
+    meta: MetaVersion,
+    // Where we're bound, if the transport could say.
+    addr: Option<SocketAddr>,
+    // Where we tell others to find us: where we're bound, until peers
+    // agree otherwise and we're configured to believe them.
+    advertised: Option<SocketAddr>,
+    // What peers' Acks and Pongs say our address is.
+    observations: Observations,
+    // How large a datagram gets through to each peer.
+    mtu: MtuProber,
+    // What our Pongs say of us, and what we make of what peers' say.
+    health: HealthConfig,
+    drops: RecentDrops,
+    // Whether to time handling messages; see timing::TimingConfig.
+    timing: TimingConfig,
+    // The smallest datagram the OS has refused to send each address, as
+    // too big for the path there.
+    too_big: HashMap<SocketAddr, usize>,
+    transport: T,
+    clock: C,
+    members: Members,
+    detector: Box<FailureDetector>,
+    events: VecDeque<MeshEvent>,
+    inbound_hooks: Hooks,
+    outbound_hooks: Hooks,
+    timer: Timer<Timeout>,
+    // Ticks longer than this are clock jumps; see CLOCK_JUMP_FACTOR.
+    jump_threshold: Duration,
+    // When we started; our Ping timestamps count from here.
+    epoch: Instant,
+    last_tick: Instant,
+    // Ours, in the session we're numbering in now.
+    next_seq: Seq,
+    pending: HashMap<Seq, Pending>,
+    // Acks we owe, by who we owe them to.
+    acks: HashMap<SocketAddr, Vec<Seq>>,
+    // How many ordered messages each peer that takes AckSacks has sent
+    // since its last, and the session the latest was sent in.
+    sacks: HashMap<SocketAddr, (usize, u32)>,
+    // Acked messages recently handled.
+    dedup: DedupCache,
+    limits: Limits,
+    handlers: Handlers,
+    // Whether Pongs still go to the built in handler, which the
+    // dispatcher can stand in for; see `set_handler`.
+    builtin_pong: bool,
+    shutdown: Arc<AtomicBool>,
+    recv_buf: Vec<u8>,
+    outbound: OutboundQueue,
+    probe: ProbeConfig,
+    join_retransmit: RetransmitPolicy,
+    data_retransmit: RetransmitPolicy,
+    probe_interval: Duration,
+    probe_gen: u32,
+    // When recent joins and state changes happened, oldest first.
+    churn: VecDeque<Instant>,
+    probe_sockets: Vec<ProbeSocket>,
+    probe_order: ProbeOrder,
+    joins: Vec<JoinAttempt>,
+    // Whether we've caught up with the mesh since joining it, shared for
+    // reading from other threads; see `converged_handle`.
+    convergence: Convergence,
+    convergence_deadline: Duration,
+    converged: Arc<AtomicBool>,
+    backoff: Backoff,
+    throttle: Throttle,
+    bandwidth: Bandwidth,
+    keepalives: Keepalives,
+    // How good each peer is to rely on; see score::Scores.
+    scores: Scores,
+    // The nonces of Pings we've sent and who to, oldest first.
+    pings: VecDeque<(u64, SocketAddr)>,
+    // Those of them sent by probe_now, and to which peer.
+    asked_probes: VecDeque<(u64, NodeId)>,
+    // Peers we've evicted, and the incarnation each was on at the time;
+    // see `evict`.
+    evicted: HashMap<NodeId, u64>,
+    // Who to hand newcomers' Joins to, if anyone; see `forward_join`.
+    coordinator: Option<SocketAddr>,
+    // Whether the Join being handled was handed to us by another peer,
+    // and so is ours to answer; see `take_over_join`.
+    taking_over: bool,
+    // Newcomers' Joins waiting for the embedder to let them in, if it's
+    // to decide; see `require_admission`. And whether the Join being
+    // handled is one it let in, and so is to be answered.
+    admissions: Option<Admissions>,
+    admitting: bool,
+    // How lately a peer must have been heard from for a newcomer at its
+    // address to replace it; see `replace_restarted`.
+    restart_window: Option<Duration>,
+    // Whether every peer is dead, what to do about it, and for a rejoin,
+    // who to ask: everyone we've been asked to join. See
+    // `check_isolation`.
+    isolation: Isolation,
+    on_isolation: OnIsolation,
+    suspicion_period: Duration,
+    seeds: Vec<SocketAddr>,
+    // What the process should exit with, if we shut ourselves down.
+    exit_code: Option<i32>,
+    // The latest datagrams that didn't parse, oldest first, for
+    // `debug_dump`.
+    malformed: VecDeque<Vec<u8>>,
+    // Whether to log corrupt datagrams whole, in hex, as we do when
+    // packets are being dumped; see Config::dump_packets.
+    log_corrupt: bool,
+    log_unsolicited: bool,
+    // Whether to answer frames we drop with a Rejected, and how often
+    // each source may be.
+    explain_rejects: bool,
+    // Whether to answer Pings from those who aren't peers.
+    ping_strangers: bool,
+    rejects: RejectLimiter,
+    // Messages we've sent ourselves, to be handled on the next poll.
+    loopback: VecDeque<Message>,
+    // Where each peer's ordered messages are up to, both ways.
+    streams: Streams,
+    // Shared with whoever asks, for reading from other threads; see
+    // `history_handle`.
+    history: Arc<Mutex<History>>,
+    // The latest snapshot of the membership table, likewise; see
+    // `snapshot_handle`.
+    snapshot: Arc<Mutex<Arc<Snapshot>>>,
+    // How big our peers last said the mesh is; see `estimated_size`.
+    sizes: SizeReports,
+    // The Scheduler whose timer thread we're watching, if any; see
+    // `watch`.
+    watchdog_config: WatchdogConfig,
+    watchdog: Option<Watchdog>,
+    pub stats: Stats,
+}
+
+impl<T: Transport, C: Clock> Dispatcher<T, C> {
+    pub fn new(transport: T, clock: C) -> Dispatcher<T, C> {
+        Dispatcher::with_config(transport, clock, &Config::default())
+    }
+
+    // A dispatcher using the protocol settings from `config`. (The socket
+    // settings are the caller's business.)
+    pub fn with_config(transport: T, clock: C, config: &Config)
+            -> Dispatcher<T, C> {
+        Dispatcher::with_seed(transport, clock, config, NodeId::random(),
+                              rand::random())
+    }
+
+    // The same, as node `id`, with whatever it picks at random (Ping
+    // nonces, who to probe next and who to hand Joins on to) drawn from
+    // `seed`: fed the same datagrams at the same times, it does just what
+    // another started with them did. See replay.
+    pub fn with_seed(transport: T, clock: C, config: &Config, id: NodeId,
+                     seed: u64) -> Dispatcher<T, C> {
+        transport.set_read_timeout(Some(TICK))
+            .unwrap();
+        let now = clock.now();
+        let mut rng = chaos::seeded_rng(seed);
+        let session = new_session(&mut rng, 0);
+
+        let mut d = Dispatcher {
+            id: id,
+            seed: seed,
+            rng: rng,
+            incarnation: 0,
+            refuted_at: None,
+            tags: BTreeMap::new(),
+            meta: MetaVersion::default(),
+            addr: transport.local_addr().ok(),
+            advertised: transport.local_addr().ok(),
+            observations: Observations::new(config.observed.clone()),
+            mtu: MtuProber::new(config.mtu.clone()),
+            health: config.health.clone(),
+            drops: RecentDrops::new(now),
+            timing: config.timing.clone(),
+            too_big: HashMap::new(),
+            transport: transport,
+            clock: clock,
+            members: Members::new(),
+            detector: config.failure_detector.build(),
+            events: VecDeque::new(),
+            inbound_hooks: Hooks::new(),
+            outbound_hooks: Hooks::new(),
+            timer: Timer::new(),
+            jump_threshold: jump_threshold(config),
+            epoch: now,
+            last_tick: now,
+            next_seq: Seq::new(session, 1),
+            pending: HashMap::new(),
+            acks: HashMap::new(),
+            sacks: HashMap::new(),
+            dedup: DedupCache::new(config.limits.dedup_per_peer,
+                                   config.limits.dedup_peers),
+            limits: config.limits.clone(),
+            handlers: Handlers::builtin(),
+            builtin_pong: true,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            recv_buf: vec![0; RECV_BUFFER_SIZE],
+            outbound: OutboundQueue::new(),
+            probe: config.probe.clone(),
+            join_retransmit: config.join_retransmit.clone(),
+            data_retransmit: config.data_retransmit.clone(),
+            probe_interval: Duration::from_secs(0),
+            probe_gen: 0,
+            churn: VecDeque::new(),
+            probe_sockets: Vec::new(),
+            probe_order: ProbeOrder::new(),
+            joins: Vec::new(),
+            convergence: Convergence::new(),
+            convergence_deadline: config.convergence_deadline,
+            converged: Arc::new(AtomicBool::new(false)),
+            backoff: Backoff::new(config.backoff.clone()),
+            throttle: Throttle::new(config.throttle.clone()),
+            bandwidth: Bandwidth::new(config.bandwidth.clone()),
+            keepalives: Keepalives::new(config.nat_refresh),
+            scores: Scores::new(),
+            pings: VecDeque::new(),
+            asked_probes: VecDeque::new(),
+            evicted: HashMap::new(),
+            coordinator: config.coordinator,
+            taking_over: false,
+            admissions: None,
+            admitting: false,
+            restart_window: config.restart_window,
+            isolation: Isolation::new(),
+            on_isolation: config.on_isolation,
+            suspicion_period: config.failure_detector.suspicion_period(),
+            seeds: Vec::new(),
+            exit_code: None,
+            malformed: VecDeque::new(),
+            log_corrupt: config.dump_packets.is_some(),
+            log_unsolicited: config.log_unsolicited,
+            explain_rejects: config.explain_rejects,
+            ping_strangers: config.ping_strangers,
+            rejects: RejectLimiter::new(),
+            loopback: VecDeque::new(),
+            streams: Streams::new(),
+            history: Arc::new(Mutex::new(History::new(config.history))),
+            snapshot: Arc::new(Mutex::new(Arc::new(Members::new()
+                                                   .snapshot()))),
+            sizes: SizeReports::new(),
+            watchdog_config: config.watchdog.clone(),
+            watchdog: None,
+            stats: Stats::new(),
+        };
+        d.update_probe_interval(now, true);
+        d
+    }
+
+    // Switch to the protocol settings in `config` that can change while
+    // we run (the probe, retransmission, limits, backoff, throttle,
+    // bandwidth, observed address, MTU, health, watchdog and timing
+    // settings, the convergence deadline, the coordinator, what to do once
+    // isolated, the restart window, and whether to explain rejects or ping
+    // strangers), logging each that's different, and return their names.
+    // The others are ignored; see Config::restart_needed. A changed probe
+    // interval takes over from the next probe already scheduled.
+    pub fn reload(&mut self, config: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.probe != config.probe {
+            println!("Reloaded probe: {:?} -> {:?}", self.probe, config.probe);
+            self.probe = config.probe.clone();
+            changed.push("probe");
+        }
+        if self.join_retransmit != config.join_retransmit {
+            println!("Reloaded join_retransmit: {:?} -> {:?}",
+                     self.join_retransmit, config.join_retransmit);
+            self.join_retransmit = config.join_retransmit.clone();
+            changed.push("join_retransmit");
+        }
+        if self.data_retransmit != config.data_retransmit {
+            println!("Reloaded data_retransmit: {:?} -> {:?}",
+                     self.data_retransmit, config.data_retransmit);
+            self.data_retransmit = config.data_retransmit.clone();
+            changed.push("data_retransmit");
+        }
+        if self.limits != config.limits {
+            println!("Reloaded limits: {:?} -> {:?}", self.limits,
+                     config.limits);
+            self.limits = config.limits.clone();
+            self.dedup.set_limits(config.limits.dedup_per_peer,
+                                  config.limits.dedup_peers);
+            if let Some(ref mut admissions) = self.admissions {
+                admissions.set_capacity(config.limits.admissions);
+            }
+            changed.push("limits");
+        }
+        if *self.backoff.config() != config.backoff {
+            println!("Reloaded backoff: {:?} -> {:?}", self.backoff.config(),
+                     config.backoff);
+            self.backoff.set_config(config.backoff.clone());
+            changed.push("backoff");
+        }
+        if *self.throttle.config() != config.throttle {
+            println!("Reloaded throttle: {:?} -> {:?}", self.throttle.config(),
+                     config.throttle);
+            self.throttle.set_config(config.throttle.clone());
+            changed.push("throttle");
+        }
+        if *self.bandwidth.config() != config.bandwidth {
+            println!("Reloaded bandwidth: {:?} -> {:?}",
+                     self.bandwidth.config(), config.bandwidth);
+            self.bandwidth.set_config(config.bandwidth.clone());
+            changed.push("bandwidth");
+        }
+        if *self.observations.config() != config.observed {
+            println!("Reloaded observed: {:?} -> {:?}",
+                     self.observations.config(), config.observed);
+            self.observations.set_config(config.observed.clone());
+            changed.push("observed");
+        }
+        // But for whether to probe at all, which goes with the socket's
+        // Don't Fragment; see Config::restart_needed.
+        let mtu = MtuConfig {
+            enabled: self.mtu.config().enabled,
+            .. config.mtu.clone()
+        };
+        if *self.mtu.config() != mtu {
+            println!("Reloaded mtu: {:?} -> {:?}", self.mtu.config(), mtu);
+            self.mtu.set_config(mtu);
+            changed.push("mtu");
+        }
+        if self.health != config.health {
+            println!("Reloaded health: {:?} -> {:?}", self.health,
+                     config.health);
+            self.health = config.health.clone();
+            changed.push("health");
+        }
+        if self.log_unsolicited != config.log_unsolicited {
+            println!("Reloaded log_unsolicited: {:?} -> {:?}",
+                     self.log_unsolicited, config.log_unsolicited);
+            self.log_unsolicited = config.log_unsolicited;
+            changed.push("log_unsolicited");
+        }
+        if self.explain_rejects != config.explain_rejects {
+            println!("Reloaded explain_rejects: {:?} -> {:?}",
+                     self.explain_rejects, config.explain_rejects);
+            self.explain_rejects = config.explain_rejects;
+            changed.push("explain_rejects");
+        }
+        if self.ping_strangers != config.ping_strangers {
+            println!("Reloaded ping_strangers: {:?} -> {:?}",
+                     self.ping_strangers, config.ping_strangers);
+            self.ping_strangers = config.ping_strangers;
+            changed.push("ping_strangers");
+        }
+        if self.watchdog_config != config.watchdog {
+            println!("Reloaded watchdog: {:?} -> {:?}", self.watchdog_config,
+                     config.watchdog);
+            self.watchdog_config = config.watchdog.clone();
+            if let Some(ref mut watchdog) = self.watchdog {
+                watchdog.set_config(config.watchdog.clone());
+            }
+            changed.push("watchdog");
+        }
+        if self.convergence_deadline != config.convergence_deadline {
+            println!("Reloaded convergence_deadline: {:?} -> {:?}",
+                     self.convergence_deadline, config.convergence_deadline);
+            self.convergence_deadline = config.convergence_deadline;
+            changed.push("convergence_deadline");
+        }
+        if self.coordinator != config.coordinator {
+            println!("Reloaded coordinator: {:?} -> {:?}", self.coordinator,
+                     config.coordinator);
+            self.coordinator = config.coordinator;
+            changed.push("coordinator");
+        }
+        if self.keepalives.interval() != config.nat_refresh {
+            println!("Reloaded nat_refresh: {:?} -> {:?}",
+                     self.keepalives.interval(), config.nat_refresh);
+            self.keepalives.set_interval(config.nat_refresh);
+            changed.push("nat_refresh");
+        }
+        if self.on_isolation != config.on_isolation {
+            println!("Reloaded on_isolation: {:?} -> {:?}", self.on_isolation,
+                     config.on_isolation);
+            self.on_isolation = config.on_isolation;
+            changed.push("on_isolation");
+        }
+        if self.timing != config.timing {
+            println!("Reloaded timing: {:?} -> {:?}", self.timing,
+                     config.timing);
+            self.timing = config.timing.clone();
+            changed.push("timing");
+        }
+        if self.restart_window != config.restart_window {
+            println!("Reloaded restart_window: {:?} -> {:?}",
+                     self.restart_window, config.restart_window);
+            self.restart_window = config.restart_window;
+            changed.push("restart_window");
+        }
+
+        self.jump_threshold = jump_threshold(config);
+        if changed.contains(&"probe") {
+            // Bumps the probe generation, so the probe already scheduled
+            // won't fire as well.
+            let now = self.clock.now();
+            self.update_probe_interval(now, true);
+        }
+        changed
+    }
+
+    // A flag which, once set, makes `run` return after its current tick.
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    // What the process should exit with, if we've shut ourselves down:
+    // isolation::EXIT_CODE once isolated, if Config::on_isolation says
+    // to exit.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // The latest changes in peers' states, newest first, only `peer`'s if
+    // given; see history::History.
+    pub fn history(&self, peer: Option<NodeId>) -> Vec<Change> {
+        self.history.lock().unwrap().changes(peer)
+    }
+
+    // How many datagrams from `addr` we've dropped for its sending too
+    // much.
+    pub fn throttled(&self, addr: &SocketAddr) -> u64 {
+        self.throttle.dropped(addr)
+    }
+
+    // Watch `scheduler`'s timer thread from our own tick, as
+    // Config::watchdog says; see scheduler::Watchdog. Its lag shows up in
+    // Stats::scheduler_lag, and a stall as MeshEvent::SchedulerStalled.
+    pub fn watch(&mut self, scheduler: SchedulerHandle) {
+        self.watchdog = Some(Watchdog::new(scheduler,
+                                           self.watchdog_config.clone()));
+    }
+
+    // The history itself, for reading while the dispatcher runs on
+    // another thread.
+    pub fn history_handle(&self) -> Arc<Mutex<History>> {
+        self.history.clone()
+    }
+
+    // The membership table as of the end of our last poll, for reading
+    // from other threads: clone the Arc inside, which is the same one for
+    // as long as nothing changes, and compare generations to tell whether
+    // anything has. See members::Snapshot.
+    pub fn snapshot_handle(&self) -> Arc<Mutex<Arc<Snapshot>>> {
+        self.snapshot.clone()
+    }
+
+    // A flag raised once we've caught up with the mesh we joined, for
+    // reading from other threads; see MeshEvent::Converged.
+    pub fn converged_handle(&self) -> Arc<AtomicBool> {
+        self.converged.clone()
+    }
+
+    // This node's id.
+    pub fn node_id(&self) -> NodeId {
+        self.id
+    }
+
+    // What this node's choices are drawn from; see `with_seed`.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Which incarnation we're on; see message::Responder.
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    // Start a new incarnation, so that peers ask us to announce ourselves
+    // afresh (with a Join) when they next get an Ack from us. Taking up a
+    // new address does this by itself.
+    // There's none past the last, which we stay on.
+    pub fn bump_incarnation(&mut self) {
+        self.incarnation = self.incarnation.saturating_add(1);
+    }
+
+    // The address we're actually receiving on, port included even if the
+    // OS picked it.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+
+    // What our socket is bound to and how it's set up, as the kernel has
+    // it now; see sockopts::SocketInfo.
+    pub fn socket_info(&self) -> io::Result<SocketInfo> {
+        self.transport.socket_info()
+    }
+
+    // The address we advertise: where we're bound, unless peers have
+    // since told us otherwise; see ObservedConfig::adopt.
+    pub fn advertised_addr(&self) -> Option<SocketAddr> {
+        self.advertised
+    }
+
+    // A snapshot of every peer we know about.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.members.peers()
+    }
+
+    // Declare peer `id` Dead on an operator's say-so, as when it's being
+    // decommissioned, whatever the failure detector makes of it. It stays
+    // Dead however much we hear from it, Joins included, until it's heard
+    // from on a later incarnation than it was evicted on (see
+    // check_incarnation) or answers a probe_now; see merge::merge.
+    // There's no gossip to spread it by, so it's our say-so alone.
+    pub fn evict(&mut self, id: NodeId) -> Result<(), MeshError> {
+        let peer = try!(self.peer_for_operator(id));
+        let update = MemberUpdate::Evict(id);
+        let (local, entry) = match self.merge(&update) {
+            (Some(local), MergeOutcome::Updated(entry)) => (local, entry),
+            _ => return Ok(()),
+        };
+        println!("Evicting {}", peer);
+        self.stats.evictions += 1;
+        self.detector.forget(id);
+        // Lest a Pong already on its way count as hearing from it.
+        let addr = peer.addr();
+        self.pings.retain(|&(_, target)| target != addr);
+        let now = self.clock.now();
+        self.apply(id, &local, &entry, Cause::Evicted, now);
+        // Rather than at the end of the next poll, for whoever asked to
+        // see it done.
+        self.publish_snapshot();
+        Ok(())
+    }
+
+    // Have every newcomer's Join wait, unacked, for the embedder to let
+    // it in or turn it away by `resolve_join`, rather than answering it
+    // at once; each is told of by a MeshEvent::JoinRequested. Those not
+    // decided on within ADMISSION_TIMEOUT are dropped, and the
+    // newcomer's retransmissions, which are held in their place, go
+    // unanswered until then. A Join from a peer we know is let through as
+    // ever. See admission::Admissions.
+    pub fn require_admission(&mut self) {
+        self.admissions = Some(Admissions::new(self.limits.admissions));
+    }
+
+    // Decide on the held Join of newcomer `id`: answer it as if it had
+    // only now arrived, turn it away with a JoinRejected giving `reason`
+    // (cut to MAX_REJECT_REASON), which a retransmission of it gets too,
+    // or leave it waiting.
+    pub fn resolve_join(&mut self, id: NodeId, decision: Admission)
+            -> Result<(), MeshError> {
+        let now = self.clock.now();
+        let (candidate, seq) = match self.admissions {
+            Some(ref mut admissions) if decision == Admission::Defer => {
+                return if admissions.is_held(id) {
+                    Ok(())
+                } else {
+                    Err(MeshError::UnknownPeer(id))
+                };
+            },
+            Some(ref mut admissions) => match admissions.take(id) {
+                Some(held) => held,
+                None => return Err(MeshError::UnknownPeer(id)),
+            },
+            None => return Err(MeshError::UnknownPeer(id)),
+        };
+        match decision {
+            Admission::Accept => {
+                println!("Admitting {} at {}", id, candidate.addr);
+                self.admitting = true;
+                let join = AckedMessage::Join(id, candidate.version);
+                self.handle_message(Message::Acked(seq, join), &candidate.addr);
+                self.admitting = false;
+                // As `evict` does, for whoever let it in.
+                self.publish_snapshot();
+            },
+            Admission::Reject(mut reason) => {
+                if reason.len() > MAX_REJECT_REASON {
+                    let mut end = MAX_REJECT_REASON;
+                    while !reason.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    reason.truncate(end);
+                }
+                self.stats.joins_refused += 1;
+                if let Some(ref mut admissions) = self.admissions {
+                    admissions.refuse(id, reason.clone(), now);
+                }
+                self.refuse_join(&candidate, reason);
+            },
+            Admission::Defer => (),
+        }
+        Ok(())
+    }
+
+    // Ping peer `id` now, however long it is till its turn and however
+    // we're backing off from it, as an operator who thinks it's wrongly
+    // suspected or dead might. If it answers, it's Alive at once, and no
+    // longer evicted if it was.
+    pub fn probe_now(&mut self, id: NodeId) -> Result<(), MeshError> {
+        let target = try!(self.peer_for_operator(id)).addr();
+        println!("Probing {} now", id);
+        let ping = self.ping_message(&target);
+        if let Message::Ping(ref body) = ping {
+            if self.asked_probes.len() == OUTSTANDING_PINGS {
+                self.asked_probes.pop_front();
+            }
+            self.asked_probes.push_back((body.nonce, id));
+        }
+        self.send(&ping, &target);
+        Ok(())
+    }
+
+    // Our tags, as peers are told of them; see `set_tag`.
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    // Which version of our tags this is; see members::MetaVersion.
+    pub fn meta(&self) -> MetaVersion {
+        self.meta
+    }
+
+    // Set our tag `key` to `value`, and tell every peer we don't take to
+    // be dead, with a Tags carrying all our tags, acked and retransmitted
+    // as data is. Each change is a new version: the incarnation we're on,
+    // and how many changes we've made since we started. A peer keeps the
+    // latest version it has had (see merge::merge), so a Tags of ours
+    // overtaken on its way by a later one changes nothing when it comes.
+    // One that misses them all the same, or that we take to be dead, is
+    // told them again when it next joins us. Tags that would take more
+    // than MAX_TAGS_SIZE are refused, as TooLarge, and stay as they were;
+    // setting a tag to what it is already does nothing.
+    pub fn set_tag(&mut self, key: &str, value: &str)
+            -> Result<(), MeshError> {
+        if self.tags.get(key).map_or(false, |v| v == value) {
+            return Ok(());
+        }
+        let mut tags = self.tags.clone();
+        tags.insert(key.to_string(), value.to_string());
+        let size = bincode::encode(&tags, bincode::SizeLimit::Infinite)
+            .unwrap().len();
+        if size > MAX_TAGS_SIZE {
+            return Err(MeshError::TooLarge);
+        }
+        self.tags = tags;
+        self.meta = MetaVersion::new(self.incarnation, self.meta.counter + 1);
+        println!("Tag {} is now {:?}, as of version {}", key, value,
+                 self.meta);
+        let peers: Vec<Peer> = self.members.peers().into_iter()
+            .filter(|p| p.state() != PeerState::Dead).collect();
+        for peer in peers.iter() {
+            self.send_tags(peer);
+        }
+        Ok(())
+    }
+
+    // Tell `peer` our tags, if it's on a version that can be told.
+    fn send_tags(&mut self, peer: &Peer) {
+        if peer.version().map_or(true, |v| v < TAGS_VERSION) {
+            return;
+        }
+        let tags = AckedMessage::Tags(self.id, self.meta, self.tags.clone());
+        self.queue_acked(tags, &peer.addr());
+    }
+
+    // `src` says node `id`'s tags, as of version `meta`, are `tags`. They
+    // replace those we hold if they're later; see merge::merge.
+    fn tags_of(&mut self, id: NodeId, meta: MetaVersion,
+               tags: BTreeMap<String, String>, src: &SocketAddr) {
+        match self.merge(&MemberUpdate::Tags(id, meta)).1 {
+            MergeOutcome::Updated(_) => {
+                println!("{} has tags {:?}, as of version {}", id, tags,
+                         meta);
+                self.members.set_tags(id, meta, tags);
+                let peer = self.members.get(id).unwrap();
+                self.events.push_back(MeshEvent::PeerTagsChanged(peer));
+            },
+            MergeOutcome::Ignored(why) =>
+                println!("Ignoring tags of {} from {}, version {}: {:?}", id,
+                         src, meta, why),
+            MergeOutcome::RefuteSelf => (),
+        }
+    }
+
+    // Peer `id`, for an operator who has named it.
+    fn peer_for_operator(&self, id: NodeId) -> Result<Peer, MeshError> {
+        if id == self.id {
+            return Err(MeshError::Ourselves);
+        }
+        self.members.get(id).ok_or(MeshError::UnknownPeer(id))
+    }
+
+    // The largest datagram to send `addr`: what MTU probing has found
+    // gets through to the peer there, or else the configured default, and
+    // either way smaller than anything the OS has refused to send there.
+    pub fn path_mtu(&self, addr: &SocketAddr) -> usize {
+        let mtu = self.members.id_of(addr)
+            .and_then(|id| self.members.get(id))
+            .and_then(|p| p.mtu())
+            .unwrap_or(self.mtu.config().default);
+        match self.too_big.get(addr) {
+            Some(&refused) => cmp::min(mtu, refused - 1),
+            None => mtu,
+        }
+    }
+
+    // Replace the failure detector (by default a TimeoutDetector). Peers
+    // are judged afresh by the new one from here on.
+    pub fn set_failure_detector(&mut self, detector: Box<FailureDetector>) {
+        self.detector = detector;
+    }
+
+    // Have `hook` look at every message received, after decoding and
+    // before it's handled, and perhaps drop it. Hooks run in the order
+    // they were added, on this thread, and must not block; see
+    // hooks::HOOK_BUDGET_US.
+    pub fn add_inbound_hook<F>(&mut self, hook: F)
+            where F: Fn(&SocketAddr, &Message) -> HookAction + 'static {
+        self.inbound_hooks.add(Box::new(hook));
+    }
+
+    // As `add_inbound_hook`, for messages as they're queued for sending.
+    // Retransmissions of an acked message aren't seen again.
+    pub fn add_outbound_hook<F>(&mut self, hook: F)
+            where F: Fn(&SocketAddr, &Message) -> HookAction + 'static {
+        self.outbound_hooks.add(Box::new(hook));
+    }
+
+    // Have `handler` deal with received messages of `kind` instead of
+    // the built in handler. Acked messages will have been acked already.
+    pub fn set_handler<F>(&mut self, kind: MessageKind, handler: F)
+            where F: Fn(&mut DispatchCtx, &SocketAddr, Message) + 'static {
+        if kind == MessageKind::Pong {
+            self.builtin_pong = false;
+        }
+        self.handlers.register(kind, Box::new(handler));
+    }
+
+    // The next thing that happened in the mesh, if any.
+    pub fn next_event(&mut self) -> Option<MeshEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    // What the dispatcher's timer has pending: each event's label and
+    // time remaining, soonest first.
+    pub fn timers(&self) -> Vec<(String, Nanos)> {
+        self.timer.dump()
+    }
+
+    // Everything about us worth attaching to a bug report, with `config`,
+    // the settings we were started with; see dump::DebugDump.
+    pub fn debug_dump(&self, config: &Config) -> DebugDump {
+        let now = self.clock.now();
+        let mut pending: Vec<(&Seq, &Pending)> = self.pending.iter().collect();
+        pending.sort_by_key(|&(&seq, p)| (p.first_sent, seq));
+        DebugDump {
+            id: self.id.to_string(),
+            incarnation: self.incarnation,
+            addr: self.addr.map(|a| a.to_string()),
+            advertised: self.advertised.map(|a| a.to_string()),
+            config: format!("{:?}", config),
+            peers: self.members.peers().iter().map(|p| {
+                PeerDump::of(p, self.evicted.contains_key(&p.id()), now)
+            }).collect(),
+            pending: pending.into_iter().map(|(&seq, p)| PendingDump {
+                seq: seq,
+                kind: format!("{:?}", p.kind),
+                target: p.target.to_string(),
+                bytes: p.bytes.len(),
+                retransmits: p.retransmits,
+                first_sent_ago_ms: dump::millis(now - p.first_sent),
+            }).collect(),
+            timers: self.timer.dump().into_iter().map(|(label, due_in)| {
+                TimerDump { label: label, due_in_ms: due_in.as_millis() }
+            }).collect(),
+            stats: self.stats.clone(),
+            history: self.history.lock().unwrap().changes(None).iter()
+                .map(|c| ChangeDump::of(c, now)).collect(),
+            malformed: self.malformed.iter().map(|b| dump::hex(b)).collect(),
+            size: self.estimated_size(),
+        }
+    }
+
+    // How big the mesh is by its nodes' own counts: ours, and those our
+    // alive peers' Pongs have said lately; see size::SizeReports. Each
+    // peer's counts for SIZE_REPORT_CYCLES probe cycles, a cycle being a
+    // probe interval for each of them, as it's probed once a cycle.
+    pub fn estimated_size(&self) -> SizeEstimate {
+        let now = self.clock.now();
+        let alive = self.members.peers().iter()
+            .filter(|p| p.state() == PeerState::Alive).count();
+        let cycle = self.probe_interval * cmp::max(alive, 1) as u32;
+        self.sizes.estimate(alive + 1, now, cycle * SIZE_REPORT_CYCLES)
+    }
+
+    // Queue a message for sending at the end of this poll. Messages to
+    // ourselves skip the socket, and are handled on the next poll as
+    // though they'd been received.
+    pub fn send(&mut self, msg: &Message, target: &SocketAddr) {
+        if self.outbound_hooks.run(target, msg) == HookAction::Drop {
+            self.stats.hook_dropped_outbound += 1;
+            return;
+        }
+        if self.is_self(target) {
+            self.loopback.push_back(msg.clone());
+        } else if msg.kind() == MessageKind::Ping || !self.suppress(target) {
+            // Pings are held back only as probes; see probe_peer.
+            self.outbound.push(msg, target);
+        }
+    }
+
+    fn is_self(&self, target: &SocketAddr) -> bool {
+        is_local(&self.addr, target)
+    }
+
+    // Whether to hold back a message to `target`, which isn't answering;
+    // see backoff::Backoff. Counts it if so.
+    fn suppress(&mut self, target: &SocketAddr) -> bool {
+        let suppress = self.backoff.suppressed(target);
+        if suppress {
+            self.stats.suppressed_sends += 1;
+        }
+        suppress
+    }
+
+    // Queue a message for a known peer.
+    pub fn send_to(&mut self, peer: &Peer, msg: &Message) {
+        self.send(msg, &peer.addr());
+    }
+
+    // Ping `target`. When (if) the Pong comes back, we learn the round
+    // trip time.
+    pub fn ping(&mut self, target: &SocketAddr) {
+        let ping = self.ping_message(target);
+        self.send(&ping, target);
+    }
+
+    // A Ping for `target`, whose Pong we'll now accept.
+    fn ping_message(&mut self, target: &SocketAddr) -> Message {
+        let nonce = self.rng.gen();
+        if self.pings.len() == OUTSTANDING_PINGS {
+            self.pings.pop_front();
+        }
+        self.pings.push_back((nonce, *target));
+        Message::Ping(PingBody {
+            nonce: nonce,
+            sent_at_micros: handlers::timestamp(self.epoch, self.clock.now()),
+            pad: Vec::new(),
+        })
+    }
+
+    // Ask the node at `target` to let us into its mesh. Returns the
+    // Join's sequence number; see `join_async` for how it turns out. The
+    // target is a seed from then on, for rejoining through should we lose
+    // every peer; see OnIsolation::Rejoin.
+    pub fn join(&mut self, target: &SocketAddr) -> Seq {
+        self.join_async(target).seq()
+    }
+
+    // As `join`, returning a ticket to follow the join by. The target
+    // answers with an Ack and a Join of its own, after which the ticket
+    // has the target as a Peer; if the Join isn't acked in time or no
+    // Join comes back within the same budget (`Config::join_retransmit`)
+    // the join fails. Joins to several targets may be in progress at once,
+    // up to Limits::joins.
+    pub fn join_async(&mut self, target: &SocketAddr) -> JoinTicket {
+        if !self.seeds.contains(target) {
+            self.seeds.push(*target);
+        }
+        self.start_join(target)
+    }
+
+    fn start_join(&mut self, target: &SocketAddr) -> JoinTicket {
+        let (seq, error) = if self.joins.len() >= self.limits.joins {
+            println!("WARNING: {} joins already in progress; refusing one \
+                      to {}", self.joins.len(), target);
+            self.stats.joins_rejected += 1;
+            (self.take_seq(), Some(MeshError::Overloaded))
+        } else {
+            let join = AckedMessage::Join(self.id, message::PROTOCOL_VERSION);
+            self.queue_acked(join, target)
+        };
+
+        let ticket = JoinTicket::new(seq, *target);
+        self.joins.push(JoinAttempt {
+            ticket: ticket.clone(),
+            acked: false,
+            started: self.clock.now(),
+            contact: None,
+        });
+        if let Some(error) = error {
+            let i = self.joins.len() - 1;
+            self.finish_join(i, JoinStatus::Failed(error));
+        }
+        ticket
+    }
+
+    // How full the structures bounded by Config::limits are.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let (dedup_peers, dedup_entries) = self.dedup.occupancy();
+        MemoryStats {
+            pending: self.pending.len(),
+            dedup_peers: dedup_peers,
+            dedup_entries: dedup_entries,
+            acks_owed_peers: self.acks.len(),
+            acks_owed: self.acks.values().map(|a| a.len()).sum(),
+            joins: self.joins.len(),
+            probe_sockets: self.probe_sockets.len(),
+            outbound: BANDS.iter().map(|&b| self.outbound.depth(b)).sum(),
+        }
+    }
+
+    // Poll until `ticket`'s join has completed or `timeout` has passed by
+    // our clock (which a ManualClock's never does by itself), and return
+    // how it stands.
+    pub fn wait_join(&mut self, ticket: &JoinTicket, timeout: Duration)
+            -> JoinStatus {
+        let start = self.clock.now();
+        while ticket.status() == JoinStatus::Pending
+                && self.clock.now() - start < timeout {
+            self.poll();
+        }
+        ticket.status()
+    }
+
+    // Send a message that must be acknowledged, retransmitting it until
+    // it is or we run out of patience. Returns its sequence number.
+    pub fn send_acked(&mut self, msg: AckedMessage, target: &SocketAddr)
+            -> Seq {
+        self.queue_acked(msg, target).0
+    }
+
+    // As `send_acked` with AckedMessage::Data, but `data` is handed to the
+    // application at `target` only after every ordered message we sent it
+    // before; see ordered::Streams. Unordered messages are unaffected.
+    pub fn send_ordered(&mut self, data: Vec<u8>, target: &SocketAddr)
+            -> Seq {
+        let stream = self.streams.next_seq(target);
+        let (seq, refused) =
+            self.queue_acked(AckedMessage::Ordered(stream, data), target);
+        // A message that never went anywhere mustn't leave a gap.
+        if refused.is_none() {
+            self.streams.sent(target);
+        }
+        seq
+    }
+
+    // The next sequence number of ours. Once the count runs out we start
+    // a new session rather than wrap round to numbers that may still be
+    // pending, or have acks on their way.
+    fn take_seq(&mut self) -> Seq {
+        let seq = self.next_seq;
+        self.next_seq = if seq.n == u32::max_value() {
+            let session = new_session(&mut self.rng, seq.session);
+            println!("Sequence numbers ran out; starting session {:08x}",
+                     session);
+            Seq::new(session, 1)
+        } else {
+            Seq::new(seq.session, seq.n + 1)
+        };
+        seq
+    }
+
+    // As `send_acked`, also saying why the message won't be sent at all
+    // if it won't be. A message refused for want of room (see
+    // Limits::pending) fails its delivery at once.
+    fn queue_acked(&mut self, msg: AckedMessage, target: &SocketAddr)
+            -> (Seq, Option<MeshError>) {
+        let seq = self.take_seq();
+
+        let kind = msg.kind();
+        let stream = match msg {
+            AckedMessage::Ordered(stream, _) => Some(stream),
+            _ => None,
+        };
+        let to_peer = self.pending.values().filter(|p| p.target == *target)
+            .count();
+        if self.pending.len() >= self.limits.pending
+                || to_peer >= self.limits.pending_per_peer {
+            println!("WARNING: too many unacked messages ({} in all, {} to \
+                      {}); refusing another", self.pending.len(), to_peer,
+                     target);
+            self.stats.pending_rejected += 1;
+            self.events.push_back(MeshEvent::DeliveryFailed {
+                peer: *target,
+                seq: seq,
+                kind: kind,
+                error: MeshError::Overloaded,
+            });
+            return (seq, Some(MeshError::Overloaded));
+        }
+
+        let msg = Message::Acked(seq, msg);
+        if self.outbound_hooks.run(target, &msg) == HookAction::Drop {
+            self.stats.hook_dropped_outbound += 1;
+            self.events.push_back(MeshEvent::DeliveryFailed {
+                peer: *target,
+                seq: seq,
+                kind: kind,
+                error: MeshError::Dropped,
+            });
+            return (seq, Some(MeshError::Dropped));
+        }
+        let band = Band::of(&msg);
+        let bytes = msg.encode();
+        if self.too_big.get(target).map_or(false, |&limit| bytes.len() >= limit) {
+            self.stats.too_big += 1;
+            self.events.push_back(MeshEvent::DeliveryFailed {
+                peer: *target,
+                seq: seq,
+                kind: kind,
+                error: MeshError::TooLarge,
+            });
+            return (seq, Some(MeshError::TooLarge));
+        }
+        if self.is_self(target) {
+            self.loopback.push_back(msg);
+        } else if kind == MessageKind::Suspected || !self.suppress(target) {
+            self.outbound.push_bytes(band, bytes.clone(), target);
+        }
+        // Even if held back, it's retransmitted (if that isn't held back
+        // as well) and fails in the usual way. A Suspected never is, being
+        // as much a probe as a message; see tell_suspected.
+        self.pending.insert(seq, Pending {
+            kind: kind,
+            band: band,
+            bytes: bytes,
+            target: *target,
+            stream: stream,
+            first_sent: self.clock.now(),
+            retransmits: 0,
+            retry_at: None,
+        });
+        self.timer.add_exact(Nanos::from(RETRANSMIT),
+                             format!("retransmit:seq={}", seq),
+                             Timeout::Retransmit(seq));
+        (seq, None)
+    }
+
+    // Run until the shutdown flag is set.
+    pub fn run(&mut self) {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            self.poll();
+        }
+    }
+
+    // Wait (up to one tick) for a datagram and handle it, do any
+    // maintenance that has come due, then send whatever that produced.
+    pub fn poll(&mut self) {
+        let mut buf = mem::replace(&mut self.recv_buf, Vec::new());
+        match self.transport.recv_from(&mut buf) {
+            // recv_from silently drops whatever didn't fit, so a full
+            // buffer means we can't trust what we got.
+            Ok((amt, src)) if amt == buf.len() => {
+                self.stats.truncated += 1;
+                println!("WARNING: dropping possibly truncated {} byte \
+                          datagram from {}", amt, src);
+            },
+            Ok((amt, src)) => self.handle(&buf[..amt], &src),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                       || e.kind() == ErrorKind::TimedOut => {
+                self.stats.read_timeouts += 1;
+            },
+            // A signal came first; whoever is polling us will look at
+            // what it meant (see signals::terminated).
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => panic!("recv_from failed: {}", e),
+        }
+        self.recv_buf = buf;
+        self.deliver_loopback();
+        self.stats.dedup_evicted = self.dedup.evicted();
+        self.check_probe_sockets();
+        self.tick();
+        self.flush();
+        self.publish_snapshot();
+    }
+
+    // Replace the snapshot readers get with a new one, if the table has
+    // changed since. Readers only ever hold the lock long enough to clone
+    // the Arc, and keep what they got for as long as they like, so we
+    // never wait on them, and they never see a change half made.
+    fn publish_snapshot(&mut self) {
+        let generation = self.members.generation();
+        if self.snapshot.lock().unwrap().generation == generation {
+            return;
+        }
+        let snapshot = Arc::new(self.members.snapshot());
+        *self.snapshot.lock().unwrap() = snapshot;
+    }
+
+    // Send queued datagrams in priority order until the queue is empty,
+    // the transport pushes back, or the bandwidth cap won't let any more
+    // go, in which case the rest wait for the next poll. Bulk traffic the
+    // cap holds back doesn't hold back the rest; see bandwidth::Bandwidth.
+    fn flush(&mut self) {
+        let now = self.clock.now();
+        let mut held = None;
+        loop {
+            let next = match held {
+                None => self.outbound.pop(),
+                Some(Band::Bulk) => self.outbound.pop_urgent(),
+                Some(_) => break,
+            };
+            let (band, bytes, target) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            let wire = bandwidth::wire_bytes(bytes.len(), &target);
+            if !self.bandwidth.admit(band, wire, now) {
+                self.stats.bandwidth_held += 1;
+                self.outbound.unpop(band, bytes, target);
+                held = Some(band);
+                continue;
+            }
+            match self.transport.send_to(&bytes, &target) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.bandwidth.refund(wire);
+                    self.outbound.unpop(band, bytes, target);
+                    break;
+                },
+                Err(ref e) if transport::too_big(e) =>
+                    self.sent_too_big(bytes, &target),
+                Err(_) => {
+                    let now = self.clock.now();
+                    self.backoff.failed(&target, now);
+                },
+                Ok(_) => self.keepalives.sent(&target, now),
+            }
+        }
+
+        if self.bandwidth.flushed(held.is_some(), now) {
+            println!("WARNING: the bandwidth cap of {} bytes a second has \
+                      kept datagrams waiting for {:?}",
+                     self.bandwidth.config().cap,
+                     self.bandwidth.config().warn_after);
+            self.stats.bandwidth_warnings += 1;
+        }
+        self.stats.bandwidth_utilization = self.bandwidth.utilization();
+
+        for &band in BANDS.iter() {
+            self.stats.outbound_depth[band as usize] = self.outbound.depth(band);
+            self.stats.outbound_dropped[band as usize] = self.outbound.dropped(band);
+        }
+    }
+
+    // Advance the timer by however much time has really passed since the
+    // last tick (which may be more or less than TICK) and fire whatever
+    // is due.
+    fn tick(&mut self) {
+        let now = self.clock.now();
+        let mut elapsed = now - self.last_tick;
+        self.last_tick = now;
+        if elapsed > self.jump_threshold {
+            self.clock_jumped(now, elapsed);
+            elapsed = TICK;
+        }
+        let elapsed = Nanos::from(elapsed);
+
+        self.expire_joins(now);
+        self.expire_admissions(now);
+        for timeout in self.timer.advance(elapsed) {
+            match timeout {
+                Timeout::Retransmit(seq) => self.retransmit(seq),
+                Timeout::Probe(gen) if gen == self.probe_gen => {
+                    self.probe_peer();
+                    self.update_probe_interval(now, true);
+                },
+                Timeout::Probe(_) => (),
+                Timeout::FlushAcks(addr) => self.flush_acks(&addr),
+                Timeout::FlushSack(addr) => self.flush_sack(&addr),
+                Timeout::Sync(addr) => self.request_sync(&addr),
+                Timeout::Unblock(addr) => {
+                    println!("Unblocking {}", addr);
+                    self.throttle.unblock(&addr);
+                },
+            }
+        }
+
+        for (id, verdict) in self.detector.poll(now) {
+            let state = match verdict {
+                Verdict::Alive => PeerState::Alive,
+                Verdict::Suspect => PeerState::Suspect,
+                Verdict::Dead => PeerState::Dead,
+            };
+            self.set_state(id, state, now, Cause::Detector);
+        }
+        self.skip_overdue_ordered(now);
+        self.send_keepalives(now);
+        self.check_scheduler(now);
+        self.check_convergence(now);
+        self.check_isolation(now);
+    }
+
+    // Whether we've caught up with the mesh since joining it, taking a
+    // probe interval without news of anyone to mean there's no more to
+    // come.
+    fn check_convergence(&mut self, now: Instant) {
+        let outcome = self.convergence.poll(now, self.probe_interval,
+                                            self.convergence_deadline);
+        let members = self.members.len();
+        match outcome {
+            Some(Outcome::Converged(took)) => {
+                println!("Converged on {} members in {:?}", members, took);
+                self.converged.store(true, Ordering::SeqCst);
+                self.events.push_back(MeshEvent::Converged {
+                    members: members,
+                    took: took,
+                });
+            },
+            Some(Outcome::TimedOut) => {
+                println!("WARNING: not converged after {:?}, with {} \
+                          members so far", self.convergence_deadline,
+                         members);
+                self.events.push_back(MeshEvent::NotConverged {
+                    members: members,
+                });
+            },
+            None => (),
+        }
+    }
+
+    // See whether every peer we have has been dead for a whole suspicion
+    // period, and if so do as Config::on_isolation says. Rejoining goes
+    // on, backing off, until someone is alive again.
+    fn check_isolation(&mut self, now: Instant) {
+        let peers = self.members.peers();
+        let alone = !peers.is_empty()
+            && peers.iter().all(|p| p.state() == PeerState::Dead);
+        if self.isolation.poll(alone, now, self.suspicion_period) {
+            println!("WARNING: all {} peers dead for {:?}; going {:?}",
+                     peers.len(), self.suspicion_period, self.on_isolation);
+            self.stats.isolations += 1;
+            self.events.push_back(MeshEvent::Isolated(self.on_isolation));
+            if self.on_isolation == OnIsolation::Exit {
+                self.exit_code = Some(isolation::EXIT_CODE);
+                self.shutdown.store(true, Ordering::SeqCst);
+            }
+        }
+        if self.on_isolation == OnIsolation::Rejoin
+                && self.isolation.rejoin_due(now) {
+            for seed in self.seeds.clone() {
+                // One attempt at a time; it has its own retransmissions.
+                if !self.joins.iter().any(|j| j.ticket.target() == seed) {
+                    println!("Rejoining through {}", seed);
+                    // The seed is likely backed off, having died with the
+                    // rest, but rejoins back off by themselves.
+                    self.backoff.reset(&seed);
+                    self.start_join(&seed);
+                }
+            }
+        }
+    }
+
+    // See whether the Scheduler we're watching, if any, is keeping time.
+    // A stalled timer thread is logged and reported, and aborts the
+    // process if WatchdogConfig::abort says to.
+    fn check_scheduler(&mut self, now: Instant) {
+        let watchdog = match self.watchdog {
+            Some(ref mut watchdog) => watchdog,
+            None => return,
+        };
+        let behind = watchdog.check(now);
+        if !watchdog.waiting() {
+            // A heartbeat just came in.
+            self.stats.scheduler_lag = watchdog.lag();
+        }
+        if let Some(behind) = behind {
+            println!("ERROR: scheduler stalled; its timer thread is {:?} \
+                      behind", behind);
+            self.stats.scheduler_stalls += 1;
+            self.events.push_back(MeshEvent::SchedulerStalled {
+                behind: behind,
+            });
+            if self.watchdog_config.abort {
+                process::abort();
+            }
+        }
+    }
+
+    // Move peer `id` to `state`, telling the embedder and recording why,
+    // if it's not there already. A peer made Suspect is suspected for
+    // what `cause` says, by us, from `now`.
+    fn set_state(&mut self, id: NodeId, state: PeerState, now: Instant,
+                 cause: Cause) {
+        let (before, ended) = match self.members.get(id) {
+            Some(ref p) if p.state() != state => (p.state(), p.suspicion()),
+            _ => return,
+        };
+        match cause.suspicion_reason() {
+            Some(reason) if state == PeerState::Suspect => {
+                self.members.suspect(id, Suspicion {
+                    reason: reason,
+                    since: now,
+                    by: self.id,
+                });
+            },
+            _ => { self.members.set_state(id, state); },
+        }
+        if state == PeerState::Suspect {
+            self.scores.flapped(id, now);
+        }
+        if state == PeerState::Dead {
+            self.sizes.forget(id);
+        }
+        let peer = self.members.get(id).unwrap();
+        self.record_change(&peer, Some(before), ended, cause, now);
+        self.events.push_back(MeshEvent::PeerStateChanged(peer.clone()));
+        self.note_churn(now);
+        // A host that says nothing is listening leaves no one to tell.
+        if state == PeerState::Suspect && cause != Cause::ProbeRefused {
+            self.tell_suspected(&peer);
+        }
+    }
+
+    // Tell `peer`, which we've just come to suspect, that we do, with an
+    // acked Suspected, so that it may refute it (see `suspected`) rather
+    // than die for want of knowing. Its Ack, like anything from it, clears
+    // the suspicion. It's retransmitted as data is (see
+    // Config::data_retransmit), even to a peer we're otherwise backing off
+    // from (see backoff::Backoff), and if that runs out unacked, the failure
+    // detector is told, and declares the peer Dead sooner (see
+    // FailureDetector::on_suspicion_unanswered), unless it has been heard
+    // from since. One at a time to any peer, and none to those on
+    // versions before SUSPECTED_VERSION.
+    fn tell_suspected(&mut self, peer: &Peer) {
+        let addr = peer.addr();
+        if peer.version().map_or(true, |v| v < SUSPECTED_VERSION) {
+            return;
+        }
+        let outstanding = self.pending.values().any(|p| {
+            p.kind == MessageKind::Suspected && p.target == addr
+        });
+        if outstanding {
+            return;
+        }
+        let incarnation = peer.incarnation().unwrap_or(0);
+        self.stats.suspicions_sent += 1;
+        self.queue_acked(AckedMessage::Suspected(peer.id(), incarnation),
+                         &addr);
+    }
+
+    // `src` says it suspects `of` on `incarnation`. If that's us as we
+    // are, we refute it by starting a new incarnation, which our Ack then
+    // carries, and which has `src` ask us to sync (see
+    // check_incarnation). Anyone else's suspicion is none of ours to
+    // answer, there being nothing to pass it on to. Nor is a stranger's,
+    // which anyone can send.
+    fn suspected(&mut self, of: NodeId, incarnation: u64, src: &SocketAddr) {
+        if self.members.id_of(src).is_none() {
+            println!("Ignoring a suspicion of {} from {}, a stranger", of,
+                     src);
+            return;
+        }
+        match self.merge(&MemberUpdate::Suspected(of, incarnation)).1 {
+            // Past whichever incarnation is the later, ours or the one
+            // suspected: a peer may have heard of one of ours we've since
+            // forgotten, say across a restart, and a refutation that
+            // isn't past it refutes nothing. There's none past the last.
+            MergeOutcome::RefuteSelf => {
+                let later = cmp::max(self.incarnation, incarnation);
+                match later.checked_add(1) {
+                    Some(next) => {
+                        self.incarnation = next;
+                        println!("WARNING: {} suspects us of having failed; \
+                                  refuting it with incarnation {}", src,
+                                 self.incarnation);
+                        self.stats.suspicions_refuted += 1;
+                    },
+                    None => println!("WARNING: {} suspects our incarnation \
+                                      {}, which is the last; we can't \
+                                      refute it", src, incarnation),
+                }
+            },
+            MergeOutcome::Ignored(merge::Ignored::Stale) =>
+                println!("{} suspects our incarnation {}, which we're past",
+                         src, incarnation),
+            _ => println!("{} suspects {}, not us; ignoring it", src, of),
+        }
+    }
+
+    // What we hold about node `id`, if it's a peer, and what `merge`
+    // makes of `update` given that.
+    fn merge(&self, update: &MemberUpdate)
+            -> (Option<MemberEntry>, MergeOutcome) {
+        let us = Us { id: self.id, incarnation: self.incarnation };
+        let local = self.member_entry(update.id());
+        let outcome = merge::merge(&us, local.as_ref(), update);
+        (local, outcome)
+    }
+
+    // What we hold about peer `id`, as `merge` goes by it.
+    fn member_entry(&self, id: NodeId) -> Option<MemberEntry> {
+        self.members.get(id).map(|peer| MemberEntry {
+            state: peer.state(),
+            incarnation: peer.incarnation(),
+            evicted: self.evicted.get(&id).cloned(),
+            meta: peer.meta(),
+        })
+    }
+
+    // If `id`, Joining from `src` and new to us, is taken for the peer
+    // there restarted (see merge::restarted), forget that peer for `id` to
+    // take its place, and return who it was.
+    fn replace_restarted(&mut self, id: NodeId, src: &SocketAddr,
+                         now: Instant) -> Option<NodeId> {
+        let window = match self.restart_window {
+            Some(window) => window,
+            None => return None,
+        };
+        if self.members.get(id).is_some() {
+            return None;
+        }
+        let old = match self.members.id_of(src) {
+            Some(old) => old,
+            None => return None,
+        };
+        let silent = now - self.members.get(old).unwrap().last_seen();
+        let local = self.member_entry(old).unwrap();
+        if !merge::restarted(&local, silent, window) {
+            return None;
+        }
+        self.members.remove(old);
+        self.detector.forget(old);
+        self.sizes.forget(old);
+        self.stats.restarts += 1;
+        Some(old)
+    }
+
+    // Make what we hold about peer `id` `entry` instead of `local`, as
+    // `merge` said to, for `cause`. A peer no longer evicted is heard from
+    // afresh, as far as the failure detector goes.
+    fn apply(&mut self, id: NodeId, local: &MemberEntry, entry: &MemberEntry,
+             cause: Cause, now: Instant) {
+        match entry.evicted {
+            Some(incarnation) => { self.evicted.insert(id, incarnation); },
+            None => if self.evicted.remove(&id).is_some() {
+                self.detector.on_message(id, now);
+            },
+        }
+        if entry.incarnation != local.incarnation {
+            if let Some(incarnation) = entry.incarnation {
+                self.members.set_incarnation(id, incarnation);
+            }
+        }
+        self.set_state(id, entry.state, now, cause);
+    }
+
+    // Hand the Join `seq` that `src` sent, saying it's `id` on `version`,
+    // to another peer to answer, if we'd rather not: to the coordinator
+    // (see Config::coordinator) if there is one and it's a peer, or else
+    // while we're overloaded (see HealthConfig::overloaded), to any peer
+    // whose last Pong didn't say it was too, favouring those that score
+    // well (see score::Scores). The peer acks the Join itself, and sends
+    // `src` its own (see `take_over_join`), and we hear no more of it;
+    // `src` retransmitting it to us has it handed on again. Only a
+    // newcomer's Join is handed on, and not one handed to us. Returns
+    // whether it was.
+    fn forward_join(&mut self, seq: Seq, id: NodeId, version: u8,
+                    src: &SocketAddr) -> bool {
+        if self.taking_over || self.admitting
+                || version < JOIN_FORWARD_VERSION
+                || self.members.get(id).is_some() {
+            return false;
+        }
+        let to = match self.coordinator {
+            Some(coordinator) => self.forwardable(&coordinator, src),
+            None => {
+                let now = self.clock.now();
+                let ours = self.health(now);
+                if self.health.overloaded(&ours) {
+                    let others: Vec<(SocketAddr, f64)> = self.members.peers()
+                        .iter()
+                        .filter(|p| p.health()
+                                .map_or(true, |h| !self.health.overloaded(&h)))
+                        .filter_map(|p| {
+                            self.forwardable(&p.addr(), src)
+                                .map(|addr| (addr, self.scores.score(p, now)))
+                        })
+                        .collect();
+                    score::choose(&mut self.rng, &others,
+                                  score::EXPLORATION).cloned()
+                } else {
+                    None
+                }
+            },
+        };
+        let to = match to {
+            Some(to) => to,
+            None => return false,
+        };
+        println!("Handing a JOIN from {} at {} on to {}", id, src, to);
+        self.stats.joins_forwarded += 1;
+        let forward = Message::JoinForward(WireAddr(*src), seq, id, version);
+        self.send(&forward, &to);
+        true
+    }
+
+    // `addr`, if a Join from `src` could be handed on to it: it's neither
+    // us nor `src`, but an Alive peer on JOIN_FORWARD_VERSION or later.
+    fn forwardable(&self, addr: &SocketAddr, src: &SocketAddr)
+            -> Option<SocketAddr> {
+        if self.is_self(addr) || addr == src {
+            return None;
+        }
+        let peer = match self.members.id_of(addr)
+                .and_then(|id| self.members.get(id)) {
+            Some(peer) => peer,
+            None => return None,
+        };
+        let evicted = self.evicted.contains_key(&peer.id());
+        let version = peer.version().unwrap_or(0);
+        if peer.state() == PeerState::Alive && !evicted
+                && version >= JOIN_FORWARD_VERSION {
+            Some(*addr)
+        } else {
+            None
+        }
+    }
+
+    // `src` has handed us the Join `seq` that `joiner` sent it, saying
+    // it's `id` on `version` (see `forward_join`), which we handle as if
+    // it had been sent to us: `joiner` gets our Ack, and our own Join, and
+    // has joined through us. Only a peer may hand us Joins, lest anyone
+    // have us send to whatever address they like.
+    fn take_over_join(&mut self, joiner: SocketAddr, seq: Seq, id: NodeId,
+                      version: u8, src: &SocketAddr) {
+        if self.members.id_of(src).is_none() {
+            println!("WARNING: ignoring a JOIN from {} at {} handed on by \
+                      {}, which isn't a peer", id, joiner, src);
+            return;
+        }
+        if self.is_self(&joiner) {
+            return;
+        }
+        println!("{} handed us a JOIN from {} at {}", src, id, joiner);
+        self.stats.joins_taken_over += 1;
+        self.taking_over = true;
+        let join = Message::Acked(seq, AckedMessage::Join(id, version));
+        self.handle_message(join, &joiner);
+        self.taking_over = false;
+    }
+
+    // Turn away the Join a newcomer `id` on `version` sent from `src`, if
+    // we're already letting in as many as we will at once (see
+    // Limits::join_handshakes), telling it with a JoinRetryAfter when to
+    // try again. It isn't acked, and it doesn't count against its
+    // retries. Returns whether it was turned away. A Join from a peer we
+    // know, or one handed to us, is always let through.
+    fn defer_join(&mut self, id: NodeId, version: u8, src: &SocketAddr)
+            -> bool {
+        if self.taking_over || self.admitting || self.is_self(src)
+                || self.members.get(id).is_some()
+                || self.joins.len() < self.limits.join_handshakes {
+            return false;
+        }
+        self.stats.joins_deferred += 1;
+        if version >= JOIN_RETRY_VERSION {
+            let millis = self.retry_after();
+            println!("Deferring a JOIN from {} at {} by {}ms; {} joins in \
+                      progress", id, src, millis, self.joins.len());
+            self.send(&Message::JoinRetryAfter(millis), src);
+        } else {
+            println!("Dropping a JOIN from {} at {}; {} joins in progress",
+                     id, src, self.joins.len());
+        }
+        true
+    }
+
+    // How long to tell a newcomer turned away for now to wait, in the
+    // milliseconds a JoinRetryAfter carries; see JOIN_RETRY_AFTER.
+    fn retry_after(&mut self) -> u32 {
+        let least = Nanos::from(JOIN_RETRY_AFTER).as_millis();
+        self.rng.gen_range(least, least * 2) as u32
+    }
+
+    // Hold the Join `seq` a newcomer `id` on `version` sent from `src`,
+    // unacked, for the embedder to decide on, if it's to (see
+    // `require_admission`): telling it of a new one, and turning away at
+    // once one it has lately turned away, or one there's no room to hold,
+    // as `defer_join` would. Returns whether it was held or turned away.
+    fn hold_join(&mut self, seq: Seq, id: NodeId, version: u8,
+                 src: &SocketAddr) -> bool {
+        if self.taking_over || self.admitting || self.is_self(src)
+                || self.members.get(id).is_some() {
+            return false;
+        }
+        let now = self.clock.now();
+        let candidate = JoinCandidate { id: id, addr: *src, version: version };
+        let held = match self.admissions {
+            Some(ref mut admissions) => {
+                admissions.hold(candidate.clone(), seq, now)
+            },
+            None => return false,
+        };
+        match held {
+            Held::New => {
+                println!("Holding a JOIN from {} at {} for admission", id, src);
+                self.stats.joins_held += 1;
+                self.events.push_back(MeshEvent::JoinRequested(candidate));
+            },
+            Held::Again => (),
+            Held::Refused(reason) => self.refuse_join(&candidate, reason),
+            Held::Full => {
+                self.stats.joins_deferred += 1;
+                println!("Deferring a JOIN from {} at {}; too many held for \
+                          admission", id, src);
+                if version >= JOIN_RETRY_VERSION {
+                    let millis = self.retry_after();
+                    self.send(&Message::JoinRetryAfter(millis), src);
+                }
+            },
+        }
+        true
+    }
+
+    // Tell `candidate` it isn't to join, and why, if it's on a version
+    // that can be told.
+    fn refuse_join(&mut self, candidate: &JoinCandidate, reason: String) {
+        println!("Turning away {} at {}: {}", candidate.id, candidate.addr,
+                 reason);
+        if candidate.version >= ADMISSION_VERSION {
+            self.send(&Message::JoinRejected(reason), &candidate.addr);
+        }
+    }
+
+    // `src`, the target of a Join of ours that hasn't been acked, won't
+    // have us join, for `reason`: the Join fails at once.
+    fn join_rejected(&mut self, reason: String, src: &SocketAddr) {
+        let i = match self.joins.iter()
+                .position(|j| !j.acked && j.ticket.target() == *src) {
+            Some(i) => i,
+            None => return,
+        };
+        println!("WARNING: {} won't have us join: {}", src, reason);
+        let seq = self.joins[i].ticket.seq();
+        self.pending.remove(&seq);
+        self.finish_join(i, JoinStatus::Failed(MeshError::NotAdmitted(reason)));
+    }
+
+    // The target of a Join of ours that hasn't been acked, `src`, has too
+    // many others to let in just now, and tells us to try again after
+    // `millis`: the Join is held back until then, and then starts over,
+    // with its retransmissions and budget as good as new.
+    fn retry_join_after(&mut self, millis: u32, src: &SocketAddr) {
+        let seq = match self.joins.iter()
+                .find(|j| !j.acked && j.ticket.target() == *src) {
+            Some(j) => j.ticket.seq(),
+            None => return,
+        };
+        let wait = cmp::min(Duration::from_millis(millis as u64),
+                            JOIN_RETRY_AFTER_MAX);
+        let at = self.clock.now() + wait;
+        if let Some(p) = self.pending.get_mut(&seq) {
+            println!("{} asks us to retry JOIN {} in {}ms", src, seq,
+                     dump::millis(wait));
+            self.stats.joins_retried += 1;
+            p.retry_at = Some(at);
+        }
+    }
+
+    // Send the held back Join `seq` again, as if for the first time.
+    fn restart_join(&mut self, seq: Seq, now: Instant) {
+        if let Some(p) = self.pending.get_mut(&seq) {
+            p.retry_at = None;
+            p.first_sent = now;
+            p.retransmits = 0;
+        }
+        if let Some(j) = self.joins.iter_mut()
+                .find(|j| j.ticket.seq() == seq) {
+            j.started = now;
+        }
+    }
+
+    // Add `peer`'s move from `from` to the state it's in now to the
+    // history, with the suspicion it began, or else the one it `ended`.
+    fn record_change(&mut self, peer: &Peer, from: Option<PeerState>,
+                     ended: Option<Suspicion>, cause: Cause, now: Instant) {
+        self.history.lock().unwrap().record(Change {
+            when: now,
+            peer: peer.id(),
+            addr: peer.addr(),
+            from: from,
+            to: peer.state(),
+            cause: cause,
+            suspicion: peer.suspicion().or(ended),
+        });
+    }
+
+    // Pick up where we left off after `jump` went missing: timers carry on
+    // from where they were, retransmission and join budgets don't count
+    // the jump, and the failure detector starts over. So do round trip
+    // times: a Pong this poll may already have counted the jump as one,
+    // and any to a Ping still outstanding would, so those go unanswered.
+    fn clock_jumped(&mut self, now: Instant, jump: Duration) {
+        println!("WARNING: clock jumped {:?} between ticks; re-anchoring \
+                  timers and resetting failure detection and round trip \
+                  times", jump);
+        self.stats.clock_jumps += 1;
+        let lost = jump - TICK;
+        for p in self.pending.values_mut() {
+            p.first_sent += lost;
+        }
+        for j in self.joins.iter_mut() {
+            j.started += lost;
+        }
+        self.convergence.shift(lost);
+        self.churn.clear();
+        self.detector.reset(now);
+        self.pings.clear();
+        self.members.reset_rtts();
+        // Whatever held us up most likely held the timer thread up too.
+        if let Some(ref mut watchdog) = self.watchdog {
+            watchdog.reset();
+        }
+    }
+
+    // Ping the next peer that isn't known to be dead; see ProbeOrder.
+    fn probe_peer(&mut self) {
+        let targets: Vec<NodeId> = self.members.peers().iter()
+            .filter(|p| p.state() != PeerState::Dead)
+            .map(|p| p.id()).collect();
+        let next = self.probe_order.next(&mut self.rng, &targets);
+        if let Some(peer) = next.and_then(|id| self.members.get(id)) {
+            let target = peer.addr();
+            let now = self.clock.now();
+            if !self.backoff.probe_due(&target, now) {
+                self.stats.suppressed_sends += 1;
+                return;
+            }
+            self.backoff.probed(&target, now);
+            self.scores.probed(peer.id());
+            let fast_fail = self.probe.fast_fail
+                && self.probe_sockets.len() < self.limits.probe_sockets;
+            if !(fast_fail && self.probe_connected(&target)) {
+                self.ping(&target);
+            }
+            self.probe_mtu(&peer, now);
+        }
+    }
+
+    // Follow `peer`'s probe with a Ping padded to the next size to try,
+    // if one is due; see mtu::MtuConfig. The probe proper goes unpadded,
+    // so that failure detection doesn't suffer for it.
+    fn probe_mtu(&mut self, peer: &Peer, now: Instant) {
+        let size = self.mtu.due(peer.id(), peer.last_seen(), now);
+        // Giving up on the search leaves the default.
+        self.members.set_mtu(peer.id(), self.mtu.mtu(peer.id()));
+        let size = match size {
+            Some(size) => size,
+            None => return,
+        };
+        let target = peer.addr();
+        let mut ping = self.ping_message(&target);
+        let bare = ping.encode().len();
+        let nonce = match ping {
+            Message::Ping(ref mut body) => {
+                let pad = size.saturating_sub(bare);
+                body.pad = vec![0; pad.min(MAX_PING_PAD)];
+                body.nonce
+            },
+            _ => return,
+        };
+        self.mtu.sent(peer.id(), nonce, size, now);
+        self.send(&ping, &target);
+    }
+
+    // Ping `target` from a socket connected to it. Returns false, leaving
+    // the caller to ping it the usual way, if that can't be done.
+    fn probe_connected(&mut self, target: &SocketAddr) -> bool {
+        let socket = match self.transport.connect(target) {
+            Ok(socket) => socket,
+            Err(_) => return false,
+        };
+        let ping = self.ping_message(target);
+        if self.outbound_hooks.run(target, &ping) == HookAction::Drop {
+            self.stats.hook_dropped_outbound += 1;
+            return true;
+        }
+        let now = self.clock.now();
+        match socket.send(&ping.encode()) {
+            Ok(_) => (),
+            Err(ref e) if probe::interpret(e) == Feedback::Refused => {
+                self.refused(target, now);
+                return true;
+            },
+            Err(_) => return false,
+        }
+        self.probe_sockets.push(ProbeSocket {
+            target: *target,
+            socket: socket,
+            until: now + self.probe_interval,
+        });
+        true
+    }
+
+    // Handle whatever has come back on probe sockets, and close those
+    // that are done with: refused, answered, or out of time.
+    fn check_probe_sockets(&mut self) {
+        let now = self.clock.now();
+        let mut buf = mem::replace(&mut self.recv_buf, Vec::new());
+        for p in mem::replace(&mut self.probe_sockets, Vec::new()) {
+            match p.socket.recv(&mut buf) {
+                Ok(amt) if amt < buf.len() =>
+                    self.handle(&buf[..amt], &p.target),
+                Err(ref e) if probe::interpret(e) == Feedback::Refused =>
+                    self.refused(&p.target, now),
+                _ if now < p.until => self.probe_sockets.push(p),
+                _ => (),
+            }
+        }
+        self.recv_buf = buf;
+    }
+
+    // The host at `target` says nothing is listening there, which is as
+    // good as word can get that the peer has died.
+    fn refused(&mut self, target: &SocketAddr, now: Instant) {
+        println!("Probe to {} refused", target);
+        self.stats.probes_refused += 1;
+        self.suspect(target, now, Cause::ProbeRefused);
+    }
+
+    // If the Pong just come with `nonce` answers a probe_now, its peer is
+    // alive, and it's said so.
+    fn answered_probe_now(&mut self, nonce: u64, now: Instant) {
+        let id = match self.asked_probes.iter()
+                .position(|&(sent, _)| sent == nonce) {
+            Some(i) => self.asked_probes.remove(i).unwrap().1,
+            None => return,
+        };
+        println!("{} answered the probe we were asked for", id);
+        if let (Some(local), MergeOutcome::Updated(entry)) =
+                self.merge(&MemberUpdate::Probed(id)) {
+            self.apply(id, &local, &entry, Cause::Probed, now);
+        }
+    }
+
+    // We've reason to think the peer at `addr`, if any, has failed.
+    fn suspect(&mut self, addr: &SocketAddr, now: Instant, cause: Cause) {
+        if let Some(id) = self.members.id_of(addr) {
+            self.detector.on_probe_result(id, None, now);
+            let alive = self.members.get(id)
+                .map_or(false, |peer| peer.state() == PeerState::Alive);
+            if alive {
+                self.set_state(id, PeerState::Suspect, now, cause);
+            }
+        }
+    }
+
+    // Record a membership change, which may tighten the probe interval.
+    fn note_churn(&mut self, now: Instant) {
+        self.churn.push_back(now);
+        self.update_probe_interval(now, false);
+    }
+
+    // Recompute the probe interval for the current membership and churn,
+    // and schedule the next probe that far out if it's changed (or
+    // regardless if `force` is set).
+    fn update_probe_interval(&mut self, now: Instant, force: bool) {
+        while self.churn.front()
+                .map_or(false, |&t| now - t > self.probe.churn_window) {
+            self.churn.pop_front();
+        }
+        let live = self.members.peers().iter()
+            .filter(|p| p.state() != PeerState::Dead).count();
+        let interval = probe::probe_interval(&self.probe, live,
+                                             self.churn.len());
+        if !force && interval == self.probe_interval {
+            return;
+        }
+
+        self.probe_interval = interval;
+        self.probe_gen += 1;
+        let interval = Nanos::from(interval);
+        self.stats.probe_interval_ms = interval.as_millis();
+        self.timer.add_named(interval, "probe",
+                             Timeout::Probe(self.probe_gen));
+    }
+
+    fn retransmit(&mut self, seq: Seq) {
+        let now = self.clock.now();
+        if let Some(at) = self.pending.get(&seq).and_then(|p| p.retry_at) {
+            if now < at {
+                self.timer.add_exact(Nanos::from(at - now),
+                                     format!("retransmit:seq={}", seq),
+                                     Timeout::Retransmit(seq));
+                return;
+            }
+            self.restart_join(seq, now);
+        }
+        let suppressed = self.pending.get(&seq).map_or(false, |p| {
+            p.kind != MessageKind::Suspected
+                && self.backoff.suppressed(&p.target)
+        });
+        let give_up = match self.pending.get_mut(&seq) {
+            // Already acked.
+            None => return,
+            Some(p) => {
+                let policy = match p.kind {
+                    MessageKind::Join => &self.join_retransmit,
+                    _ => &self.data_retransmit,
+                };
+                if p.retransmits < policy.attempts
+                        && now - p.first_sent < policy.budget {
+                    p.retransmits += 1;
+                    if is_local(&self.addr, &p.target) {
+                        self.loopback.push_back(Message::decode(&p.bytes));
+                    } else if suppressed {
+                        self.stats.suppressed_sends += 1;
+                    } else {
+                        self.outbound.push_bytes(p.band, p.bytes.clone(),
+                                                 &p.target);
+                    }
+                    false
+                } else {
+                    println!("Giving up on message {} to {}", seq, p.target);
+                    true
+                }
+            },
+        };
+
+        if give_up {
+            let p = self.pending.remove(&seq).unwrap();
+            self.delivery_failed(seq, p, now);
+        } else {
+            self.timer.add_exact(Nanos::from(RETRANSMIT),
+                                 format!("retransmit:seq={}", seq),
+                                 Timeout::Retransmit(seq));
+        }
+    }
+
+    // Handle what we sent ourselves by the last poll. Whatever that makes
+    // us send ourselves waits for the next, so nothing can loop forever
+    // in here.
+    fn deliver_loopback(&mut self) {
+        let src = match self.addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        for msg in mem::replace(&mut self.loopback, VecDeque::new()) {
+            self.stats.self_delivered += 1;
+            self.handle_message(msg, &src);
+        }
+    }
+
+    // Handle datagram `buf`, just received from `src`, timing it as
+    // self.timing says.
+    fn handle(&mut self, buf: &[u8], src: &SocketAddr) {
+        if !self.timing.enabled {
+            self.receive(buf, src);
+            return;
+        }
+        let received = self.clock.now();
+        if let Some(kind) = self.receive(buf, src) {
+            let took = self.clock.now() - received;
+            self.stats.handling.record(kind, took);
+            if self.timing.slow.map_or(false, |slow| took >= slow) {
+                self.stats.slow_handlings += 1;
+                println!("WARNING: handling a {:?} from {} took {:?}", kind,
+                         src, took);
+            }
+        }
+    }
+
+    // Returns the kind of message `buf` held, unless it was dropped
+    // unread.
+    fn receive(&mut self, buf: &[u8], src: &SocketAddr)
+            -> Option<MessageKind> {
+        if fastpath::is_keepalive(buf) {
+            self.keepalive(src);
+            return Some(MessageKind::Keepalive);
+        }
+        if !self.admit(buf, src) {
+            return None;
+        }
+        let parsed = match fastpath::parse(buf) {
+            Some(msg) => {
+                self.stats.fast_path += 1;
+                Ok(msg)
+            },
+            None => message::parse_datagram(buf),
+        };
+        match parsed {
+            Ok(msg) => {
+                let kind = msg.kind();
+                self.handle_message(msg, src);
+                return Some(kind);
+            },
+            // Whatever it says, the sender is alive to say it.
+            Err(DecodeError::UnknownType(t)) => {
+                self.stats.unknown_types += 1;
+                println!("Unknown message type {} from {}", t, src);
+                let now = self.clock.now();
+                self.heard_from(src, now);
+                self.events.push_back(MeshEvent::UnknownMessageType {
+                    from: *src,
+                    type_id: t,
+                });
+            },
+            // As is a node on another version. One too old for us to
+            // read is told what we speak; a newer one reads ours anyway,
+            // but may be told we couldn't read it.
+            Err(DecodeError::UnsupportedVersion(v)) => {
+                self.stats.unsupported_versions += 1;
+                println!("Dropping protocol version {} datagram from {}", v,
+                         src);
+                let now = self.clock.now();
+                self.heard_from(src, now);
+                if v < message::MIN_PROTOCOL_VERSION {
+                    let reply = Message::VersionMismatch(
+                        message::PROTOCOL_VERSION);
+                    self.send(&reply, src);
+                } else {
+                    self.explain(RejectCode::BadVersion, src);
+                }
+            },
+            // Scanner noise, which would drown out the rest of the log.
+            Err(DecodeError::Runt(_)) => self.stats.runts += 1,
+            // Nothing it says can be believed, not even that it's from a
+            // peer, so it isn't a sign of life either.
+            Err(DecodeError::Corrupt) => {
+                self.stats.corrupt += 1;
+                if self.log_corrupt {
+                    println!("WARNING: corrupt datagram from {}: {}", src,
+                             dump::hex(buf));
+                } else {
+                    println!("Dropping corrupt datagram from {}", src);
+                }
+            },
+            Err(e) => {
+                self.stats.malformed += 1;
+                match e {
+                    DecodeError::BadLength(LengthError::LengthMismatch) =>
+                        self.stats.length_mismatches += 1,
+                    DecodeError::BadLength(LengthError::CountTooLarge) =>
+                        self.stats.counts_too_large += 1,
+                    DecodeError::BadLength(LengthError::TrailingBytes) =>
+                        self.stats.trailing_bytes += 1,
+                    _ => (),
+                }
+                println!("Dropping datagram from {}: {}", src, e);
+                if self.malformed.len() == MALFORMED_KEPT {
+                    self.malformed.pop_front();
+                }
+                let kept = cmp::min(buf.len(), MAX_MESSAGE_SIZE);
+                self.malformed.push_back(buf[..kept].to_vec());
+            },
+        }
+        None
+    }
+
+    fn handle_message(&mut self, msg: Message, src: &SocketAddr) {
+        if self.inbound_hooks.run(src, &msg) == HookAction::Drop {
+            self.stats.hook_dropped_inbound += 1;
+            // Never a Rejected for a Rejected, lest two nodes that block
+            // each other keep one another busy.
+            if msg.kind() != MessageKind::Rejected {
+                self.explain(RejectCode::Blocked, src);
+            }
+            return;
+        }
+        // Before anything else takes it as a sign of life.
+        if !self.solicited(&msg, src) {
+            self.stats.unsolicited += 1;
+            if self.stale_ack(&msg) {
+                self.stats.stale_acks += 1;
+            }
+            if self.log_unsolicited {
+                println!("WARNING: dropping unsolicited {:?} from {}",
+                         msg.kind(), src);
+            }
+            return;
+        }
+        if msg.kind() == MessageKind::Ping && !self.ping_strangers
+                && !self.is_self(src) && self.members.id_of(src).is_none() {
+            self.stats.stranger_pings += 1;
+            return;
+        }
+        if self.claims_to_be_us(&msg, src) {
+            self.impersonated(&msg, src);
+            return;
+        }
+
+        let now = self.clock.now();
+        // A Join from another node than the one we hold at `src` is no
+        // news of that one, which it may yet replace for having been heard
+        // from lately; see replace_restarted.
+        let newcomer = match msg {
+            Message::Acked(_, AckedMessage::Join(id, _)) =>
+                self.members.id_of(src).map_or(false, |known| known != id),
+            _ => false,
+        };
+        if newcomer {
+            self.backoff.heard(src);
+        } else {
+            self.heard_from(src, now);
+        }
+        if let Some(addr) = msg.observed() {
+            self.observed(src, &addr);
+        }
+
+        match msg {
+            Message::Ack(seq, _, from) => {
+                println!("Received ACK: {}", seq);
+                self.took_over(seq, src);
+                self.acked(seq);
+                if let Some(from) = from {
+                    self.check_incarnation(from, src);
+                }
+                return;
+            },
+            // Perhaps answering an MTU probe, and perhaps saying how its
+            // sender is doing.
+            Message::Pong(ref body, _, health) => {
+                if let Some(id) = self.mtu.answered(body.nonce) {
+                    let mtu = self.mtu.mtu(id);
+                    self.members.set_mtu(id, mtu);
+                }
+                self.peer_health(src, health);
+                self.answered_probe_now(body.nonce, now);
+                // All the built in handler does, without its DispatchCtx.
+                if self.builtin_pong {
+                    handlers::record_rtt(&mut self.members,
+                                         &mut *self.detector, self.epoch,
+                                         now, src, body.sent_at_micros);
+                    return;
+                }
+            },
+            Message::VersionMismatch(v) => {
+                println!("WARNING: {} can't read what we sent; it speaks \
+                          protocol version {}, we speak {}", src, v,
+                         message::PROTOCOL_VERSION);
+                return;
+            },
+            // Only peers get an answer, there being nothing to sync with
+            // anyone else.
+            Message::SyncRequest(incarnation) => {
+                println!("{} saw us on incarnation {} and asks us to sync",
+                         src, incarnation);
+                if self.members.id_of(src).is_some() {
+                    let join = AckedMessage::Join(self.id,
+                                                  message::PROTOCOL_VERSION);
+                    self.queue_acked(join, src);
+                }
+                return;
+            },
+            Message::Rejected(code) => {
+                self.rejected(code, src);
+                return;
+            },
+            Message::JoinForward(joiner, seq, id, version) => {
+                self.take_over_join(joiner.0, seq, id, version, src);
+                return;
+            },
+            Message::JoinRetryAfter(millis) => {
+                self.retry_join_after(millis, src);
+                return;
+            },
+            Message::JoinRejected(reason) => {
+                self.join_rejected(reason, src);
+                return;
+            },
+            // Decoded the long way, for running on past its two bytes.
+            Message::Keepalive => {
+                self.stats.keepalives_received += 1;
+                return;
+            },
+            Message::AckMulti(seqs) => {
+                println!("Received ACKs: {:?}", seqs);
+                for seq in seqs {
+                    if self.awaiting_ack(seq, src) {
+                        self.acked(seq);
+                    }
+                }
+                return;
+            },
+            Message::AckSack(session, next, bitmap) => {
+                println!("Received SACK: {} then {:#x}", next, bitmap);
+                let seqs: Vec<Seq> = self.pending.iter()
+                    .filter(|&(seq, _)| seq.session == session)
+                    .filter(|&(_, p)| self.same_node(&p.target, src))
+                    .filter(|&(_, p)| p.stream.map_or(false, |stream| {
+                        ordered::sacked(stream, next, bitmap)
+                    }))
+                    .map(|(&seq, _)| seq).collect();
+                for seq in seqs {
+                    self.acked(seq);
+                }
+                return;
+            },
+            // Joining is latency sensitive, so acked at once.
+            Message::Acked(seq, AckedMessage::Join(id, version)) => {
+                if self.forward_join(seq, id, version, src)
+                        || self.defer_join(id, version, src)
+                        || self.hold_join(seq, id, version, src) {
+                    return;
+                }
+                let ack = self.ack_message(seq, src);
+                self.send(&ack, src);
+                // A Join starts the peer's sequence numbers afresh, as
+                // when it has restarted, and is harmless to handle twice.
+                self.dedup.forget(src);
+                // A new node where another was has ordered streams afresh
+                // too, but the same one joining again keeps its own.
+                if self.members.id_of(src).map_or(false, |known| known != id) {
+                    self.streams.forget(src);
+                }
+            },
+            Message::Acked(seq, AckedMessage::Ordered(stream, data)) => {
+                self.receive_ordered(seq, stream, data, src, now);
+                return;
+            },
+            Message::Acked(seq, AckedMessage::Suspected(of, incarnation)) => {
+                self.ack_later(seq, src);
+                if self.dedup.first_time(src, seq) {
+                    self.suspected(of, incarnation, src);
+                }
+                return;
+            },
+            Message::Acked(seq, AckedMessage::Tags(id, meta, tags)) => {
+                self.ack_later(seq, src);
+                if self.dedup.first_time(src, seq) {
+                    self.tags_of(id, meta, tags, src);
+                }
+                return;
+            },
+            Message::Acked(seq, _) => {
+                self.ack_later(seq, src);
+                if !self.dedup.first_time(src, seq) {
+                    return;
+                }
+            },
+            _ => (),
+        }
+
+        // Joins are applied by their handler; see handlers::join.
+        if let Message::Acked(_, AckedMessage::Join(id, _)) = msg {
+            if let MergeOutcome::Ignored(why) =
+                    self.merge(&MemberUpdate::Join(id)).1 {
+                println!("Ignoring a JOIN from {} at {}: {:?}", id, src, why);
+                return;
+            }
+        }
+
+        let join = msg.kind() == MessageKind::Join;
+        let restarted = match msg {
+            Message::Acked(_, AckedMessage::Join(id, _)) =>
+                self.replace_restarted(id, src, now),
+            _ => None,
+        };
+        // Who's joining, and what state we had them in if any, and why if
+        // Suspect, so that a new or returning peer makes the history.
+        let joiner = match msg {
+            Message::Acked(_, AckedMessage::Join(id, _)) => {
+                let known = self.members.get(id);
+                Some((id, known.as_ref().map(|p| p.state()),
+                      known.and_then(|p| p.suspicion())))
+            },
+            _ => None,
+        };
+        let health = if self.health.report && msg.kind() == MessageKind::Ping {
+            Some(self.health(now))
+        } else {
+            None
+        };
+        let (replies, churned) = {
+            let mut ctx = DispatchCtx::new(now, self.epoch, &mut self.members,
+                                           &mut *self.detector,
+                                           &mut self.events);
+            ctx.health = health;
+            ctx.restarted = restarted;
+            self.handlers.dispatch(&mut ctx, src, msg);
+            (ctx.replies, ctx.churned)
+        };
+        for (reply, target) in replies {
+            self.send(&reply, &target);
+        }
+        if churned {
+            self.note_churn(now);
+            self.convergence.learned(now);
+        }
+        if let Some((id, before, ended)) = joiner {
+            let cause = if restarted.is_some() {
+                Cause::Restarted
+            } else {
+                Cause::Join
+            };
+            match self.members.get(id) {
+                Some(ref peer) if Some(peer.state()) != before =>
+                    self.record_change(peer, before, ended, cause, now),
+                _ => (),
+            }
+        }
+
+        if join {
+            // Anyone joining us, new or again, is told our tags, which it
+            // may never have had, or have missed.
+            if let Some((id, _, _)) = joiner {
+                match self.members.get(id) {
+                    Some(ref peer) if self.meta != MetaVersion::default() =>
+                        self.send_tags(peer),
+                    _ => (),
+                }
+            }
+            // Someone new joining through us needs to know who we are,
+            // unless it's a node we're joining ourselves and so will hear
+            // about us anyway.
+            let joining = self.joins.iter().any(|j| {
+                j.ticket.target() == *src || j.contact == Some(*src)
+            });
+            if churned && !joining {
+                self.start_join(src);
+            }
+            self.complete_joins();
+        }
+    }
+
+    // `src` says it saw us at `addr`. What we tell ourselves doesn't count.
+    fn observed(&mut self, src: &SocketAddr, addr: &SocketAddr) {
+        if self.is_self(src) {
+            return;
+        }
+        let detected = self.observations.observe(src, addr, self.advertised);
+        if let Some(addr) = detected {
+            println!("Peers see us at {}", addr);
+            self.events.push_back(MeshEvent::ExternalAddressDetected(addr));
+            if self.observations.config().adopt {
+                self.advertised = Some(addr);
+                self.bump_incarnation();
+            }
+        }
+    }
+
+    // How we're doing, for our Pongs to say: what's waiting to go out
+    // (there being no inbound queue to speak of), what we've dropped
+    // lately, outbound or in, and how many peers we take to be alive.
+    fn health(&mut self, now: Instant) -> Health {
+        let clamp = |n: usize| cmp::min(n, u32::max_value() as usize) as u32;
+        let queued = BANDS.iter().map(|&b| self.outbound.depth(b))
+            .sum::<usize>();
+        let dropped = BANDS.iter().map(|&b| self.outbound.dropped(b))
+            .sum::<u64>() + self.stats.throttled + self.stats.pending_rejected;
+        let alive = self.members.peers().iter()
+            .filter(|p| p.state() == PeerState::Alive).count();
+        Health {
+            queued: clamp(queued),
+            dropped: self.drops.count(dropped, now),
+            alive: clamp(alive),
+        }
+    }
+
+    // `src` answered a Ping, saying how it's doing or (None) not. A peer
+    // that says it's overloaded is given longer before it's suspected,
+    // until a Pong says otherwise.
+    fn peer_health(&mut self, src: &SocketAddr, health: Option<Health>) {
+        let id = match self.members.id_of(src) {
+            Some(id) => id,
+            None => return,
+        };
+        let was = self.members.get(id).and_then(|p| p.health())
+            .map_or(false, |h| self.health.overloaded(&h));
+        let is = health.map_or(false, |h| self.health.overloaded(&h));
+        if is && !was {
+            println!("{} says it's overloaded ({:?}); giving it {:?} longer",
+                     src, health.unwrap(), self.health.grace);
+        } else if was && !is {
+            println!("{} is no longer overloaded", src);
+        }
+        self.members.set_health(id, health);
+        self.detector.set_grace(id, self.health.grace(health));
+        if let Some(health) = health {
+            let now = self.clock.now();
+            self.sizes.report(id, health.alive as usize + 1, now);
+        }
+    }
+
+    // `src` sent us a Keepalive, which asks nothing of us but to note
+    // that we've heard from it. It skips the throttle, which a peer
+    // keeping the way to us open shouldn't use up its allowance of, there
+    // being nothing to handle.
+    fn keepalive(&mut self, src: &SocketAddr) {
+        self.stats.keepalives_received += 1;
+        let now = self.clock.now();
+        self.heard_from(src, now);
+    }
+
+    fn heard_from(&mut self, src: &SocketAddr, now: Instant) {
+        self.backoff.heard(src);
+        self.members.seen(src, now);
+        match self.members.id_of(src) {
+            Some(id) if !self.evicted.contains_key(&id) =>
+                self.detector.on_message(id, now),
+            _ => (),
+        }
+    }
+
+    // Whether `msg` from `src`, if it's a Pong or an Ack, answers
+    // something we sent there: a Ping we remember, or an acked message
+    // not yet acked. (So an Ack repeated, as when a retransmission is
+    // acked as well, counts as unsolicited.) A Pong is accepted once. An
+    // Ack of a Join may come from elsewhere; see `forwarded_ack`.
+    fn solicited(&mut self, msg: &Message, src: &SocketAddr) -> bool {
+        match *msg {
+            Message::Pong(ref body, ..) => {
+                let sent = self.pings.iter().position(|&(nonce, target)| {
+                    nonce == body.nonce && self.same_node(&target, src)
+                });
+                match sent {
+                    Some(i) => {
+                        self.pings.remove(i);
+                        if let Some(id) = self.members.id_of(src) {
+                            self.scores.answered(id);
+                        }
+                        true
+                    },
+                    None => false,
+                }
+            },
+            Message::Ack(seq, _, from) => self.awaiting_ack(seq, src)
+                || (from.is_some() && self.forwarded_ack(seq)),
+            Message::AckMulti(ref seqs) =>
+                seqs.iter().any(|&seq| self.awaiting_ack(seq, src)),
+            Message::AckSack(session, ..) => self.pending.iter()
+                .any(|(seq, p)| {
+                    seq.session == session && p.stream.is_some()
+                        && self.same_node(&p.target, src)
+                }),
+            // Only from somewhere we've sent something that needs an
+            // answer, so that no one else can fail our joins.
+            Message::Rejected(_) =>
+                self.pending.values().any(|p| self.same_node(&p.target, src))
+                || self.joins.iter()
+                    .any(|j| self.same_node(&j.ticket.target(), src)),
+            _ => true,
+        }
+    }
+
+    // Whether `msg`, unsolicited, acks only what was sent in sessions
+    // other than ours: from before we restarted, most likely, so that the
+    // same counts in ours are no concern of it.
+    fn stale_ack(&self, msg: &Message) -> bool {
+        let ours = |session| session == self.next_seq.session
+            || self.pending.keys().any(|seq| seq.session == session);
+        match *msg {
+            Message::Ack(seq, ..) => !ours(seq.session),
+            Message::AckMulti(ref seqs) =>
+                !seqs.is_empty() && seqs.iter().all(|seq| !ours(seq.session)),
+            Message::AckSack(session, ..) => !ours(session),
+            _ => false,
+        }
+    }
+
+    // Whether `msg` from `src`, which isn't us, says it is, by carrying
+    // our NodeId: a Join with it, or an Ack saying we sent it.
+    fn claims_to_be_us(&self, msg: &Message, src: &SocketAddr) -> bool {
+        if self.is_self(src) {
+            return false;
+        }
+        match *msg {
+            Message::Acked(_, AckedMessage::Join(id, _)) => id == self.id,
+            Message::Ack(_, _, Some(from)) => from.id == self.id,
+            _ => false,
+        }
+    }
+
+    // `src` sent `msg` as though it were us, whether it's forged or from
+    // a node that somehow has our id. It's dropped, unacked, and we count
+    // it. Whoever sent a Join as us may have sent it to our peers too, who
+    // would then have us at its address: so we refute it, starting a new
+    // incarnation and announcing ourselves to every peer afresh. Only once
+    // every RETRANSMIT, though, while the last round of Joins is still
+    // on its way: however many Joins are forged, each costs nothing but
+    // the counting, and our peers hear from us a round at a time.
+    fn impersonated(&mut self, msg: &Message, src: &SocketAddr) {
+        println!("WARNING: dropping {:?} from {}, which claims to be from \
+                  us", msg.kind(), src);
+        self.stats.impersonations += 1;
+        let update = match *msg {
+            Message::Acked(_, AckedMessage::Join(id, _)) =>
+                MemberUpdate::Join(id),
+            Message::Ack(_, _, Some(from)) =>
+                MemberUpdate::Incarnation(from.id, from.incarnation),
+            _ => return,
+        };
+        if self.merge(&update).1 != MergeOutcome::RefuteSelf {
+            return;
+        }
+        let now = self.clock.now();
+        if self.refuted_at.map_or(false, |at| now - at < RETRANSMIT) {
+            return;
+        }
+        self.refuted_at = Some(now);
+        self.bump_incarnation();
+        let peers: Vec<SocketAddr> = self.members.peers().iter()
+            .filter(|p| p.state() != PeerState::Dead)
+            .map(|p| p.addr()).collect();
+        for addr in peers {
+            let join = AckedMessage::Join(self.id, message::PROTOCOL_VERSION);
+            self.queue_acked(join, &addr);
+        }
+    }
+
+    // `src` acked something as `from`. If that's the peer we know there,
+    // and it's on a newer incarnation than we'd recorded, our record may be
+    // out of date in ways the Ack doesn't say, so it's asked to sync. The
+    // first incarnation we hear of is just recorded.
+    fn check_incarnation(&mut self, from: Responder, src: &SocketAddr) {
+        match self.members.get(from.id) {
+            Some(ref peer) if peer.addr() == *src => (),
+            _ => return,
+        }
+        let update = MemberUpdate::Incarnation(from.id, from.incarnation);
+        let (local, entry) = match self.merge(&update) {
+            (Some(local), MergeOutcome::Updated(entry)) => (local, entry),
+            _ => return,
+        };
+        if local.evicted.is_some() && entry.evicted.is_none() {
+            println!("{} is back on incarnation {}, so no longer evicted",
+                     src, from.incarnation);
+        }
+        if let Some(known) = local.incarnation {
+            println!("{} has moved on from incarnation {} to {}", src,
+                     known, from.incarnation);
+            self.stats.syncs_requested += 1;
+            self.timer.add_named(Nanos::from(SYNC_DELAY),
+                                 format!("sync:{}", src),
+                                 Timeout::Sync(*src));
+        }
+        let now = self.clock.now();
+        self.apply(from.id, &local, &entry, Cause::Refuted, now);
+    }
+
+    // Whether to go on to decode `buf` from `src`, or drop it for `src`
+    // sending too much; see throttle::Throttle. Only the type byte is
+    // looked at.
+    fn admit(&mut self, buf: &[u8], src: &SocketAddr) -> bool {
+        if self.is_self(src) {
+            return true;
+        }
+        let liveness = message::frame_type(buf)
+            .map_or(false, message::is_liveness_type);
+        let now = self.clock.now();
+        match self.throttle.check(src, liveness, now) {
+            throttle::Verdict::Admit => true,
+            throttle::Verdict::Drop => {
+                self.stats.throttled += 1;
+                false
+            },
+            throttle::Verdict::Block => {
+                let blocked_for = self.throttle.config().block_for;
+                println!("WARNING: blocking {} for {:?} for flooding us",
+                         src, blocked_for);
+                self.stats.throttled += 1;
+                self.stats.sources_blocked += 1;
+                self.timer.add_named(Nanos::from(blocked_for),
+                                     format!("unblock:{}", src),
+                                     Timeout::Unblock(*src));
+                self.events.push_back(MeshEvent::PeerThrottled {
+                    from: *src,
+                    blocked_for: blocked_for,
+                });
+                false
+            },
+        }
+    }
+
+    // Tell `src` we dropped what it sent, and roughly why, if we're to
+    // and it hasn't been told too often lately.
+    fn explain(&mut self, code: RejectCode, src: &SocketAddr) {
+        if !self.explain_rejects || self.is_self(src) {
+            return;
+        }
+        let now = self.clock.now();
+        if self.rejects.allow(src, now) {
+            self.stats.rejects_sent += 1;
+            self.send(&Message::Rejected(code), src);
+        }
+    }
+
+    // `src` dropped something we sent it. Said loudly, since it's most
+    // likely a misconfiguration; and when it's one no retry will get past,
+    // our joins to it fail at once rather than when they time out.
+    fn rejected(&mut self, code: RejectCode, src: &SocketAddr) {
+        self.stats.rejects_received += 1;
+        println!("WARNING: {} rejected what we sent: {}", src, code);
+        if !code.ends_joins() {
+            return;
+        }
+        while let Some(i) = self.joins.iter()
+                .position(|j| self.same_node(&j.ticket.target(), src)) {
+            let seq = self.joins[i].ticket.seq();
+            self.pending.remove(&seq);
+            self.finish_join(i, JoinStatus::Failed(MeshError::Rejected(code)));
+        }
+    }
+
+    // Ask the peer at `target`, if it's still one, to send us its Join.
+    fn request_sync(&mut self, target: &SocketAddr) {
+        let incarnation = self.members.id_of(target)
+            .and_then(|id| self.members.get(id))
+            .and_then(|peer| peer.incarnation());
+        if let Some(incarnation) = incarnation {
+            self.send(&Message::SyncRequest(incarnation), target);
+        }
+    }
+
+    // Whether `seq` is a Join of ours not yet acked, which whoever its
+    // target handed it to may ack (see `forward_join`). Nothing but the
+    // seq ties such an Ack to our Join, there being no cluster name or
+    // key on the wire to check it by.
+    fn forwarded_ack(&self, seq: Seq) -> bool {
+        self.pending.get(&seq).map_or(false, |p| p.kind == MessageKind::Join)
+    }
+
+    fn awaiting_ack(&self, seq: Seq, src: &SocketAddr) -> bool {
+        self.pending.get(&seq).map_or(false, |p| self.same_node(&p.target, src))
+    }
+
+    // Whether `a` and `b` are the same node: the same address, or both
+    // ourselves.
+    fn same_node(&self, a: &SocketAddr, b: &SocketAddr) -> bool {
+        a == b || (self.is_self(a) && self.is_self(b))
+    }
+
+    // If `src`, acking `seq`, acks a Join of ours sent elsewhere, it's
+    // who we're joining through now.
+    fn took_over(&mut self, seq: Seq, src: &SocketAddr) {
+        let target = match self.pending.get(&seq) {
+            Some(p) if p.kind == MessageKind::Join => p.target,
+            _ => return,
+        };
+        if self.same_node(&target, src) {
+            return;
+        }
+        println!("Join {} to {} was handed on to {}", seq, target, src);
+        for j in self.joins.iter_mut().filter(|j| j.ticket.seq() == seq) {
+            j.contact = Some(*src);
+        }
+    }
+
+    fn acked(&mut self, seq: Seq) {
+        self.pending.remove(&seq);
+        let mut any = false;
+        for j in self.joins.iter_mut().filter(|j| j.ticket.seq() == seq) {
+            j.acked = true;
+            any = true;
+        }
+        if any {
+            self.complete_joins();
+        }
+    }
+
+    // Resolve the joins that have been acked and whose target, or the
+    // peer it handed them to, we've now heard from with a Join.
+    fn complete_joins(&mut self) {
+        let mut i = 0;
+        while i < self.joins.len() {
+            let peer = {
+                let j = &self.joins[i];
+                if j.acked {
+                    let contact = j.contact.unwrap_or(j.ticket.target());
+                    self.members.id_of(&contact)
+                        .and_then(|id| self.members.get(id))
+                } else {
+                    None
+                }
+            };
+            match peer {
+                Some(peer) => self.finish_join(i, JoinStatus::Joined(peer)),
+                None => i += 1,
+            }
+        }
+    }
+
+    // Drop the held Joins the embedder hasn't decided on in time; see
+    // `require_admission`.
+    fn expire_admissions(&mut self, now: Instant) {
+        let expired = match self.admissions {
+            Some(ref mut admissions) => admissions.expire(now),
+            None => return,
+        };
+        for candidate in expired {
+            println!("WARNING: dropping the JOIN from {} at {}, never \
+                      admitted", candidate.id, candidate.addr);
+            self.stats.joins_expired += 1;
+        }
+    }
+
+    // Fail joins that were acked but got no Join back in time. (Those
+    // never acked fail along with their Join; see `delivery_failed`.)
+    fn expire_joins(&mut self, now: Instant) {
+        let budget = self.join_retransmit.budget;
+        while let Some(i) = self.joins.iter()
+                .position(|j| j.acked && now - j.started >= budget) {
+            self.finish_join(i, JoinStatus::Failed(MeshError::Timeout));
+        }
+    }
+
+    fn finish_join(&mut self, i: usize, status: JoinStatus) {
+        let j = self.joins.remove(i);
+        println!("Join {} to {}: {:?}", j.ticket.seq(), j.ticket.target(),
+                 status);
+        // The target has told us who it is, which is all there is to
+        // syncing with it yet.
+        if let JoinStatus::Joined(_) = status {
+            let now = self.clock.now();
+            self.convergence.joined(now);
+            self.convergence.synced();
+        }
+        j.ticket.resolve(status.clone());
+        self.events.push_back(MeshEvent::JoinCompleted {
+            target: j.ticket.target(),
+            status: status,
+        });
+    }
+
+    // An acked message ran out of retransmissions. Whoever sent it hears
+    // about it, the peer becomes suspect, and anything else queued for it
+    // that isn't protocol control traffic is dropped rather than sent
+    // into the void.
+    fn delivery_failed(&mut self, seq: Seq, p: Pending, now: Instant) {
+        self.events.push_back(MeshEvent::DeliveryFailed {
+            peer: p.target,
+            seq: seq,
+            kind: p.kind,
+            error: MeshError::Timeout,
+        });
+        if let Some(i) = self.joins.iter().position(|j| j.ticket.seq() == seq) {
+            self.finish_join(i, JoinStatus::Failed(MeshError::Timeout));
+        }
+        if p.kind == MessageKind::Suspected {
+            let id = self.members.id_of(&p.target);
+            let suspect = id.and_then(|id| self.members.get(id))
+                .map_or(false, |peer| peer.state() == PeerState::Suspect);
+            if suspect {
+                println!("{} never answered our suspecting it", p.target);
+                self.stats.suspicions_unanswered += 1;
+                self.detector.on_suspicion_unanswered(id.unwrap());
+            }
+        }
+
+        self.suspect(&p.target, now, Cause::Unacked);
+        self.outbound.drop_to(Band::Probe, &p.target);
+        self.outbound.drop_to(Band::Bulk, &p.target);
+    }
+
+    // The OS refused to send `bytes` to `target` as too big for the path
+    // there. With no way to send a message in pieces, it can't go at all:
+    // if it's an acked message, its delivery fails now rather than after
+    // retransmitting it to no avail, and so does that of any as large to
+    // `target` from now on. The peer's MTU (see Peer::mtu) comes down to
+    // match. Warned of once per address, and as an error if even MIN_MTU
+    // bytes were too many.
+    fn sent_too_big(&mut self, bytes: Vec<u8>, target: &SocketAddr) {
+        self.stats.too_big += 1;
+        let size = bytes.len();
+        if !self.too_big.contains_key(target) {
+            println!("WARNING: {} can't be sent a {} byte datagram; \
+                      refusing messages as large to it from now on",
+                     target, size);
+        }
+        let limit = cmp::min(size, *self.too_big.get(target).unwrap_or(&size));
+        self.too_big.insert(*target, limit);
+        if size <= MIN_MTU {
+            println!("ERROR: {} can't be sent even a {} byte datagram; \
+                      nothing much will get through", target, size);
+        }
+        if let Some(id) = self.members.id_of(target) {
+            self.mtu.refused(id, size);
+            let mtu = self.mtu.mtu(id);
+            self.members.set_mtu(id, mtu);
+        }
+
+        let seq = self.pending.iter()
+            .find(|&(_, p)| p.target == *target && p.bytes == bytes)
+            .map(|(&seq, _)| seq);
+        if let Some(seq) = seq {
+            let p = self.pending.remove(&seq).unwrap();
+            self.events.push_back(MeshEvent::DeliveryFailed {
+                peer: p.target,
+                seq: seq,
+                kind: p.kind,
+                error: MeshError::TooLarge,
+            });
+            if let Some(i) = self.joins.iter()
+                    .position(|j| j.ticket.seq() == seq) {
+                self.finish_join(i, JoinStatus::Failed(MeshError::TooLarge));
+            }
+        }
+    }
+
+    // An Ack of `seq` for `target`, saying who we are.
+    fn ack_message(&self, seq: Seq, target: &SocketAddr) -> Message {
+        Message::Ack(seq, WireAddr(*target), Some(Responder {
+            id: self.id,
+            incarnation: self.incarnation,
+        }))
+    }
+
+    // Take in acked message `seq`, number `stream` of `src`'s ordered
+    // stream, and hand the application whatever that makes ready, in
+    // order. It's acked unless it's too far ahead to hold, so that it's
+    // sent again later. Its place in the stream says whether it's new, so
+    // the dedup cache isn't needed, and it goes to the application as Data
+    // without passing through the handlers.
+    fn receive_ordered(&mut self, seq: Seq, stream: u32, data: Vec<u8>,
+                       src: &SocketAddr, now: Instant) {
+        match self.streams.receive(src, stream, data, now) {
+            Arrival::Deliver(ready) => {
+                self.ack_ordered(seq, src);
+                for data in ready {
+                    self.events.push_back(MeshEvent::Data(*src, data));
+                }
+            },
+            Arrival::Held | Arrival::Duplicate => self.ack_ordered(seq, src),
+            Arrival::Refused => self.stats.ordered_refused += 1,
+        }
+    }
+
+    // Owe `src` an ack for ordered message `seq`. A peer that takes
+    // AckSacks gets one soon, saying all we have of its stream, so that
+    // losing one costs nothing the next doesn't make up for.
+    fn ack_ordered(&mut self, seq: Seq, src: &SocketAddr) {
+        let version = self.members.id_of(src)
+            .and_then(|id| self.members.get(id))
+            .and_then(|peer| peer.version());
+        if version.map_or(true, |v| v < SACK_VERSION) {
+            self.ack_later(seq, src);
+            return;
+        }
+        let full = {
+            let owed = self.sacks.entry(*src).or_insert((0, seq.session));
+            if owed.0 == 0 {
+                self.timer.add_named(Nanos::from(ACK_DELAY),
+                                     format!("sack:{}", src),
+                                     Timeout::FlushSack(*src));
+            }
+            *owed = (owed.0 + 1, seq.session);
+            owed.0 >= ACK_BATCH
+        };
+        if full {
+            self.flush_sack(src);
+        }
+    }
+
+    fn flush_sack(&mut self, target: &SocketAddr) {
+        if let Some((_, session)) = self.sacks.remove(target) {
+            let (next, bitmap) = self.streams.sack(target);
+            self.send(&Message::AckSack(session, next, bitmap), target);
+        }
+    }
+
+    // Give up on ordered messages that have held up later ones for as long
+    // as their senders would have kept retransmitting them, and deliver
+    // what was waiting.
+    fn skip_overdue_ordered(&mut self, now: Instant) {
+        let deadline = self.data_retransmit.budget;
+        for skipped in self.streams.skip_overdue(now, deadline) {
+            println!("WARNING: ordered stream from {} stalled for {:?}; \
+                      skipping {} missing message(s)", skipped.from,
+                     deadline, skipped.missing);
+            self.stats.ordered_skipped += skipped.missing as u64;
+            for data in skipped.ready {
+                self.events.push_back(MeshEvent::Data(skipped.from, data));
+            }
+        }
+    }
+
+    // Send a Keepalive to each peer we've sent nothing else for
+    // Config::nat_refresh, that isn't dead or evicted and can read one.
+    fn send_keepalives(&mut self, now: Instant) {
+        if !self.keepalives.check_due(now) {
+            return;
+        }
+        let peers: Vec<SocketAddr> = self.members.peers().iter()
+            .filter(|p| p.state() != PeerState::Dead
+                    && !self.evicted.contains_key(&p.id())
+                    && p.version().map_or(false, |v| v >= KEEPALIVE_VERSION))
+            .map(|p| p.addr()).collect();
+        for addr in self.keepalives.due(&peers, now) {
+            self.stats.keepalives_sent += 1;
+            self.send(&Message::Keepalive, &addr);
+        }
+    }
+
+    // Owe `src` an ack for `seq`, to be sent along with any others soon.
+    fn ack_later(&mut self, seq: Seq, src: &SocketAddr) {
+        let full = {
+            let acks = self.acks.entry(*src).or_insert(Vec::new());
+            if acks.is_empty() {
+                self.timer.add_named(Nanos::from(ACK_DELAY),
+                                     format!("acks:{}", src),
+                                     Timeout::FlushAcks(*src));
+            }
+            acks.push(seq);
+            acks.len() >= ACK_BATCH
+        };
+        if full {
+            self.flush_acks(src);
+        }
+    }
+
+    fn flush_acks(&mut self, target: &SocketAddr) {
+        let mut seqs = match self.acks.remove(target) {
+            Some(seqs) => seqs,
+            None => return,
+        };
+        if seqs.len() == 1 {
+            let ack = self.ack_message(seqs.pop().unwrap(), target);
+            self.send(&ack, target);
+        } else {
+            self.send(&Message::AckMulti(seqs), target);
+        }
+    }
+}
+
+// A session for our sequence numbers to start afresh in (see
+// message::Seq): any but `old`, or 0, which version 9's were taken to be
+// of and is best left meaning none.
+fn new_session<R: Rng>(rng: &mut R, old: u32) -> u32 {
+    loop {
+        let session = rng.gen();
+        if session != old && session != 0 {
+            return session;
+        }
+    }
+}
+
+// The longest tick that isn't a clock jump; see CLOCK_JUMP_FACTOR.
+fn jump_threshold(config: &Config) -> Duration {
+    *[config.probe.max_interval,
+      config.join_retransmit.budget,
+      config.data_retransmit.budget]
+        .iter().max().unwrap() * CLOCK_JUMP_FACTOR
+}
+
+// Whether `target` is the address we're bound to, `local`. If we're bound
+// to every interface, our port on the loopback interface counts.
+fn is_local(local: &Option<SocketAddr>, target: &SocketAddr) -> bool {
+    match *local {
+        Some(addr) if addr == *target => true,
+        Some(addr) => addr.ip().is_unspecified()
+            && target.ip().is_loopback() && addr.port() == target.port(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+fn test_dispatcher() -> Dispatcher<::transport::SimTransport, clock::ManualClock> {
+    Dispatcher::new(::transport::SimTransport::new(), clock::ManualClock::new())
+}
+
+#[cfg(test)]
+fn peer() -> SocketAddr {
+    "127.0.0.1:9000".parse().unwrap()
+}
+
+// Where peer() sees us, as it would say in an Ack or Pong.
+#[cfg(test)]
+fn seen_at_us() -> WireAddr {
+    WireAddr("127.0.0.1:7000".parse().unwrap())
+}
+
+// Seq `n` of peer()'s.
+#[cfg(test)]
+fn peer_seq(n: u32) -> Seq {
+    Seq::new(0x9ee7, n)
+}
+
+// The seq `k` after `seq`, in the same session.
+#[cfg(test)]
+fn after(seq: Seq, k: u32) -> Seq {
+    Seq::new(seq.session, seq.n + k)
+}
+
+#[cfg(test)]
+fn ack_from_peer(seq: Seq) -> Message {
+    Message::Ack(seq, seen_at_us(), Some(Responder {
+        id: NodeId(1),
+        incarnation: 0,
+    }))
+}
+
+#[cfg(test)]
+fn ack_to_peer<T: Transport, C: Clock>(d: &Dispatcher<T, C>, n: u32)
+        -> Message {
+    d.ack_message(peer_seq(n), &peer())
+}
+
+// A Join from `id`, speaking our version, as peer()'s seq `n`.
+// How many ticks there are in `d`.
+#[cfg(test)]
+fn ticks_in(d: Duration) -> u64 {
+    Nanos::from(d) / Nanos::from(TICK)
+}
+
+#[cfg(test)]
+fn join_msg(n: u32, id: NodeId) -> Message {
+    Message::Acked(peer_seq(n),
+                   AckedMessage::Join(id, message::PROTOCOL_VERSION))
+}
+
+#[cfg(test)]
+fn ping_msg() -> Message {
+    Message::Ping(PingBody { nonce: 1, sent_at_micros: 0, pad: vec![] })
+}
+
+// Have peer() join `d`, acking the Join `d` sends back.
+#[cfg(test)]
+fn join_from_peer(d: &mut Dispatcher<::transport::SimTransport,
+                                     clock::ManualClock>) {
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    let seq = d.pending.keys().cloned().max().unwrap();
+    d.transport.deliver(ack_from_peer(seq).encode(), peer());
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+}
+
+#[test]
+fn retransmits_while_no_packets_arrive() {
+    let mut d = test_dispatcher();
+    d.join(&peer());
+    d.poll();
+    assert_eq!(d.transport.sent.borrow().len(), 1);
+
+    // Nothing due yet.
+    d.clock.advance(RETRANSMIT - Duration::from_millis(1));
+    d.poll();
+    assert_eq!(d.transport.sent.borrow().len(), 1);
+
+    let attempts = Config::default().join_retransmit.attempts;
+    for n in 0..attempts {
+        d.clock.advance(RETRANSMIT);
+        d.poll();
+        assert_eq!(d.transport.sent.borrow().len(), 2 + n as usize);
+    }
+
+    // Out of retries: the message is dropped and never sent again.
+    for _ in 0..3 {
+        d.clock.advance(RETRANSMIT);
+        d.poll();
+    }
+    assert_eq!(d.transport.sent.borrow().len(), 1 + attempts as usize);
+    assert!(d.pending.is_empty());
+}
+
+#[test]
+fn exhausted_retransmission_fails_delivery() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let seq = d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    d.poll();
+    d.next_event();
+
+    // With the socket backed up, queue a probe and a payload, and owe
+    // an ack, all for the same peer.
+    d.transport.blocked.set(true);
+    d.ping(&peer());
+    d.send_acked(AckedMessage::Data(vec![2]), &peer());
+    d.send(&ack_to_peer(&d, 9), &peer());
+
+    let policy = Config::default().data_retransmit;
+    for _ in 0..policy.attempts + 1 {
+        d.clock.advance(RETRANSMIT);
+        d.poll();
+    }
+
+    let mut failed = Vec::new();
+    while let Some(e) = d.next_event() {
+        if let MeshEvent::DeliveryFailed { peer: p, seq, kind, error } = e {
+            assert_eq!(p, peer());
+            assert_eq!(kind, MessageKind::Data);
+            assert_eq!(error, MeshError::Timeout);
+            failed.push(seq);
+        }
+    }
+    assert_eq!(failed, vec![seq, after(seq, 1)]);
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+    assert_eq!(d.peers()[0].suspicion().map(|s| s.reason),
+               Some(SuspicionReason::Unacked));
+
+    // Only the control traffic is still waiting to go: the ack, and word
+    // to the peer that we now suspect it.
+    assert_eq!(d.outbound.depth(Band::Probe), 0);
+    assert_eq!(d.outbound.depth(Band::Bulk), 0);
+    assert_eq!(d.outbound.depth(Band::Control), 2);
+    d.transport.blocked.set(false);
+    d.transport.sent.borrow_mut().clear();
+    d.poll();
+    let suspected = Message::Acked(after(seq, 2),
+                                   AckedMessage::Suspected(NodeId(1), 0));
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(ack_to_peer(&d, 9).encode(), peer()),
+                    (suspected.encode(), peer())]);
+}
+
+#[test]
+fn retransmission_stops_at_time_budget() {
+    let config = Config {
+        data_retransmit: RetransmitPolicy {
+            attempts: 100,
+            budget: RETRANSMIT * 2,
+        },
+        .. Config::default()
+    };
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    d.send_acked(AckedMessage::Data(vec![]), &peer());
+    for _ in 0..5 {
+        d.clock.advance(RETRANSMIT);
+        d.poll();
+    }
+    // The original and one resend; the second falls due at the budget.
+    assert_eq!(d.transport.sent.borrow().len(), 2);
+    assert!(d.pending.is_empty());
+}
+
+#[test]
+fn ack_stops_retransmission() {
+    let mut d = test_dispatcher();
+    let seq = d.join(&peer());
+
+    d.transport.deliver(ack_from_peer(seq).encode(), peer());
+    d.poll();
+    assert!(d.pending.is_empty());
+
+    d.clock.advance(RETRANSMIT * 2);
+    d.poll();
+    assert_eq!(d.transport.sent.borrow().len(), 1);
+}
+
+#[test]
+fn acks_from_before_a_restart_ack_nothing_since() {
+    let config = Config::default();
+    let seeded = |seed| Dispatcher::with_seed(
+        ::transport::SimTransport::new(), clock::ManualClock::new(),
+        &config, NodeId(0xaa), seed);
+    let before = seeded(1).next_seq.session;
+    let mut d = seeded(2);
+    assert!(d.next_seq.session != before);
+    join_from_peer(&mut d);
+    let seq = d.send_acked(AckedMessage::Data(vec![1]), &peer());
+
+    // The peer's acks of what we sent before restarting, late, carry the
+    // same counts as ours now.
+    let late = Seq::new(before, seq.n);
+    for msg in vec![ack_from_peer(late), Message::AckMulti(vec![late]),
+                    Message::AckSack(before, seq.n + 1, 0)] {
+        d.transport.deliver(msg.encode(), peer());
+        d.poll();
+    }
+    assert!(d.pending.contains_key(&seq));
+    assert_eq!(d.stats.stale_acks, 3);
+
+    d.transport.deliver(ack_from_peer(seq).encode(), peer());
+    d.poll();
+    assert!(d.pending.is_empty());
+    assert_eq!(d.stats.stale_acks, 3);
+}
+
+#[test]
+fn running_out_of_sequence_numbers_starts_a_new_session() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let session = d.next_seq.session;
+    d.next_seq = Seq::new(session, u32::max_value());
+    let last = d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    let first = d.send_acked(AckedMessage::Data(vec![2]), &peer());
+    assert_eq!(last, Seq::new(session, u32::max_value()));
+    assert!(first.session != session);
+    assert_eq!(first.n, 1);
+
+    // Each is acked by its own, not mistaken for the other.
+    d.transport.deliver(ack_from_peer(first).encode(), peer());
+    d.poll();
+    assert_eq!(d.pending.keys().cloned().collect::<Vec<_>>(), vec![last]);
+    d.transport.deliver(ack_from_peer(last).encode(), peer());
+    d.poll();
+    assert!(d.pending.is_empty());
+    assert_eq!(d.stats.stale_acks, 0);
+}
+
+#[test]
+fn retransmit_timers_are_labelled() {
+    let mut d = test_dispatcher();
+    let seq = d.join(&peer());
+    let retransmits = |d: &Dispatcher<_, _>| -> Vec<(String, Nanos)> {
+        d.timers().into_iter().filter(|t| t.0.starts_with("retransmit"))
+            .collect()
+    };
+    assert_eq!(retransmits(&d), vec![(format!("retransmit:seq={}", seq),
+                                      Nanos::from(RETRANSMIT))]);
+
+    d.clock.advance(RETRANSMIT);
+    d.poll();
+    assert_eq!(retransmits(&d).len(), 1);
+}
+
+#[test]
+fn join_adds_peer_and_emits_event() {
+    let mut d = test_dispatcher();
+    let joiner = NodeId(42);
+    d.transport.deliver(join_msg(7, joiner).encode(), peer());
+    d.poll();
+
+    let peers = d.peers();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].id(), joiner);
+    assert_eq!(peers[0].addr(), peer());
+    match d.next_event() {
+        Some(MeshEvent::PeerJoined(p)) => assert!(p == peers[0]),
+        other => panic!("expected PeerJoined, got {:?}", other),
+    }
+
+    // Joining again (a retransmission, say) is acked but not news.
+    d.transport.deliver(join_msg(7, joiner).encode(), peer());
+    d.poll();
+    assert_eq!(d.peers().len(), 1);
+    assert!(d.next_event().is_none());
+
+    // The first time, we tell it who we are too.
+    let our_join = latest_join(&d);
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(ack_to_peer(&d, 7).encode(), peer()),
+                    (our_join.encode(), peer()),
+                    (ack_to_peer(&d, 7).encode(), peer())]);
+}
+
+#[test]
+fn concurrent_joins_resolve_independently() {
+    let mut d = test_dispatcher();
+    let other: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    let good = d.join_async(&peer());
+    let bad = d.join_async(&other);
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+
+    // peer() acks our Join and sends its own, which we don't answer in
+    // kind: we're joining it already.
+    d.transport.deliver(ack_from_peer(good.seq()).encode(), peer());
+    d.poll();
+    assert_eq!(good.status(), JoinStatus::Pending);
+    d.transport.deliver(join_msg(1, NodeId(5)).encode(), peer());
+    d.poll();
+    match good.status() {
+        JoinStatus::Joined(p) => {
+            assert_eq!(p.id(), NodeId(5));
+            assert_eq!(p.addr(), peer());
+        },
+        other => panic!("expected Joined, got {:?}", other),
+    }
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(ack_to_peer(&d, 1).encode(), peer())]);
+    assert_eq!(bad.status(), JoinStatus::Pending);
+
+    let policy = Config::default().join_retransmit;
+    for _ in 0..policy.attempts + 1 {
+        d.clock.advance(RETRANSMIT);
+        d.poll();
+    }
+    assert_eq!(bad.status(), JoinStatus::Failed(MeshError::Timeout));
+
+    let completed: Vec<(SocketAddr, JoinStatus)> = d.events.drain(..)
+        .filter_map(|e| match e {
+            MeshEvent::JoinCompleted { target, status } =>
+                Some((target, status)),
+            _ => None,
+        }).collect();
+    assert_eq!(completed, vec![(peer(), good.status()), (other, bad.status())]);
+}
+
+// A dispatcher with `config`, bound to `addr`.
+#[cfg(test)]
+fn dispatcher_at(addr: &str, config: &Config)
+        -> Dispatcher<::transport::SimTransport, clock::ManualClock> {
+    let mut transport = ::transport::SimTransport::new();
+    transport.addr = addr.parse().unwrap();
+    Dispatcher::with_config(transport, clock::ManualClock::new(), config)
+}
+
+// Carry whatever each of `nodes` sends to whichever of them it's sent to,
+// each handling it at once, until they've nothing left to say.
+#[cfg(test)]
+fn exchange(nodes: &mut [&mut Dispatcher<::transport::SimTransport,
+                                         clock::ManualClock>]) {
+    loop {
+        let mut in_flight = Vec::new();
+        for n in nodes.iter_mut() {
+            n.poll();
+            let from = n.transport.addr;
+            in_flight.extend(n.transport.sent.borrow_mut().drain(..)
+                             .map(|(bytes, to)| (bytes, from, to)));
+        }
+        if in_flight.is_empty() {
+            return;
+        }
+        for (bytes, from, to) in in_flight {
+            if let Some(n) = nodes.iter_mut()
+                    .find(|n| n.transport.addr == to) {
+                n.transport.deliver(bytes, from);
+                n.poll();
+            }
+        }
+    }
+}
+
+#[test]
+fn joins_handed_to_the_coordinator_are_answered_by_it() {
+    let coordinator: SocketAddr = "127.0.0.1:7002".parse().unwrap();
+    let mut seed = dispatcher_at("127.0.0.1:7000", &Config {
+        coordinator: Some(coordinator),
+        .. Config::default()
+    });
+    let mut third = dispatcher_at("127.0.0.1:7002", &Config::default());
+    let mut joiner = dispatcher_at("127.0.0.1:7001", &Config::default());
+    let seed_addr = seed.transport.addr;
+
+    // The coordinator's own Join the seed answers itself.
+    let ticket = third.join_async(&seed_addr);
+    exchange(&mut [&mut seed, &mut third, &mut joiner]);
+    match ticket.status() {
+        JoinStatus::Joined(p) => assert_eq!(p.id(), seed.node_id()),
+        other => panic!("expected Joined, got {:?}", other),
+    }
+
+    // Anyone else's it hands to the coordinator, whose Ack and Join the
+    // joiner takes in place of the seed's.
+    let ticket = joiner.join_async(&seed_addr);
+    exchange(&mut [&mut seed, &mut third, &mut joiner]);
+    match ticket.status() {
+        JoinStatus::Joined(p) => {
+            assert_eq!(p.id(), third.node_id());
+            assert_eq!(p.addr(), coordinator);
+        },
+        other => panic!("expected Joined, got {:?}", other),
+    }
+    assert_eq!(seed.stats.joins_forwarded, 1);
+    assert_eq!(third.stats.joins_taken_over, 1);
+    let ids = |d: &Dispatcher<::transport::SimTransport, clock::ManualClock>| {
+        d.peers().iter().map(|p| p.id()).collect::<Vec<NodeId>>()
+    };
+    assert_eq!(ids(&seed), vec![third.node_id()]);
+    assert!(ids(&third).contains(&joiner.node_id()));
+    assert_eq!(ids(&joiner), vec![third.node_id()]);
+    let completed: Vec<SocketAddr> = joiner.events.drain(..)
+        .filter_map(|e| match e {
+            MeshEvent::JoinCompleted { target, .. } => Some(target),
+            _ => None,
+        }).collect();
+    assert_eq!(completed, vec![seed_addr]);
+}
+
+#[test]
+fn overloaded_nodes_hand_joins_to_peers_that_can_take_them() {
+    let mut d = Dispatcher::with_config(
+        ::transport::SimTransport::new(), clock::ManualClock::new(),
+        &Config {
+            health: HealthConfig { queued: 0, .. HealthConfig::default() },
+            .. Config::default()
+        });
+    join_from_peer(&mut d);
+    let newcomer: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    d.transport.deliver(join_msg(1, NodeId(7)).encode(), newcomer);
+    d.poll();
+    let forward = Message::JoinForward(WireAddr(newcomer), peer_seq(1),
+                                       NodeId(7), message::PROTOCOL_VERSION);
+    assert_eq!(*d.transport.sent.borrow(), vec![(forward.encode(), peer())]);
+    assert_eq!(d.peers().len(), 1);
+    d.transport.sent.borrow_mut().clear();
+
+    // A joiner before JoinForward wouldn't take an Ack from elsewhere, so
+    // its Join is answered here, as is one from a peer we know.
+    let old = Message::Acked(peer_seq(1),
+                             AckedMessage::Join(NodeId(8),
+                                                JOIN_FORWARD_VERSION - 1));
+    d.transport.deliver(old.encode(), newcomer);
+    d.poll();
+    d.transport.deliver(join_msg(2, NodeId(1)).encode(), peer());
+    d.poll();
+    let acks: Vec<SocketAddr> = d.transport.sent.borrow().iter()
+        .filter(|&&(ref bytes, _)| {
+            Message::decode(bytes).kind() == MessageKind::Ack
+        })
+        .map(|&(_, to)| to).collect();
+    assert_eq!(acks, vec![newcomer, peer()]);
+    assert_eq!(d.stats.joins_forwarded, 1);
+
+    // And only a peer may hand us a Join.
+    let stranger: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+    d.transport.sent.borrow_mut().clear();
+    d.transport.deliver(forward.encode(), stranger);
+    d.poll();
+    assert!(d.transport.sent.borrow().is_empty());
+    assert_eq!(d.stats.joins_taken_over, 0);
+}
+
+#[test]
+fn a_seed_lets_a_crowd_of_joiners_in_a_few_at_a_time() {
+    let cap = 8;
+    let mut nodes = vec![dispatcher_at("127.0.0.1:7000", &Config {
+        limits: Limits { join_handshakes: cap, .. Limits::default() },
+        .. Config::default()
+    })];
+    let seed_addr = nodes[0].transport.addr;
+    for i in 0..50 {
+        let addr = format!("127.0.0.1:{}", 8000 + i);
+        nodes.push(dispatcher_at(&addr, &Config::default()));
+    }
+    let tickets: Vec<JoinTicket> = nodes[1..].iter_mut()
+        .map(|d| d.join_async(&seed_addr)).collect();
+    let joined = |t: &JoinTicket| match t.status() {
+        JoinStatus::Joined(_) => true,
+        _ => false,
+    };
+
+    // As `exchange`, but watching the seed after everything it takes in,
+    // and letting time pass for those told to come back later.
+    for _ in 0..200 {
+        loop {
+            let mut in_flight = Vec::new();
+            for n in nodes.iter_mut() {
+                n.poll();
+                let from = n.transport.addr;
+                in_flight.extend(n.transport.sent.borrow_mut().drain(..)
+                                 .map(|(bytes, to)| (bytes, from, to)));
+            }
+            if in_flight.is_empty() {
+                break;
+            }
+            for (bytes, from, to) in in_flight {
+                if let Some(n) = nodes.iter_mut()
+                        .find(|n| n.transport.addr == to) {
+                    n.transport.deliver(bytes, from);
+                    n.poll();
+                }
+                assert!(nodes[0].joins.len() <= cap);
+            }
+        }
+        if tickets.iter().all(|t| joined(t)) {
+            break;
+        }
+        for n in nodes.iter_mut() {
+            n.clock.advance(TICK);
+        }
+    }
+    for t in &tickets {
+        match t.status() {
+            JoinStatus::Joined(p) => assert_eq!(p.addr(), seed_addr),
+            other => panic!("expected Joined, got {:?}", other),
+        }
+    }
+    assert_eq!(nodes[0].peers().len(), 50);
+    let deferred = nodes[0].stats.joins_deferred;
+    assert!(deferred >= 50 - cap as u64, "{} deferred", deferred);
+    let retried: u64 = nodes[1..].iter().map(|d| d.stats.joins_retried)
+        .sum();
+    assert_eq!(retried, deferred);
+}
+
+#[test]
+fn joins_told_to_retry_are_held_back_then_start_over() {
+    let mut d = test_dispatcher();
+    let seq = d.join(&peer());
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+    d.transport.deliver(Message::JoinRetryAfter(2000).encode(), peer());
+    d.poll();
+    assert_eq!(d.stats.joins_retried, 1);
+
+    // Nothing goes out until it's time, though retransmission was due.
+    for _ in 0..ticks_in(Duration::from_millis(2000)) - 1 {
+        d.clock.advance(TICK);
+        d.poll();
+    }
+    assert!(d.transport.sent.borrow().is_empty());
+    d.clock.advance(TICK);
+    d.poll();
+    assert_eq!(d.transport.sent.borrow().len(), 1);
+    assert_eq!(Message::decode(&d.transport.sent.borrow()[0].0).kind(),
+               MessageKind::Join);
+    assert_eq!(d.pending[&seq].retransmits, 1);
+    assert_eq!(d.pending[&seq].first_sent, d.clock.now());
+
+    // Only the target may say so.
+    let other: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    d.transport.deliver(Message::JoinRetryAfter(2000).encode(), other);
+    d.poll();
+    assert_eq!(d.stats.joins_retried, 1);
+}
+
+#[test]
+fn joins_past_the_handshake_cap_are_told_to_retry() {
+    let mut d = Dispatcher::with_config(
+        ::transport::SimTransport::new(), clock::ManualClock::new(),
+        &Config {
+            limits: Limits { join_handshakes: 1, .. Limits::default() },
+            .. Config::default()
+        });
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    assert_eq!(d.joins.len(), 1);
+    d.transport.sent.borrow_mut().clear();
+
+    let newcomer: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    d.transport.deliver(join_msg(1, NodeId(7)).encode(), newcomer);
+    d.poll();
+    let sent = d.transport.sent.borrow_mut().drain(..).collect::<Vec<_>>();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].1, newcomer);
+    match Message::decode(&sent[0].0) {
+        Message::JoinRetryAfter(ms) => {
+            let wait = Duration::from_millis(ms as u64);
+            assert!(wait >= JOIN_RETRY_AFTER && wait < JOIN_RETRY_AFTER * 2);
+        },
+        other => panic!("expected JoinRetryAfter, got {:?}", other),
+    }
+    assert_eq!(d.peers().len(), 1);
+
+    // One that wouldn't understand is just not answered, while a peer we
+    // know is answered regardless.
+    let old = Message::Acked(peer_seq(2),
+                             AckedMessage::Join(NodeId(8),
+                                                JOIN_RETRY_VERSION - 1));
+    d.transport.deliver(old.encode(), newcomer);
+    d.poll();
+    d.transport.deliver(join_msg(2, NodeId(1)).encode(), peer());
+    d.poll();
+    let sent: Vec<SocketAddr> = d.transport.sent.borrow().iter()
+        .map(|&(_, to)| to).collect();
+    assert_eq!(sent, vec![peer()]);
+    assert_eq!(d.stats.joins_deferred, 2);
+}
+
+#[cfg(test)]
+fn join_requests(d: &mut Dispatcher<::transport::SimTransport,
+                                    clock::ManualClock>)
+        -> Vec<JoinCandidate> {
+    let mut requests = Vec::new();
+    while let Some(event) = d.next_event() {
+        if let MeshEvent::JoinRequested(candidate) = event {
+            requests.push(candidate);
+        }
+    }
+    requests
+}
+
+#[test]
+fn held_joins_wait_for_the_embedder_to_let_them_in() {
+    let mut d = test_dispatcher();
+    d.require_admission();
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    assert_eq!(join_requests(&mut d), vec![JoinCandidate {
+        id: NodeId(1),
+        addr: peer(),
+        version: message::PROTOCOL_VERSION,
+    }]);
+    // Retransmissions wait with it, unanswered and untold of.
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    assert!(join_requests(&mut d).is_empty());
+    assert_eq!(d.resolve_join(NodeId(1), Admission::Defer), Ok(()));
+    assert!(d.transport.sent.borrow().is_empty());
+    assert_eq!(d.stats.joins_held, 1);
+
+    assert_eq!(d.resolve_join(NodeId(1), Admission::Accept), Ok(()));
+    d.poll();
+    let kinds: Vec<MessageKind> = d.transport.sent.borrow().iter()
+        .map(|&(ref bytes, _)| Message::decode(bytes).kind()).collect();
+    assert_eq!(kinds, vec![MessageKind::Ack, MessageKind::Join]);
+    assert_eq!(d.peers().len(), 1);
+    assert_eq!(d.resolve_join(NodeId(1), Admission::Accept),
+               Err(MeshError::UnknownPeer(NodeId(1))));
+
+    // And a peer we know isn't held again.
+    d.transport.sent.borrow_mut().clear();
+    d.transport.deliver(join_msg(2, NodeId(1)).encode(), peer());
+    d.poll();
+    assert_eq!(Message::decode(&d.transport.sent.borrow()[0].0),
+               ack_to_peer(&d, 2));
+    assert_eq!(d.stats.joins_held, 1);
+}
+
+#[test]
+fn joins_turned_away_are_told_why_again_and_again() {
+    let mut d = test_dispatcher();
+    d.require_admission();
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    let reason = format!("x{}", "\u{e9}".repeat(200));
+    assert_eq!(d.resolve_join(NodeId(1), Admission::Reject(reason.clone())),
+               Ok(()));
+    d.poll();
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    // Cut short where a character starts.
+    let expected = Message::JoinRejected(reason[..MAX_REJECT_REASON - 1]
+                                         .to_string());
+    let sent: Vec<(Message, SocketAddr)> = d.transport.sent.borrow().iter()
+        .map(|&(ref bytes, to)| (Message::decode(bytes), to)).collect();
+    assert_eq!(sent, vec![(expected.clone(), peer()),
+                          (expected, peer())]);
+    assert!(d.peers().is_empty());
+    assert_eq!(d.stats.joins_refused, 1);
+
+    // One that wouldn't understand is just not answered.
+    let newcomer: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    let old = Message::Acked(peer_seq(1),
+                             AckedMessage::Join(NodeId(2),
+                                                ADMISSION_VERSION - 1));
+    d.transport.deliver(old.encode(), newcomer);
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+    assert_eq!(d.resolve_join(NodeId(2), Admission::Reject("no".to_string())),
+               Ok(()));
+    assert!(d.transport.sent.borrow().is_empty());
+}
+
+#[test]
+fn joins_never_decided_on_are_dropped() {
+    let mut d = test_dispatcher();
+    d.require_admission();
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    for _ in 0..ticks_in(ADMISSION_TIMEOUT) {
+        d.clock.advance(TICK);
+        d.poll();
+    }
+    assert_eq!(d.stats.joins_expired, 1);
+    assert_eq!(d.resolve_join(NodeId(1), Admission::Accept),
+               Err(MeshError::UnknownPeer(NodeId(1))));
+    assert!(d.transport.sent.borrow().is_empty());
+
+    // Its next retransmission is held afresh.
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    assert_eq!(join_requests(&mut d).len(), 2);
+}
+
+#[test]
+fn a_join_deferred_past_a_retransmission_joins_once_let_in() {
+    let config = Config::default();
+    let mut seed = dispatcher_at("127.0.0.1:7001", &config);
+    let mut joiner = dispatcher_at("127.0.0.1:7002", &config);
+    seed.require_admission();
+    let ticket = joiner.join_async(&seed.transport.addr);
+    let seq = ticket.seq();
+    exchange(&mut [&mut seed, &mut joiner]);
+    let requests = join_requests(&mut seed);
+    assert_eq!(requests.len(), 1);
+    let id = requests[0].id;
+    assert_eq!(seed.resolve_join(id, Admission::Defer), Ok(()));
+
+    while joiner.pending[&seq].retransmits == 0 {
+        seed.clock.advance(TICK);
+        joiner.clock.advance(TICK);
+        exchange(&mut [&mut seed, &mut joiner]);
+    }
+    assert_eq!(ticket.status(), JoinStatus::Pending);
+    assert!(join_requests(&mut seed).is_empty());
+
+    assert_eq!(seed.resolve_join(id, Admission::Accept), Ok(()));
+    exchange(&mut [&mut seed, &mut joiner]);
+    match ticket.status() {
+        JoinStatus::Joined(p) => assert_eq!(p.addr(), seed.transport.addr),
+        other => panic!("expected Joined, got {:?}", other),
+    }
+    assert_eq!(seed.peers().len(), 1);
+}
+
+#[test]
+fn a_join_turned_away_fails_saying_why() {
+    let mut d = test_dispatcher();
+    let ticket = d.join_async(&peer());
+    d.poll();
+    // Only the target may say so.
+    let other: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    let rejected = Message::JoinRejected("not in the inventory".to_string());
+    d.transport.deliver(rejected.encode(), other);
+    d.poll();
+    assert_eq!(ticket.status(), JoinStatus::Pending);
+
+    d.transport.deliver(rejected.encode(), peer());
+    d.poll();
+    let reason = "not in the inventory".to_string();
+    assert_eq!(ticket.status(),
+               JoinStatus::Failed(MeshError::NotAdmitted(reason)));
+    assert!(d.pending.is_empty());
+}
+
+#[cfg(test)]
+fn converged_events(d: &mut Dispatcher<::transport::SimTransport,
+                                       clock::ManualClock>)
+        -> Vec<MeshEvent> {
+    d.events.drain(..).filter(|e| match *e {
+        MeshEvent::Converged { .. } | MeshEvent::NotConverged { .. } => true,
+        _ => false,
+    }).collect()
+}
+
+#[test]
+fn joining_converges_once_nothing_new_turns_up() {
+    let mut d = test_dispatcher();
+    let ticket = d.join_async(&peer());
+    d.poll();
+    d.transport.deliver(ack_from_peer(ticket.seq()).encode(), peer());
+    d.transport.deliver(join_msg(1, NodeId(5)).encode(), peer());
+    d.poll();
+    d.poll();
+    assert!(converged_events(&mut d).is_empty());
+    assert!(!d.converged_handle().load(Ordering::SeqCst));
+
+    // Within a probe interval, however long that is by then.
+    let mut events = Vec::new();
+    let max_interval = Config::default().probe.max_interval;
+    while events.is_empty() && d.clock.now() - d.epoch <= max_interval {
+        d.clock.advance(TICK);
+        d.poll();
+        events = converged_events(&mut d);
+    }
+    assert_eq!(events.len(), 1);
+    match events[0] {
+        MeshEvent::Converged { members, took } => {
+            assert_eq!(members, 1);
+            assert!(took >= Config::default().probe.min_interval);
+        },
+        ref other => panic!("expected Converged, got {:?}", other),
+    }
+    assert!(d.converged_handle().load(Ordering::SeqCst));
+}
+
+#[test]
+fn joining_warns_if_it_never_converges() {
+    let mut d = test_dispatcher();
+    let mut config = Config::default();
+    config.convergence_deadline = Duration::from_millis(1);
+    assert_eq!(d.reload(&config), vec!["convergence_deadline"]);
+    let ticket = d.join_async(&peer());
+    d.poll();
+    d.transport.deliver(ack_from_peer(ticket.seq()).encode(), peer());
+    d.transport.deliver(join_msg(1, NodeId(5)).encode(), peer());
+    d.poll();
+    d.poll();
+
+    d.clock.advance(Duration::from_millis(1));
+    d.poll();
+    let events = converged_events(&mut d);
+    assert_eq!(events.len(), 1);
+    match events[0] {
+        MeshEvent::NotConverged { members } => assert_eq!(members, 1),
+        ref other => panic!("expected NotConverged, got {:?}", other),
+    }
+    assert!(!d.converged_handle().load(Ordering::SeqCst));
+}
+
+// A dispatcher set to do `on_isolation` once isolated, which suspects a
+// peer after a second's silence and declares it dead after three, and
+// has joined peer().
+#[cfg(test)]
+fn seeded_dispatcher(on_isolation: OnIsolation)
+        -> Dispatcher<::transport::SimTransport, clock::ManualClock> {
+    use detector::DetectorConfig;
+
+    let config = Config {
+        failure_detector: DetectorConfig::Timeout {
+            suspect_after: Duration::from_secs(1),
+            dead_after: Duration::from_secs(3),
+        },
+        on_isolation: on_isolation,
+        .. Config::default()
+    };
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    let ticket = d.join_async(&peer());
+    d.poll();
+    d.transport.deliver(ack_from_peer(ticket.seq()).encode(), peer());
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    d.poll();
+    assert!(ticket.status() != JoinStatus::Pending);
+    d.transport.sent.borrow_mut().clear();
+    d.events.clear();
+    d
+}
+
+// Let peer() die, and stay dead for `for_ms` more.
+#[cfg(test)]
+fn kill_peer(d: &mut Dispatcher<::transport::SimTransport,
+                                clock::ManualClock>, for_ms: u64) {
+    d.clock.advance(Duration::from_secs(3));
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Dead);
+    d.clock.advance(Duration::from_millis(for_ms));
+    d.poll();
+}
+
+#[cfg(test)]
+fn isolated_events(d: &mut Dispatcher<::transport::SimTransport,
+                                      clock::ManualClock>)
+        -> Vec<OnIsolation> {
+    d.events.drain(..).filter_map(|e| match e {
+        MeshEvent::Isolated(action) => Some(action),
+        _ => None,
+    }).collect()
+}
+
+// The sequence numbers of the Joins `d` has sent since last asked.
+#[cfg(test)]
+fn joins_sent(d: &Dispatcher<::transport::SimTransport, clock::ManualClock>)
+        -> Vec<Seq> {
+    let mut seqs: Vec<Seq> = d.transport.sent.borrow_mut().drain(..)
+        .filter_map(|(bytes, _)| match Message::decode(&bytes) {
+            Message::Acked(seq, AckedMessage::Join(..)) => Some(seq),
+            _ => None,
+        }).collect();
+    seqs.dedup();
+    seqs
+}
+
+#[test]
+fn isolation_waits_out_a_suspicion_period() {
+    let mut d = seeded_dispatcher(OnIsolation::Standalone);
+    kill_peer(&mut d, 500);
+    assert!(isolated_events(&mut d).is_empty());
+
+    // Back in time, so nothing comes of it.
+    d.transport.deliver(join_msg(2, NodeId(1)).encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    assert!(isolated_events(&mut d).is_empty());
+
+    // Nor of having no peers at all.
+    let mut lonely = test_dispatcher();
+    lonely.clock.advance(Duration::from_secs(60));
+    lonely.poll();
+    assert!(isolated_events(&mut lonely).is_empty());
+}
+
+#[test]
+fn standalone_nodes_carry_on_alone() {
+    let mut d = seeded_dispatcher(OnIsolation::Standalone);
+    kill_peer(&mut d, 1000);
+    assert_eq!(isolated_events(&mut d), vec![OnIsolation::Standalone]);
+    assert_eq!(d.stats.isolations, 1);
+    assert!(!d.shutdown_handle().load(Ordering::SeqCst));
+    assert_eq!(d.exit_code(), None);
+
+    d.clock.advance(Duration::from_secs(60));
+    d.poll();
+    assert!(joins_sent(&d).is_empty());
+    assert!(isolated_events(&mut d).is_empty());
+}
+
+#[test]
+fn isolated_nodes_exit_if_told_to() {
+    let mut d = seeded_dispatcher(OnIsolation::Exit);
+    kill_peer(&mut d, 500);
+    assert!(!d.shutdown_handle().load(Ordering::SeqCst));
+    d.clock.advance(Duration::from_millis(500));
+    d.poll();
+    assert_eq!(isolated_events(&mut d), vec![OnIsolation::Exit]);
+    assert!(d.shutdown_handle().load(Ordering::SeqCst));
+    assert_eq!(d.exit_code(), Some(isolation::EXIT_CODE));
+    // Returns at once.
+    d.run();
+}
+
+#[test]
+fn isolated_nodes_rejoin_their_seeds_backing_off() {
+    let mut d = seeded_dispatcher(OnIsolation::Rejoin);
+    kill_peer(&mut d, 500);
+    assert!(joins_sent(&d).is_empty());
+    d.clock.advance(Duration::from_millis(500));
+    d.poll();
+    assert_eq!(isolated_events(&mut d), vec![OnIsolation::Rejoin]);
+    assert!(!d.shutdown_handle().load(Ordering::SeqCst));
+    let first = joins_sent(&d);
+    assert_eq!(first.len(), 1);
+
+    // Nothing comes back. Each attempt runs its course before the next,
+    // and the next comes later each time.
+    let mut attempts = Vec::new();
+    let start = d.clock.now();
+    while attempts.len() < 3
+            && d.clock.now() - start < Duration::from_secs(120) {
+        d.clock.advance(RETRANSMIT);
+        d.poll();
+        for seq in joins_sent(&d) {
+            if seq != first[0] && !attempts.iter().any(|&(s, _)| s == seq) {
+                attempts.push((seq, d.clock.now() - start));
+            }
+        }
+    }
+    assert_eq!(attempts.len(), 3);
+    let gaps: Vec<Duration> = attempts.windows(2)
+        .map(|w| w[1].1 - w[0].1).collect();
+    assert!(gaps[1] > gaps[0], "{:?}", attempts);
+
+    // The seed answers the latest, and that's the end of it.
+    let seq = attempts[2].0;
+    d.transport.deliver(ack_from_peer(seq).encode(), peer());
+    d.transport.deliver(join_msg(3, NodeId(1)).encode(), peer());
+    d.poll();
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+    assert!(!d.isolation.isolated());
+    let joined = d.events.iter().any(|e| match *e {
+        MeshEvent::JoinCompleted { status: JoinStatus::Joined(_), .. } =>
+            true,
+        _ => false,
+    });
+    assert!(joined);
+}
+
+#[test]
+fn acked_join_fails_if_target_never_says_who_it_is() {
+    let mut d = test_dispatcher();
+    let ticket = d.join_async(&peer());
+    d.transport.deliver(ack_from_peer(ticket.seq()).encode(), peer());
+    d.poll();
+
+    let budget = Config::default().join_retransmit.budget;
+    d.clock.advance(budget);
+    d.poll();
+    assert_eq!(ticket.status(), JoinStatus::Failed(MeshError::Timeout));
+}
+
+#[test]
+fn messages_to_self_skip_the_socket() {
+    let mut d = test_dispatcher();
+    let me = d.transport.addr;
+    d.send_acked(AckedMessage::Data(vec![1, 2]), &me);
+    d.ping(&me);
+    for _ in 0..3 {
+        d.clock.advance(ACK_DELAY);
+        d.poll();
+    }
+
+    match d.next_event() {
+        Some(MeshEvent::Data(from, data)) => {
+            assert_eq!(from, me);
+            assert_eq!(data, vec![1, 2]);
+        },
+        other => panic!("expected Data, got {:?}", other),
+    }
+    assert!(d.next_event().is_none());
+    // The Data and its Ack, the Ping and its Pong.
+    assert_eq!(d.stats.self_delivered, 4);
+    assert!(d.pending.is_empty());
+    assert!(d.transport.sent.borrow().is_empty());
+}
+
+#[test]
+fn loopback_counts_as_self_when_bound_to_any() {
+    let any = Some("0.0.0.0:7000".parse().unwrap());
+    assert!(is_local(&any, &"127.0.0.1:7000".parse().unwrap()));
+    assert!(!is_local(&any, &"127.0.0.1:7001".parse().unwrap()));
+    assert!(!is_local(&any, &"10.0.0.1:7000".parse().unwrap()));
+    let lo = Some("127.0.0.1:7000".parse().unwrap());
+    assert!(!is_local(&lo, &"0.0.0.0:7000".parse().unwrap()));
+    assert!(!is_local(&None, &"127.0.0.1:7000".parse().unwrap()));
+}
+
+#[cfg(test)]
+fn limited_dispatcher(limits: Limits)
+        -> Dispatcher<::transport::SimTransport, clock::ManualClock> {
+    let config = Config { limits: limits, .. Config::default() };
+    Dispatcher::with_config(::transport::SimTransport::new(),
+                            clock::ManualClock::new(), &config)
+}
+
+#[test]
+fn pending_acks_are_capped_per_peer_and_in_all() {
+    let mut d = limited_dispatcher(Limits {
+        pending: 3,
+        pending_per_peer: 2,
+        .. Limits::default()
+    });
+    let other: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    let third: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+    d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    d.send_acked(AckedMessage::Data(vec![2]), &peer());
+    // Over peer()'s share.
+    let over_peer = d.send_acked(AckedMessage::Data(vec![3]), &peer());
+    d.send_acked(AckedMessage::Data(vec![4]), &other);
+    // Over the total.
+    let over_all = d.send_acked(AckedMessage::Data(vec![5]), &third);
+
+    let failed: Vec<(Seq, MeshError)> = d.events.drain(..)
+        .filter_map(|e| match e {
+            MeshEvent::DeliveryFailed { seq, error, .. } => Some((seq, error)),
+            _ => None,
+        }).collect();
+    assert_eq!(failed, vec![(over_peer, MeshError::Overloaded),
+                            (over_all, MeshError::Overloaded)]);
+    assert_eq!(d.stats.pending_rejected, 2);
+    assert_eq!(d.memory_stats().pending, 3);
+
+    // Refused messages never go out.
+    d.poll();
+    assert_eq!(d.transport.sent.borrow().len(), 3);
+}
+
+#[test]
+fn joins_in_progress_are_capped() {
+    let mut d = limited_dispatcher(Limits { joins: 1, .. Limits::default() });
+    let first = d.join_async(&peer());
+    let second = d.join_async(&"127.0.0.1:9001".parse().unwrap());
+    assert_eq!(first.status(), JoinStatus::Pending);
+    assert_eq!(second.status(), JoinStatus::Failed(MeshError::Overloaded));
+    assert_eq!(d.stats.joins_rejected, 1);
+    assert_eq!(d.memory_stats().joins, 1);
+}
+
+#[test]
+fn dedup_forgets_the_oldest_peer_at_its_cap() {
+    let mut d = limited_dispatcher(Limits {
+        dedup_peers: 1,
+        .. Limits::default()
+    });
+    let other: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    let data = Message::Acked(peer_seq(1), AckedMessage::Data(vec![1]))
+        .encode();
+    for &from in [peer(), other, peer()].iter() {
+        d.transport.deliver(data.clone(), from);
+        d.poll();
+    }
+    // peer() was forgotten to make room for other, so its retransmission
+    // is taken for new.
+    assert_eq!(d.events.len(), 3);
+    assert_eq!(d.memory_stats().dedup_peers, 1);
+    assert_eq!(d.stats.dedup_evicted, 2);
+
+    d.transport.deliver(data, peer());
+    d.poll();
+    assert_eq!(d.events.len(), 3);
+}
+
+#[test]
+fn send_to_uses_peer_addr() {
+    let mut d = test_dispatcher();
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+
+    let p = d.peers().pop().unwrap();
+    d.send_to(&p, &ping_msg());
+    d.poll();
+    assert_eq!(d.transport.sent.borrow()[0].1, peer());
+}
+
+#[test]
+fn pong_gives_round_trip_time() {
+    let mut d = test_dispatcher();
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+
+    d.ping(&peer());
+    d.poll();
+    let ping = match Message::decode(&d.transport.sent.borrow()[0].0) {
+        Message::Ping(body) => body,
+        other => panic!("expected Ping, got {:?}", other),
+    };
+    d.clock.advance(Duration::from_millis(20));
+    let pong = Message::Pong(ping.echo(), seen_at_us(), None);
+    d.transport.deliver(pong.encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].rtt(), Some(Duration::from_millis(20)));
+    assert_eq!(d.stats.fast_path, 1);
+}
+
+// As exchange, letting `by` pass a millisecond at a time, for `model`
+// and every one of `nodes`, so that what they send arrives when `model`
+// says.
+#[cfg(test)]
+fn exchange_for(model: &LatencyModel,
+                nodes: &mut [&mut Dispatcher<::transport::SimTransport,
+                                             clock::ManualClock>],
+                by: Duration) {
+    let step = Duration::from_millis(1);
+    for _ in 0..Nanos::from(by) / Nanos::from(step) {
+        model.advance(step);
+        for n in nodes.iter_mut() {
+            n.clock.advance(step);
+        }
+        exchange(nodes);
+    }
+}
+
+#[test]
+fn round_trip_times_follow_both_ways_of_a_link_as_it_changes() {
+    let ms = Duration::from_millis;
+    let model = Rc::new(LatencyModel::new(1, Latency::Fixed(ms(0))));
+    let config = Config::default();
+    let mut a = dispatcher_at("127.0.0.1:7001", &config);
+    let mut b = dispatcher_at("127.0.0.1:7002", &config);
+    let (to_a, to_b) = (a.transport.addr, b.transport.addr);
+    // Slower there than back, until it slows down further.
+    model.between(to_a, to_b,
+                  Latency::Steps(vec![(ms(0), Latency::Fixed(ms(10))),
+                                      (ms(1000), Latency::Fixed(ms(50)))]),
+                  Latency::Fixed(ms(30)));
+    for d in &[&a, &b] {
+        *d.transport.delays.borrow_mut() = Some(model.clone());
+    }
+    a.join(&to_b);
+    exchange_for(&model, &mut [&mut a, &mut b], ms(100));
+    assert_eq!(a.peers().len(), 1);
+
+    // Sent before any time passes, being timed from when asked for.
+    a.ping(&to_b);
+    b.ping(&to_a);
+    a.poll();
+    b.poll();
+    exchange_for(&model, &mut [&mut a, &mut b], ms(100));
+    assert_eq!(a.peers()[0].rtt(), Some(ms(40)));
+    assert_eq!(b.peers()[0].rtt(), Some(ms(40)));
+
+    exchange_for(&model, &mut [&mut a, &mut b], ms(1000));
+    a.ping(&to_b);
+    a.poll();
+    exchange_for(&model, &mut [&mut a, &mut b], ms(100));
+    assert_eq!(a.peers()[0].rtt(), Some(ms(80)));
+}
+
+#[test]
+fn lost_and_answered_probes_move_a_peers_score() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let fresh = d.scores.score(&d.peers()[0], d.clock.now());
+    d.transport.sent.borrow_mut().clear();
+    for _ in 0..3 {
+        d.clock.advance(Duration::from_millis(d.stats.probe_interval_ms));
+        d.poll();
+    }
+    let lost = d.scores.loss(NodeId(1));
+    assert!(lost > 0.0);
+    assert!(d.scores.score(&d.peers()[0], d.clock.now()) < fresh);
+
+    // Answering the latest brings it back down.
+    let ping = d.transport.sent.borrow().iter().rev()
+        .filter_map(|&(ref bytes, _)| match Message::decode(bytes) {
+            Message::Ping(body) => Some(body),
+            _ => None,
+        })
+        .next().unwrap();
+    let pong = Message::Pong(ping.echo(), seen_at_us(), None);
+    d.transport.deliver(pong.encode(), peer());
+    d.poll();
+    assert!(d.scores.loss(NodeId(1)) < lost);
+}
+
+#[test]
+fn pongs_off_the_fast_path_and_custom_handlers_see_the_same() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let mut d = test_dispatcher();
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    let pinged = |d: &mut Dispatcher<::transport::SimTransport,
+                                     clock::ManualClock>| {
+        d.transport.sent.borrow_mut().clear();
+        d.ping(&peer());
+        d.poll();
+        match Message::decode(&d.transport.sent.borrow()[0].0) {
+            Message::Ping(body) => body,
+            other => panic!("expected Ping, got {:?}", other),
+        }
+    };
+
+    // Padded, which only the general decoder reads.
+    let mut body = pinged(&mut d).echo();
+    body.pad = vec![0; 4];
+    d.clock.advance(Duration::from_millis(20));
+    d.transport.deliver(Message::Pong(body, seen_at_us(), None).encode(),
+                        peer());
+    d.poll();
+    assert_eq!(d.stats.fast_path, 0);
+    assert_eq!(d.peers()[0].rtt(), Some(Duration::from_millis(20)));
+
+    let pongs = Rc::new(Cell::new(0));
+    let seen = pongs.clone();
+    d.set_handler(MessageKind::Pong, move |_, _, msg| {
+        if let Message::Pong(..) = msg {
+            seen.set(seen.get() + 1);
+        }
+    });
+    let body = pinged(&mut d).echo();
+    d.transport.deliver(Message::Pong(body, seen_at_us(), None).encode(),
+                        peer());
+    d.poll();
+    assert_eq!(d.stats.fast_path, 1);
+    assert_eq!(pongs.get(), 1);
+}
+
+#[test]
+fn overloaded_peers_are_given_longer() {
+    use detector::TimeoutDetector;
+
+    let mut d = test_dispatcher();
+    d.set_failure_detector(Box::new(TimeoutDetector::new(
+        Duration::from_secs(1), Duration::from_secs(30))));
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+
+    // Our own Pongs say how we're doing.
+    d.transport.deliver(ping_msg().encode(), peer());
+    d.poll();
+    match Message::decode(&d.transport.sent.borrow()[0].0) {
+        Message::Pong(_, _, Some(health)) => assert_eq!(health.alive, 1),
+        other => panic!("expected Pong with health, got {:?}", other),
+    }
+    d.transport.sent.borrow_mut().clear();
+
+    d.ping(&peer());
+    d.poll();
+    let ping = match Message::decode(&d.transport.sent.borrow()[0].0) {
+        Message::Ping(body) => body,
+        other => panic!("expected Ping, got {:?}", other),
+    };
+    let busy = Health { queued: d.health.queued, dropped: 0, alive: 1 };
+    let pong = Message::Pong(ping.echo(), seen_at_us(), Some(busy));
+    d.transport.deliver(pong.encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].health(), Some(busy));
+
+    let grace = d.health.grace;
+    d.clock.advance(Duration::from_secs(1) + grace / 2);
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+    d.clock.advance(grace / 2);
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+}
+
+#[test]
+fn peers_pongs_say_how_big_the_mesh_is_until_they_fall_silent() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    assert_eq!(d.estimated_size(),
+               SizeEstimate { size: 2, min: 2, max: 2, reports: 1 });
+
+    // The peer counts four alive, itself not among them.
+    d.ping(&peer());
+    d.poll();
+    let ping = match Message::decode(&d.transport.sent.borrow()[0].0) {
+        Message::Ping(body) => body,
+        other => panic!("expected Ping, got {:?}", other),
+    };
+    let health = Health { queued: 0, dropped: 0, alive: 4 };
+    let pong = Message::Pong(ping.echo(), seen_at_us(), Some(health));
+    d.transport.deliver(pong.encode(), peer());
+    d.poll();
+    assert_eq!(d.estimated_size(),
+               SizeEstimate { size: 2, min: 2, max: 5, reports: 2 });
+
+    // Its say lasts a few probe cycles, of one probe each here.
+    let max_age = d.probe_interval * SIZE_REPORT_CYCLES;
+    d.clock.advance(max_age);
+    assert_eq!(d.estimated_size().reports, 2);
+    d.clock.advance(Duration::from_millis(1));
+    assert_eq!(d.estimated_size(),
+               SizeEstimate { size: 2, min: 2, max: 2, reports: 1 });
+
+    // And is forgotten as soon as it's found dead.
+    d.transport.sent.borrow_mut().clear();
+    d.ping(&peer());
+    d.poll();
+    let ping = d.transport.sent.borrow().iter()
+        .filter_map(|&(ref bytes, _)| match Message::decode(bytes) {
+            Message::Ping(body) => Some(body),
+            _ => None,
+        })
+        .last().unwrap();
+    let pong = Message::Pong(ping.echo(), seen_at_us(), Some(health));
+    d.transport.deliver(pong.encode(), peer());
+    d.poll();
+    assert_eq!(d.estimated_size().reports, 2);
+    d.evict(NodeId(1)).unwrap();
+    assert_eq!(d.estimated_size(),
+               SizeEstimate { size: 1, min: 1, max: 1, reports: 1 });
+}
+
+#[test]
+fn silent_peer_is_suspected_then_dead() {
+    use detector::TimeoutDetector;
+
+    let mut d = test_dispatcher();
+    d.set_failure_detector(Box::new(TimeoutDetector::new(
+        Duration::from_secs(1), Duration::from_secs(3))));
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+
+    // Hearing from it clears suspicion.
+    d.transport.deliver(ping_msg().encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+
+    d.clock.advance(Duration::from_secs(3));
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Dead);
+}
+
+// The Suspecteds `d` has sent, as their sequence numbers, who they
+// suspect and on which incarnation.
+#[cfg(test)]
+fn suspecteds_sent(d: &Dispatcher<::transport::SimTransport,
+                                  clock::ManualClock>)
+        -> Vec<(Seq, NodeId, u64)> {
+    d.transport.sent.borrow().iter()
+        .filter_map(|&(ref bytes, _)| match Message::decode(bytes) {
+            Message::Acked(seq, AckedMessage::Suspected(of, incarnation)) =>
+                Some((seq, of, incarnation)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn suspects_are_told_and_cleared_by_their_acks() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    d.clock.advance(Duration::from_secs(5));
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+    let sent = suspecteds_sent(&d);
+    assert_eq!(sent.len(), 1);
+    let (seq, of, incarnation) = sent[0];
+    assert_eq!((of, incarnation), (NodeId(1), 0));
+    assert_eq!(d.stats.suspicions_sent, 1);
+
+    d.transport.deliver(ack_from_peer(seq).encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+    assert!(d.pending.is_empty());
+
+    // A peer on a version without Suspected isn't told.
+    let mut d = test_dispatcher();
+    let old = AckedMessage::Join(NodeId(1), SUSPECTED_VERSION - 1);
+    d.transport.deliver(Message::Acked(peer_seq(1), old).encode(), peer());
+    d.poll();
+    d.clock.advance(Duration::from_secs(5));
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+    assert!(suspecteds_sent(&d).is_empty());
+}
+
+#[test]
+fn unanswered_suspicions_bring_death_forward() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    d.clock.advance(Duration::from_secs(5));
+    d.poll();
+
+    // Retransmitted, backing off from the peer or not, then given up on.
+    let policy = Config::default().data_retransmit;
+    for _ in 0..policy.attempts + 1 {
+        d.clock.advance(RETRANSMIT);
+        d.poll();
+    }
+    assert_eq!(suspecteds_sent(&d).len(), 1 + policy.attempts as usize);
+    assert_eq!(d.stats.suspicions_unanswered, 1);
+
+    // Dead after 17.5s of silence, halfway from 5s to 30s.
+    d.clock.advance(Duration::from_secs(9));
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Dead);
+}
+
+#[test]
+fn suspicions_of_us_are_refuted() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let us = d.node_id();
+    let suspected = |n, of, incarnation| {
+        Message::Acked(peer_seq(n), AckedMessage::Suspected(of, incarnation))
+    };
+    d.transport.deliver(suspected(10, us, 0).encode(), peer());
+    d.poll();
+    assert_eq!(d.incarnation(), 1);
+    assert_eq!(d.stats.suspicions_refuted, 1);
+
+    // Acked, saying so.
+    d.clock.advance(ACK_DELAY);
+    d.poll();
+    let acked = d.transport.sent.borrow().iter()
+        .any(|&(ref bytes, _)| match Message::decode(bytes) {
+            Message::Ack(seq, _, Some(from)) if seq == peer_seq(10) =>
+                from.incarnation == 1,
+            _ => false,
+        });
+    assert!(acked);
+
+    // Not for an incarnation we're past, nor for anyone else, nor twice.
+    for m in &[suspected(11, us, 0), suspected(12, NodeId(7), 1),
+               suspected(10, us, 1)] {
+        d.transport.deliver(m.encode(), peer());
+        d.poll();
+    }
+    assert_eq!(d.incarnation(), 1);
+    assert_eq!(d.stats.suspicions_refuted, 1);
+}
+
+#[test]
+fn suspicions_of_a_later_incarnation_of_ours_are_refuted_past_it() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let us = d.node_id();
+    d.transport.deliver(Message::Acked(peer_seq(10), AckedMessage::Suspected(
+        us, 7)).encode(), peer());
+    d.poll();
+    assert_eq!(d.incarnation(), 8);
+    assert_eq!(d.stats.suspicions_refuted, 1);
+}
+
+#[test]
+fn suspicions_of_our_last_incarnation_go_unrefuted() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let us = d.node_id();
+    d.transport.deliver(Message::Acked(peer_seq(10), AckedMessage::Suspected(
+        us, u64::MAX)).encode(), peer());
+    d.poll();
+    assert_eq!(d.incarnation(), 0);
+    assert_eq!(d.stats.suspicions_refuted, 0);
+
+    // Nor is one just short of it refuted any further than the last.
+    d.transport.deliver(Message::Acked(peer_seq(11), AckedMessage::Suspected(
+        us, u64::MAX - 1)).encode(), peer());
+    d.poll();
+    assert_eq!(d.incarnation(), u64::MAX);
+    d.bump_incarnation();
+    assert_eq!(d.incarnation(), u64::MAX);
+}
+
+#[test]
+fn suspicions_of_us_from_strangers_are_ignored() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let us = d.node_id();
+    let stranger: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+    d.transport.deliver(Message::Acked(peer_seq(10), AckedMessage::Suspected(
+        us, 7)).encode(), stranger);
+    d.poll();
+    assert_eq!(d.incarnation(), 0);
+    assert_eq!(d.stats.suspicions_refuted, 0);
+}
+
+// The Tags `d` has sent, as their sequence numbers, versions and tags.
+#[cfg(test)]
+fn tags_sent(d: &Dispatcher<::transport::SimTransport, clock::ManualClock>)
+        -> Vec<(Seq, MetaVersion, BTreeMap<String, String>)> {
+    d.transport.sent.borrow().iter()
+        .filter_map(|&(ref bytes, _)| match Message::decode(bytes) {
+            Message::Acked(seq, AckedMessage::Tags(_, meta, tags)) =>
+                Some((seq, meta, tags)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn tags(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn each_change_of_tags_goes_to_peers_with_a_later_version() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    d.set_tag("role", "standby").unwrap();
+    d.poll();
+    d.set_tag("role", "active").unwrap();
+    // Setting a tag to what it is already is no change.
+    d.set_tag("role", "active").unwrap();
+    d.bump_incarnation();
+    d.set_tag("zone", "b").unwrap();
+    d.poll();
+    let sent = tags_sent(&d);
+    let versions: Vec<(MetaVersion, BTreeMap<String, String>)> = sent.iter()
+        .map(|&(_, meta, ref tags)| (meta, tags.clone())).collect();
+    assert_eq!(versions, vec![
+        (MetaVersion::new(0, 1), tags(&[("role", "standby")])),
+        (MetaVersion::new(0, 2), tags(&[("role", "active")])),
+        (MetaVersion::new(1, 3), tags(&[("role", "active"), ("zone", "b")])),
+    ]);
+    assert_eq!(d.meta(), MetaVersion::new(1, 3));
+    assert_eq!(d.tags(), &versions[2].1);
+
+    // Retransmitted, as data is, until acked.
+    d.transport.sent.borrow_mut().clear();
+    d.clock.advance(RETRANSMIT);
+    d.poll();
+    assert_eq!(tags_sent(&d).len(), 3);
+    for &(seq, _, _) in sent.iter() {
+        d.transport.deliver(ack_from_peer(seq).encode(), peer());
+    }
+    poll_all(&mut d);
+    assert!(d.pending.is_empty());
+
+    // Too many to send are refused, leaving those we had.
+    let long: String = (0..MAX_TAGS_SIZE).map(|_| 'x').collect();
+    assert_eq!(d.set_tag("notes", &long), Err(MeshError::TooLarge));
+    assert_eq!(d.meta(), MetaVersion::new(1, 3));
+    assert_eq!(d.tags(), &versions[2].1);
+}
+
+#[test]
+fn joiners_are_told_our_tags() {
+    let mut d = test_dispatcher();
+    d.set_tag("role", "active").unwrap();
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    let sent = tags_sent(&d);
+    assert_eq!(sent.len(), 1);
+    assert_eq!((sent[0].1, sent[0].2.clone()),
+               (MetaVersion::new(0, 1), tags(&[("role", "active")])));
+
+    // But not those on a version without Tags.
+    let mut d = test_dispatcher();
+    d.set_tag("role", "active").unwrap();
+    let old = AckedMessage::Join(NodeId(1), TAGS_VERSION - 1);
+    d.transport.deliver(Message::Acked(peer_seq(1), old).encode(), peer());
+    d.poll();
+    d.set_tag("role", "standby").unwrap();
+    d.poll();
+    assert!(tags_sent(&d).is_empty());
+}
+
+#[test]
+fn tags_overtaken_on_the_way_change_nothing_when_they_come() {
+    let ms = Duration::from_millis;
+    // The first change takes 30ms to arrive, the second, from 5ms on, 1ms.
+    let model = Rc::new(LatencyModel::new(1, Latency::Steps(vec![
+        (ms(0), Latency::Fixed(ms(30))),
+        (ms(5), Latency::Fixed(ms(1))),
+    ])));
+    let mut sender = test_dispatcher();
+    join_from_peer(&mut sender);
+    *sender.transport.delays.borrow_mut() = Some(model.clone());
+    let mut d = test_dispatcher();
+    d.transport.deliver(join_msg(1, sender.node_id()).encode(), peer());
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+
+    sender.set_tag("role", "standby").unwrap();
+    sender.poll();
+    assert!(carry(&model, &mut sender, &mut d, ms(5)).is_empty());
+    sender.set_tag("role", "active").unwrap();
+    let arrived: Vec<MetaVersion> = carry(&model, &mut sender, &mut d,
+                                          ms(40)).iter()
+        .filter_map(|bytes| match Message::decode(bytes) {
+            Message::Acked(_, AckedMessage::Tags(_, meta, _)) => Some(meta),
+            _ => None,
+        }).collect();
+    assert_eq!(arrived, vec![MetaVersion::new(0, 2), MetaVersion::new(0, 1)]);
+    let held = d.members.get(sender.node_id()).unwrap();
+    assert_eq!(held.meta(), Some(MetaVersion::new(0, 2)));
+    assert_eq!(held.tags(), &tags(&[("role", "active")]));
+    // Both acked even so, lest the stale one be resent until it fails.
+    assert_eq!(acked(&mut d).len(), 2);
+}
+
+#[test]
+fn a_restarted_peers_tags_win_over_those_from_before() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let tags_msg = |n, meta, role| {
+        Message::Acked(peer_seq(n), AckedMessage::Tags(
+            NodeId(1), meta, tags(&[("role", role)])))
+    };
+    let held = |d: &Dispatcher<_, _>| {
+        let peer = d.members.get(NodeId(1)).unwrap();
+        (peer.meta().unwrap(), peer.tags()["role"].clone())
+    };
+    while d.next_event().is_some() {}
+    d.transport.deliver(tags_msg(2, MetaVersion::new(0, 7), "active")
+                        .encode(), peer());
+    d.poll();
+    assert_eq!(held(&d), (MetaVersion::new(0, 7), "active".to_string()));
+    match d.next_event() {
+        Some(MeshEvent::PeerTagsChanged(p)) =>
+            assert_eq!(p.tags()["role"], "active"),
+        other => panic!("expected PeerTagsChanged, got {:?}", other),
+    }
+
+    // It restarts, on a later incarnation, counting its changes afresh:
+    // its first wins, and one from before it restarted, still on its
+    // way, loses.
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.transport.deliver(tags_msg(2, MetaVersion::new(1, 1), "standby")
+                        .encode(), peer());
+    d.transport.deliver(tags_msg(3, MetaVersion::new(0, 8), "active")
+                        .encode(), peer());
+    poll_all(&mut d);
+    assert_eq!(held(&d), (MetaVersion::new(1, 1), "standby".to_string()));
+
+    // Tags claiming to be ours aren't taken for anyone's.
+    let ours = Message::Acked(peer_seq(4), AckedMessage::Tags(
+        d.node_id(), MetaVersion::new(9, 9), tags(&[("role", "evil")])));
+    d.transport.deliver(ours.encode(), peer());
+    d.poll();
+    assert!(d.tags().is_empty());
+    assert_eq!(held(&d), (MetaVersion::new(1, 1), "standby".to_string()));
+}
+
+#[test]
+fn evicted_peers_stay_dead_until_they_refute_it() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let us = d.node_id();
+    assert_eq!(d.evict(us), Err(MeshError::Ourselves));
+    assert_eq!(d.evict(NodeId(9)), Err(MeshError::UnknownPeer(NodeId(9))));
+    assert_eq!(d.evict(NodeId(1)), Ok(()));
+    assert_eq!(d.peers()[0].state(), PeerState::Dead);
+    assert_eq!(d.history(None)[0].cause, Cause::Evicted);
+
+    // Hearing from it, on the incarnation it was evicted on, changes
+    // nothing, and nor does its joining again.
+    for m in &[ping_msg(), join_msg(2, NodeId(1))] {
+        d.clock.advance(Duration::from_secs(1));
+        d.transport.deliver(m.encode(), peer());
+        d.poll();
+        assert_eq!(d.peers()[0].state(), PeerState::Dead);
+    }
+
+    // A later incarnation does.
+    let seq = d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    let ack = Message::Ack(seq, seen_at_us(), Some(Responder {
+        id: NodeId(1),
+        incarnation: 1,
+    }));
+    d.transport.deliver(ack.encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+    assert_eq!(d.history(None)[0].cause, Cause::Refuted);
+}
+
+#[test]
+fn probe_now_revives_a_peer_that_answers() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let us = d.node_id();
+    assert_eq!(d.probe_now(us), Err(MeshError::Ourselves));
+    d.evict(NodeId(1)).unwrap();
+
+    d.probe_now(NodeId(1)).unwrap();
+    d.poll();
+    let body = d.transport.sent.borrow().iter()
+        .filter_map(|&(ref bytes, _)| match Message::decode(bytes) {
+            Message::Ping(body) => Some(body),
+            _ => None,
+        })
+        .next().expect("no Ping sent");
+    d.transport.deliver(Message::Pong(body.echo(), seen_at_us(), None)
+                            .encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+    assert_eq!(d.history(None)[0].cause, Cause::Probed);
+
+    // No longer evicted, it's judged by the failure detector as before.
+    d.clock.advance(Duration::from_secs(5));
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+}
+
+// The PeerJoineds and PeerRestarteds `d` has had since last asked, as
+// who joined and who restarted as whom.
+#[cfg(test)]
+fn joins_and_restarts(d: &mut Dispatcher<::transport::SimTransport,
+                                         clock::ManualClock>)
+        -> (Vec<NodeId>, Vec<(NodeId, NodeId)>) {
+    let (mut joined, mut restarted) = (Vec::new(), Vec::new());
+    while let Some(event) = d.next_event() {
+        match event {
+            MeshEvent::PeerJoined(p) => joined.push(p.id()),
+            MeshEvent::PeerRestarted { old_id, new_id, addr } => {
+                assert_eq!(addr, peer());
+                restarted.push((old_id, new_id));
+            },
+            _ => (),
+        }
+    }
+    (joined, restarted)
+}
+
+#[test]
+fn a_peer_restarting_at_once_takes_its_own_place() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    joins_and_restarts(&mut d);
+    d.clock.advance(Duration::from_secs(1));
+    d.transport.deliver(join_msg(1, NodeId(2)).encode(), peer());
+    d.poll();
+    assert_eq!(joins_and_restarts(&mut d),
+               (vec![], vec![(NodeId(1), NodeId(2))]));
+    let peers = d.peers();
+    assert_eq!(peers.len(), 1);
+    assert_eq!((peers[0].id(), peers[0].state()),
+               (NodeId(2), PeerState::Alive));
+    assert_eq!(d.stats.restarts, 1);
+    assert_eq!(d.history(None)[0].cause, Cause::Restarted);
+
+    // The old one, forgotten, is never found dead.
+    for _ in 0..60 {
+        d.clock.advance(Duration::from_secs(1));
+        d.poll();
+        while let Some(event) = d.next_event() {
+            assert!(event.peer_id() != Some(NodeId(1)), "{:?}", event);
+        }
+    }
+}
+
+#[test]
+fn a_peer_restarting_too_late_or_unlooked_for_joins_beside_itself() {
+    for &window in &[Some(Duration::from_secs(2)), None] {
+        let mut d = test_dispatcher();
+        d.restart_window = window;
+        join_from_peer(&mut d);
+        joins_and_restarts(&mut d);
+        d.clock.advance(Duration::from_secs(3));
+        d.poll();
+        assert_eq!(d.peers()[0].state(), PeerState::Alive);
+        d.transport.deliver(join_msg(1, NodeId(2)).encode(), peer());
+        d.poll();
+        assert_eq!(joins_and_restarts(&mut d), (vec![NodeId(2)], vec![]));
+        assert_eq!(d.peers().len(), 2);
+        assert_eq!(d.stats.restarts, 0);
+    }
+}
+
+#[test]
+fn another_node_taking_over_a_quiet_peers_address_joins_beside_it() {
+    use detector::TimeoutDetector;
+
+    let mut d = test_dispatcher();
+    d.restart_window = Some(Duration::from_secs(2));
+    d.set_failure_detector(Box::new(TimeoutDetector::new(
+        Duration::from_secs(1), Duration::from_secs(10))));
+    join_from_peer(&mut d);
+    joins_and_restarts(&mut d);
+    d.clock.advance(Duration::from_secs(3));
+    d.poll();
+    assert!(d.peers()[0].state() != PeerState::Dead);
+
+    // Past the window, but still alive to us: a different node.
+    d.transport.deliver(join_msg(1, NodeId(2)).encode(), peer());
+    d.poll();
+    assert_eq!(joins_and_restarts(&mut d), (vec![NodeId(2)], vec![]));
+    // What comes from the address now is the newcomer's, so the peer it
+    // displaced goes quiet and is found dead in time.
+    answer_pings(&mut d, 20);
+    let states: Vec<(NodeId, PeerState)> = d.peers().iter()
+        .map(|p| (p.id(), p.state())).collect();
+    assert_eq!(states, vec![(NodeId(1), PeerState::Dead),
+                            (NodeId(2), PeerState::Alive)]);
+}
+
+#[test]
+fn a_node_where_a_dead_peer_was_is_a_newcomer() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    d.evict(NodeId(1)).unwrap();
+    joins_and_restarts(&mut d);
+    d.clock.advance(Duration::from_secs(1));
+    d.transport.deliver(join_msg(1, NodeId(2)).encode(), peer());
+    d.poll();
+    assert_eq!(joins_and_restarts(&mut d), (vec![NodeId(2)], vec![]));
+    let states: Vec<(NodeId, PeerState)> = d.peers().iter()
+        .map(|p| (p.id(), p.state())).collect();
+    assert_eq!(states, vec![(NodeId(1), PeerState::Dead),
+                            (NodeId(2), PeerState::Alive)]);
+}
+
+#[test]
+fn state_changes_are_reported() {
+    use detector::TimeoutDetector;
+
+    let mut d = test_dispatcher();
+    d.set_failure_detector(Box::new(TimeoutDetector::new(
+        Duration::from_secs(1), Duration::from_secs(3))));
+    join_from_peer(&mut d);
+    d.events.clear();
+
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    d.transport.deliver(ping_msg().encode(), peer());
+    d.poll();
+    d.clock.advance(Duration::from_secs(3));
+    d.poll();
+    let states: Vec<PeerState> = d.events.drain(..)
+        .filter_map(|e| match e {
+            MeshEvent::PeerStateChanged(p) => {
+                assert_eq!(p.id(), NodeId(1));
+                Some(p.state())
+            },
+            _ => None,
+        })
+        .collect();
+    assert_eq!(states, vec![PeerState::Suspect, PeerState::Alive,
+                            PeerState::Dead]);
+}
+
+#[test]
+fn state_changes_are_recorded_with_their_causes() {
+    use detector::TimeoutDetector;
+
+    let mut d = test_dispatcher();
+    d.set_failure_detector(Box::new(TimeoutDetector::new(
+        Duration::from_secs(1), Duration::from_secs(3))));
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    d.clock.advance(Duration::from_secs(2));
+    d.poll();
+    // And back, with a Join of its own, as after a restart.
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+
+    let mut history = d.history(Some(NodeId(1)));
+    history.reverse();
+    let changes: Vec<(Option<PeerState>, PeerState, Cause)> = history.iter()
+        .map(|c| {
+            assert_eq!(c.addr, peer());
+            (c.from, c.to, c.cause)
+        })
+        .collect();
+    assert_eq!(changes, vec![
+        (None, PeerState::Alive, Cause::Join),
+        (Some(PeerState::Alive), PeerState::Suspect, Cause::Detector),
+        (Some(PeerState::Suspect), PeerState::Dead, Cause::Detector),
+        (Some(PeerState::Dead), PeerState::Alive, Cause::Join),
+    ]);
+    assert!(history.windows(2).all(|w| w[0].when <= w[1].when));
+    assert!(d.history(Some(NodeId(2))).is_empty());
+
+    // The suspicion the detector began, and its verdict ended.
+    let reasons: Vec<Option<SuspicionReason>> = history.iter()
+        .map(|c| c.suspicion.map(|s| s.reason)).collect();
+    assert_eq!(reasons, vec![None, Some(SuspicionReason::Silent),
+                             Some(SuspicionReason::Silent), None]);
+    assert_eq!(history[1].suspicion, history[2].suspicion);
+    let suspicion = history[1].suspicion.unwrap();
+    assert_eq!(suspicion.since, history[1].when);
+    assert_eq!(suspicion.by, d.node_id());
+    assert_eq!(d.peers()[0].suspicion(), None);
+}
+
+#[test]
+fn a_suspicion_a_join_refutes_is_recorded_as_ended() {
+    use detector::TimeoutDetector;
+
+    let mut d = test_dispatcher();
+    d.set_failure_detector(Box::new(TimeoutDetector::new(
+        Duration::from_secs(1), Duration::from_secs(3))));
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    let suspicion = d.peers()[0].suspicion().unwrap();
+    assert_eq!(suspicion.reason, SuspicionReason::Silent);
+
+    d.transport.deliver(join_msg(2, NodeId(1)).encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+    assert_eq!(d.peers()[0].suspicion(), None);
+    let refuted = &d.history(Some(NodeId(1)))[0];
+    assert_eq!((refuted.from, refuted.to, refuted.cause),
+               (Some(PeerState::Suspect), PeerState::Alive, Cause::Join));
+    assert_eq!(refuted.suspicion, Some(suspicion));
+}
+
+#[test]
+fn unsolicited_pong_does_not_revive_a_suspect() {
+    use detector::TimeoutDetector;
+
+    let mut d = test_dispatcher();
+    d.set_failure_detector(Box::new(TimeoutDetector::new(
+        Duration::from_secs(1), Duration::from_secs(3))));
+    join_from_peer(&mut d);
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+
+    let forged = PingBody { nonce: 1234, sent_at_micros: 0, pad: vec![] };
+    let pong = Message::Pong(forged, seen_at_us(), None);
+    d.transport.deliver(pong.encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+    assert_eq!(d.stats.unsolicited, 1);
+
+    // Nor does a real one, from somewhere else.
+    d.ping(&peer());
+    d.poll();
+    let last = d.transport.sent.borrow().last().unwrap().0.clone();
+    let ping = match Message::decode(&last) {
+        Message::Ping(body) => body,
+        other => panic!("expected Ping, got {:?}", other),
+    };
+    let pong = Message::Pong(ping.echo(), seen_at_us(), None);
+    d.transport.deliver(pong.encode(), "127.0.0.1:9001".parse().unwrap());
+    d.poll();
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+    assert_eq!(d.stats.unsolicited, 2);
+
+    // It is accepted from the peer, but only once.
+    for _ in 0..2 {
+        let pong = Message::Pong(ping.echo(), seen_at_us(), None);
+        d.transport.deliver(pong.encode(), peer());
+        d.poll();
+    }
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+    assert_eq!(d.stats.unsolicited, 3);
+}
+
+#[test]
+fn unsolicited_acks_leave_pending_alone() {
+    let mut d = test_dispatcher();
+    let seq = d.send_acked(AckedMessage::Data(vec![1]), &peer());
+
+    d.transport.deliver(ack_from_peer(after(seq, 1)).encode(), peer());
+    d.transport.deliver(ack_from_peer(seq).encode(),
+                        "127.0.0.1:9001".parse().unwrap());
+    let acks = Message::AckMulti(vec![after(seq, 1), after(seq, 2)]);
+    d.transport.deliver(acks.encode(), peer());
+    for _ in 0..3 {
+        d.poll();
+    }
+    assert!(d.pending.contains_key(&seq));
+    assert_eq!(d.stats.unsolicited, 3);
+
+    d.transport.deliver(Message::AckMulti(vec![after(seq, 1), seq]).encode(),
+                        peer());
+    d.poll();
+    assert!(d.pending.is_empty());
+}
+
+#[test]
+fn acks_from_a_new_incarnation_ask_for_a_sync() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    assert_eq!(d.peers()[0].incarnation(), Some(0));
+
+    let seq = d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    let from = Responder { id: NodeId(1), incarnation: 3 };
+    d.transport.deliver(Message::Ack(seq, seen_at_us(), Some(from)).encode(),
+                        peer());
+    d.poll();
+    assert_eq!(d.peers()[0].incarnation(), Some(3));
+    assert_eq!(d.stats.syncs_requested, 1);
+    assert!(d.timers().iter().any(|t| t.0 == "sync:127.0.0.1:9000"));
+
+    d.transport.sent.borrow_mut().clear();
+    d.clock.advance(SYNC_DELAY);
+    d.poll();
+    let sync = Message::SyncRequest(3).encode();
+    assert!(d.transport.sent.borrow().contains(&(sync, peer())));
+
+    // Nothing more for the same incarnation, nor an older one.
+    for &incarnation in &[3, 2] {
+        let seq = d.send_acked(AckedMessage::Data(vec![1]), &peer());
+        let from = Responder { id: NodeId(1), incarnation: incarnation };
+        d.transport.deliver(
+            Message::Ack(seq, seen_at_us(), Some(from)).encode(), peer());
+        d.poll();
+    }
+    assert_eq!(d.peers()[0].incarnation(), Some(3));
+    assert_eq!(d.stats.syncs_requested, 1);
+}
+
+// A Join from `d` itself, as the latest of its seqs.
+#[cfg(test)]
+fn latest_join(d: &Dispatcher<::transport::SimTransport, clock::ManualClock>)
+        -> Message {
+    let seq = Seq::new(d.next_seq.session, d.next_seq.n - 1);
+    Message::Acked(seq, AckedMessage::Join(d.node_id(),
+                                           message::PROTOCOL_VERSION))
+}
+
+#[test]
+fn sync_requests_are_answered_with_a_join() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    d.transport.deliver(Message::SyncRequest(0).encode(), peer());
+    d.poll();
+    let sent: Vec<Message> = d.transport.sent.borrow().iter()
+        .map(|&(ref bytes, _)| Message::decode(bytes)).collect();
+    assert_eq!(sent, vec![latest_join(&d)]);
+
+    // Strangers have nothing to sync.
+    d.transport.sent.borrow_mut().clear();
+    d.transport.deliver(Message::SyncRequest(0).encode(),
+                        "127.0.0.1:9001".parse().unwrap());
+    d.poll();
+    assert!(d.transport.sent.borrow().is_empty());
+}
+
+#[test]
+fn joins_and_acks_claiming_to_be_us_are_dropped() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let incarnation = d.incarnation();
+
+    // A Join as us is refuted: it's not acked, and our peers hear from
+    // us afresh, on a new incarnation.
+    let impostor: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    d.transport.deliver(join_msg(1, d.node_id()).encode(), impostor);
+    d.poll();
+    assert_eq!(d.peers().len(), 1);
+    assert!(d.members.get(d.node_id()).is_none());
+    assert_eq!(d.incarnation(), incarnation + 1);
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(latest_join(&d).encode(), peer())]);
+
+    // An Ack as us acks nothing.
+    let seq = d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    let forged = Message::Ack(seq, seen_at_us(), Some(Responder {
+        id: d.node_id(),
+        incarnation: 0,
+    }));
+    d.transport.deliver(forged.encode(), peer());
+    d.poll();
+    assert!(d.pending.contains_key(&seq));
+    assert_eq!(d.incarnation(), incarnation + 1);
+    assert_eq!(d.stats.impersonations, 2);
+}
+
+#[test]
+fn a_flood_of_joins_as_us_is_refuted_once_a_round() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let incarnation = d.incarnation();
+    d.transport.sent.borrow_mut().clear();
+
+    for i in 0..50 {
+        let impostor = SocketAddr::new("127.0.0.1".parse().unwrap(),
+                                       9100 + i as u16);
+        d.transport.deliver(join_msg(i + 1, d.node_id()).encode(), impostor);
+        d.poll();
+        d.clock.advance(RETRANSMIT / 100);
+    }
+    assert_eq!(d.stats.impersonations, 50);
+    assert_eq!(d.incarnation(), incarnation + 1);
+    let joins = d.transport.sent.borrow().iter()
+        .filter(|s| Message::decode(&s.0).kind() == MessageKind::Join)
+        .count();
+    assert_eq!(joins, 1);
+
+    // Another round once the last has had its time.
+    d.clock.advance(RETRANSMIT);
+    d.transport.deliver(join_msg(51, d.node_id()).encode(), peer());
+    d.poll();
+    assert_eq!(d.incarnation(), incarnation + 2);
+}
+
+#[test]
+fn inbound_hook_can_drop_pings() {
+    let mut d = test_dispatcher();
+    d.add_inbound_hook(|_, msg| match *msg {
+        Message::Ping(_) => HookAction::Drop,
+        _ => HookAction::Continue,
+    });
+
+    d.transport.deliver(ping_msg().encode(), peer());
+    d.poll();
+    assert!(d.transport.sent.borrow().is_empty());
+    assert_eq!(d.stats.hook_dropped_inbound, 1);
+    assert_eq!(d.stats.malformed, 0);
+
+    d.transport.deliver(join_msg(3, NodeId(1)).encode(), peer());
+    d.poll();
+    assert_eq!(d.peers().len(), 1);
+    let our_join = latest_join(&d);
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(ack_to_peer(&d, 3).encode(), peer()),
+                    (our_join.encode(), peer())]);
+}
+
+#[test]
+fn outbound_hook_can_drop_messages() {
+    let mut d = test_dispatcher();
+    d.add_outbound_hook(|_, msg| match *msg {
+        Message::Pong(..) => HookAction::Drop,
+        _ => HookAction::Continue,
+    });
+
+    d.transport.deliver(ping_msg().encode(), peer());
+    d.poll();
+    assert!(d.transport.sent.borrow().is_empty());
+    assert_eq!(d.stats.hook_dropped_outbound, 1);
+}
+
+#[test]
+fn acked_messages_a_hook_drops_fail_as_dropped() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    d.events.drain(..);
+    d.add_outbound_hook(|_, msg| match *msg {
+        Message::Acked(_, AckedMessage::Data(_)) => HookAction::Drop,
+        _ => HookAction::Continue,
+    });
+
+    let seq = d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    assert_eq!(d.stats.hook_dropped_outbound, 1);
+    assert!(d.pending.is_empty());
+    let failed = d.events.drain(..).any(|e| match e {
+        MeshEvent::DeliveryFailed { seq: s, error: MeshError::Dropped, .. } =>
+            s == seq,
+        _ => false,
+    });
+    assert!(failed);
+}
+
+#[test]
+fn probe_interval_follows_membership_and_churn() {
+    let config = Config {
+        probe: ProbeConfig {
+            per_member: Duration::from_secs(1),
+            min_interval: Duration::from_millis(100),
+            max_interval: Duration::from_secs(10),
+            churn_window: Duration::from_secs(1),
+            .. ProbeConfig::default()
+        },
+        // Nobody answers our probes, but don't let that become churn.
+        failure_detector: ::detector::DetectorConfig::Timeout {
+            suspect_after: Duration::from_secs(60),
+            dead_after: Duration::from_secs(120),
+        },
+        .. Config::default()
+    };
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    assert_eq!(d.stats.probe_interval_ms, 100);
+
+    // Each join is both another member and churn.
+    for n in 1..4 {
+        let join = join_msg(n, NodeId(n as u64));
+        d.transport.deliver(join.encode(),
+                            format!("127.0.0.1:{}", 9000 + n).parse().unwrap());
+        d.poll();
+        assert_eq!(d.stats.probe_interval_ms, 1000 * n as u64 / (n as u64 + 1));
+    }
+
+    // Once things settle down the interval relaxes to a second a member,
+    // and probes keep going out.
+    d.transport.sent.borrow_mut().clear();
+    for _ in 0..20 {
+        d.clock.advance(Duration::from_millis(500));
+        d.poll();
+    }
+    assert_eq!(d.stats.probe_interval_ms, 3000);
+    let probes = d.transport.sent.borrow().iter()
+        .filter(|s| Message::decode(&s.0).kind() == MessageKind::Ping)
+        .count();
+    assert!(probes >= 3 && probes <= 5, "{} probes sent", probes);
+}
+
+#[test]
+fn reload_reschedules_the_probe() {
+    let mut config = Config::default();
+    config.probe.min_interval = Duration::from_secs(1);
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    join_from_peer(&mut d);
+    assert_eq!(d.reload(&config), Vec::<&str>::new());
+
+    config.probe.min_interval = Duration::from_secs(3);
+    config.limits.joins = 1;
+    assert_eq!(d.reload(&config), vec!["probe", "limits"]);
+    assert_eq!(d.stats.probe_interval_ms, 3000);
+    // Alongside the one it supersedes.
+    let probes: Vec<Nanos> = d.timers().into_iter()
+        .filter(|t| t.0 == "probe").map(|t| t.1).collect();
+    assert_eq!(probes, vec![Nanos::from_secs(1), Nanos::from_secs(3)]);
+
+    // The probe scheduled before the reload, due in a second, doesn't go
+    // out; the new one does, once.
+    fn pings(d: &Dispatcher<::transport::SimTransport, clock::ManualClock>)
+            -> usize {
+        d.transport.sent.borrow().iter()
+            .filter(|s| Message::decode(&s.0).kind() == MessageKind::Ping)
+            .count()
+    }
+    for _ in 0..5 {
+        d.clock.advance(Duration::from_millis(500));
+        d.poll();
+    }
+    assert_eq!(pings(&d), 0);
+    d.clock.advance(Duration::from_millis(500));
+    d.poll();
+    assert_eq!(pings(&d), 1);
+}
+
+#[cfg(test)]
+fn backoff_dispatcher()
+        -> Dispatcher<::transport::SimTransport, clock::ManualClock> {
+    use backoff::BackoffConfig;
+
+    let config = Config {
+        // Nobody answers our probes, but the peer mustn't die of it.
+        failure_detector: ::detector::DetectorConfig::Timeout {
+            suspect_after: Duration::from_secs(600),
+            dead_after: Duration::from_secs(1200),
+        },
+        backoff: BackoffConfig {
+            after: 3,
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(4),
+        },
+        .. Config::default()
+    };
+    Dispatcher::with_config(::transport::SimTransport::new(),
+                            clock::ManualClock::new(), &config)
+}
+
+#[test]
+fn probes_to_a_silent_peer_back_off_until_it_answers() {
+    let mut d = backoff_dispatcher();
+    join_from_peer(&mut d);
+
+    // Run for `ms`, returning the gaps in ms between the probes sent.
+    fn probe_gaps(d: &mut Dispatcher<::transport::SimTransport,
+                                     clock::ManualClock>, ms: u64)
+            -> Vec<u64> {
+        let mut probes = Vec::new();
+        for t in 0..ms / 50 {
+            d.clock.advance(Duration::from_millis(50));
+            d.poll();
+            let pings = d.transport.sent.borrow_mut().drain(..)
+                .filter(|s| Message::decode(&s.0).kind() == MessageKind::Ping)
+                .count();
+            if pings > 0 {
+                probes.push(t * 50);
+            }
+        }
+        probes.windows(2).map(|w| w[1] - w[0]).collect()
+    }
+    let gaps = probe_gaps(&mut d, 20000);
+    // Three unanswered probes, then a probe a second later, two seconds,
+    // and every four from then on.
+    assert_eq!(&gaps[..6], &[200, 200, 200, 1000, 2000, 4000]);
+    assert!(gaps[6..].iter().all(|&g| g == 4000), "{:?}", gaps);
+
+    // Everything else to the peer is held back meanwhile.
+    let before = d.stats.suppressed_sends;
+    d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    d.poll();
+    assert!(d.transport.sent.borrow().is_empty());
+    assert_eq!(d.stats.suppressed_sends, before + 1);
+
+    // Hearing from it at all resumes the usual rate.
+    let data = Message::Acked(peer_seq(50), AckedMessage::Data(vec![]));
+    d.transport.deliver(data.encode(), peer());
+    let gaps = probe_gaps(&mut d, 1000);
+    assert!(gaps.len() >= 3 && gaps.iter().all(|&g| g == 200),
+            "{:?}", gaps);
+}
+
+#[test]
+fn send_failures_back_off_an_unreachable_peer() {
+    let mut d = backoff_dispatcher();
+    join_from_peer(&mut d);
+    d.transport.unreachable.borrow_mut().insert(peer());
+    for n in 0..3 {
+        d.send(&ack_to_peer(&d, n), &peer());
+        d.poll();
+    }
+    assert_eq!(d.stats.suppressed_sends, 0);
+    d.send(&ack_to_peer(&d, 3), &peer());
+    d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    assert_eq!(d.stats.suppressed_sends, 2);
+    assert_eq!(d.memory_stats().outbound, 0);
+}
+
+#[test]
+fn fast_fail_probe_suspects_refusing_peer() {
+    use detector::TimeoutDetector;
+
+    let config = Config {
+        probe: ProbeConfig { fast_fail: true, .. ProbeConfig::default() },
+        .. Config::default()
+    };
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    // Long enough that only the refusal could make it suspect.
+    d.set_failure_detector(Box::new(TimeoutDetector::new(
+        Duration::from_secs(60), Duration::from_secs(120))));
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+    d.transport.refusing.borrow_mut().insert(peer());
+
+    // The one peer is probed once the interval is up, and the refusal
+    // comes back on the next poll.
+    d.clock.advance(Duration::from_millis(d.stats.probe_interval_ms));
+    d.poll();
+    d.poll();
+    let sent = d.transport.sent.borrow().clone();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(Message::decode(&sent[0].0).kind(), MessageKind::Ping);
+    assert_eq!(d.stats.probes_refused, 1);
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+    assert_eq!(d.peers()[0].suspicion().map(|s| s.reason),
+               Some(SuspicionReason::ProbeRefused));
+}
+
+#[test]
+fn acks_for_a_burst_are_aggregated() {
+    let mut d = test_dispatcher();
+    for n in 1..6 {
+        let m = Message::Acked(peer_seq(n), AckedMessage::Data(vec![n as u8]));
+        d.transport.deliver(m.encode(), peer());
+        d.poll();
+    }
+    assert!(d.transport.sent.borrow().is_empty());
+    for seq in 1..6 {
+        match d.next_event() {
+            Some(MeshEvent::Data(_, data)) => assert_eq!(data, vec![seq as u8]),
+            other => panic!("expected Data, got {:?}", other),
+        }
+    }
+
+    d.clock.advance(ACK_DELAY);
+    d.poll();
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(Message::AckMulti((1..6).map(peer_seq).collect())
+                     .encode(), peer())]);
+}
+
+#[test]
+fn retransmitted_data_is_acked_but_delivered_once() {
+    let mut d = test_dispatcher();
+    let m = Message::Acked(peer_seq(3), AckedMessage::Data(vec![1]));
+    for _ in 0..2 {
+        d.transport.deliver(m.encode(), peer());
+        d.poll();
+        d.clock.advance(ACK_DELAY);
+        d.poll();
+    }
+    assert!(d.next_event().is_some());
+    assert!(d.next_event().is_none());
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(ack_to_peer(&d, 3).encode(), peer()); 2]);
+}
+
+#[test]
+fn full_ack_batch_is_sent_at_once() {
+    let mut d = test_dispatcher();
+    for n in 0..ACK_BATCH as u32 {
+        let m = Message::Acked(peer_seq(n), AckedMessage::Data(vec![]));
+        d.transport.deliver(m.encode(), peer());
+        d.poll();
+    }
+    assert_eq!(d.transport.sent.borrow().len(), 1);
+}
+
+#[test]
+fn ack_multi_resolves_every_pending_message() {
+    let mut d = test_dispatcher();
+    let seqs: Vec<Seq> = (0..5)
+        .map(|_| d.send_acked(AckedMessage::Data(vec![]), &peer()))
+        .collect();
+    assert_eq!(d.pending.len(), 5);
+
+    d.transport.deliver(Message::AckMulti(seqs).encode(), peer());
+    d.poll();
+    assert!(d.pending.is_empty());
+}
+
+#[test]
+fn clock_jump_reanchors_instead_of_firing() {
+    use detector::TimeoutDetector;
+
+    let mut d = test_dispatcher();
+    d.set_failure_detector(Box::new(TimeoutDetector::new(
+        Duration::from_secs(1), Duration::from_secs(3))));
+    join_from_peer(&mut d);
+    d.send_acked(AckedMessage::Data(vec![1]), &peer());
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+    d.ping(&peer());
+    d.ping(&peer());
+    d.poll();
+    let pongs: Vec<Vec<u8>> = d.transport.sent.borrow().iter()
+        .filter_map(|&(ref bytes, _)| match Message::decode(bytes) {
+            Message::Ping(body) =>
+                Some(Message::Pong(body.echo(), seen_at_us(), None).encode()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(pongs.len(), 2);
+    d.events.clear();
+    let timers = d.timers().len();
+
+    // A Pong that comes with the jump counts it as a round trip, until
+    // the jump is noticed.
+    d.clock.advance(Duration::from_secs(600));
+    d.transport.deliver(pongs[0].clone(), peer());
+    d.poll();
+    assert_eq!(d.stats.clock_jumps, 1);
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+    assert_eq!(d.pending.len(), 1);
+    assert_eq!(d.timers().len(), timers);
+    assert!(d.next_event().is_none());
+    assert_eq!(d.peers()[0].rtt(), None);
+    assert!(d.peers()[0].rtts().is_empty());
+
+    // One that comes after answers nothing still outstanding.
+    d.transport.deliver(pongs[1].clone(), peer());
+    d.poll();
+    assert_eq!(d.stats.unsolicited, 1);
+    assert_eq!(d.peers()[0].rtt(), None);
+
+    // Time runs normally again afterwards.
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    assert_eq!(d.stats.clock_jumps, 1);
+    assert_eq!(d.peers()[0].state(), PeerState::Suspect);
+}
+
+#[test]
+fn tick_uses_real_elapsed_time() {
+    let mut d = test_dispatcher();
+    d.join(&peer());
+
+    // Many ticks with no time passing must not add up to a retransmit.
+    for _ in 0..100 {
+        d.poll();
+    }
+    assert_eq!(d.transport.sent.borrow().len(), 1);
+}
+
+#[test]
+fn oversized_datagram_is_dropped_as_truncated() {
+    let mut d = test_dispatcher();
+    let huge = Message::Ping(PingBody { nonce: 1, sent_at_micros: 0,
+                                        pad: vec![0; 70000] });
+
+    d.transport.deliver(huge.encode(), peer());
+    d.poll();
+    assert_eq!(d.stats.truncated, 1);
+    // No Pong: the Ping handler never saw it.
+    assert!(d.transport.sent.borrow().is_empty());
+}
+
+#[test]
+fn polls_with_nothing_to_read_are_counted() {
+    let mut d = test_dispatcher();
+    d.poll();
+    d.poll();
+    assert_eq!(d.stats.read_timeouts, 2);
+    d.transport.deliver(ping_msg().encode(), peer());
+    d.poll();
+    assert_eq!(d.stats.read_timeouts, 2);
+}
+
+#[test]
+fn malformed_datagram_is_counted_not_fatal() {
+    let mut d = test_dispatcher();
+    let header = [message::VERSION_FLAG | message::PROTOCOL_VERSION, 0];
+    // An Acked with its seq cut short.
+    d.transport.deliver(message::frame(&header, &[0, 9]), peer());
+    d.poll();
+    assert_eq!(d.stats.malformed, 1);
+    assert!(d.transport.sent.borrow().is_empty());
+
+    // And kept for debug dumps, the latest few.
+    for i in 0..MALFORMED_KEPT {
+        d.transport.deliver(message::frame(&header, &[1, i as u8]), peer());
+        d.poll();
+    }
+    let malformed = d.debug_dump(&Config::default()).malformed;
+    assert_eq!(malformed.len(), MALFORMED_KEPT);
+    assert_eq!(malformed[0], dump::hex(&message::frame(&header, &[1, 0])));
+}
+
+#[test]
+fn lengths_that_dont_add_up_are_counted_by_how() {
+    let mut d = test_dispatcher();
+    let header = |t| [message::VERSION_FLAG | message::PROTOCOL_VERSION, t];
+    let data = |len: u8, payload: &[u8]| {
+        let mut contents = vec![0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 1,
+                                0, 0, 0, 0, 0, 0, 0, len];
+        contents.extend_from_slice(payload);
+        message::frame(&header(0), &contents)
+    };
+    let mut too_many = vec![0, 0, 0, 0, 0, 0, 0, ACK_BATCH as u8 + 1];
+    too_many.extend(vec![0; 8 * (ACK_BATCH + 1)]);
+    let frames = vec![
+        data(3, &[1, 2]),
+        data(1, &[1, 2]),
+        message::frame(&header(6), &[0, 0, 0, 0, 0, 0, 0, 5, 0]),
+        message::frame(&header(4), &too_many),
+    ];
+    for bytes in frames {
+        d.transport.deliver(bytes, peer());
+        d.poll();
+    }
+    assert_eq!(d.stats.length_mismatches, 1);
+    assert_eq!(d.stats.trailing_bytes, 2);
+    assert_eq!(d.stats.counts_too_large, 1);
+    // Malformed all the same, and so neither acked nor handed over.
+    assert_eq!(d.stats.malformed, 4);
+    assert!(d.events.is_empty());
+    d.clock.advance(ACK_DELAY);
+    d.poll();
+    assert!(d.transport.sent.borrow().is_empty());
+}
+
+#[test]
+fn runts_are_counted_alone() {
+    let mut d = test_dispatcher();
+    let version = message::VERSION_FLAG | message::PROTOCOL_VERSION;
+    for runt in vec![vec![], vec![0], vec![version], vec![version, 0, 0]] {
+        d.transport.deliver(runt, peer());
+        d.poll();
+    }
+    assert_eq!(d.stats.runts, 4);
+    assert_eq!(d.stats.malformed, 0);
+    assert_eq!(d.stats.unsupported_versions, 0);
+    // Not even a VersionMismatch for the one with no version.
+    assert!(d.transport.sent.borrow().is_empty());
+    assert!(d.debug_dump(&Config::default()).malformed.is_empty());
+}
+
+#[test]
+fn unknown_message_type_is_reported_and_counts_as_heard() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    d.events.clear();
+    d.clock.advance(Duration::from_secs(1));
+    let version = message::VERSION_FLAG | message::PROTOCOL_VERSION;
+    d.transport.deliver(vec![version, message::MESSAGE_TYPES + 3], peer());
+    d.poll();
+    assert_eq!(d.stats.unknown_types, 1);
+    assert_eq!(d.stats.malformed, 0);
+    match d.events.pop_front() {
+        Some(MeshEvent::UnknownMessageType { from, type_id }) => {
+            assert_eq!(from, peer());
+            assert_eq!(type_id, message::MESSAGE_TYPES + 3);
+        },
+        other => panic!("expected UnknownMessageType, got {:?}", other),
+    }
+    assert_eq!(d.peers()[0].last_seen(), d.clock.now());
+}
+
+#[test]
+fn corrupt_datagrams_are_counted_apart_and_otherwise_ignored() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let seen = d.peers()[0].last_seen();
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    d.transport.sent.borrow_mut().clear();
+    let mut bytes = ping_msg().encode();
+    *bytes.last_mut().unwrap() ^= 0x10;
+    d.transport.deliver(bytes, peer());
+    d.poll();
+    assert_eq!(d.stats.corrupt, 1);
+    assert_eq!(d.stats.malformed, 0);
+    // Not answered, nor taken as word from the peer.
+    assert!(d.transport.sent.borrow().is_empty());
+    assert_eq!(d.peers()[0].last_seen(), seen);
+}
+
+// Have three peers ack Data from `d`, each saying it saw us at `seen`.
+#[cfg(test)]
+fn acks_seeing(d: &mut Dispatcher<::transport::SimTransport,
+                                  clock::ManualClock>, seen: &str) {
+    for n in 1..4 {
+        let from: SocketAddr = format!("127.0.0.1:{}", 9000 + n).parse()
+            .unwrap();
+        let seq = d.send_acked(AckedMessage::Data(vec![]), &from);
+        let ack = Message::Ack(seq, WireAddr(seen.parse().unwrap()), None);
+        d.transport.deliver(ack.encode(), from);
+        d.poll();
+    }
+}
+
+#[test]
+fn agreeing_peers_reveal_external_address() {
+    let config = Config {
+        observed: ObservedConfig { quorum: 3, adopt: true },
+        .. Config::default()
+    };
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    let local = d.advertised_addr();
+    assert_eq!(local, Some(d.transport.addr));
+
+    // Seeing us where we're bound is no news.
+    acks_seeing(&mut d, "127.0.0.1:7000");
+    assert!(d.next_event().is_none());
+
+    acks_seeing(&mut d, "203.0.113.7:61000");
+    let external = "203.0.113.7:61000".parse().unwrap();
+    match d.next_event() {
+        Some(MeshEvent::ExternalAddressDetected(addr)) =>
+            assert_eq!(addr, external),
+        other => panic!("expected ExternalAddressDetected, got {:?}", other),
+    }
+    assert!(d.next_event().is_none());
+    assert_eq!(d.advertised_addr(), Some(external));
+}
+
+#[test]
+fn detected_address_is_only_adopted_if_configured() {
+    let mut d = test_dispatcher();
+    acks_seeing(&mut d, "203.0.113.7:61000");
+    match d.next_event() {
+        Some(MeshEvent::ExternalAddressDetected(_)) => (),
+        other => panic!("expected ExternalAddressDetected, got {:?}", other),
+    }
+    assert_eq!(d.advertised_addr(), Some(d.transport.addr));
+}
+
+// Step `d` on a second at a time for `secs`, peer() answering every Ping
+// that reaches it.
+#[cfg(test)]
+fn answer_pings(d: &mut Dispatcher<::transport::SimTransport,
+                                   clock::ManualClock>, secs: u32) {
+    for _ in 0..secs {
+        d.clock.advance(Duration::from_secs(1));
+        d.poll();
+        let sent: Vec<Vec<u8>> = d.transport.sent.borrow_mut().drain(..)
+            .map(|(bytes, _)| bytes).collect();
+        for bytes in sent {
+            if let Message::Ping(body) = Message::decode(&bytes) {
+                let pong = Message::Pong(body.echo(), seen_at_us(), None);
+                d.transport.deliver(pong.encode(), peer());
+            }
+        }
+        d.poll();
+    }
+}
+
+#[test]
+fn reloading_leaves_mtu_probing_on_or_off() {
+    let mut d = test_dispatcher();
+    let mut config = Config::default();
+    config.mtu.enabled = true;
+    assert_eq!(d.reload(&config), Vec::<&str>::new());
+    assert!(!d.mtu.config().enabled);
+
+    config.mtu.interval = Duration::from_secs(2);
+    assert_eq!(d.reload(&config), vec!["mtu"]);
+    assert!(!d.mtu.config().enabled);
+    assert_eq!(d.mtu.config().interval, Duration::from_secs(2));
+}
+
+#[test]
+fn mtu_probing_finds_what_gets_through() {
+    let config = Config {
+        mtu: MtuConfig {
+            enabled: true,
+            interval: Duration::from_secs(2),
+            .. MtuConfig::default()
+        },
+        .. Config::default()
+    };
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    d.transport.max_datagram.set(Some(3000));
+    join_from_peer(&mut d);
+    assert_eq!(d.path_mtu(&peer()), 1400);
+
+    answer_pings(&mut d, 30);
+    let mtu = d.peers()[0].mtu().unwrap();
+    assert!(mtu <= 3000 && mtu > 3000 - 64, "found an MTU of {}", mtu);
+    assert_eq!(d.path_mtu(&peer()), mtu);
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+
+    // And that's that: no more padded Pings.
+    d.clock.advance(Duration::from_secs(1));
+    d.poll();
+    assert!(d.transport.sent.borrow().iter()
+            .all(|&(ref bytes, _)| bytes.len() < 100));
+}
+
+#[test]
+fn sends_too_big_for_the_path_fail_and_lower_the_mtu() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    d.events.drain(..);
+    d.transport.path_mtu.set(Some(1000));
+
+    let big = d.send_acked(AckedMessage::Data(vec![0; 1200]), &peer());
+    d.poll();
+    assert_eq!(d.stats.too_big, 1);
+    assert!(d.pending.is_empty());
+    assert_eq!(d.peers()[0].mtu(), Some(MIN_MTU));
+    assert_eq!(d.path_mtu(&peer()), MIN_MTU);
+    let failed = d.events.drain(..).any(|e| match e {
+        MeshEvent::DeliveryFailed { seq, error: MeshError::TooLarge, .. } =>
+            seq == big,
+        _ => false,
+    });
+    assert!(failed);
+    // Not retransmitted, nor the peer suspected for it.
+    d.clock.advance(RETRANSMIT);
+    d.poll();
+    assert!(d.transport.sent.borrow().iter()
+            .all(|&(ref bytes, _)| bytes.len() < 1000));
+    assert_eq!(d.peers()[0].state(), PeerState::Alive);
+
+    // As large again fails without being tried; smaller goes through.
+    d.send_acked(AckedMessage::Data(vec![0; 1500]), &peer());
+    assert_eq!(d.stats.too_big, 2);
+    assert!(d.pending.is_empty());
+    d.send_acked(AckedMessage::Data(vec![0; 500]), &peer());
+    d.poll();
+    assert_eq!(d.pending.len(), 1);
+    assert_eq!(d.stats.too_big, 2);
+}
+
+#[test]
+fn mtu_probing_is_off_by_default() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    d.transport.max_datagram.set(Some(3000));
+    answer_pings(&mut d, 30);
+    assert_eq!(d.peers()[0].mtu(), None);
+    assert_eq!(d.path_mtu(&peer()), Config::default().mtu.default);
+}
+
+#[test]
+fn acks_and_pongs_say_where_we_saw_the_sender() {
+    let mut d = test_dispatcher();
+    d.transport.deliver(ping_msg().encode(), peer());
+    d.transport.deliver(join_msg(3, NodeId(1)).encode(), peer());
+    d.poll();
+    d.poll();
+    let sent: Vec<Message> = d.transport.sent.borrow().iter()
+        .map(|&(ref bytes, _)| Message::decode(bytes)).collect();
+    assert!(sent.iter().any(|m| m.kind() == MessageKind::Pong));
+    for m in sent.iter().filter(|m| m.kind() != MessageKind::Join) {
+        assert_eq!(m.observed(), Some(peer()));
+    }
+}
+
+#[test]
+fn frames_too_old_to_read_get_a_version_mismatch() {
+    let mut d = test_dispatcher();
+    let old = message::VERSION_FLAG | (message::MIN_PROTOCOL_VERSION - 1);
+    d.transport.deliver(vec![old, 2, 0, 0], peer());
+    d.poll();
+    assert_eq!(d.stats.unsupported_versions, 1);
+    assert_eq!(d.stats.malformed, 0);
+    let mismatch = Message::VersionMismatch(message::PROTOCOL_VERSION);
+    assert_eq!(*d.transport.sent.borrow(), vec![(mismatch.encode(), peer())]);
+
+    // A newer node can read ours, so needn't be told.
+    let new = message::VERSION_FLAG | (message::PROTOCOL_VERSION + 1);
+    d.transport.deliver(vec![new, 2, 0, 0], peer());
+    d.poll();
+    assert_eq!(d.stats.unsupported_versions, 2);
+    assert_eq!(d.transport.sent.borrow().len(), 1);
+}
+
+#[test]
+fn floods_are_throttled_then_blocked_until_expiry() {
+    use std::ops::Range;
+    use throttle::ThrottleConfig;
+
+    fn flood(d: &mut Dispatcher<::transport::SimTransport,
+                                clock::ManualClock>, seqs: Range<u32>)
+            -> usize {
+        let handled = |d: &Dispatcher<_, _>| d.events.iter()
+            .filter(|e| match **e {
+                MeshEvent::Data(..) => true,
+                _ => false,
+            }).count();
+        let before = handled(&*d);
+        for n in seqs {
+            let data = Message::Acked(peer_seq(n), AckedMessage::Data(vec![1]));
+            d.transport.deliver(data.encode(), peer());
+            d.poll();
+        }
+        handled(&*d) - before
+    }
+
+    let mut config = Config::default();
+    config.throttle = ThrottleConfig {
+        rate: 10,
+        burst: 10,
+        liveness_rate: 2,
+        block_after: Duration::from_secs(1),
+        block_for: Duration::from_secs(5),
+    };
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    assert_eq!(flood(&mut d, 1..31), 10);
+    assert_eq!(d.throttled(&peer()), 20);
+    assert_eq!(d.stats.throttled, 20);
+
+    // Pings still get a trickle through.
+    for _ in 0..3 {
+        d.transport.deliver(ping_msg().encode(), peer());
+        d.poll();
+    }
+    let pongs = d.transport.sent.borrow().iter()
+        .filter(|&&(ref bytes, _)| Message::decode(bytes).kind()
+                                   == MessageKind::Pong)
+        .count();
+    assert_eq!(pongs, 2);
+
+    // A second on, still flooding: blocked.
+    d.clock.advance(Duration::from_secs(1));
+    assert_eq!(flood(&mut d, 31..51), 10);
+    assert_eq!(d.throttled(&peer()), 31);
+    assert_eq!(d.stats.sources_blocked, 1);
+    let throttled: Vec<(SocketAddr, Duration)> = d.events.drain(..)
+        .filter_map(|e| match e {
+            MeshEvent::PeerThrottled { from, blocked_for } =>
+                Some((from, blocked_for)),
+            _ => None,
+        }).collect();
+    assert_eq!(throttled, vec![(peer(), Duration::from_secs(5))]);
+    assert!(d.timers().iter().any(|t| t.0 == "unblock:127.0.0.1:9000"));
+
+    // However slowly it sends, until the block expires.
+    d.clock.advance(Duration::from_secs(2));
+    assert_eq!(flood(&mut d, 51..52), 0);
+    d.clock.advance(Duration::from_secs(3));
+    d.poll();
+    assert_eq!(flood(&mut d, 52..55), 3);
+    assert_eq!(d.throttled(&peer()), 32);
+}
+
+#[cfg(test)]
+fn keepalives_sent(d: &Dispatcher<::transport::SimTransport,
+                                  clock::ManualClock>) -> usize {
+    d.transport.sent.borrow().iter()
+        .filter(|s| s.0 == Message::Keepalive.encode())
+        .count()
+}
+
+#[test]
+fn keepalives_go_only_to_peers_sent_nothing_else_lately() {
+    let mut config = Config::default();
+    config.nat_refresh = Some(Duration::from_millis(500));
+    // Probes would keep the way open by themselves.
+    config.probe.min_interval = Duration::from_secs(60);
+    config.probe.max_interval = Duration::from_secs(60);
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    join_from_peer(&mut d);
+    fn step(d: &mut Dispatcher<::transport::SimTransport,
+                               clock::ManualClock>) {
+        d.clock.advance(TICK);
+        d.poll();
+    }
+    let start = d.clock.now();
+    while keepalives_sent(&d) == 0 {
+        step(&mut d);
+    }
+    let first = d.clock.now();
+    assert!(first - start >= Duration::from_millis(500));
+    assert!(first - start <= Duration::from_millis(500) + TICK);
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(vec![message::VERSION_FLAG | message::PROTOCOL_VERSION,
+                          10], peer())]);
+    assert_eq!(d.stats.keepalives_sent, 1);
+
+    // Every interval after, while there's nothing else to send.
+    for _ in 0..ticks_in(Duration::from_millis(500)) {
+        step(&mut d);
+    }
+    assert_eq!(keepalives_sent(&d), 2);
+
+    // A Pong, sent a little after, puts the next off.
+    for _ in 0..2 {
+        step(&mut d);
+    }
+    d.transport.deliver(ping_msg().encode(), peer());
+    d.poll();
+    for _ in 0..ticks_in(Duration::from_millis(500)) - 1 {
+        step(&mut d);
+    }
+    assert_eq!(keepalives_sent(&d), 2);
+    for _ in 0..2 {
+        step(&mut d);
+    }
+    assert_eq!(keepalives_sent(&d), 3);
+    assert_eq!(d.stats.keepalives_sent, 3);
+}
+
+#[test]
+fn keepalives_are_heard_from_but_not_answered_or_throttled() {
+    use throttle::ThrottleConfig;
+
+    let mut config = Config::default();
+    config.throttle = ThrottleConfig {
+        rate: 1,
+        burst: 1,
+        liveness_rate: 1,
+        block_after: Duration::from_secs(1),
+        block_for: Duration::from_secs(5),
+    };
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    join_from_peer(&mut d);
+    d.clock.advance(TICK);
+    for _ in 0..10 {
+        d.transport.deliver(Message::Keepalive.encode(), peer());
+        d.poll();
+    }
+    assert_eq!(d.peers()[0].last_seen(), d.clock.now());
+    assert!(d.transport.sent.borrow().is_empty());
+    assert_eq!(d.stats.keepalives_received, 10);
+    assert_eq!(d.throttled(&peer()), 0);
+    // Nor is anyone sent one unless asked to.
+    assert_eq!(d.stats.keepalives_sent, 0);
+}
+
+#[test]
+fn the_bandwidth_cap_holds_back_bulk_but_not_control() {
+    use bandwidth::BandwidthConfig;
+
+    let mut config = Config::default();
+    config.bandwidth = BandwidthConfig {
+        cap: 3000,
+        reserved: 25,
+        burst: Duration::from_millis(100),
+        warn_after: Duration::from_secs(2),
+    };
+    // So that the data outlasts the test, rather than failing and having
+    // the peer suspected.
+    config.data_retransmit = RetransmitPolicy {
+        attempts: 100,
+        budget: Duration::from_secs(60),
+    };
+    let mut d = Dispatcher::with_config(::transport::SimTransport::new(),
+                                        clock::ManualClock::new(), &config);
+    join_from_peer(&mut d);
+    // Far more than the cap lets out in the time, in datagrams of 184
+    // bytes on the wire.
+    for _ in 0..200 {
+        d.send_acked(AckedMessage::Data(vec![0; 92]), &peer());
+    }
+
+    // The peer pings us five times a second, and every Pong gets out
+    // within the second, however much bulk is waiting.
+    let mut pings = 0;
+    let mut seconds = Vec::new();
+    for _ in 0..5 {
+        let before = d.transport.sent.borrow().len();
+        for tick in 0..20 {
+            if tick % 4 == 0 {
+                d.transport.deliver(ping_msg().encode(), peer());
+                pings += 1;
+            }
+            d.poll();
+            d.clock.advance(TICK);
+        }
+        let sent = d.transport.sent.borrow();
+        seconds.push(sent[before..].iter()
+                     .map(|&(ref bytes, ref to)| {
+                         bandwidth::wire_bytes(bytes.len(), to)
+                     })
+                     .sum::<usize>());
+    }
+    let pongs = d.transport.sent.borrow().iter()
+        .filter(|&&(ref bytes, _)| Message::decode(bytes).kind()
+                                   == MessageKind::Pong)
+        .count();
+    assert_eq!(pongs, pings);
+    assert_eq!(d.outbound.depth(Band::Control), 0);
+
+    // Never more than a second at the cap and the burst.
+    assert!(seconds.iter().all(|&bytes| bytes <= 3000 + 300),
+            "{:?}", seconds);
+    assert!(seconds.iter().all(|&bytes| bytes > 2000), "{:?}", seconds);
+    assert!(d.outbound.depth(Band::Bulk) > 0);
+    assert!(d.stats.bandwidth_held > 0);
+    assert!(d.stats.bandwidth_utilization > 50);
+    assert_eq!(d.stats.bandwidth_warnings, 1);
+}
+
+#[test]
+fn a_wedged_scheduler_is_reported_as_stalled() {
+    use scheduler::Scheduler;
+    use std::thread;
+
+    let s = Scheduler::new();
+    let mut d = test_dispatcher();
+    let mut config = Config::default();
+    config.watchdog.interval = Duration::from_millis(10);
+    config.watchdog.tolerance = Duration::from_secs(1);
+    assert_eq!(d.reload(&config), vec!["watchdog"]);
+    d.watch(s.handle());
+
+    // A heartbeat goes out and, on a healthy scheduler, comes back.
+    d.poll();
+    thread::sleep(Duration::from_millis(100));
+    d.poll();
+    assert_eq!(d.stats.scheduler_lag.len(), 1);
+
+    d.poll();
+    s.handle().wedge(Duration::from_millis(500));
+    d.clock.advance(Duration::from_secs(2));
+    d.poll();
+    d.poll();
+    let stalls: Vec<Duration> = d.events.drain(..).filter_map(|e| match e {
+        MeshEvent::SchedulerStalled { behind } => Some(behind),
+        _ => None,
+    }).collect();
+    assert_eq!(stalls, vec![Duration::from_millis(1990)]);
+    assert_eq!(d.stats.scheduler_stalls, 1);
+
+    // Unstuck, it catches up, and the lateness shows in its lag.
+    thread::sleep(Duration::from_millis(700));
+    d.poll();
+    assert!(d.stats.scheduler_lag.p99().unwrap()
+            >= Duration::from_millis(400));
+}
+
+#[test]
+fn dropped_frames_are_explained_only_when_asked() {
+    let mut d = test_dispatcher();
+    d.add_inbound_hook(|_, msg| match *msg {
+        Message::Acked(..) => HookAction::Drop,
+        _ => HookAction::Continue,
+    });
+    let newer = message::VERSION_FLAG | (message::PROTOCOL_VERSION + 1);
+    d.transport.deliver(join_msg(1, NodeId(1)).encode(), peer());
+    d.transport.deliver(vec![newer, 2, 0, 0], peer());
+    d.poll();
+    d.poll();
+    assert!(d.transport.sent.borrow().is_empty());
+
+    let mut config = Config::default();
+    config.explain_rejects = true;
+    assert_eq!(d.reload(&config), vec!["explain_rejects"]);
+    d.transport.deliver(vec![newer, 2, 0, 0], peer());
+    d.poll();
+    let bad_version = Message::Rejected(RejectCode::BadVersion);
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(bad_version.encode(), peer())]);
+
+    // However much more it sends, a source is told only a few times.
+    d.transport.sent.borrow_mut().clear();
+    for seq in 2..10 {
+        d.transport.deliver(join_msg(seq, NodeId(1)).encode(), peer());
+        d.poll();
+    }
+    let blocked = Message::Rejected(RejectCode::Blocked).encode();
+    assert_eq!(*d.transport.sent.borrow(),
+               vec![(blocked.clone(), peer()), (blocked, peer())]);
+    assert_eq!(d.stats.rejects_sent, 3);
+    assert!(d.peers().is_empty());
+}
+
+#[test]
+fn logging_unsolicited_frames_can_be_reloaded() {
+    let mut d = test_dispatcher();
+    let mut config = Config::default();
+    config.log_unsolicited = true;
+    assert_eq!(d.reload(&config), vec!["log_unsolicited"]);
+    assert!(d.log_unsolicited);
+    assert_eq!(d.reload(&config), Vec::<&str>::new());
+}
+
+#[test]
+fn only_peers_pings_are_answered_when_asked() {
+    let mut d = test_dispatcher();
+    join_from_peer(&mut d);
+    let mut config = Config::default();
+    config.ping_strangers = false;
+    assert_eq!(d.reload(&config), vec!["ping_strangers"]);
+
+    let stranger: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    d.transport.deliver(ping_msg().encode(), stranger);
+    d.poll();
+    assert!(d.transport.sent.borrow().is_empty());
+    assert_eq!(d.stats.stranger_pings, 1);
+
+    d.transport.deliver(ping_msg().encode(), peer());
+    d.poll();
+    match Message::decode(&d.transport.sent.borrow()[0].0) {
+        Message::Pong(..) => (),
+        other => panic!("expected Pong, got {:?}", other),
+    }
+    assert_eq!(d.transport.sent.borrow()[0].1, peer());
+    assert_eq!(d.stats.stranger_pings, 1);
+}
+
+#[test]
+fn joins_rejected_for_good_fail_at_once() {
+    let mut d = test_dispatcher();
+    let ticket = d.join_async(&peer());
+    d.poll();
+
+    // Anyone else can't fail it for us.
+    let elsewhere: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    let mismatch = Message::Rejected(RejectCode::ClusterMismatch);
+    d.transport.deliver(mismatch.encode(), elsewhere);
+    d.poll();
+    assert_eq!(d.stats.unsolicited, 1);
+    // Nor does what a retry might get past.
+    d.transport.deliver(Message::Rejected(RejectCode::Blocked).encode(),
+                        peer());
+    d.poll();
+    assert_eq!(ticket.status(), JoinStatus::Pending);
+
+    d.transport.deliver(mismatch.encode(), peer());
+    d.poll();
+    assert_eq!(ticket.status(), JoinStatus::Failed(
+        MeshError::Rejected(RejectCode::ClusterMismatch)));
+    assert_eq!(d.stats.rejects_received, 2);
+    assert!(d.pending.is_empty());
+    let completed: Vec<JoinStatus> = d.events.drain(..)
+        .filter_map(|e| match e {
+            MeshEvent::JoinCompleted { status, .. } => Some(status),
+            _ => None,
+        }).collect();
+    assert_eq!(completed, vec![ticket.status()]);
+
+    // And it's given up on, not retried.
+    d.clock.advance(RETRANSMIT * 4);
+    d.poll();
+    assert_eq!(d.transport.sent.borrow().len(), 1);
+}
+
+#[test]
+fn peers_show_the_version_they_joined_with() {
+    use compat;
+
+    let mut d = test_dispatcher();
+    // A Join as the oldest version we read sent it: version, type, then
+    // session, seq, inner tag, id and version.
+    let old = message::MIN_PROTOCOL_VERSION;
+    let frame = message::frame(&[message::VERSION_FLAG | old, 0],
+                               &[0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 0,
+                                 0, 0, 0, 0, 0, 0, 0, 1, old]);
+    assert!(compat::parse_frame(old, &frame).is_ok());
+    d.transport.deliver(frame, peer());
+    d.poll();
+    assert_eq!(d.peers()[0].version(), Some(old));
+
+    d.transport.deliver(join_msg(2, NodeId(1)).encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].version(), Some(message::PROTOCOL_VERSION));
+}
+
+#[test]
+fn snapshots_change_only_with_the_table() {
+    let mut d = test_dispatcher();
+    let handle = d.snapshot_handle();
+    let empty = handle.lock().unwrap().clone();
+    assert!(empty.peers.is_empty());
+    d.poll();
+    assert!(Arc::ptr_eq(&empty, &handle.lock().unwrap()));
+
+    join_from_peer(&mut d);
+    let joined = handle.lock().unwrap().clone();
+    assert!(joined.generation > empty.generation);
+    assert_eq!(joined.peers, d.peers());
+    d.poll();
+    assert!(Arc::ptr_eq(&joined, &handle.lock().unwrap()));
+
+    // Hearing from a peer changes only when it was last heard from.
+    d.clock.advance(Duration::from_millis(10));
+    d.transport.deliver(Message::Keepalive.encode(), peer());
+    d.poll();
+    assert_eq!(d.peers()[0].last_seen(), d.clock.now());
+    assert!(Arc::ptr_eq(&joined, &handle.lock().unwrap()));
+
+    // Seen at once, for an operator waiting on it.
+    d.evict(NodeId(1)).unwrap();
+    assert_eq!(handle.lock().unwrap().peers[0].state(), PeerState::Dead);
+}
+
+#[test]
+fn snapshots_hold_still_for_readers_while_peers_churn() {
+    use std::thread;
+
+    // Peer `i` is at port 10000 + i, in every snapshot, or it's torn.
+    let at = |i: u64| -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), 10000 + i as u16)
+    };
+    // Room for every one to join, none acking our Joins back.
+    let mut d = Dispatcher::with_config(
+        ::transport::SimTransport::new(), clock::ManualClock::new(),
+        &Config {
+            limits: Limits { joins: 64, join_handshakes: 64,
+                             .. Limits::default() },
+            .. Config::default()
+        });
+    let handle = d.snapshot_handle();
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4).map(|_| {
+        let handle = handle.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut generation = 0;
+            while !done.load(Ordering::SeqCst) {
+                let snapshot = handle.lock().unwrap().clone();
+                assert!(snapshot.generation >= generation);
+                generation = snapshot.generation;
+                for p in &snapshot.peers {
+                    assert_eq!(p.addr().port() as u64, 10000 + p.id().0);
+                }
+                let ids: Vec<NodeId> = snapshot.peers.iter()
+                    .map(|p| p.id()).collect();
+                let mut sorted = ids.clone();
+                sorted.sort();
+                sorted.dedup();
+                assert_eq!(ids, sorted);
+            }
+            generation
+        })
+    }).collect();
+
+    for round in 0..20 {
+        for i in 1..51 {
+            d.transport.deliver(join_msg(round + 1, NodeId(i)).encode(),
+                                at(i));
+            d.poll();
+        }
+        for i in (1..51).filter(|i| i % 5 == round as u64 % 5) {
+            d.evict(NodeId(i)).unwrap();
+        }
+        d.clock.advance(TICK);
+        d.poll();
+    }
+    done.store(true, Ordering::SeqCst);
+    let last = handle.lock().unwrap().generation;
+    for r in readers {
+        assert!(r.join().unwrap() <= last);
+    }
+    assert_eq!(handle.lock().unwrap().peers.len(), 50);
+}
+
+#[cfg(test)]
+fn ordered_msg(n: u32, stream: u32) -> Message {
+    Message::Acked(peer_seq(n),
+                   AckedMessage::Ordered(stream, vec![stream as u8]))
+}
+
+#[cfg(test)]
+fn data_events(d: &mut Dispatcher<::transport::SimTransport,
+                                  clock::ManualClock>) -> Vec<Vec<u8>> {
+    d.events.drain(..).filter_map(|e| match e {
+        MeshEvent::Data(_, data) => Some(data),
+        _ => None,
+    }).collect()
+}
+
+// Every seq acked so far, once the acks owed have gone out.
+#[cfg(test)]
+fn acked(d: &mut Dispatcher<::transport::SimTransport, clock::ManualClock>)
+        -> Vec<Seq> {
+    d.clock.advance(ACK_DELAY);
+    d.poll();
+    let mut seqs = Vec::new();
+    for (bytes, _) in d.transport.sent.borrow_mut().drain(..) {
+        match Message::decode(&bytes) {
+            Message::Ack(seq, _, _) => seqs.push(seq),
+            Message::AckMulti(more) => seqs.extend(more),
+            _ => (),
+        }
+    }
+    seqs.sort();
+    seqs
+}
+
+// Every seq of peer()'s acked so far, by its count.
+#[cfg(test)]
+fn acked_seqs(d: &mut Dispatcher<::transport::SimTransport,
+                                clock::ManualClock>) -> Vec<u32> {
+    let seqs = acked(d);
+    assert!(seqs.iter().all(|seq| seq.session == peer_seq(0).session));
+    seqs.iter().map(|seq| seq.n).collect()
+}
+
+// Poll `d` until it has received everything delivered to it, a datagram
+// a poll.
+#[cfg(test)]
+fn poll_all(d: &mut Dispatcher<::transport::SimTransport,
+                               clock::ManualClock>) {
+    d.poll();
+    while d.transport.queued() > 0 {
+        d.poll();
+    }
+}
+
+// Let `by` pass, a millisecond at a time, for `model`, `from` and `to`,
+// handing `to` whatever of `from`'s arrives, as from peer(), when it
+// does. Returns what was handed over, in the order it arrived.
+#[cfg(test)]
+fn carry(model: &LatencyModel,
+         from: &mut Dispatcher<::transport::SimTransport, clock::ManualClock>,
+         to: &mut Dispatcher<::transport::SimTransport, clock::ManualClock>,
+         by: Duration) -> Vec<Vec<u8>> {
+    let step = Duration::from_millis(1);
+    let mut carried = Vec::new();
+    for _ in 0..Nanos::from(by) / Nanos::from(step) {
+        model.advance(step);
+        from.clock.advance(step);
+        to.clock.advance(step);
+        from.poll();
+        for (bytes, _) in from.transport.sent.borrow_mut().drain(..) {
+            to.transport.deliver(bytes.clone(), peer());
+            carried.push(bytes);
+        }
+        poll_all(to);
+    }
+    carried
+}
+
+#[test]
+fn ordered_messages_reach_the_application_in_order() {
+    // Sent over a link jittery enough that they overtake one another.
+    let jitter = Latency::Uniform(Duration::from_millis(1),
+                                  Duration::from_millis(40));
+    let model = Rc::new(LatencyModel::new(7, jitter));
+    let mut sender = test_dispatcher();
+    *sender.transport.delays.borrow_mut() = Some(model.clone());
+    let mut d = test_dispatcher();
+    for i in 0..8 {
+        sender.send_ordered(vec![i], &peer());
+    }
+    sender.poll();
+    let arrived = carry(&model, &mut sender, &mut d,
+                        Duration::from_millis(40));
+    let streams: Vec<u32> = arrived.iter()
+        .filter_map(|bytes| match Message::decode(bytes) {
+            Message::Acked(_, AckedMessage::Ordered(stream, _)) => Some(stream),
+            _ => None,
+        }).collect();
+    assert_eq!(streams.len(), 8);
+    assert!(streams != (0..8).collect::<Vec<_>>());
+    assert_eq!(data_events(&mut d),
+               (0..8).map(|i| vec![i]).collect::<Vec<_>>());
+    // Each was acked even so, and a retransmission of one isn't delivered
+    // again.
+    let session = sender.next_seq.session;
+    let seqs = acked(&mut d);
+    assert_eq!(seqs.len(), 8);
+    assert!(seqs.iter().all(|seq| seq.session == session));
+    d.transport.deliver(arrived[0].clone(), peer());
+    d.poll();
+    assert!(data_events(&mut d).is_empty());
+    assert_eq!(acked(&mut d).len(), 1);
+
+    // The unordered path is as it was.
+    let m = Message::Acked(peer_seq(5), AckedMessage::Data(vec![9]));
+    d.transport.deliver(m.encode(), peer());
+    d.poll();
+    assert_eq!(data_events(&mut d), vec![vec![9]]);
+
+    // And ours go out numbered in turn.
+    acked_seqs(&mut d);
+    d.send_ordered(vec![1], &peer());
+    d.send_ordered(vec![2], &peer());
+    d.poll();
+    let streams: Vec<u32> = d.transport.sent.borrow().iter()
+        .filter_map(|&(ref bytes, _)| match Message::decode(bytes) {
+            Message::Acked(_, AckedMessage::Ordered(stream, _)) => Some(stream),
+            _ => None,
+        }).collect();
+    assert_eq!(streams, vec![0, 1]);
+}
+
+#[test]
+fn ordered_messages_past_the_window_are_left_to_be_resent() {
+    use protocol::limits::ORDERED_WINDOW;
+
+    let mut d = test_dispatcher();
+    for stream in 1..ORDERED_WINDOW {
+        d.transport.deliver(ordered_msg(stream, stream).encode(), peer());
+    }
+    poll_all(&mut d);
+    assert_eq!(acked_seqs(&mut d).len(), ORDERED_WINDOW as usize - 1);
+    // Too far ahead to hold: not acked, so its sender tries again.
+    d.transport.deliver(ordered_msg(ORDERED_WINDOW, ORDERED_WINDOW).encode(),
+                        peer());
+    d.poll();
+    assert_eq!(d.stats.ordered_refused, 1);
+    assert!(acked_seqs(&mut d).is_empty());
+    assert!(data_events(&mut d).is_empty());
+
+    d.transport.deliver(ordered_msg(ORDERED_WINDOW + 1, 0).encode(), peer());
+    d.poll();
+    assert_eq!(data_events(&mut d).len(), ORDERED_WINDOW as usize);
+    d.transport.deliver(ordered_msg(ORDERED_WINDOW, ORDERED_WINDOW).encode(),
+                        peer());
+    d.poll();
+    assert_eq!(data_events(&mut d), vec![vec![ORDERED_WINDOW as u8]]);
+}
+
+#[test]
+fn a_missing_ordered_message_is_given_up_on_after_the_retransmit_budget() {
+    let mut d = test_dispatcher();
+    d.transport.deliver(ordered_msg(2, 1).encode(), peer());
+    d.poll();
+    assert!(data_events(&mut d).is_empty());
+
+    let budget = Config::default().data_retransmit.budget;
+    d.clock.advance(budget);
+    d.poll();
+    assert_eq!(data_events(&mut d), vec![vec![1]]);
+    assert_eq!(d.stats.ordered_skipped, 1);
+    // Should it turn up after all, it's too late.
+    d.transport.deliver(ordered_msg(1, 0).encode(), peer());
+    d.poll();
+    assert!(data_events(&mut d).is_empty());
+}
+
+// The ordered messages among what `d` has sent, by their stream numbers,
+// taken out of `d.transport.sent`.
+#[cfg(test)]
+fn sent_ordered(d: &mut Dispatcher<::transport::SimTransport,
+                                   clock::ManualClock>)
+        -> Vec<(u32, Vec<u8>)> {
+    let mut sent = Vec::new();
+    for (bytes, _) in d.transport.sent.borrow_mut().drain(..) {
+        if let Message::Acked(_, AckedMessage::Ordered(stream, _)) =
+                Message::decode(&bytes) {
+            sent.push((stream, bytes));
+        }
+    }
+    sent
+}
+
+// The acks of any sort among what `d` has sent, which is cleared.
+#[cfg(test)]
+fn sent_acks(d: &mut Dispatcher<::transport::SimTransport,
+                                clock::ManualClock>) -> Vec<Message> {
+    d.transport.sent.borrow_mut().drain(..)
+        .map(|(bytes, _)| Message::decode(&bytes))
+        .filter(|m| m.kind() == MessageKind::Ack).collect()
+}
+
+#[test]
+fn a_burst_with_one_loss_resends_only_that_one() {
+    let mut sender = test_dispatcher();
+    let mut receiver = test_dispatcher();
+    // Each takes the other to be peer(); the receiver has had its Join,
+    // so knows it takes AckSacks.
+    join_from_peer(&mut receiver);
+
+    for i in 0..10 {
+        sender.send_ordered(vec![i], &peer());
+    }
+    sender.poll();
+    for (stream, bytes) in sent_ordered(&mut sender) {
+        if stream != 3 {
+            receiver.transport.deliver(bytes, peer());
+        }
+    }
+    poll_all(&mut receiver);
+    receiver.clock.advance(ACK_DELAY);
+    receiver.poll();
+    let sacks = sent_acks(&mut receiver);
+    let session = sender.next_seq.session;
+    assert_eq!(sacks, vec![Message::AckSack(session, 3, 0b111111)]);
+
+    for sack in &sacks {
+        sender.transport.deliver(sack.encode(), peer());
+    }
+    sender.poll();
+    sender.clock.advance(RETRANSMIT);
+    sender.poll();
+    let resent = sent_ordered(&mut sender);
+    assert_eq!(resent.iter().map(|&(stream, _)| stream).collect::<Vec<_>>(),
+               vec![3]);
+
+    // Which fills the gap, and the next AckSack covers everything.
+    receiver.transport.deliver(resent[0].1.clone(), peer());
+    receiver.poll();
+    assert_eq!(data_events(&mut receiver),
+               (0..10).map(|i| vec![i]).collect::<Vec<_>>());
+    receiver.clock.advance(ACK_DELAY);
+    receiver.poll();
+    let sacks = sent_acks(&mut receiver);
+    assert_eq!(sacks, vec![Message::AckSack(session, 10, 0)]);
+    sender.transport.deliver(sacks[0].encode(), peer());
+    sender.poll();
+    assert!(sender.pending.is_empty());
+}
+
+#[test]
+fn a_lost_sack_is_made_up_for_by_the_next() {
+    let mut d = test_dispatcher();
+    for i in 0..4 {
+        d.send_ordered(vec![i], &peer());
+    }
+    let data = d.send_acked(AckedMessage::Data(vec![9]), &peer());
+    d.poll();
+    // The AckSack for the first two went missing; this one says the
+    // first three arrived, and nothing of the Data.
+    let session = d.next_seq.session;
+    d.transport.deliver(Message::AckSack(session, 3, 0).encode(), peer());
+    d.poll();
+    let pending: Vec<Option<u32>> = {
+        let mut seqs: Vec<&Seq> = d.pending.keys().collect();
+        seqs.sort();
+        seqs.into_iter().map(|seq| d.pending[seq].stream).collect()
+    };
+    assert_eq!(pending, vec![Some(3), None]);
+    // The Data still takes a plain Ack.
+    d.transport.deliver(ack_from_peer(data).encode(), peer());
+    d.poll();
+    assert_eq!(d.pending.len(), 1);
+
+    // Ordered messages from a peer on an older version get plain Acks.
+    let mut old = test_dispatcher();
+    let v5_join = Message::Acked(peer_seq(1),
+                                 AckedMessage::Join(NodeId(1), 5));
+    old.transport.deliver(v5_join.encode(), peer());
+    old.poll();
+    old.transport.sent.borrow_mut().clear();
+    old.transport.deliver(ordered_msg(2, 0).encode(), peer());
+    assert_eq!(acked_seqs(&mut old), vec![2]);
+}
+
+#[test]
+fn run_returns_on_shutdown() {
+    let mut d = test_dispatcher();
+    d.shutdown_handle().store(true, Ordering::SeqCst);
+    d.run();
+}
+
+#[test]
+fn slow_handlers_are_timed_by_kind_and_warned_of() {
+    let clock = Rc::new(clock::ManualClock::new());
+    let mut d = Dispatcher::new(::transport::SimTransport::new(),
+                                clock.clone());
+    d.timing.slow = Some(Duration::from_millis(10));
+    let slow = clock.clone();
+    d.set_handler(MessageKind::Data, move |_, _, _| {
+        slow.advance(Duration::from_millis(30));
+    });
+    let data = |n| {
+        Message::Acked(peer_seq(n), AckedMessage::Data(vec![n as u8]))
+    };
+    d.transport.deliver(data(1).encode(), peer());
+    d.poll();
+    assert_eq!(d.stats.slow_handlings, 1);
+    let times = d.stats.handling.get(MessageKind::Data).unwrap().clone();
+    assert_eq!(times.len(), 1);
+    // Its bucket runs from 28.8ms to 32ms.
+    assert_eq!(times.p99(), Some(Duration::from_millis(32)));
+
+    // A Ping takes no time at all, as far as the clock can tell.
+    let body = PingBody { nonce: 1, sent_at_micros: 0, pad: vec![] };
+    d.transport.deliver(Message::Ping(body).encode(), peer());
+    d.poll();
+    assert_eq!(d.stats.slow_handlings, 1);
+    let pings = d.stats.handling.get(MessageKind::Ping).unwrap();
+    assert_eq!(pings.p99(), Some(Duration::from_micros(100)));
+
+    // Nor is anything timed with timing turned off.
+    d.timing.enabled = false;
+    d.transport.deliver(data(2).encode(), peer());
+    d.poll();
+    assert_eq!(d.stats.slow_handlings, 1);
+    assert_eq!(d.stats.handling.get(MessageKind::Data).unwrap().len(), 1);
+    assert_eq!(d.stats.handling.total().len(), 2);
+}
@@ -0,0 +1,259 @@
+use config::{self, Config};
+use hooks::HookAction;
+use message::Message;
+use node;
+use rustc_serialize::json;
+use sockopts;
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Datagram sizes to try sending ourselves, largest first: the most UDP
+// can carry, a jumbo frame, and what fits in a 1500 byte Ethernet MTU
+// and the smaller MTUs seen on tunnels and the like.
+const LOOPBACK_SIZES: [usize; 5] = [65507, 8972, 1472, 1200, 548];
+
+// A node that can't get a single-frame datagram to itself will struggle
+// with padded Pings, never mind anything bigger.
+const LOOPBACK_MIN: usize = 1472;
+
+// How long TARGET has to answer a Ping.
+pub const PING_TIMEOUT_MS: u64 = 2000;
+
+// How one check went, and what it found.
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<String, String>) -> Check {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        Check { name: name.to_string(), passed: passed, detail: detail }
+    }
+}
+
+// Every check that applies to a node started with `config` and joining
+// `target`, in the order `mesh doctor` runs them.
+pub fn diagnostics(config: &Config, target: Option<&str>) -> Vec<Check> {
+    let mut checks = vec![check_bind(config), check_loopback(),
+                          check_cluster(config)];
+    if let Some(target) = target {
+        checks.push(check_target(config, target,
+                                 Duration::from_millis(PING_TIMEOUT_MS)));
+    }
+    checks
+}
+
+// Can we bind the host and port asked for, with the socket options asked
+// for? Says what the kernel made of those options, where it can.
+pub fn check_bind(config: &Config) -> Check {
+    let result = config.socket_opts().bind((&config.host[..], config.port))
+        .and_then(|s| match sockopts::socket_info(&s) {
+            Ok(info) => Ok(info.to_string()),
+            Err(_) => s.local_addr().map(|addr| addr.to_string()),
+        })
+        .map(|bound| format!("bound {}", bound))
+        .map_err(|e| format!("can't bind {}:{}: {}", config.host,
+                             config.port, e));
+    Check::new("bind", result)
+}
+
+// How big a datagram can we send ourselves over loopback?
+pub fn check_loopback() -> Check {
+    Check::new("loopback", largest_loopback().and_then(|largest| {
+        if largest >= LOOPBACK_MIN {
+            Ok(format!("sent ourselves {} bytes", largest))
+        } else if largest > 0 {
+            Err(format!("sent ourselves only {} bytes, under {}", largest,
+                        LOOPBACK_MIN))
+        } else {
+            Err("couldn't send ourselves anything".to_string())
+        }
+    }))
+}
+
+fn largest_loopback() -> Result<usize, String> {
+    let socket = try!(UdpSocket::bind("127.0.0.1:0")
+        .map_err(|e| format!("can't bind loopback: {}", e)));
+    let addr = try!(socket.local_addr().map_err(|e| e.to_string()));
+    try!(socket.set_read_timeout(Some(Duration::from_millis(200)))
+        .map_err(|e| e.to_string()));
+    let mut buf = vec![0; LOOPBACK_SIZES[0] + 1];
+    for &size in LOOPBACK_SIZES.iter() {
+        if socket.send_to(&buf[..size], addr).is_err() {
+            continue;
+        }
+        match socket.recv_from(&mut buf) {
+            Ok((amt, _)) if amt == size => return Ok(size),
+            _ => (),
+        }
+    }
+    Ok(0)
+}
+
+// Is the cluster name one we can print on a READY line?
+pub fn check_cluster(config: &Config) -> Check {
+    let result = config::check_cluster(&config.cluster)
+        .map(|_| format!("cluster {}", config.cluster))
+        .map_err(|e| e.to_string());
+    Check::new("cluster", result)
+}
+
+// Does `target` resolve to somewhere we may send, and does it answer a
+// Ping within `timeout`? Pings from a node of our own on an OS-assigned
+// port, so as not to need the one in `config`. A target that only
+// answers its peers (see Config::ping_strangers) fails this.
+pub fn check_target(config: &Config, target: &str, timeout: Duration)
+        -> Check {
+    Check::new("target", ping_target(config, target, timeout))
+}
+
+fn ping_target(config: &Config, target: &str, timeout: Duration)
+        -> Result<String, String> {
+    let addr = try!(node::resolve(target)
+        .map_err(|e| format!("{}: {}", target, e)));
+    let config = Config {
+        host: config.host.clone(),
+        .. Config::default()
+    };
+    let mut node = try!(node::start(&config)
+        .map_err(|e| format!("can't start a node to ping from: {}", e)));
+
+    let answered = Rc::new(Cell::new(false));
+    let flag = answered.clone();
+    node.add_inbound_hook(move |src, msg| {
+        if let Message::Pong(..) = *msg {
+            if *src == addr {
+                flag.set(true);
+            }
+        }
+        HookAction::Continue
+    });
+    let start = Instant::now();
+    node.ping(&addr);
+    while !answered.get() && start.elapsed() < timeout {
+        node.poll();
+    }
+    if answered.get() {
+        Ok(format!("{} answered in {:?}", addr, start.elapsed()))
+    } else {
+        Err(format!("{} didn't answer within {:?} (it won't if it was \
+                     started with --no-ping-reply)", addr, timeout))
+    }
+}
+
+// Run every check, printing how each went to `out`, one per line as
+// `PASS name: detail` (or FAIL), or as a JSON object if `config.json`.
+// Returns whether they all passed.
+pub fn run<W: Write>(config: &Config, target: Option<&str>, out: &mut W)
+        -> io::Result<bool> {
+    let checks = diagnostics(config, target);
+    for check in checks.iter() {
+        if config.json {
+            try!(writeln!(out, "{}", json::encode(check).unwrap()));
+        } else {
+            try!(writeln!(out, "{} {}: {}",
+                          if check.passed { "PASS" } else { "FAIL" },
+                          check.name, check.detail));
+        }
+    }
+    Ok(checks.iter().all(|c| c.passed))
+}
+
+#[test]
+fn bind_fails_on_an_occupied_port() {
+    let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let config = Config {
+        port: taken.local_addr().unwrap().port(),
+        .. Config::default()
+    };
+    let check = check_bind(&config);
+    assert_eq!(check.name, "bind");
+    assert!(!check.passed, "{:?}", check);
+
+    assert!(check_bind(&Config::default()).passed);
+}
+
+#[test]
+fn loopback_carries_a_full_frame() {
+    let check = check_loopback();
+    assert!(check.passed, "{:?}", check);
+}
+
+#[test]
+fn cluster_names_must_print_cleanly() {
+    for &(name, ok) in [("mesh", true), ("prod-eu", true), ("", false),
+                        ("prod eu", false), ("prod\neu", false)].iter() {
+        let config = Config { cluster: name.to_string(),
+                              .. Config::default() };
+        assert_eq!(check_cluster(&config).passed, ok, "{:?}", name);
+    }
+}
+
+#[test]
+fn target_check_fails_on_dead_or_bogus_targets() {
+    let dead = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let timeout = Duration::from_millis(300);
+    let check = check_target(&Config::default(), &dead.to_string(), timeout);
+    assert!(!check.passed);
+    assert!(check.detail.contains("didn't answer"), "{}", check.detail);
+
+    let check = check_target(&Config::default(), "255.255.255.255:9000",
+                             timeout);
+    assert!(!check.passed);
+    assert!(check.detail.contains("broadcast"), "{}", check.detail);
+}
+
+#[test]
+fn target_check_passes_against_a_running_node() {
+    use std::sync::mpsc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    let (tx, rx) = mpsc::channel();
+    let seed = thread::spawn(move || {
+        let mut node = node::start(&Config::default()).unwrap();
+        tx.send((node.local_addr().unwrap(), node.shutdown_handle())).unwrap();
+        node.run();
+    });
+    let (addr, shutdown) = rx.recv().unwrap();
+    let check = check_target(&Config::default(), &addr.to_string(),
+                             Duration::from_secs(5));
+    shutdown.store(true, Ordering::SeqCst);
+    seed.join().unwrap();
+    assert!(check.passed, "{:?}", check);
+}
+
+#[test]
+fn run_reports_each_check() {
+    let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut config = Config {
+        port: taken.local_addr().unwrap().port(),
+        .. Config::default()
+    };
+    let mut out = Vec::new();
+    assert!(!run(&config, None, &mut out).unwrap());
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("FAIL bind: "), "{}", lines[0]);
+    assert!(lines[1].starts_with("PASS loopback: "), "{}", lines[1]);
+    assert_eq!(lines[2], "PASS cluster: cluster mesh");
+
+    config.port = 0;
+    config.json = true;
+    let mut out = Vec::new();
+    assert!(run(&config, None, &mut out).unwrap());
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().last().unwrap(),
+               "{\"name\":\"cluster\",\"passed\":true,\
+                \"detail\":\"cluster mesh\"}");
+}
@@ -0,0 +1,189 @@
+use clock::Nanos;
+use histogram::Histogram;
+use history::Change;
+use members::{Peer, Suspicion};
+use message::{Health, Seq};
+use rustc_serialize::json;
+use size::SizeEstimate;
+use stats::Stats;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// Everything a node knows that's worth attaching to a bug report, as
+// Dispatcher::debug_dump gathers it, for writing out with `to_json`.
+// Times are in milliseconds before (`_ago_ms`) or after (`_in_ms`) the
+// dump was taken.
+#[derive(RustcEncodable)]
+pub struct DebugDump {
+    pub id: String,
+    pub incarnation: u64,
+    pub addr: Option<String>,
+    pub advertised: Option<String>,
+    // The settings the node is running with, as their Debug shows them:
+    // those it was started with, as reloaded since. Nothing in a Config
+    // is secret.
+    pub config: String,
+    pub peers: Vec<PeerDump>,
+    // Acked messages not yet acked, oldest first.
+    pub pending: Vec<PendingDump>,
+    // Soonest first.
+    pub timers: Vec<TimerDump>,
+    pub stats: Stats,
+    // Newest first.
+    pub history: Vec<ChangeDump>,
+    // The latest malformed datagrams, in hex, oldest first; see
+    // protocol::limits::MALFORMED_KEPT.
+    pub malformed: Vec<String>,
+    // See Dispatcher::estimated_size.
+    pub size: SizeEstimate,
+}
+
+impl DebugDump {
+    pub fn to_json(&self) -> String {
+        json::as_pretty_json(self).to_string()
+    }
+}
+
+// Everything in a Peer.
+#[derive(RustcEncodable)]
+pub struct PeerDump {
+    pub id: String,
+    pub addr: String,
+    pub state: String,
+    // See Dispatcher::evict.
+    pub evicted: bool,
+    pub rtt_us: Option<u64>,
+    pub rtts: Histogram,
+    pub last_seen_ago_ms: u64,
+    pub tags: BTreeMap<String, String>,
+    pub version: Option<u8>,
+    pub mtu: Option<usize>,
+    pub incarnation: Option<u64>,
+    pub health: Option<Health>,
+    pub suspicion: Option<SuspicionDump>,
+}
+
+impl PeerDump {
+    pub fn of(peer: &Peer, evicted: bool, now: Instant) -> PeerDump {
+        PeerDump {
+            id: peer.id().to_string(),
+            addr: peer.addr().to_string(),
+            state: format!("{:?}", peer.state()),
+            evicted: evicted,
+            rtt_us: peer.rtt().map(|rtt| Nanos::from(rtt).as_micros()),
+            rtts: peer.rtts().clone(),
+            last_seen_ago_ms: millis(now - peer.last_seen()),
+            tags: peer.tags().clone(),
+            version: peer.version(),
+            mtu: peer.mtu(),
+            incarnation: peer.incarnation(),
+            health: peer.health(),
+            suspicion: peer.suspicion().map(|s| SuspicionDump::of(&s, now)),
+        }
+    }
+}
+
+#[derive(RustcEncodable)]
+pub struct PendingDump {
+    pub seq: Seq,
+    pub kind: String,
+    pub target: String,
+    pub bytes: usize,
+    pub retransmits: u32,
+    pub first_sent_ago_ms: u64,
+}
+
+#[derive(RustcEncodable)]
+pub struct TimerDump {
+    pub label: String,
+    pub due_in_ms: u64,
+}
+
+#[derive(RustcEncodable)]
+pub struct ChangeDump {
+    pub ago_ms: u64,
+    pub peer: String,
+    pub addr: String,
+    // None if the peer was new to us.
+    pub from: Option<String>,
+    pub to: String,
+    pub cause: String,
+    // The suspicion begun or ended; see history::Change.
+    pub suspicion: Option<SuspicionDump>,
+}
+
+impl ChangeDump {
+    pub fn of(change: &Change, now: Instant) -> ChangeDump {
+        ChangeDump {
+            ago_ms: millis(now - change.when),
+            peer: change.peer.to_string(),
+            addr: change.addr.to_string(),
+            from: change.from.map(|state| format!("{:?}", state)),
+            to: format!("{:?}", change.to),
+            cause: format!("{:?}", change.cause),
+            suspicion: change.suspicion.map(|s| SuspicionDump::of(&s, now)),
+        }
+    }
+}
+
+#[derive(RustcEncodable)]
+pub struct SuspicionDump {
+    pub reason: String,
+    pub since_ago_ms: u64,
+    pub by: String,
+}
+
+impl SuspicionDump {
+    pub fn of(suspicion: &Suspicion, now: Instant) -> SuspicionDump {
+        SuspicionDump {
+            reason: format!("{:?}", suspicion.reason),
+            since_ago_ms: millis(now - suspicion.since),
+            by: suspicion.by.to_string(),
+        }
+    }
+}
+
+pub fn millis(d: Duration) -> u64 {
+    Nanos::from(d).as_millis()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn dumps_encode_as_json() {
+    use members::NodeId;
+    use std::net::SocketAddr;
+
+    let now = Instant::now();
+    let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    let mut members = ::members::Members::new();
+    members.join(NodeId(0xab), addr, now);
+    let dump = DebugDump {
+        id: NodeId(1).to_string(),
+        incarnation: 2,
+        addr: Some("127.0.0.1:8000".to_string()),
+        advertised: None,
+        config: format!("{:?}", ::config::Config::default()),
+        peers: vec![PeerDump::of(&members.get(NodeId(0xab)).unwrap(), false,
+                                 now + Duration::from_millis(1500))],
+        pending: Vec::new(),
+        timers: vec![TimerDump { label: "probe".to_string(), due_in_ms: 7 }],
+        stats: Stats::new(),
+        history: Vec::new(),
+        malformed: vec![hex(&[0, 0xab, 9])],
+        size: SizeEstimate { size: 2, min: 2, max: 3, reports: 2 },
+    };
+
+    let parsed = json::Json::from_str(&dump.to_json()).unwrap();
+    let peer = &parsed["peers"][0];
+    assert_eq!(peer["id"].as_string(), Some("00000000000000ab"));
+    assert_eq!(peer["state"].as_string(), Some("Alive"));
+    assert_eq!(peer["last_seen_ago_ms"].as_u64(), Some(1500));
+    assert_eq!(peer["rtts"]["len"].as_u64(), Some(0));
+    assert_eq!(parsed["timers"][0]["due_in_ms"].as_u64(), Some(7));
+    assert_eq!(parsed["stats"]["malformed"].as_u64(), Some(0));
+    assert_eq!(parsed["malformed"][0].as_string(), Some("00ab09"));
+    assert_eq!(parsed["size"]["max"].as_u64(), Some(3));
+}
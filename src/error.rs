@@ -0,0 +1,62 @@
+use members::NodeId;
+use message::RejectCode;
+use std::fmt;
+
+// Why something the mesh was asked to do didn't happen.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MeshError {
+    // An acked message was never acknowledged, however often we resent
+    // it, a join never completed, or a peer waited for never turned up.
+    Timeout,
+    // It would have taken more memory than Config::limits allows.
+    Overloaded,
+    // An outbound hook dropped it.
+    Dropped,
+    // It's larger than the OS will send its target in one datagram, as a
+    // send has shown; see Dispatcher::sent_too_big.
+    TooLarge,
+    // The node we sent it to told us it won't take it, and why (see
+    // Message::Rejected).
+    Rejected(RejectCode),
+    // The node we asked to join won't let us in, saying why (see
+    // Message::JoinRejected).
+    NotAdmitted(String),
+    // A node couldn't be started with the settings given (see
+    // Config::validate), or on the socket asked for.
+    InvalidCluster(String),
+    InvalidProbeInterval,
+    InvalidTarget(String),
+    Bind(String),
+    // There's no such peer, or it's us.
+    UnknownPeer(NodeId),
+    Ourselves,
+    // The node has stopped running.
+    Stopped,
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MeshError::Timeout => write!(f, "timed out"),
+            MeshError::Overloaded => write!(f, "over a memory limit"),
+            MeshError::Dropped => write!(f, "dropped by a hook"),
+            MeshError::TooLarge =>
+                write!(f, "too large for the path to the target"),
+            MeshError::Rejected(code) =>
+                write!(f, "rejected by the target: {}", code),
+            MeshError::NotAdmitted(ref why) =>
+                write!(f, "not admitted: {}", why),
+            MeshError::InvalidCluster(ref name) =>
+                write!(f, "cluster name {:?} is empty or has spaces or \
+                           control characters", name),
+            MeshError::InvalidProbeInterval =>
+                write!(f, "probe interval bounds are zero or out of order"),
+            MeshError::InvalidTarget(ref why) =>
+                write!(f, "bad target: {}", why),
+            MeshError::Bind(ref why) => write!(f, "can't bind: {}", why),
+            MeshError::UnknownPeer(id) => write!(f, "no peer {}", id),
+            MeshError::Ourselves => write!(f, "that's us, not a peer"),
+            MeshError::Stopped => write!(f, "the node has stopped"),
+        }
+    }
+}
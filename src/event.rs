@@ -0,0 +1,281 @@
+use admission::JoinCandidate;
+use error::MeshError;
+use isolation::OnIsolation;
+use join::JoinStatus;
+use members::{NodeId, Peer};
+use message::{MessageKind, Seq};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+#[cfg(test)] use members::{Members, PeerState};
+#[cfg(test)] use std::time::Instant;
+
+// Things that happen in the mesh which an embedder may want to act on.
+#[derive(Clone, Debug)]
+pub enum MeshEvent {
+    // A node we didn't know about joined through us.
+    PeerJoined(Peer),
+    // A newcomer asks to join, and its Join waits for us to decide; see
+    // Dispatcher::require_admission.
+    JoinRequested(JoinCandidate),
+    // A node we didn't know joined through us from the address of peer
+    // `old_id`, and is taken for it restarted: it has taken the old one's
+    // place, which is forgotten, rather than joined beside it, and there's
+    // no PeerJoined for it, nor PeerStateChanged for the old one dying.
+    // See Config::restart_window.
+    PeerRestarted {
+        old_id: NodeId,
+        new_id: NodeId,
+        addr: SocketAddr,
+    },
+    // A peer went from alive to suspect to dead, or back; the Peer is as
+    // it is now.
+    PeerStateChanged(Peer),
+    // A peer sent tags later than those we held; see
+    // Dispatcher::set_tag. The Peer has them.
+    PeerTagsChanged(Peer),
+    // An acked Data message arrived. Retransmissions of it are acked but
+    // not reported again.
+    Data(SocketAddr, Vec<u8>),
+    // We gave up on getting acked message `seq` through to `peer`.
+    DeliveryFailed {
+        peer: SocketAddr,
+        seq: Seq,
+        kind: MessageKind,
+        error: MeshError,
+    },
+    // A join we started came to something, one way or the other.
+    JoinCompleted {
+        target: SocketAddr,
+        status: JoinStatus,
+    },
+    // A well-formed frame of a message type we don't know arrived, most
+    // likely from a node running a newer version.
+    UnknownMessageType {
+        from: SocketAddr,
+        type_id: u8,
+    },
+    // Enough peers agree they see us at an address other than the one we
+    // advertise; see observed::Observations.
+    ExternalAddressDetected(SocketAddr),
+    // This address kept sending us more than ThrottleConfig allows, and
+    // we'll take nothing from it but liveness traffic for `blocked_for`.
+    PeerThrottled {
+        from: SocketAddr,
+        blocked_for: Duration,
+    },
+    // The Scheduler we're watching (see Dispatcher::watch) has a heartbeat
+    // this far overdue: its timer thread is stuck, and whatever it times
+    // isn't happening.
+    SchedulerStalled {
+        behind: Duration,
+    },
+    // Having joined, we've caught up with the mesh, `took` after the
+    // join, knowing of this many members besides ourselves; see
+    // converge::Convergence. Comes once, if at all.
+    Converged {
+        members: usize,
+        took: Duration,
+    },
+    // Or we hadn't by Config::convergence_deadline, knowing of this many
+    // so far.
+    NotConverged {
+        members: usize,
+    },
+    // Every peer has been dead for as long as it takes to suspect one
+    // (see isolation::Isolation), and we're doing as Config::on_isolation
+    // says. Comes again only once a peer has been alive in between.
+    Isolated(OnIsolation),
+}
+
+impl MeshEvent {
+    // The peer a membership event is about. Other events have none.
+    pub fn peer_id(&self) -> Option<NodeId> {
+        match *self {
+            MeshEvent::PeerJoined(ref p) |
+            MeshEvent::PeerStateChanged(ref p) |
+            MeshEvent::PeerTagsChanged(ref p) => Some(p.id()),
+            MeshEvent::PeerRestarted { new_id, .. } => Some(new_id),
+            _ => None,
+        }
+    }
+}
+
+// What a full EventQueue does with another event.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    // Drop the oldest event queued to make room.
+    DropOldest,
+    // Drop the new event.
+    DropNewest,
+    // Keep only the latest membership event for each peer: one replaces
+    // whatever is queued about the same peer, full or not, and moves to
+    // the back. A membership event about a peer with nothing queued makes
+    // room by dropping the oldest other event. Other events, and
+    // membership events once there's nothing else left to drop, are
+    // dropped as with DropNewest.
+    Coalesce,
+}
+
+// Events waiting for a subscriber that may not keep up, at most
+// `capacity` of them, with the Overflow policy it chose deciding what's
+// lost. Counts what it drops and coalesces, so a subscriber can tell it
+// has fallen behind.
+pub struct EventQueue {
+    capacity: usize,
+    overflow: Overflow,
+    events: VecDeque<MeshEvent>,
+    dropped: u64,
+    coalesced: u64,
+}
+
+impl EventQueue {
+    pub fn new(capacity: usize, overflow: Overflow) -> EventQueue {
+        EventQueue {
+            capacity: capacity,
+            overflow: overflow,
+            events: VecDeque::new(),
+            dropped: 0,
+            coalesced: 0,
+        }
+    }
+
+    pub fn push(&mut self, event: MeshEvent) {
+        if self.overflow == Overflow::Coalesce {
+            if let Some(id) = event.peer_id() {
+                let queued = self.events.iter()
+                    .position(|e| e.peer_id() == Some(id));
+                if let Some(i) = queued {
+                    self.events.remove(i);
+                    self.coalesced += 1;
+                } else if self.events.len() >= self.capacity {
+                    let other = self.events.iter()
+                        .position(|e| e.peer_id().is_none());
+                    if let Some(i) = other {
+                        self.events.remove(i);
+                        self.dropped += 1;
+                    }
+                }
+            }
+        }
+
+        if self.events.len() < self.capacity {
+            self.events.push_back(event);
+            return;
+        }
+        self.dropped += 1;
+        if self.overflow == Overflow::DropOldest && self.capacity > 0 {
+            self.events.pop_front();
+            self.events.push_back(event);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<MeshEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    // Events lost to the overflow policy.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // Membership events replaced by later ones about the same peer.
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced
+    }
+}
+
+// Two peers, 'a' and 'b', each joining and then changing state, with Data
+// in between, fed to a queue of three with `overflow`. Returns what's left
+// in the queue, in order, with the queue.
+#[cfg(test)]
+fn fill(overflow: Overflow) -> (Vec<String>, EventQueue) {
+    let mut members = Members::new();
+    let now = Instant::now();
+    let (a, b) = (NodeId(0xa), NodeId(0xb));
+    members.join(a, "10.0.0.1:4000".parse().unwrap(), now);
+    members.join(b, "10.0.0.2:4000".parse().unwrap(), now);
+    let joined = |m: &Members, id| MeshEvent::PeerJoined(m.get(id).unwrap());
+    let data = |n| MeshEvent::Data("10.0.0.3:4000".parse().unwrap(), vec![n]);
+
+    let mut q = EventQueue::new(3, overflow);
+    q.push(joined(&members, a));
+    q.push(data(1));
+    q.push(joined(&members, b));
+    members.set_state(a, PeerState::Suspect);
+    q.push(MeshEvent::PeerStateChanged(members.get(a).unwrap()));
+    q.push(data(2));
+    members.set_state(b, PeerState::Dead);
+    q.push(MeshEvent::PeerStateChanged(members.get(b).unwrap()));
+    (drain(&mut q), q)
+}
+
+// Each event in `q`, briefly.
+#[cfg(test)]
+fn drain(q: &mut EventQueue) -> Vec<String> {
+    let mut events = Vec::new();
+    while let Some(e) = q.pop() {
+        events.push(match e {
+            MeshEvent::PeerJoined(p) => format!("joined {}", p.id().0),
+            MeshEvent::PeerStateChanged(p) =>
+                format!("{} {:?}", p.id().0, p.state()),
+            MeshEvent::Data(_, data) => format!("data {}", data[0]),
+            other => format!("{:?}", other),
+        });
+    }
+    events
+}
+
+#[test]
+fn drop_oldest_keeps_the_latest_events() {
+    let (events, q) = fill(Overflow::DropOldest);
+    assert_eq!(events, vec!["10 Suspect", "data 2", "11 Dead"]);
+    assert_eq!(q.dropped(), 3);
+    assert_eq!(q.coalesced(), 0);
+}
+
+#[test]
+fn drop_newest_keeps_the_earliest_events() {
+    let (events, q) = fill(Overflow::DropNewest);
+    assert_eq!(events, vec!["joined 10", "data 1", "joined 11"]);
+    assert_eq!(q.dropped(), 3);
+    assert_eq!(q.coalesced(), 0);
+}
+
+#[test]
+fn coalesce_keeps_each_peers_latest_state() {
+    let (events, q) = fill(Overflow::Coalesce);
+    // Data 2 found the queue full with nothing to replace.
+    assert_eq!(events, vec!["data 1", "10 Suspect", "11 Dead"]);
+    assert_eq!(q.dropped(), 1);
+    assert_eq!(q.coalesced(), 2);
+}
+
+#[test]
+fn coalesce_makes_room_for_membership_over_other_events() {
+    let mut members = Members::new();
+    let now = Instant::now();
+    let mut q = EventQueue::new(2, Overflow::Coalesce);
+    q.push(MeshEvent::Data("10.0.0.3:4000".parse().unwrap(), vec![1]));
+    for n in 1..4 {
+        members.join(NodeId(n), format!("10.0.0.{}:4000", n).parse().unwrap(),
+                     now);
+        q.push(MeshEvent::PeerJoined(members.get(NodeId(n)).unwrap()));
+    }
+    // The third peer's join found only membership events queued.
+    assert_eq!(drain(&mut q), vec!["joined 1", "joined 2"]);
+    assert_eq!(q.dropped(), 2);
+    assert_eq!(q.coalesced(), 0);
+}
@@ -0,0 +1,196 @@
+use members::NodeId;
+use message::{self, Health, Message, PingBody, Responder, Seq, WireAddr,
+              PROTOCOL_VERSION, VERSION_FLAG};
+#[cfg(test)] use message::{parse_datagram, DecodeError};
+#[cfg(test)] use quickcheck::quickcheck;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+// Acks and Pongs are most of what a busy node receives, and come in only
+// a few layouts, whose fields are all fixed in size once the address's
+// and the last option's are known. So they're read here straight off the
+// datagram, without the general decoder (see decoder::SliceDecoder) and
+// without allocating, once their checksum is found to match. Whatever
+// doesn't fit one of those layouts exactly, damaged, malformed or merely
+// unusual (an old version, a padded Pong, trailing bytes), is left to
+// message::parse_datagram, which for anything read here gives the same
+// message. Keepalives, which are all header, are
+// picked out before even that; see `is_keepalive`.
+
+// Their type bytes; see message::MESSAGE_TYPES.
+const ACK: u8 = 1;
+const PONG: u8 = 3;
+const KEEPALIVE: u8 = 10;
+
+// Whether `bytes` is a Keepalive of this version, its two bytes and no
+// more: to be noted without being throttled or decoded, the one thing it
+// says being said by its having arrived. See Dispatcher::keepalive.
+pub fn is_keepalive(bytes: &[u8]) -> bool {
+    bytes == [VERSION_FLAG | PROTOCOL_VERSION, KEEPALIVE]
+}
+
+// The Ack or Pong `bytes` holds, if it's one of this version in a layout
+// we know; None for parse_datagram to decode instead.
+pub fn parse(bytes: &[u8]) -> Option<Message> {
+    if bytes.len() < 2 || bytes[0] != VERSION_FLAG | PROTOCOL_VERSION {
+        return None;
+    }
+    if bytes[1] != ACK && bytes[1] != PONG {
+        return None;
+    }
+    let contents = match message::checked_contents(bytes) {
+        Ok(contents) => contents,
+        Err(_) => return None,
+    };
+    if bytes[1] == ACK { ack(contents) } else { pong(contents) }
+}
+
+// Its seq, session then count, the address, and who's responding if it
+// says.
+fn ack(body: &[u8]) -> Option<Message> {
+    if body.len() < 8 {
+        return None;
+    }
+    let (addr, rest) = match wire_addr(&body[8..]) {
+        Some(found) => found,
+        None => return None,
+    };
+    let from = match (rest.len(), rest.first()) {
+        (1, Some(&0)) => None,
+        (17, Some(&1)) => Some(Responder {
+            id: NodeId(be(&rest[1..9])),
+            incarnation: be(&rest[9..17]),
+        }),
+        _ => return None,
+    };
+    let seq = Seq::new(be(&body[..4]) as u32, be(&body[4..8]) as u32);
+    Some(Message::Ack(seq, addr, from))
+}
+
+// The nonce and timestamp, no padding, the address, and how the sender
+// is doing if it says.
+fn pong(body: &[u8]) -> Option<Message> {
+    if body.len() < 24 || be(&body[16..24]) != 0 {
+        return None;
+    }
+    let (addr, rest) = match wire_addr(&body[24..]) {
+        Some(found) => found,
+        None => return None,
+    };
+    let health = match (rest.len(), rest.first()) {
+        (1, Some(&0)) => None,
+        (13, Some(&1)) => Some(Health {
+            queued: be(&rest[1..5]) as u32,
+            dropped: be(&rest[5..9]) as u32,
+            alive: be(&rest[9..13]) as u32,
+        }),
+        _ => return None,
+    };
+    let body = PingBody {
+        nonce: be(&body[..8]),
+        sent_at_micros: be(&body[8..16]),
+        // Empty, so not allocated.
+        pad: Vec::new(),
+    };
+    Some(Message::Pong(body, addr, health))
+}
+
+// The WireAddr at the front of `bytes`, and whatever follows it.
+fn wire_addr(bytes: &[u8]) -> Option<(WireAddr, &[u8])> {
+    if bytes.len() < 8 {
+        return None;
+    }
+    let len = match be(&bytes[..8]) {
+        4 => 4,
+        16 => 16,
+        _ => return None,
+    };
+    let ip = &bytes[8..];
+    if ip.len() < len + 2 {
+        return None;
+    }
+    let port = be(&ip[len..len + 2]) as u16;
+    let addr = if len == 4 {
+        SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]), port))
+    } else {
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&ip[..16]);
+        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+    };
+    Some((WireAddr(addr), &ip[len + 2..]))
+}
+
+// A big-endian integer of however many bytes `bytes` is, up to eight.
+fn be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+#[test]
+fn only_usual_acks_and_pongs_take_the_fast_path() {
+    let addr = WireAddr("127.0.0.1:9000".parse().unwrap());
+    let body = PingBody { nonce: 1, sent_at_micros: 2, pad: vec![] };
+    for msg in vec![Message::Ack(Seq::new(7, 1), addr, None),
+                    Message::Pong(body.clone(), addr, None)] {
+        let bytes = msg.encode();
+        assert_eq!(parse(&bytes), Some(msg.clone()));
+        // Cut short, or run on.
+        assert_eq!(parse(&bytes[..bytes.len() - 1]), None);
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(parse(&longer), None);
+        // Damaged, which the general decoder says.
+        let mut damaged = bytes.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert_eq!(parse(&damaged), None);
+        assert_eq!(parse_datagram(&damaged), Err(DecodeError::Corrupt));
+        // Of another version.
+        let mut older = bytes.clone();
+        older[0] -= 1;
+        assert_eq!(parse(&older), None);
+    }
+    let padded = PingBody { pad: vec![0; 4], .. body.clone() };
+    assert_eq!(parse(&Message::Pong(padded, addr, None).encode()), None);
+    assert_eq!(parse(&Message::Ping(body).encode()), None);
+    assert_eq!(parse(&Message::AckMulti(vec![Seq::new(7, 1)]).encode()),
+               None);
+    assert_eq!(parse(&[]), None);
+}
+
+#[test]
+fn keepalives_are_two_bytes_of_this_version() {
+    let bytes = Message::Keepalive.encode();
+    assert_eq!(bytes.len(), 2);
+    assert!(is_keepalive(&bytes));
+    assert_eq!(parse_datagram(&bytes), Ok(Message::Keepalive));
+    // Not the fast path's to decode.
+    assert_eq!(parse(&bytes), None);
+
+    assert!(!is_keepalive(&bytes[..1]));
+    assert!(!is_keepalive(&[bytes[0], bytes[1], 0]));
+    assert!(!is_keepalive(&[bytes[0] - 1, bytes[1]]));
+    assert!(!is_keepalive(&[]));
+    let ping = PingBody { nonce: 1, sent_at_micros: 2, pad: vec![] };
+    assert!(!is_keepalive(&Message::Ping(ping).encode()));
+}
+
+#[test]
+fn prop_fast_path_agrees_with_parse_datagram() {
+    fn prop(msg: Message, cut: usize) -> bool {
+        let bytes = msg.encode();
+        let usual = match msg {
+            Message::Ack(..) => true,
+            Message::Pong(ref body, ..) => body.pad.is_empty(),
+            _ => false,
+        };
+        let agrees = match parse(&bytes) {
+            Some(fast) => usual && fast == msg,
+            None => !usual,
+        };
+        // Whatever is taken from a prefix, the general decoder takes too.
+        let prefix = &bytes[..cut % (bytes.len() + 1)];
+        agrees && parse(prefix).map_or(true, |fast| {
+            parse_datagram(prefix) == Ok(fast)
+        })
+    }
+    quickcheck(prop as fn(Message, usize) -> bool);
+}
@@ -0,0 +1,239 @@
+use builder::Mesh;
+use dump::DebugDump;
+use std::cmp;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Who knows whom in a mesh, and how well, for drawing with Graphviz; see
+// to_dot. Every member that answered is there as it told of itself, with
+// an edge to each peer it knows of, and those that didn't answer only as
+// their peers see them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Graph {
+    // In the order first heard of.
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphNode {
+    pub id: String,
+    pub addr: String,
+    // Alive if it answered; otherwise the most hopeful state any of its
+    // peers has it in.
+    pub state: String,
+    // Whether it answered, rather than being known of second-hand.
+    pub answered: bool,
+}
+
+// A peer as one that answered sees it: its state, and round trip time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+    pub state: String,
+    pub rtt_us: Option<u64>,
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph { nodes: Vec::new(), edges: Vec::new() }
+    }
+
+    // Take in what a member told of itself and its peers.
+    pub fn add(&mut self, dump: &DebugDump) {
+        let addr = dump.addr.clone().unwrap_or_else(|| "?".to_string());
+        match self.nodes.iter().position(|n| n.id == dump.id) {
+            Some(i) => {
+                let node = &mut self.nodes[i];
+                node.addr = addr;
+                node.state = "Alive".to_string();
+                node.answered = true;
+            },
+            None => self.nodes.push(GraphNode {
+                id: dump.id.clone(),
+                addr: addr,
+                state: "Alive".to_string(),
+                answered: true,
+            }),
+        }
+        for peer in dump.peers.iter() {
+            match self.nodes.iter().position(|n| n.id == peer.id) {
+                Some(i) => {
+                    let node = &mut self.nodes[i];
+                    if !node.answered && hope(&peer.state) < hope(&node.state) {
+                        node.state = peer.state.clone();
+                    }
+                },
+                None => self.nodes.push(GraphNode {
+                    id: peer.id.clone(),
+                    addr: peer.addr.clone(),
+                    state: peer.state.clone(),
+                    answered: false,
+                }),
+            }
+            self.edges.push(GraphEdge {
+                from: dump.id.clone(),
+                to: peer.id.clone(),
+                state: peer.state.clone(),
+                rtt_us: peer.rtt_us,
+            });
+        }
+    }
+
+    // As a Graphviz digraph: each node labelled with the start of its id,
+    // its address and state, and dashed if it didn't answer; each edge
+    // labelled with its round trip time, if known, and coloured by the
+    // state it gives its peer.
+    pub fn to_dot(&self) -> String {
+        let mut lines = vec!["digraph mesh {".to_string()];
+        for node in self.nodes.iter() {
+            let short = &node.id[..cmp::min(8, node.id.len())];
+            lines.push(format!("    \"{}\" [label=\"{}\\n{}\\n{}\"{}];",
+                               node.id, short, node.addr, node.state,
+                               if node.answered { "" } else {
+                                   ", style=dashed"
+                               }));
+        }
+        for edge in self.edges.iter() {
+            let rtt = match edge.rtt_us {
+                Some(us) => format!("label=\"{}.{}ms\", ", us / 1000,
+                                    us % 1000 / 100),
+                None => String::new(),
+            };
+            lines.push(format!("    \"{}\" -> \"{}\" [{}color={}];",
+                               edge.from, edge.to, rtt, colour(&edge.state)));
+        }
+        lines.push("}".to_string());
+        lines.join("\n")
+    }
+}
+
+// Lower for states more likely to mean a peer is up.
+fn hope(state: &str) -> u8 {
+    match state {
+        "Alive" => 0,
+        "Suspect" => 1,
+        "Dead" => 2,
+        _ => 3,
+    }
+}
+
+fn colour(state: &str) -> &'static str {
+    match state {
+        "Alive" => "green",
+        "Suspect" => "orange",
+        "Dead" => "red",
+        _ => "gray",
+    }
+}
+
+// Ask every one of `meshes` for its debug_dump at once, and make a Graph
+// of those that answer within `timeout`. A node that has stopped, or
+// doesn't poll in time, is left to its peers to tell of.
+pub fn collect(meshes: &[&Mesh], timeout: Duration) -> Graph {
+    let asked = meshes.iter().filter_map(|m| m.request_dump()).collect();
+    let mut graph = Graph::new();
+    for dump in gather(asked, timeout) {
+        graph.add(&dump);
+    }
+    graph
+}
+
+// The answers from `asked` that come within `timeout` of now, in the
+// order asked, each waited for on a thread of its own. Those yet to come
+// by then are given up on, their threads left to finish whenever they
+// come, or their senders are dropped.
+pub fn gather<T: Send + 'static>(asked: Vec<Receiver<T>>, timeout: Duration)
+        -> Vec<T> {
+    let deadline = Instant::now() + timeout;
+    let (tx, rx) = mpsc::channel();
+    for (i, answer) in asked.into_iter().enumerate() {
+        let tx = tx.clone();
+        thread::spawn(move || if let Ok(answer) = answer.recv() {
+            let _ = tx.send((i, answer));
+        });
+    }
+    drop(tx);
+    let mut answers = Vec::new();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match rx.recv_timeout(deadline - now) {
+            Ok(answer) => answers.push(answer),
+            // Everyone has answered or never will, or time's up.
+            Err(_) => break,
+        }
+    }
+    answers.sort_by_key(|&(i, _)| i);
+    answers.into_iter().map(|(_, answer)| answer).collect()
+}
+
+// The nodes of `dot`, as to_dot writes it, each with whether it's dashed,
+// and its edges, without minding anything else about them.
+#[cfg(test)]
+pub fn parse_dot(dot: &str) -> (Vec<(String, bool)>, Vec<(String, String)>) {
+    let mut nodes = Vec::new();
+    let mut edges = Vec::new();
+    for line in dot.lines().map(|l| l.trim()).filter(|l| l.starts_with('"')) {
+        let quoted: Vec<&str> = line.split('"').collect();
+        if quoted.len() > 3 && quoted[2].trim() == "->" {
+            edges.push((quoted[1].to_string(), quoted[3].to_string()));
+        } else {
+            nodes.push((quoted[1].to_string(), line.contains("style=dashed")));
+        }
+    }
+    (nodes, edges)
+}
+
+#[test]
+fn a_graph_is_written_as_dot() {
+    let node = |id: &str, state: &str, answered| GraphNode {
+        id: id.to_string(),
+        addr: "127.0.0.1:9000".to_string(),
+        state: state.to_string(),
+        answered: answered,
+    };
+    let edge = |to: &str, state: &str, rtt_us| GraphEdge {
+        from: "00000000000000aa".to_string(),
+        to: to.to_string(),
+        state: state.to_string(),
+        rtt_us: rtt_us,
+    };
+    let graph = Graph {
+        nodes: vec![node("00000000000000aa", "Alive", true),
+                    node("00000000000000bb", "Suspect", false)],
+        edges: vec![edge("00000000000000bb", "Suspect", Some(1234)),
+                    edge("00000000000000cc", "Dead", None)],
+    };
+    assert_eq!(graph.to_dot(), "digraph mesh {
+    \"00000000000000aa\" [label=\"00000000\\n127.0.0.1:9000\\nAlive\"];
+    \"00000000000000bb\" [label=\"00000000\\n127.0.0.1:9000\\nSuspect\", \
+                                  style=dashed];
+    \"00000000000000aa\" -> \"00000000000000bb\" [label=\"1.2ms\", \
+                                                  color=orange];
+    \"00000000000000aa\" -> \"00000000000000cc\" [color=red];
+}");
+    let (nodes, edges) = parse_dot(&graph.to_dot());
+    assert_eq!(nodes, vec![("00000000000000aa".to_string(), false),
+                           ("00000000000000bb".to_string(), true)]);
+    assert_eq!(edges.len(), 2);
+}
+
+#[test]
+fn gathering_gives_up_on_those_yet_to_answer() {
+    let (quick, answered) = mpsc::channel();
+    let (_slow, waiting) = mpsc::channel();
+    let (gone, stopped) = mpsc::channel::<u32>();
+    drop(gone);
+    quick.send(7).unwrap();
+
+    let start = Instant::now();
+    let timeout = Duration::from_millis(100);
+    assert_eq!(gather(vec![waiting, answered, stopped], timeout), vec![7]);
+    let took = start.elapsed();
+    assert!(took >= timeout && took < Duration::from_secs(2), "{:?}", took);
+}
@@ -0,0 +1,289 @@
+use clock::Nanos;
+use detector::FailureDetector;
+use event::MeshEvent;
+use members::{Members, NodeId};
+use message::{AckedMessage, Health, Message, MessageKind, WireAddr};
+#[cfg(test)] use message::{PingBody, Seq};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// What a handler gets to work with: the parts of the dispatcher's state
+// that message handling may touch, and somewhere to leave replies. The
+// dispatcher sends the replies (through its outbound hooks) once the
+// handler returns. Acks, and everything else to do with the Acked
+// wrapper, are the dispatcher's business and aren't in here.
+pub struct DispatchCtx<'a> {
+    pub now: Instant,
+    // When the dispatcher started, which Ping timestamps count from.
+    pub epoch: Instant,
+    pub members: &'a mut Members,
+    pub detector: &'a mut FailureDetector,
+    pub events: &'a mut VecDeque<MeshEvent>,
+    pub replies: Vec<(Message, SocketAddr)>,
+    // Set by a handler that changed who is in the mesh.
+    pub churned: bool,
+    // How we're doing, for a Pong to say, if it's to say; see
+    // health::HealthConfig.
+    pub health: Option<Health>,
+    // The peer a Joining node is taking the place of, restarted, if it
+    // is; see merge::restarted.
+    pub restarted: Option<NodeId>,
+}
+
+impl<'a> DispatchCtx<'a> {
+    pub fn new(now: Instant, epoch: Instant, members: &'a mut Members,
+               detector: &'a mut FailureDetector,
+               events: &'a mut VecDeque<MeshEvent>) -> DispatchCtx<'a> {
+        DispatchCtx {
+            now: now,
+            epoch: epoch,
+            members: members,
+            detector: detector,
+            events: events,
+            replies: Vec::new(),
+            churned: false,
+            health: None,
+            restarted: None,
+        }
+    }
+
+    pub fn reply(&mut self, msg: Message, target: &SocketAddr) {
+        self.replies.push((msg, *target));
+    }
+}
+
+// A Ping timestamp for `now`: microseconds since `epoch`.
+pub fn timestamp(epoch: Instant, now: Instant) -> u64 {
+    Nanos::from(now - epoch).as_micros()
+}
+
+// Handles one kind of message. For acked kinds the message is still
+// wrapped, so the handler can see its sequence number, but by the time
+// it's called the dispatcher has already seen to the ack.
+pub type Handler = Fn(&mut DispatchCtx, &SocketAddr, Message);
+
+// Which handler deals with which kind of message.
+pub struct Handlers {
+    handlers: HashMap<MessageKind, Box<Handler>>,
+}
+
+impl Handlers {
+    pub fn new() -> Handlers {
+        Handlers { handlers: HashMap::new() }
+    }
+
+    // Handlers for everything the protocol itself sends.
+    pub fn builtin() -> Handlers {
+        let mut h = Handlers::new();
+        h.register(MessageKind::Join, Box::new(join));
+        h.register(MessageKind::Data, Box::new(data));
+        h.register(MessageKind::Ping, Box::new(ping));
+        h.register(MessageKind::Pong, Box::new(pong));
+        h
+    }
+
+    // Have `handler` deal with `kind`, replacing whatever did before.
+    pub fn register(&mut self, kind: MessageKind, handler: Box<Handler>) {
+        self.handlers.insert(kind, handler);
+    }
+
+    // Pass `msg` to its handler. Returns false if there isn't one.
+    pub fn dispatch(&self, ctx: &mut DispatchCtx, from: &SocketAddr,
+                    msg: Message) -> bool {
+        match self.handlers.get(&msg.kind()) {
+            Some(handler) => {
+                handler(ctx, from, msg);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+pub fn join(ctx: &mut DispatchCtx, from: &SocketAddr, msg: Message) {
+    let (seq, id, version) = match msg {
+        Message::Acked(seq, AckedMessage::Join(id, version)) =>
+            (seq, id, version),
+        _ => return,
+    };
+    println!("Received a JOIN request {} from {} (protocol version {})", seq,
+             from, version);
+    let new = ctx.members.join(id, *from, ctx.now);
+    ctx.members.set_version(id, version);
+    ctx.detector.on_message(id, ctx.now);
+    if new {
+        let peer = ctx.members.get(id).unwrap();
+        match ctx.restarted {
+            Some(old) => {
+                println!("{} restarted as {}", old, peer);
+                ctx.events.push_back(MeshEvent::PeerRestarted {
+                    old_id: old,
+                    new_id: id,
+                    addr: *from,
+                });
+            },
+            None => {
+                println!("{} joined", peer);
+                ctx.events.push_back(MeshEvent::PeerJoined(peer));
+            },
+        }
+        ctx.churned = true;
+    }
+}
+
+pub fn data(ctx: &mut DispatchCtx, from: &SocketAddr, msg: Message) {
+    if let Message::Acked(_, AckedMessage::Data(data)) = msg {
+        ctx.events.push_back(MeshEvent::Data(*from, data));
+    }
+}
+
+pub fn ping(ctx: &mut DispatchCtx, from: &SocketAddr, msg: Message) {
+    if let Message::Ping(body) = msg {
+        let pong = Message::Pong(body.echo(), WireAddr(*from), ctx.health);
+        ctx.reply(pong, from);
+    }
+}
+
+// A Pong answers one of our Pings, so its timestamp is ours and gives the
+// round trip time. One from the future is someone else's, or a forgery,
+// and is ignored.
+pub fn pong(ctx: &mut DispatchCtx, from: &SocketAddr, msg: Message) {
+    if let Message::Pong(body, ..) = msg {
+        record_rtt(ctx.members, ctx.detector, ctx.epoch, ctx.now, from,
+                   body.sent_at_micros);
+    }
+}
+
+// What `pong` does, for the dispatcher to do without a DispatchCtx while
+// it's the Pong handler; see fastpath.
+pub fn record_rtt(members: &mut Members, detector: &mut FailureDetector,
+                  epoch: Instant, now: Instant, from: &SocketAddr,
+                  sent_at_micros: u64) {
+    let rtt = match timestamp(epoch, now).checked_sub(sent_at_micros) {
+        Some(micros) => Duration::from_micros(micros),
+        None => return,
+    };
+    if let Some(id) = members.id_of(from) {
+        members.set_rtt(id, rtt);
+        detector.on_probe_result(id, Some(rtt), now);
+    }
+}
+
+// A context over fresh state, for trying handlers out on.
+#[cfg(test)]
+struct MockCtx {
+    members: Members,
+    detector: Box<FailureDetector>,
+    events: VecDeque<MeshEvent>,
+}
+
+#[cfg(test)]
+impl MockCtx {
+    fn new() -> MockCtx {
+        MockCtx {
+            members: Members::new(),
+            detector: ::detector::DetectorConfig::default().build(),
+            events: VecDeque::new(),
+        }
+    }
+
+    fn ctx(&mut self) -> DispatchCtx {
+        let now = Instant::now();
+        DispatchCtx::new(now, now, &mut self.members,
+                         &mut *self.detector, &mut self.events)
+    }
+}
+
+#[cfg(test)]
+fn from() -> SocketAddr {
+    "127.0.0.1:9000".parse().unwrap()
+}
+
+#[test]
+fn join_handler_adds_member_once() {
+
+    let mut mock = MockCtx::new();
+    let join_msg = Message::Acked(Seq::new(1, 7),
+                                  AckedMessage::Join(NodeId(42), 1));
+    {
+        let mut ctx = mock.ctx();
+        join(&mut ctx, &from(), join_msg.clone());
+        assert!(ctx.churned);
+        assert!(ctx.replies.is_empty());
+    }
+    assert_eq!(mock.members.id_of(&from()), Some(NodeId(42)));
+    match mock.events.pop_front() {
+        Some(MeshEvent::PeerJoined(p)) => {
+            assert_eq!(p.id(), NodeId(42));
+            assert_eq!(p.version(), Some(1));
+        },
+        other => panic!("expected PeerJoined, got {:?}", other),
+    }
+
+    {
+        let mut ctx = mock.ctx();
+        join(&mut ctx, &from(), join_msg);
+        assert!(!ctx.churned);
+    }
+    assert_eq!(mock.members.len(), 1);
+    assert!(mock.events.is_empty());
+}
+
+#[test]
+fn ping_handler_replies_with_pong() {
+    let mut mock = MockCtx::new();
+    let mut ctx = mock.ctx();
+    let body = PingBody { nonce: 5, sent_at_micros: 100, pad: vec![0; 64] };
+    ping(&mut ctx, &from(), Message::Ping(body.clone()));
+    let echo = PingBody { nonce: 5, sent_at_micros: 100, pad: vec![] };
+    assert_eq!(ctx.replies,
+               vec![(Message::Pong(echo.clone(), WireAddr(from()), None),
+                     from())]);
+    assert!(!ctx.churned);
+
+    // Saying how we're doing, if there's something to say.
+    let health = Health { queued: 2, dropped: 0, alive: 1 };
+    ctx.replies.clear();
+    ctx.health = Some(health);
+    ping(&mut ctx, &from(), Message::Ping(body));
+    assert_eq!(ctx.replies,
+               vec![(Message::Pong(echo, WireAddr(from()), Some(health)),
+                     from())]);
+}
+
+#[test]
+fn pong_handler_records_rtt() {
+
+    let mut mock = MockCtx::new();
+    mock.members.join(NodeId(1), from(), Instant::now());
+    {
+        let mut ctx = mock.ctx();
+        ctx.now = ctx.epoch + Duration::from_millis(30);
+        let body = PingBody { nonce: 5, sent_at_micros: 10000, pad: vec![] };
+        pong(&mut ctx, &from(), Message::Pong(body, WireAddr(from()), None));
+
+        // Not one of ours.
+        let body = PingBody { nonce: 6, sent_at_micros: 50000, pad: vec![] };
+        pong(&mut ctx, &from(), Message::Pong(body, WireAddr(from()), None));
+    }
+    let peer = mock.members.get(NodeId(1)).unwrap();
+    assert_eq!(peer.rtt(), Some(Duration::from_millis(20)));
+    assert_eq!(peer.rtts().len(), 1);
+    // Its bucket runs from 19.2ms to 20.8ms.
+    assert_eq!(peer.rtts().p99(), Some(Duration::from_micros(20800)));
+}
+
+#[test]
+fn unregistered_kinds_are_not_dispatched() {
+    let mut mock = MockCtx::new();
+    let mut ctx = mock.ctx();
+    let mut handlers = Handlers::builtin();
+    let ack = Message::Ack(Seq::new(1, 1), WireAddr(from()), None);
+    assert!(!handlers.dispatch(&mut ctx, &from(), ack));
+
+    handlers.register(MessageKind::Ping, Box::new(|_, _, _| ()));
+    let body = PingBody { nonce: 1, sent_at_micros: 0, pad: vec![] };
+    assert!(handlers.dispatch(&mut ctx, &from(), Message::Ping(body)));
+    assert!(ctx.replies.is_empty());
+}
@@ -0,0 +1,112 @@
+use message::Health;
+use protocol::limits::HEALTH_WINDOW;
+use std::cmp;
+use std::time::{Duration, Instant};
+
+// What our Pongs say of how we're doing, and what's made of what peers'
+// say. With `report` set, every Pong we send carries a message::Health.
+// A peer whose last Pong showed at least `queued` datagrams waiting to go
+// out, or `dropped` dropped lately, is taken to be overloaded: it's slow
+// to answer for being busy rather than for being gone, so the failure
+// detector gives it `grace` longer than usual before suspecting it. A
+// `grace` of zero makes nothing of it. By the same measure of our own
+// health, we hand Joins on while we're overloaded; see
+// Dispatcher::forward_join.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthConfig {
+    pub report: bool,
+    pub queued: u32,
+    pub dropped: u32,
+    pub grace: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> HealthConfig {
+        HealthConfig {
+            report: true,
+            queued: 256,
+            dropped: 64,
+            grace: Duration::from_secs(5),
+        }
+    }
+}
+
+impl HealthConfig {
+    pub fn overloaded(&self, health: &Health) -> bool {
+        health.queued >= self.queued || health.dropped >= self.dropped
+    }
+
+    // How much longer than usual to give a peer whose last Pong said
+    // `health`, if it said anything.
+    pub fn grace(&self, health: Option<Health>) -> Duration {
+        match health {
+            Some(ref h) if self.overloaded(h) => self.grace,
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+// How many datagrams we've dropped lately, for our Health: those in the
+// current HEALTH_WINDOW, or in the one before if that had more, so
+// that the count doesn't fall to nothing as each window starts.
+pub struct RecentDrops {
+    started: Instant,
+    // The running total when the current window started.
+    at_start: u64,
+    // How many were dropped in the window before.
+    last: u64,
+}
+
+impl RecentDrops {
+    pub fn new(now: Instant) -> RecentDrops {
+        RecentDrops { started: now, at_start: 0, last: 0 }
+    }
+
+    // How many of the `total` dropped so far were dropped lately.
+    pub fn count(&mut self, total: u64, now: Instant) -> u32 {
+        if now - self.started >= HEALTH_WINDOW {
+            // After a quiet spell, what went before isn't recent.
+            self.last = if now - self.started >= HEALTH_WINDOW * 2 {
+                0
+            } else {
+                total - self.at_start
+            };
+            self.at_start = total;
+            self.started = now;
+        }
+        let recent = cmp::max(self.last, total - self.at_start);
+        cmp::min(recent, u32::max_value() as u64) as u32
+    }
+}
+
+#[test]
+fn either_threshold_makes_a_peer_overloaded() {
+    let config = HealthConfig::default();
+    let calm = Health { queued: 10, dropped: 0, alive: 5 };
+    assert!(!config.overloaded(&calm));
+    assert_eq!(config.grace(Some(calm)), Duration::from_secs(0));
+    assert_eq!(config.grace(None), Duration::from_secs(0));
+
+    let backed_up = Health { queued: config.queued, .. calm };
+    let dropping = Health { dropped: config.dropped, .. calm };
+    for health in &[backed_up, dropping] {
+        assert!(config.overloaded(health));
+        assert_eq!(config.grace(Some(*health)), config.grace);
+    }
+}
+
+#[test]
+fn recent_drops_outlast_their_window() {
+    let start = Instant::now();
+    let window = HEALTH_WINDOW;
+    let mut drops = RecentDrops::new(start);
+    assert_eq!(drops.count(0, start), 0);
+    assert_eq!(drops.count(30, start + window / 2), 30);
+    // A new window: the last one's still the most, until this one has
+    // more.
+    assert_eq!(drops.count(35, start + window), 35);
+    assert_eq!(drops.count(40, start + window * 3 / 2), 35);
+    assert_eq!(drops.count(80, start + window * 7 / 4), 45);
+    // And after a quiet spell, none of it is recent.
+    assert_eq!(drops.count(80, start + window * 4), 0);
+}
@@ -0,0 +1,218 @@
+use rustc_serialize::{Encodable, Encoder};
+use std::fmt;
+use std::time::Duration;
+
+// Histogram buckets are log-spaced from MIN_MICROS up, in OCTAVES
+// doublings, each split into SUB_BUCKETS equal parts, so a recorded time
+// is known to within an eighth of itself. Below MIN_MICROS is one bucket,
+// and so is everything from MAX_MICROS (a little over 13s) up.
+const MIN_MICROS: u64 = 100;
+const OCTAVES: u32 = 17;
+const SUB_BUCKETS: u64 = 8;
+const MAX_MICROS: u64 = MIN_MICROS << OCTAVES;
+// The underflow bucket, the log-spaced ones, then the overflow bucket.
+const BUCKETS: usize = 1 + OCTAVES as usize * SUB_BUCKETS as usize + 1;
+
+// How round trip times have been spread, closely enough to tell the tail
+// from the median: a count per bucket, a few hundred bytes in all however
+// many are recorded.
+#[derive(Clone, PartialEq)]
+pub struct Histogram {
+    counts: Vec<u32>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram { counts: vec![0; BUCKETS], total: 0 }
+    }
+
+    pub fn record(&mut self, d: Duration) {
+        let i = bucket(micros(d));
+        self.counts[i] = self.counts[i].saturating_add(1);
+        self.total += 1;
+    }
+
+    // Add everything recorded in `other`, as if it had been recorded here.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (c, o) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *c = c.saturating_add(*o);
+        }
+        self.total += other.total;
+    }
+
+    // How many times have been recorded.
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    // The time `percent`% of those recorded were within, rounded up to
+    // the top of its bucket, or None if none have been. Times past the
+    // last bucket come out as its start.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((self.total as f64 * percent / 100.0).ceil() as u64)
+            .max(1).min(self.total);
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count as u64;
+            if seen >= rank {
+                let top = if i + 1 < BUCKETS { lower_bound(i + 1) }
+                          else { MAX_MICROS };
+                return Some(Duration::from_micros(top));
+            }
+        }
+        // The counts saturated short of the total.
+        Some(Duration::from_micros(MAX_MICROS))
+    }
+
+    pub fn p50(&self) -> Option<Duration> { self.percentile(50.0) }
+    pub fn p90(&self) -> Option<Duration> { self.percentile(90.0) }
+    pub fn p99(&self) -> Option<Duration> { self.percentile(99.0) }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Histogram {{ len: {}, p50: {:?}, p90: {:?}, p99: {:?} }}",
+               self.total, self.p50(), self.p90(), self.p99())
+    }
+}
+
+// As its count and percentiles in microseconds, for debug dumps; the
+// buckets themselves mean nothing to a reader.
+impl Encodable for Histogram {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let us = |d: Option<Duration>| d.map(micros);
+        let (p50, p90, p99) = (us(self.p50()), us(self.p90()), us(self.p99()));
+        s.emit_struct("Histogram", 4, |s| {
+            try!(s.emit_struct_field("len", 0, |s| self.total.encode(s)));
+            try!(s.emit_struct_field("p50_us", 1, |s| p50.encode(s)));
+            try!(s.emit_struct_field("p90_us", 2, |s| p90.encode(s)));
+            s.emit_struct_field("p99_us", 3, |s| p99.encode(s))
+        })
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs().saturating_mul(1000000) + d.subsec_nanos() as u64 / 1000
+}
+
+// The bucket `micros` falls in. Counting in eighths of MIN_MICROS, the
+// sub-buckets of the octave starting at 8 << k eighths are each 1 << k
+// wide, so the octave is in the leading bit and the sub-bucket in the
+// three after it.
+fn bucket(micros: u64) -> usize {
+    if micros < MIN_MICROS {
+        return 0;
+    }
+    if micros >= MAX_MICROS {
+        return BUCKETS - 1;
+    }
+    let eighths = micros * SUB_BUCKETS / MIN_MICROS;
+    let octave = 63 - eighths.leading_zeros() - 3;
+    let sub = (eighths >> octave) - SUB_BUCKETS;
+    1 + (octave as u64 * SUB_BUCKETS + sub) as usize
+}
+
+// The least number of microseconds in bucket `i`.
+fn lower_bound(i: usize) -> u64 {
+    if i == 0 {
+        return 0;
+    }
+    if i == BUCKETS - 1 {
+        return MAX_MICROS;
+    }
+    let octave = (i as u64 - 1) / SUB_BUCKETS;
+    let sub = (i as u64 - 1) % SUB_BUCKETS;
+    let eighths = (SUB_BUCKETS + sub) << octave;
+    // Rounded up, a bucket's start not always being a whole microsecond.
+    (eighths * MIN_MICROS + SUB_BUCKETS - 1) / SUB_BUCKETS
+}
+
+#[test]
+fn every_bucket_starts_where_the_last_ends() {
+    assert_eq!(bucket(0), 0);
+    for i in 1..BUCKETS {
+        let start = lower_bound(i);
+        assert!(start > lower_bound(i - 1), "bucket {} is empty", i - 1);
+        assert_eq!(bucket(start), i, "start of bucket {}", i);
+        assert_eq!(bucket(start - 1), i - 1, "end of bucket {}", i - 1);
+    }
+    assert_eq!(bucket(u64::max_value()), BUCKETS - 1);
+}
+
+#[test]
+fn bucket_boundaries_are_where_expected() {
+    assert_eq!(lower_bound(1), MIN_MICROS);
+    // The first octave's sub-buckets are 12.5us wide, rounded up.
+    assert_eq!(lower_bound(2), 113);
+    assert_eq!(lower_bound(3), 125);
+    assert_eq!(lower_bound(9), 200);
+    assert_eq!(lower_bound(17), 400);
+    assert_eq!(lower_bound(BUCKETS - 2), 12288000);
+    assert_eq!(lower_bound(BUCKETS - 1), 13107200);
+
+    assert_eq!(bucket(99), 0);
+    assert_eq!(bucket(100), 1);
+    assert_eq!(bucket(112), 1);
+    assert_eq!(bucket(113), 2);
+    assert_eq!(bucket(199), 8);
+    assert_eq!(bucket(200), 9);
+    // 10s still has a bucket of its own.
+    assert_eq!(bucket(10000000), BUCKETS - 5);
+    assert_eq!(bucket(13107199), BUCKETS - 2);
+    assert_eq!(bucket(13107200), BUCKETS - 1);
+}
+
+#[test]
+fn buckets_are_within_an_eighth() {
+    for i in 1..BUCKETS - 1 {
+        let (start, end) = (lower_bound(i), lower_bound(i + 1));
+        assert!((end - start) * SUB_BUCKETS <= start + SUB_BUCKETS,
+                "bucket {} is {}us to {}us", i, start, end);
+    }
+}
+
+#[test]
+fn histogram_is_a_few_hundred_bytes() {
+    assert!(BUCKETS * 4 < 1024);
+}
+
+#[test]
+fn percentiles_find_the_tail() {
+    let mut h = Histogram::new();
+    assert_eq!(h.p50(), None);
+    for _ in 0..98 {
+        h.record(Duration::from_millis(1));
+    }
+    h.record(Duration::from_millis(50));
+    h.record(Duration::from_secs(20));
+
+    // 1ms falls in the bucket from 1000us to 1100us.
+    assert_eq!(h.p50(), Some(Duration::from_micros(1100)));
+    assert_eq!(h.p90(), Some(Duration::from_micros(1100)));
+    assert_eq!(h.p99(), Some(Duration::from_micros(51200)));
+    assert_eq!(h.percentile(100.0), Some(Duration::from_micros(MAX_MICROS)));
+    assert_eq!(h.percentile(0.0), h.p50());
+}
+
+#[test]
+fn merged_histograms_count_both() {
+    let mut a = Histogram::new();
+    let mut b = Histogram::new();
+    for _ in 0..9 {
+        a.record(Duration::from_micros(150));
+    }
+    b.record(Duration::from_millis(3));
+    a.merge(&b);
+    assert_eq!(a.len(), 10);
+    assert_eq!(a.p50(), Some(Duration::from_micros(163)));
+    assert_eq!(a.p99(), Some(Duration::from_micros(3200)));
+    assert_eq!(b.len(), 1);
+}
@@ -0,0 +1,145 @@
+use members::{NodeId, PeerState, Suspicion, SuspicionReason};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+// Why a peer's state changed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cause {
+    // It sent us a Join, whether new to us or coming back.
+    Join,
+    // It sent us a Join from the address of a peer it was taken to be a
+    // restart of; see MeshEvent::PeerRestarted.
+    Restarted,
+    // The failure detector's verdict on how lately we've heard from it.
+    Detector,
+    // Its host refused a probe; nothing was listening.
+    ProbeRefused,
+    // Something we sent it ran out of retransmissions unacked.
+    Unacked,
+    // An operator evicted it; see Dispatcher::evict.
+    Evicted,
+    // It answered a probe an operator asked for; see
+    // Dispatcher::probe_now.
+    Probed,
+    // Evicted, it has since been heard from on a later incarnation.
+    Refuted,
+}
+
+impl Cause {
+    // What a peer made Suspect for this is suspected of, if this is what
+    // a peer may be suspected for.
+    pub fn suspicion_reason(&self) -> Option<SuspicionReason> {
+        match *self {
+            Cause::Detector => Some(SuspicionReason::Silent),
+            Cause::ProbeRefused => Some(SuspicionReason::ProbeRefused),
+            Cause::Unacked => Some(SuspicionReason::Unacked),
+            _ => None,
+        }
+    }
+}
+
+// One change in a peer's state, as kept in a History.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Change {
+    pub when: Instant,
+    pub peer: NodeId,
+    pub addr: SocketAddr,
+    // None if the peer was new to us.
+    pub from: Option<PeerState>,
+    pub to: PeerState,
+    pub cause: Cause,
+    // For a move to Suspect, the suspicion it began; for a move from it,
+    // the suspicion `cause` ended, whether it was borne out (to Dead) or
+    // refuted (to Alive).
+    pub suspicion: Option<Suspicion>,
+}
+
+// The latest membership changes, for answering after the fact when a
+// peer left or came back and why, without the logs. A ring of fixed
+// capacity (Config::history), allocated up front, so that recording a
+// change never allocates; past capacity the oldest change is overwritten.
+pub struct History {
+    changes: Vec<Change>,
+    capacity: usize,
+    // Where the next change goes once the ring is full.
+    next: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            changes: Vec::with_capacity(capacity),
+            capacity: capacity,
+            next: 0,
+        }
+    }
+
+    pub fn record(&mut self, change: Change) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.changes.len() < self.capacity {
+            self.changes.push(change);
+        } else {
+            self.changes[self.next] = change;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    // The changes kept, newest first, only `peer`'s if given.
+    pub fn changes(&self, peer: Option<NodeId>) -> Vec<Change> {
+        let (older, newer) = self.changes.split_at(self.next);
+        newer.iter().chain(older.iter()).rev()
+            .filter(|c| peer.map_or(true, |id| c.peer == id))
+            .cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+}
+
+#[cfg(test)]
+fn change(peer: u64, to: PeerState) -> Change {
+    Change {
+        when: Instant::now(),
+        peer: NodeId(peer),
+        addr: "127.0.0.1:9000".parse().unwrap(),
+        from: None,
+        to: to,
+        cause: Cause::Detector,
+        suspicion: None,
+    }
+}
+
+#[test]
+fn history_is_newest_first_and_filters_by_peer() {
+    let mut history = History::new(8);
+    history.record(change(1, PeerState::Alive));
+    history.record(change(2, PeerState::Alive));
+    history.record(change(1, PeerState::Suspect));
+    let states = |changes: Vec<Change>| -> Vec<(u64, PeerState)> {
+        changes.iter().map(|c| (c.peer.0, c.to)).collect()
+    };
+    assert_eq!(states(history.changes(None)),
+               vec![(1, PeerState::Suspect), (2, PeerState::Alive),
+                    (1, PeerState::Alive)]);
+    assert_eq!(states(history.changes(Some(NodeId(1)))),
+               vec![(1, PeerState::Suspect), (1, PeerState::Alive)]);
+}
+
+#[test]
+fn history_keeps_only_the_latest() {
+    let mut history = History::new(3);
+    for peer in 0..5 {
+        history.record(change(peer, PeerState::Alive));
+    }
+    assert_eq!(history.len(), 3);
+    let peers: Vec<u64> = history.changes(None).iter()
+        .map(|c| c.peer.0).collect();
+    assert_eq!(peers, vec![4, 3, 2]);
+
+    let mut none = History::new(0);
+    none.record(change(1, PeerState::Alive));
+    assert!(none.changes(None).is_empty());
+}
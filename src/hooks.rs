@@ -0,0 +1,76 @@
+use message::Message;
+#[cfg(test)] use message::{PingBody, Seq, WireAddr};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookAction {
+    Continue,
+    Drop,
+}
+
+pub type Hook = Fn(&SocketAddr, &Message) -> HookAction;
+
+// How long a hook may take before we complain. Hooks run inline on the
+// dispatcher's thread, so one that blocks stalls the whole node: they
+// must not do I/O, take contended locks or otherwise wait.
+pub const HOOK_BUDGET_US: u64 = 1000;
+
+// A chain of hooks, run in the order they were added until one says to
+// drop the message.
+pub struct Hooks {
+    hooks: Vec<Box<Hook>>,
+}
+
+impl Hooks {
+    pub fn new() -> Hooks {
+        Hooks { hooks: Vec::new() }
+    }
+
+    pub fn add(&mut self, hook: Box<Hook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn run(&self, addr: &SocketAddr, msg: &Message) -> HookAction {
+        let budget = Duration::from_micros(HOOK_BUDGET_US);
+        for (n, hook) in self.hooks.iter().enumerate() {
+            let start = Instant::now();
+            let action = hook(addr, msg);
+            let took = start.elapsed();
+            if took > budget {
+                println!("WARNING: hook {} took {:?} (budget {:?}) on {:?}",
+                         n, took, budget, msg);
+            }
+            if action == HookAction::Drop {
+                return HookAction::Drop;
+            }
+        }
+        HookAction::Continue
+    }
+}
+
+#[test]
+fn hooks_run_in_order_until_dropped() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut hooks = Hooks::new();
+    for n in 0..3 {
+        let order = order.clone();
+        hooks.add(Box::new(move |_, msg| {
+            order.borrow_mut().push(n);
+            match *msg {
+                Message::Ping(_) if n == 1 => HookAction::Drop,
+                _ => HookAction::Continue,
+            }
+        }));
+    }
+
+    let addr = "127.0.0.1:9000".parse().unwrap();
+    let ack = Message::Ack(Seq::new(1, 1), WireAddr(addr), None);
+    assert_eq!(hooks.run(&addr, &ack), HookAction::Continue);
+    let ping = Message::Ping(PingBody { nonce: 0, sent_at_micros: 0, pad: vec![] });
+    assert_eq!(hooks.run(&addr, &ping), HookAction::Drop);
+    assert_eq!(*order.borrow(), vec![0, 1, 2, 0, 1]);
+}
@@ -0,0 +1,179 @@
+use rustc_serialize::{Decodable, Decoder};
+use std::cmp;
+use std::time::{Duration, Instant};
+
+// What the binary exits with when a node shuts itself down for being
+// isolated (see OnIsolation::Exit), so that a supervisor can tell it
+// from a crash (1) or a clean stop (0).
+pub const EXIT_CODE: i32 = 3;
+
+// How soon an isolated node set to rejoin tries its seeds again after
+// the first try, doubling with each try after that up to REJOIN_MAX_MS.
+pub const REJOIN_BASE_MS: u64 = 1000;
+pub const REJOIN_MAX_MS: u64 = 60000;
+
+// What a node does once every peer it has is dead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnIsolation {
+    // Carry on alone, as a mesh of one that others may still join.
+    Standalone,
+    // Join the seeds we first joined through again, backing off, until
+    // a peer is alive. A node that never joined anyone has no seeds, and
+    // carries on alone.
+    Rejoin,
+    // Shut down, for a supervisor to start us again somewhere else; the
+    // binary exits with EXIT_CODE.
+    Exit,
+}
+
+impl Default for OnIsolation {
+    fn default() -> OnIsolation {
+        OnIsolation::Standalone
+    }
+}
+
+impl OnIsolation {
+    pub fn parse(s: &str) -> Result<OnIsolation, String> {
+        match s {
+            "standalone" => Ok(OnIsolation::Standalone),
+            "rejoin" => Ok(OnIsolation::Rejoin),
+            "exit" => Ok(OnIsolation::Exit),
+            _ => Err(format!("{} is not standalone, rejoin or exit", s)),
+        }
+    }
+}
+
+// So that a policy on the command line is checked along with the rest.
+impl Decodable for OnIsolation {
+    fn decode<D: Decoder>(d: &mut D) -> Result<OnIsolation, D::Error> {
+        let s = try!(d.read_str());
+        OnIsolation::parse(&s).map_err(|e| d.error(&e))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // Some peer is alive or suspect, or we've none to lose.
+    Connected,
+    // Every peer has been dead since then.
+    Alone(Instant),
+    // Alone for long enough; the next rejoin, if we make them, is due at
+    // `retry`, `delay` after the last.
+    Isolated {
+        retry: Instant,
+        delay: Duration,
+    },
+}
+
+// Whether a node has lost the whole mesh: it had peers, and every one of
+// them has been dead for a whole `debounce` (as long as a peer is given
+// to answer before it's suspected), so that a blip that takes them all
+// out at once for a moment doesn't count. Any peer coming back, or a new
+// one, ends it.
+pub struct Isolation {
+    state: State,
+}
+
+impl Isolation {
+    pub fn new() -> Isolation {
+        Isolation { state: State::Connected }
+    }
+
+    pub fn isolated(&self) -> bool {
+        match self.state {
+            State::Isolated { .. } => true,
+            _ => false,
+        }
+    }
+
+    // Whether we're `alone` (have peers, all dead) at `now`. True the
+    // once we've been alone for `debounce`, until we no longer are.
+    pub fn poll(&mut self, alone: bool, now: Instant, debounce: Duration)
+            -> bool {
+        match (self.state, alone) {
+            (_, false) => self.state = State::Connected,
+            (State::Connected, true) => self.state = State::Alone(now),
+            (State::Alone(since), true) if now - since >= debounce => {
+                self.state = State::Isolated {
+                    retry: now,
+                    delay: Duration::from_millis(REJOIN_BASE_MS),
+                };
+                return true;
+            },
+            _ => (),
+        }
+        false
+    }
+
+    // Whether, being isolated, it's time to try the seeds again, at once
+    // on isolation and then backing off. Each true puts the next try
+    // further off.
+    pub fn rejoin_due(&mut self, now: Instant) -> bool {
+        match self.state {
+            State::Isolated { retry, delay } if now >= retry => {
+                self.state = State::Isolated {
+                    retry: now + delay,
+                    delay: cmp::min(delay * 2,
+                                    Duration::from_millis(REJOIN_MAX_MS)),
+                };
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+fn secs(n: u64) -> Duration {
+    Duration::from_secs(n)
+}
+
+#[test]
+fn isolated_only_once_alone_for_the_debounce() {
+    let start = Instant::now();
+    let mut i = Isolation::new();
+    assert!(!i.poll(true, start, secs(5)));
+    // A blip.
+    assert!(!i.poll(true, start + secs(4), secs(5)));
+    assert!(!i.poll(false, start + secs(4), secs(5)));
+    assert!(!i.poll(true, start + secs(6), secs(5)));
+    assert!(!i.isolated());
+
+    assert!(i.poll(true, start + secs(11), secs(5)));
+    assert!(i.isolated());
+    // Said once.
+    assert!(!i.poll(true, start + secs(20), secs(5)));
+    assert!(i.isolated());
+
+    assert!(!i.poll(false, start + secs(21), secs(5)));
+    assert!(!i.isolated());
+}
+
+#[test]
+fn rejoins_back_off() {
+    let start = Instant::now();
+    let mut i = Isolation::new();
+    assert!(!i.rejoin_due(start));
+    i.poll(true, start, secs(0));
+    assert!(i.poll(true, start, secs(0)));
+
+    let mut tried = Vec::new();
+    for n in 0..200 {
+        if i.rejoin_due(start + secs(n)) {
+            tried.push(n);
+        }
+    }
+    assert_eq!(tried, vec![0, 1, 3, 7, 15, 31, 63, 123, 183]);
+
+    i.poll(false, start + secs(200), secs(0));
+    assert!(!i.rejoin_due(start + secs(300)));
+}
+
+#[test]
+fn on_isolation_parses() {
+    assert_eq!(OnIsolation::parse("rejoin"), Ok(OnIsolation::Rejoin));
+    assert_eq!(OnIsolation::parse("exit"), Ok(OnIsolation::Exit));
+    assert_eq!(OnIsolation::parse("standalone"),
+               Ok(OnIsolation::Standalone));
+    assert!(OnIsolation::parse("restart").is_err());
+}
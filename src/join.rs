@@ -0,0 +1,53 @@
+use error::MeshError;
+use members::Peer;
+use message::Seq;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+// How a join is going.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JoinStatus {
+    Pending,
+    // The target let us in, and this is what we know of it.
+    Joined(Peer),
+    Failed(MeshError),
+}
+
+// A join in progress, as started by Dispatcher::join_async. Check on it
+// with `status`, or wait for it with Dispatcher::wait_join. However it
+// turns out is also reported as a MeshEvent::JoinCompleted.
+#[derive(Clone, Debug)]
+pub struct JoinTicket {
+    seq: Seq,
+    target: SocketAddr,
+    status: Rc<RefCell<JoinStatus>>,
+}
+
+impl JoinTicket {
+    pub fn new(seq: Seq, target: SocketAddr) -> JoinTicket {
+        JoinTicket {
+            seq: seq,
+            target: target,
+            status: Rc::new(RefCell::new(JoinStatus::Pending)),
+        }
+    }
+
+    // The sequence number of the Join message.
+    pub fn seq(&self) -> Seq {
+        self.seq
+    }
+
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    pub fn status(&self) -> JoinStatus {
+        self.status.borrow().clone()
+    }
+
+    // Settle the join, for this ticket and every copy of it.
+    pub fn resolve(&self, status: JoinStatus) {
+        *self.status.borrow_mut() = status;
+    }
+}
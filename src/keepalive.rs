@@ -0,0 +1,127 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Which peers to send a Keepalive (see message::Message), so that NATs and
+// firewalls between us, which forget a UDP flow that's gone quiet for
+// long enough, keep letting our peers' datagrams through to us. A peer is
+// sent one only once we've sent it nothing else for `interval`: any
+// datagram keeps the flow open as well, and most peers are probed often
+// enough to need none. None sends none at all, as suits peers with
+// nothing between them.
+pub struct Keepalives {
+    interval: Option<Duration>,
+    // When we last sent each peer anything, while `interval` is set.
+    last_sent: HashMap<SocketAddr, Instant>,
+    // No peer is due one before this.
+    next_check: Option<Instant>,
+}
+
+impl Keepalives {
+    pub fn new(interval: Option<Duration>) -> Keepalives {
+        Keepalives {
+            interval: interval,
+            last_sent: HashMap::new(),
+            next_check: None,
+        }
+    }
+
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    pub fn set_interval(&mut self, interval: Option<Duration>) {
+        self.interval = interval;
+        self.next_check = None;
+        if interval.is_none() {
+            self.last_sent.clear();
+        }
+    }
+
+    // We sent a datagram to `addr` at `now`.
+    pub fn sent(&mut self, addr: &SocketAddr, now: Instant) {
+        if self.interval.is_some() {
+            self.last_sent.insert(*addr, now);
+        }
+    }
+
+    // Whether any peer could be due one at `now`, for `due` to say which.
+    pub fn check_due(&self, now: Instant) -> bool {
+        self.interval.is_some() && self.next_check.map_or(true, |t| now >= t)
+    }
+
+    // Which of `peers`, those we're to keep the way open to, are due one
+    // at `now`, taking them to be sent it. A peer we've never sent
+    // anything is taken to have been sent something now. Whoever isn't
+    // among `peers` any more is forgotten.
+    pub fn due(&mut self, peers: &[SocketAddr], now: Instant)
+            -> Vec<SocketAddr> {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return Vec::new(),
+        };
+        let peers: HashSet<&SocketAddr> = peers.iter().collect();
+        self.last_sent.retain(|addr, _| peers.contains(addr));
+        let mut due = Vec::new();
+        for addr in peers {
+            let last = *self.last_sent.entry(*addr).or_insert(now);
+            if now >= last + interval {
+                due.push(*addr);
+                self.last_sent.insert(*addr, now);
+            }
+        }
+        due.sort();
+        let earliest = self.last_sent.values().min().cloned()
+            .unwrap_or(now);
+        self.next_check = Some(earliest + interval);
+        due
+    }
+}
+
+#[cfg(test)]
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+}
+
+#[test]
+fn peers_are_due_once_nothing_else_has_gone_to_them_for_the_interval() {
+    let start = Instant::now();
+    let secs = |n| start + Duration::from_secs(n);
+    let mut k = Keepalives::new(Some(Duration::from_secs(20)));
+    let peers = [addr(1), addr(2)];
+    k.sent(&addr(1), start);
+    k.sent(&addr(2), secs(5));
+    assert!(k.due(&peers, secs(19)).is_empty());
+    assert!(!k.check_due(secs(19)));
+
+    assert!(k.check_due(secs(20)));
+    assert_eq!(k.due(&peers, secs(20)), vec![addr(1)]);
+    // Until the other's time comes.
+    assert!(!k.check_due(secs(24)));
+    assert_eq!(k.due(&peers, secs(25)), vec![addr(2)]);
+
+    // Anything else sent puts off the next.
+    k.sent(&addr(1), secs(30));
+    assert_eq!(k.due(&peers, secs(45)), vec![addr(2)]);
+    assert!(k.due(&peers, secs(49)).is_empty());
+    assert_eq!(k.due(&peers, secs(50)), vec![addr(1)]);
+}
+
+#[test]
+fn only_peers_are_remembered_and_none_without_an_interval() {
+    let start = Instant::now();
+    let later = start + Duration::from_secs(60);
+    let mut k = Keepalives::new(Some(Duration::from_secs(20)));
+    k.sent(&addr(1), start);
+    k.sent(&addr(9), start);
+    // One we've sent nothing is given its interval from now.
+    assert_eq!(k.due(&[addr(1), addr(2)], later), vec![addr(1)]);
+    assert_eq!(k.last_sent.len(), 2);
+    assert!(!k.last_sent.contains_key(&addr(9)));
+
+    k.set_interval(None);
+    assert!(!k.check_due(later));
+    k.sent(&addr(1), later);
+    assert!(k.last_sent.is_empty());
+    assert!(k.due(&[addr(1)], later + Duration::from_secs(60)).is_empty());
+}
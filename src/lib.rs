@@ -0,0 +1,63 @@
+extern crate bincode;
+extern crate docopt;
+extern crate rand;
+extern crate rustc_serialize;
+#[cfg(test)] extern crate quickcheck;
+
+pub mod admission;
+pub mod backoff;
+pub mod bandwidth;
+pub mod builder;
+pub mod chaos;
+pub mod cli;
+pub mod clock;
+pub mod compat;
+pub mod config;
+pub mod converge;
+pub mod crc32c;
+pub mod decoder;
+pub mod dedup;
+pub mod delta;
+pub mod detector;
+pub mod dispatch;
+pub mod doctor;
+pub mod dump;
+pub mod error;
+pub mod event;
+pub mod fastpath;
+pub mod graph;
+pub mod handlers;
+pub mod health;
+pub mod histogram;
+pub mod history;
+pub mod hooks;
+pub mod isolation;
+pub mod join;
+pub mod keepalive;
+pub mod members;
+pub mod merge;
+pub mod message;
+pub mod mtu;
+pub mod node;
+pub mod observed;
+pub mod ordered;
+pub mod outbound;
+pub mod probe;
+pub mod protocol;
+pub mod rejects;
+pub mod replay;
+pub mod scheduler;
+pub mod score;
+pub mod signals;
+pub mod size;
+pub mod sockopts;
+pub mod stats;
+pub mod swarm;
+#[cfg(any(test, feature = "testing"))] pub mod testing;
+pub mod throttle;
+pub mod timing;
+pub mod trace;
+pub mod transport;
+
+pub use builder::{Mesh, MeshBuilder};
+pub use message::{parse_datagram, DecodeError};
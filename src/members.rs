@@ -0,0 +1,549 @@
+use histogram::Histogram;
+use message::Health;
+use rand;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Identifies a node for as long as it runs, independently of the address
+// we happen to reach it at. Picked at random on startup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord,
+         RustcEncodable, RustcDecodable)]
+pub struct NodeId(pub u64);
+
+impl NodeId {
+    pub fn random() -> NodeId {
+        NodeId(rand::random())
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+// Which version of a node's tags something is: the incarnation (see
+// message::Responder) the node was on when it last changed them, and how
+// many times it has changed them since it started. Versions compare
+// incarnation first, so a node that has restarted, starting its count
+// afresh on a later incarnation, has its tags win over any from before,
+// however many changes those had.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd,
+         Ord, RustcEncodable, RustcDecodable)]
+pub struct MetaVersion {
+    pub incarnation: u64,
+    pub counter: u64,
+}
+
+impl MetaVersion {
+    pub fn new(incarnation: u64, counter: u64) -> MetaVersion {
+        MetaVersion { incarnation: incarnation, counter: counter }
+    }
+}
+
+impl fmt::Display for MetaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.incarnation, self.counter)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeerState {
+    Alive,
+    // Not heard from lately; may be dead.
+    Suspect,
+    Dead,
+}
+
+// What made us suspect a peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SuspicionReason {
+    // We hadn't heard from it for longer than the failure detector allows.
+    Silent,
+    // Its host refused a probe; nothing was listening.
+    ProbeRefused,
+    // Something we sent it ran out of retransmissions unacked.
+    Unacked,
+}
+
+// Why, since when, and on whose word a peer is suspect. With nothing
+// passed on between peers, `by` is always ourselves for now.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Suspicion {
+    pub reason: SuspicionReason,
+    pub since: Instant,
+    pub by: NodeId,
+}
+
+// What we know about another member of the mesh. Peers handed out by the
+// library are snapshots: they don't change when the membership table
+// does, and can't be used to change it.
+#[derive(Clone, Debug)]
+pub struct Peer {
+    id: NodeId,
+    addr: SocketAddr,
+    state: PeerState,
+    rtt: Option<Duration>,
+    rtts: Histogram,
+    last_seen: Instant,
+    tags: BTreeMap<String, String>,
+    meta: Option<MetaVersion>,
+    version: Option<u8>,
+    mtu: Option<usize>,
+    incarnation: Option<u64>,
+    health: Option<Health>,
+    suspicion: Option<Suspicion>,
+}
+
+impl Peer {
+    pub fn id(&self) -> NodeId { self.id }
+    pub fn addr(&self) -> SocketAddr { self.addr }
+    pub fn state(&self) -> PeerState { self.state }
+    // The most recent round trip time measured to the peer, if any.
+    pub fn rtt(&self) -> Option<Duration> { self.rtt }
+    // Every round trip time measured to the peer, for its percentiles.
+    pub fn rtts(&self) -> &Histogram { &self.rtts }
+    // When we last received anything from the peer.
+    pub fn last_seen(&self) -> Instant { self.last_seen }
+    pub fn tags(&self) -> &BTreeMap<String, String> { &self.tags }
+    // Which version of its tags the peer last sent us, if it has; see
+    // Dispatcher::set_tag.
+    pub fn meta(&self) -> Option<MetaVersion> { self.meta }
+    // The protocol version the peer joined with, if we've had its Join.
+    // During an upgrade, peers still on an older one show up here.
+    pub fn version(&self) -> Option<u8> { self.version }
+    // The largest datagram known to get through to the peer, if MTU
+    // probing has found one; see mtu::MtuConfig.
+    pub fn mtu(&self) -> Option<usize> { self.mtu }
+    // The incarnation the peer's Acks last gave, if any has; see
+    // message::Responder.
+    pub fn incarnation(&self) -> Option<u64> { self.incarnation }
+    // How the peer said it was doing in its last Pong, if it said; see
+    // health::HealthConfig.
+    pub fn health(&self) -> Option<Health> { self.health }
+    // Why the peer is suspect, if it is.
+    pub fn suspicion(&self) -> Option<Suspicion> { self.suspicion }
+}
+
+// Two snapshots are the same peer if they have the same id, whatever else
+// has changed in between.
+impl PartialEq for Peer {
+    fn eq(&self, other: &Peer) -> bool {
+        self.id == other.id
+    }
+}
+
+impl fmt::Display for Peer {
+    // The first 8 hex digits of the id are plenty to tell peers apart by
+    // eye.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}@{}", self.id.0 >> 32, self.addr)
+    }
+}
+
+// A peer to wait for, by Mesh::wait_for_member: the one with this id,
+// the one at this address, or any with this tag set to this value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeerMatch {
+    Id(NodeId),
+    Addr(SocketAddr),
+    Tag(String, String),
+}
+
+impl PeerMatch {
+    pub fn matches(&self, peer: &Peer) -> bool {
+        match *self {
+            PeerMatch::Id(id) => peer.id == id,
+            PeerMatch::Addr(addr) => peer.addr == addr,
+            PeerMatch::Tag(ref key, ref value) =>
+                peer.tags.get(key) == Some(value),
+        }
+    }
+}
+
+// The membership table: the single source of truth about other nodes,
+// from which Peer snapshots are made.
+pub struct Members {
+    peers: HashMap<NodeId, Peer>,
+    // Who is at each address: the last to join from it. Two peers can
+    // share one for a while, when another node takes it over without
+    // being taken for the first restarted; see merge::restarted. It's
+    // then the newcomer we hear from there, not the one it displaced.
+    holders: HashMap<SocketAddr, NodeId>,
+    // Goes up with every change to any peer, but to when it was last
+    // heard from; see `generation`.
+    generation: u64,
+}
+
+// Every peer as of one generation of the table, for handing to other
+// threads as it is; see Dispatcher::snapshot_handle. Two snapshots of
+// the same generation hold the same peers, exactly, but for when each
+// was last heard from, which is as of when the snapshot was made; see
+// Members::seen.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub generation: u64,
+    // Ordered by id, as from Members::peers.
+    pub peers: Vec<Peer>,
+}
+
+impl Members {
+    pub fn new() -> Members {
+        Members {
+            peers: HashMap::new(),
+            holders: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    // Record that `id` is at `addr` and alive. Returns true if we didn't
+    // know about it before.
+    pub fn join(&mut self, id: NodeId, addr: SocketAddr, now: Instant) -> bool {
+        if let Some(old) = self.peers.get(&id).map(|p| p.addr) {
+            if old != addr && self.holders.get(&old) == Some(&id) {
+                self.holders.remove(&old);
+            }
+        }
+        self.holders.insert(addr, id);
+        match self.peers.get_mut(&id) {
+            Some(p) => {
+                p.addr = addr;
+                p.state = PeerState::Alive;
+                p.suspicion = None;
+                p.last_seen = now;
+                self.generation += 1;
+                return false;
+            },
+            None => (),
+        }
+
+        self.peers.insert(id, Peer {
+            id: id,
+            addr: addr,
+            state: PeerState::Alive,
+            rtt: None,
+            rtts: Histogram::new(),
+            last_seen: now,
+            tags: BTreeMap::new(),
+            meta: None,
+            version: None,
+            mtu: None,
+            incarnation: None,
+            health: None,
+            suspicion: None,
+        });
+        self.generation += 1;
+        true
+    }
+
+    // Forget `id` altogether, as when another node takes its place; see
+    // merge::restarted. Returns what we knew of it, if anything.
+    pub fn remove(&mut self, id: NodeId) -> Option<Peer> {
+        let removed = self.peers.remove(&id);
+        if let Some(ref p) = removed {
+            if self.holders.get(&p.addr) == Some(&id) {
+                self.holders.remove(&p.addr);
+            }
+            self.generation += 1;
+        }
+        removed
+    }
+
+    // Note that we've just heard from whoever is at `addr`, if anyone.
+    // That's with nearly every datagram, so it doesn't move the
+    // generation on, or every poll would make a new snapshot.
+    pub fn seen(&mut self, addr: &SocketAddr, now: Instant) {
+        if let Some(id) = self.id_of(addr) {
+            self.peers.get_mut(&id).unwrap().last_seen = now;
+        }
+    }
+
+    // Returns false if there's no such peer. Any suspicion ends with the
+    // peer leaving Suspect; see `suspect` for entering it with one.
+    pub fn set_state(&mut self, id: NodeId, state: PeerState) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(p) => {
+                p.state = state;
+                if state != PeerState::Suspect {
+                    p.suspicion = None;
+                }
+                self.generation += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Make `id` Suspect for `suspicion`. Returns false if there's no such
+    // peer.
+    pub fn suspect(&mut self, id: NodeId, suspicion: Suspicion) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(p) => {
+                p.state = PeerState::Suspect;
+                p.suspicion = Some(suspicion);
+                self.generation += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Returns false if there's no such peer.
+    pub fn set_rtt(&mut self, id: NodeId, rtt: Duration) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(p) => {
+                p.rtt = Some(rtt);
+                p.rtts.record(rtt);
+                self.generation += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Forget every peer's round trip times, when they can't be trusted.
+    pub fn reset_rtts(&mut self) {
+        for p in self.peers.values_mut() {
+            p.rtt = None;
+            p.rtts = Histogram::new();
+        }
+        self.generation += 1;
+    }
+
+    // Returns false if there's no such peer.
+    pub fn set_version(&mut self, id: NodeId, version: u8) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(p) => {
+                p.version = Some(version);
+                self.generation += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Returns false if there's no such peer.
+    pub fn set_mtu(&mut self, id: NodeId, mtu: Option<usize>) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(p) => {
+                p.mtu = mtu;
+                self.generation += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Returns false if there's no such peer.
+    pub fn set_incarnation(&mut self, id: NodeId, incarnation: u64) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(p) => {
+                p.incarnation = Some(incarnation);
+                self.generation += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Returns false if there's no such peer.
+    pub fn set_tags(&mut self, id: NodeId, meta: MetaVersion,
+                    tags: BTreeMap<String, String>) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(p) => {
+                p.meta = Some(meta);
+                p.tags = tags;
+                self.generation += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Returns false if there's no such peer.
+    pub fn set_health(&mut self, id: NodeId, health: Option<Health>) -> bool {
+        match self.peers.get_mut(&id) {
+            Some(p) => {
+                p.health = health;
+                self.generation += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Whoever is at `addr`: the last to join from it, or, once that one
+    // has gone, any other peer left there.
+    pub fn id_of(&self, addr: &SocketAddr) -> Option<NodeId> {
+        match self.holders.get(addr) {
+            Some(&id) => Some(id),
+            None => self.peers.values().find(|p| p.addr == *addr)
+                .map(|p| p.id),
+        }
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<Peer> {
+        self.peers.get(&id).cloned()
+    }
+
+    // Every known peer, ordered by id.
+    pub fn peers(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self.peers.values().cloned().collect();
+        peers.sort_by_key(|p| p.id);
+        peers
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    // How many times the table has changed, for telling cheaply whether
+    // it has since a snapshot was made.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { generation: self.generation, peers: self.peers() }
+    }
+}
+
+#[cfg(test)]
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+}
+
+#[test]
+fn join_adds_once() {
+    let mut m = Members::new();
+    let now = Instant::now();
+    assert!(m.join(NodeId(1), addr(9000), now));
+    assert!(!m.join(NodeId(1), addr(9001), now));
+    assert_eq!(m.len(), 1);
+    assert_eq!(m.get(NodeId(1)).unwrap().addr(), addr(9001));
+    assert_eq!(m.id_of(&addr(9001)), Some(NodeId(1)));
+    assert_eq!(m.id_of(&addr(9000)), None);
+}
+
+#[test]
+fn an_address_is_the_last_joiners_until_it_goes() {
+    let mut m = Members::new();
+    let start = Instant::now();
+    m.join(NodeId(1), addr(9001), start);
+    m.join(NodeId(2), addr(9001), start);
+    assert_eq!(m.id_of(&addr(9001)), Some(NodeId(2)));
+
+    // Only the newcomer is heard from there.
+    let later = start + Duration::from_secs(1);
+    m.seen(&addr(9001), later);
+    assert_eq!(m.get(NodeId(1)).unwrap().last_seen(), start);
+    assert_eq!(m.get(NodeId(2)).unwrap().last_seen(), later);
+
+    m.remove(NodeId(2));
+    assert_eq!(m.id_of(&addr(9001)), Some(NodeId(1)));
+    m.join(NodeId(1), addr(9002), later);
+    assert_eq!(m.id_of(&addr(9001)), None);
+}
+
+#[test]
+fn snapshots_reflect_table_but_not_later_changes() {
+    let mut m = Members::new();
+    let start = Instant::now();
+    m.join(NodeId(2), addr(9002), start);
+    m.join(NodeId(1), addr(9001), start);
+
+    let before = m.peers();
+    assert_eq!(before.iter().map(|p| p.id()).collect::<Vec<_>>(),
+               vec![NodeId(1), NodeId(2)]);
+
+    let later = start + Duration::from_secs(1);
+    assert!(m.set_state(NodeId(1), PeerState::Suspect));
+    m.seen(&addr(9002), later);
+
+    // The old snapshot is untouched...
+    assert_eq!(before[0].state(), PeerState::Alive);
+    assert_eq!(before[1].last_seen(), start);
+    // ...and a new one shows the changes.
+    let after = m.peers();
+    assert_eq!(after[0].state(), PeerState::Suspect);
+    assert_eq!(after[1].last_seen(), later);
+    assert!(before == after);
+}
+
+#[test]
+fn every_change_moves_the_generation_on() {
+    let mut m = Members::new();
+    let now = Instant::now();
+    assert_eq!(m.snapshot().generation, 0);
+    m.join(NodeId(1), addr(9001), now);
+    let joined = m.generation();
+    assert!(joined > 0);
+
+    // Nothing to change, nothing changed.
+    m.seen(&addr(9002), now);
+    assert!(!m.set_rtt(NodeId(2), Duration::from_millis(1)));
+    assert_eq!(m.generation(), joined);
+
+    // Nor does hearing from a peer.
+    m.seen(&addr(9001), now + Duration::from_secs(1));
+    assert_eq!(m.generation(), joined);
+
+    m.set_mtu(NodeId(1), Some(1200));
+    assert!(m.generation() > joined);
+    let snapshot = m.snapshot();
+    assert_eq!(snapshot.generation, m.generation());
+    assert_eq!(snapshot.peers[0].mtu(), Some(1200));
+}
+
+#[test]
+fn a_suspicion_lasts_as_long_as_the_suspect_state() {
+    let mut m = Members::new();
+    let now = Instant::now();
+    m.join(NodeId(1), addr(9001), now);
+    let suspicion = Suspicion {
+        reason: SuspicionReason::Unacked,
+        since: now,
+        by: NodeId(7),
+    };
+    assert!(m.suspect(NodeId(1), suspicion));
+    assert!(!m.suspect(NodeId(2), suspicion));
+    let peer = m.get(NodeId(1)).unwrap();
+    assert_eq!(peer.state(), PeerState::Suspect);
+    assert_eq!(peer.suspicion(), Some(suspicion));
+
+    m.set_state(NodeId(1), PeerState::Dead);
+    assert_eq!(m.get(NodeId(1)).unwrap().suspicion(), None);
+    m.suspect(NodeId(1), suspicion);
+    m.join(NodeId(1), addr(9001), now);
+    assert_eq!(m.get(NodeId(1)).unwrap().suspicion(), None);
+}
+
+#[test]
+fn peer_display_is_short_id_at_addr() {
+    let mut m = Members::new();
+    m.join(NodeId(0x0123456789abcdef), addr(9000), Instant::now());
+    let p = m.get(NodeId(0x0123456789abcdef)).unwrap();
+    assert_eq!(p.to_string(), "01234567@127.0.0.1:9000");
+    assert_eq!(NodeId(0xab).to_string(), "00000000000000ab");
+}
+
+#[test]
+fn peers_match_by_id_address_or_tag() {
+    let mut m = Members::new();
+    m.join(NodeId(1), addr(9001), Instant::now());
+    let mut tags = BTreeMap::new();
+    tags.insert("role".to_string(), "active".to_string());
+    m.set_tags(NodeId(1), MetaVersion::new(1, 1), tags);
+    let peer = m.get(NodeId(1)).unwrap();
+
+    assert!(PeerMatch::Id(NodeId(1)).matches(&peer));
+    assert!(!PeerMatch::Id(NodeId(2)).matches(&peer));
+    assert!(PeerMatch::Addr(addr(9001)).matches(&peer));
+    assert!(!PeerMatch::Addr(addr(9002)).matches(&peer));
+    let tag = |k: &str, v: &str| PeerMatch::Tag(k.to_string(), v.to_string());
+    assert!(tag("role", "active").matches(&peer));
+    assert!(!tag("role", "standby").matches(&peer));
+    assert!(!tag("zone", "active").matches(&peer));
+}
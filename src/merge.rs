@@ -0,0 +1,417 @@
+use members::{MetaVersion, NodeId, PeerState};
+use std::time::Duration;
+
+// Every way news of a node reaches us goes through `merge`, which says
+// what to make of it given what we already hold, so that the rules for
+// which news wins are written down once. Applying the outcome, and
+// whatever else the news calls for (acking it, asking to sync), is left
+// to whoever took it in.
+//
+// The rules, for news of a peer:
+//
+// - A Join is the peer's own word that it's alive, and beats whatever
+//   state we had it in, unless an operator evicted it, which nothing but
+//   a later incarnation or an answered probe_now undoes.
+// - An incarnation is recorded if it's later than the one we hold, and
+//   ignored as stale if it isn't. One later than the peer was evicted on
+//   ends its eviction, and brings it back Alive.
+// - An eviction makes the peer Dead, remembering (as a tombstone) the
+//   incarnation it was evicted on.
+// - An answered probe_now makes the peer Alive, evicted or not.
+// - A peer's tags are recorded if their version (see MetaVersion) is
+//   later than the one we hold, and ignored as stale if it isn't,
+//   whatever state the peer is in. They say nothing of whether it's
+//   alive.
+// - Another node's suspicion of a peer is none of ours; there's no
+//   gossip to pass it on by.
+// - News of a peer we don't know, but a Join, is ignored.
+// - A Join from a node we don't know, from the address of a peer that
+//   isn't Dead and was heard from lately (see `restarted`), is that peer
+//   restarted, and takes its place rather than joining beside it. From
+//   anyone else's address, or with restarts not looked for, it's a
+//   newcomer like any other.
+//
+// And for news of us:
+//
+// - A Join, or a suspicion of our current incarnation or a later one, is
+//   refuted, by starting a new incarnation. A suspicion of an earlier
+//   one is stale.
+// - Anything else is ignored: an Ack or tags claiming to be from us are
+//   someone impersonating us, and operators can't evict or probe us.
+
+// What we hold about a peer, as far as taking in news of it goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemberEntry {
+    pub state: PeerState,
+    // The latest incarnation we've heard of it on, if any.
+    pub incarnation: Option<u64>,
+    // The incarnation it was evicted on, for as long as it stays evicted;
+    // see Dispatcher::evict.
+    pub evicted: Option<u64>,
+    // The version of its tags we hold, if it has sent us any.
+    pub meta: Option<MetaVersion>,
+}
+
+// News of node `id`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemberUpdate {
+    // It sent us a Join.
+    Join(NodeId),
+    // It acked something as being on this incarnation; see
+    // message::Responder.
+    Incarnation(NodeId, u64),
+    // A node told us it suspects it on this incarnation, with a
+    // Suspected.
+    Suspected(NodeId, u64),
+    // An operator evicted it.
+    Evict(NodeId),
+    // It answered a probe an operator asked for.
+    Probed(NodeId),
+    // It sent us its tags as of this version.
+    Tags(NodeId, MetaVersion),
+}
+
+impl MemberUpdate {
+    pub fn id(&self) -> NodeId {
+        match *self {
+            MemberUpdate::Join(id) | MemberUpdate::Incarnation(id, _)
+                | MemberUpdate::Suspected(id, _) | MemberUpdate::Evict(id)
+                | MemberUpdate::Probed(id) | MemberUpdate::Tags(id, _) => id,
+        }
+    }
+}
+
+// Why news was ignored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ignored {
+    // Of a peer we don't know.
+    Unknown,
+    // Of a peer an operator evicted.
+    Evicted,
+    // Of an incarnation, or a version of tags, no later than the one we
+    // hold.
+    Stale,
+    // Of another node's suspicion of a peer.
+    NotOurs,
+    // Of us, but not from us.
+    Impersonation,
+    // Of us, from an operator.
+    Ourselves,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergeOutcome {
+    // What we hold about the peer should now be this.
+    Updated(MemberEntry),
+    Ignored(Ignored),
+    // We should start a new incarnation, to show we're alive and
+    // ourselves.
+    RefuteSelf,
+}
+
+// Who we are, for telling news of us from news of a peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Us {
+    pub id: NodeId,
+    pub incarnation: u64,
+}
+
+// What to make of `incoming`, given `local`, what we hold about the node
+// it's news of, if anything.
+pub fn merge(us: &Us, local: Option<&MemberEntry>, incoming: &MemberUpdate)
+        -> MergeOutcome {
+    if incoming.id() == us.id {
+        return merge_us(us, incoming);
+    }
+    if let MemberUpdate::Suspected(..) = *incoming {
+        return MergeOutcome::Ignored(Ignored::NotOurs);
+    }
+    let local = match (local, *incoming) {
+        (Some(local), _) => *local,
+        (None, MemberUpdate::Join(_)) => {
+            return MergeOutcome::Updated(MemberEntry {
+                state: PeerState::Alive,
+                incarnation: None,
+                evicted: None,
+                meta: None,
+            });
+        },
+        (None, _) => return MergeOutcome::Ignored(Ignored::Unknown),
+    };
+    let entry = match *incoming {
+        MemberUpdate::Join(_) => {
+            if local.evicted.is_some() {
+                return MergeOutcome::Ignored(Ignored::Evicted);
+            }
+            MemberEntry { state: PeerState::Alive, .. local }
+        },
+        MemberUpdate::Incarnation(_, incarnation) => {
+            if local.incarnation.map_or(false, |known| known >= incarnation) {
+                return MergeOutcome::Ignored(Ignored::Stale);
+            }
+            match local.evicted {
+                Some(evicted) if incarnation > evicted => MemberEntry {
+                    state: PeerState::Alive,
+                    incarnation: Some(incarnation),
+                    evicted: None,
+                    .. local
+                },
+                _ => MemberEntry { incarnation: Some(incarnation), .. local },
+            }
+        },
+        MemberUpdate::Evict(_) => MemberEntry {
+            state: PeerState::Dead,
+            evicted: Some(local.incarnation.unwrap_or(0)),
+            .. local
+        },
+        MemberUpdate::Probed(_) => MemberEntry {
+            state: PeerState::Alive,
+            evicted: None,
+            .. local
+        },
+        MemberUpdate::Tags(_, meta) => {
+            if local.meta.map_or(false, |known| known >= meta) {
+                return MergeOutcome::Ignored(Ignored::Stale);
+            }
+            MemberEntry { meta: Some(meta), .. local }
+        },
+        MemberUpdate::Suspected(..) => unreachable!(),
+    };
+    MergeOutcome::Updated(entry)
+}
+
+// Whether a node we don't know, Joining from the address of a peer we
+// hold as `local` and last heard from `silent` ago, is that peer
+// restarted under a new id: so if the peer was heard from within
+// `window`, and isn't Dead, which evicted peers are too. A node found
+// dead before another turns up at its address is taken to have gone for
+// good, and the address to have been handed on. Inside the window, though,
+// a different node taking the address over can't be told from the peer
+// restarted, and is taken for it: the peer is replaced, not found dead.
+pub fn restarted(local: &MemberEntry, silent: Duration, window: Duration)
+        -> bool {
+    local.state != PeerState::Dead && local.evicted.is_none()
+        && silent < window
+}
+
+fn merge_us(us: &Us, incoming: &MemberUpdate) -> MergeOutcome {
+    match *incoming {
+        MemberUpdate::Join(_) => MergeOutcome::RefuteSelf,
+        MemberUpdate::Suspected(_, incarnation)
+                if incarnation < us.incarnation =>
+            MergeOutcome::Ignored(Ignored::Stale),
+        MemberUpdate::Suspected(..) => MergeOutcome::RefuteSelf,
+        MemberUpdate::Incarnation(..) | MemberUpdate::Tags(..) =>
+            MergeOutcome::Ignored(Ignored::Impersonation),
+        MemberUpdate::Evict(_) | MemberUpdate::Probed(_) =>
+            MergeOutcome::Ignored(Ignored::Ourselves),
+    }
+}
+
+// The cases below, as a table: what we hold about the node the news is
+// of, what kind of news it is, and how its incarnation (if it has one)
+// compares with the one we hold. Each local entry but Unknown and New is
+// on incarnation 5, as are we; Evicted was evicted on it. Every local
+// entry but Unknown holds tags of version 5.1, and tags come as of
+// version 4.1, 5.1 or 6.1.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Local { Unknown, New, Alive, Suspect, Dead, Evicted, Us }
+
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind { Join, Incarnation, Suspected, Evict, Probed, Tags }
+
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Than { Older, Same, Newer, Any }
+
+#[cfg(test)]
+fn entry(state: PeerState, incarnation: Option<u64>, evicted: Option<u64>)
+        -> MemberEntry {
+    MemberEntry {
+        state: state,
+        incarnation: incarnation,
+        evicted: evicted,
+        meta: Some(MetaVersion::new(5, 1)),
+    }
+}
+
+#[cfg(test)]
+fn updated(state: PeerState, incarnation: Option<u64>,
+           evicted: Option<u64>) -> MergeOutcome {
+    MergeOutcome::Updated(entry(state, incarnation, evicted))
+}
+
+// As `updated`, with tags of version 6.1.
+#[cfg(test)]
+fn tagged(state: PeerState, incarnation: Option<u64>,
+          evicted: Option<u64>) -> MergeOutcome {
+    MergeOutcome::Updated(MemberEntry {
+        meta: Some(MetaVersion::new(6, 1)),
+        .. entry(state, incarnation, evicted)
+    })
+}
+
+#[test]
+fn merge_table() {
+    use self::Kind::*;
+    use self::Than::*;
+    use members::PeerState::{Alive, Dead, Suspect};
+
+    let ignored = MergeOutcome::Ignored;
+    let refute = MergeOutcome::RefuteSelf;
+    let new = MergeOutcome::Updated(MemberEntry {
+        meta: None,
+        .. entry(Alive, None, None)
+    });
+    let table = vec![
+        (Local::Unknown, Join, Any, new),
+        (Local::Unknown, Incarnation, Any, ignored(Ignored::Unknown)),
+        (Local::Unknown, Suspected, Any, ignored(Ignored::NotOurs)),
+        (Local::Unknown, Evict, Any, ignored(Ignored::Unknown)),
+        (Local::Unknown, Probed, Any, ignored(Ignored::Unknown)),
+        (Local::Unknown, Tags, Any, ignored(Ignored::Unknown)),
+
+        // Known, but not yet on any incarnation.
+        (Local::New, Join, Any, updated(Alive, None, None)),
+        (Local::New, Incarnation, Older, updated(Alive, Some(4), None)),
+        (Local::New, Incarnation, Same, updated(Alive, Some(5), None)),
+        (Local::New, Incarnation, Newer, updated(Alive, Some(6), None)),
+        (Local::New, Suspected, Any, ignored(Ignored::NotOurs)),
+        (Local::New, Evict, Any, updated(Dead, None, Some(0))),
+        (Local::New, Probed, Any, updated(Alive, None, None)),
+        (Local::New, Tags, Older, ignored(Ignored::Stale)),
+        (Local::New, Tags, Same, ignored(Ignored::Stale)),
+        (Local::New, Tags, Newer, tagged(Alive, None, None)),
+
+        (Local::Alive, Join, Any, updated(Alive, Some(5), None)),
+        (Local::Alive, Incarnation, Older, ignored(Ignored::Stale)),
+        (Local::Alive, Incarnation, Same, ignored(Ignored::Stale)),
+        (Local::Alive, Incarnation, Newer, updated(Alive, Some(6), None)),
+        (Local::Alive, Suspected, Any, ignored(Ignored::NotOurs)),
+        (Local::Alive, Evict, Any, updated(Dead, Some(5), Some(5))),
+        (Local::Alive, Probed, Any, updated(Alive, Some(5), None)),
+        (Local::Alive, Tags, Older, ignored(Ignored::Stale)),
+        (Local::Alive, Tags, Same, ignored(Ignored::Stale)),
+        (Local::Alive, Tags, Newer, tagged(Alive, Some(5), None)),
+
+        (Local::Suspect, Join, Any, updated(Alive, Some(5), None)),
+        (Local::Suspect, Incarnation, Older, ignored(Ignored::Stale)),
+        (Local::Suspect, Incarnation, Same, ignored(Ignored::Stale)),
+        (Local::Suspect, Incarnation, Newer,
+         updated(Suspect, Some(6), None)),
+        (Local::Suspect, Suspected, Any, ignored(Ignored::NotOurs)),
+        (Local::Suspect, Evict, Any, updated(Dead, Some(5), Some(5))),
+        (Local::Suspect, Probed, Any, updated(Alive, Some(5), None)),
+        (Local::Suspect, Tags, Older, ignored(Ignored::Stale)),
+        (Local::Suspect, Tags, Same, ignored(Ignored::Stale)),
+        (Local::Suspect, Tags, Newer, tagged(Suspect, Some(5), None)),
+
+        (Local::Dead, Join, Any, updated(Alive, Some(5), None)),
+        (Local::Dead, Incarnation, Older, ignored(Ignored::Stale)),
+        (Local::Dead, Incarnation, Same, ignored(Ignored::Stale)),
+        // Recorded, but only hearing from it revives it.
+        (Local::Dead, Incarnation, Newer, updated(Dead, Some(6), None)),
+        (Local::Dead, Suspected, Any, ignored(Ignored::NotOurs)),
+        (Local::Dead, Evict, Any, updated(Dead, Some(5), Some(5))),
+        (Local::Dead, Probed, Any, updated(Alive, Some(5), None)),
+        (Local::Dead, Tags, Older, ignored(Ignored::Stale)),
+        (Local::Dead, Tags, Same, ignored(Ignored::Stale)),
+        (Local::Dead, Tags, Newer, tagged(Dead, Some(5), None)),
+
+        (Local::Evicted, Join, Any, ignored(Ignored::Evicted)),
+        (Local::Evicted, Incarnation, Older, ignored(Ignored::Stale)),
+        (Local::Evicted, Incarnation, Same, ignored(Ignored::Stale)),
+        (Local::Evicted, Incarnation, Newer, updated(Alive, Some(6), None)),
+        (Local::Evicted, Suspected, Any, ignored(Ignored::NotOurs)),
+        (Local::Evicted, Evict, Any, updated(Dead, Some(5), Some(5))),
+        (Local::Evicted, Probed, Any, updated(Alive, Some(5), None)),
+        (Local::Evicted, Tags, Older, ignored(Ignored::Stale)),
+        (Local::Evicted, Tags, Same, ignored(Ignored::Stale)),
+        (Local::Evicted, Tags, Newer, tagged(Dead, Some(5), Some(5))),
+
+        (Local::Us, Join, Any, refute),
+        (Local::Us, Incarnation, Any, ignored(Ignored::Impersonation)),
+        (Local::Us, Suspected, Older, ignored(Ignored::Stale)),
+        (Local::Us, Suspected, Same, refute),
+        (Local::Us, Suspected, Newer, refute),
+        (Local::Us, Evict, Any, ignored(Ignored::Ourselves)),
+        (Local::Us, Probed, Any, ignored(Ignored::Ourselves)),
+        (Local::Us, Tags, Any, ignored(Ignored::Impersonation)),
+    ];
+
+    let us = Us { id: NodeId(1), incarnation: 5 };
+    let peer = NodeId(2);
+    let locals = [Local::Unknown, Local::New, Local::Alive, Local::Suspect,
+                  Local::Dead, Local::Evicted, Local::Us];
+    let kinds = [Join, Incarnation, Suspected, Evict, Probed, Tags];
+    let mut cases = 0;
+    for &local in locals.iter() {
+        for &kind in kinds.iter() {
+            for &(than, incarnation) in [(Older, 4), (Same, 5), (Newer, 6)]
+                    .iter() {
+                let rows: Vec<MergeOutcome> = table.iter()
+                    .filter(|row| row.0 == local && row.1 == kind
+                            && (row.2 == than || row.2 == Any))
+                    .map(|row| row.3).collect();
+                assert_eq!(rows.len(), 1, "{:?} {:?} {:?} is in the table \
+                           {} times", local, kind, than, rows.len());
+
+                let id = if local == Local::Us { us.id } else { peer };
+                let held = match local {
+                    Local::Unknown | Local::Us => None,
+                    Local::New => Some(entry(Alive, None, None)),
+                    Local::Alive => Some(entry(Alive, Some(5), None)),
+                    Local::Suspect => Some(entry(Suspect, Some(5), None)),
+                    Local::Dead => Some(entry(Dead, Some(5), None)),
+                    Local::Evicted => Some(entry(Dead, Some(5), Some(5))),
+                };
+                let incoming = match kind {
+                    Join => MemberUpdate::Join(id),
+                    Incarnation => MemberUpdate::Incarnation(id, incarnation),
+                    Suspected => MemberUpdate::Suspected(id, incarnation),
+                    Evict => MemberUpdate::Evict(id),
+                    Probed => MemberUpdate::Probed(id),
+                    Tags => MemberUpdate::Tags(id,
+                                               MetaVersion::new(incarnation,
+                                                                1)),
+                };
+                assert_eq!(merge(&us, held.as_ref(), &incoming), rows[0],
+                           "{:?} {:?} {:?}", local, kind, than);
+                cases += 1;
+            }
+        }
+    }
+    assert_eq!(cases, 126);
+}
+
+#[test]
+fn tags_after_a_restart_win_however_few_changes_they_have_seen() {
+    let us = Us { id: NodeId(1), incarnation: 0 };
+    let held = MemberEntry { meta: Some(MetaVersion::new(0, 7)),
+                             .. entry(PeerState::Alive, Some(0), None) };
+    // Its first change since restarting on incarnation 1.
+    let restarted = MemberUpdate::Tags(NodeId(2), MetaVersion::new(1, 1));
+    let entry = match merge(&us, Some(&held), &restarted) {
+        MergeOutcome::Updated(entry) => entry,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(entry.meta, Some(MetaVersion::new(1, 1)));
+    // And a change from before, still on its way, comes too late.
+    let late = MemberUpdate::Tags(NodeId(2), MetaVersion::new(0, 8));
+    assert_eq!(merge(&us, Some(&entry), &late),
+               MergeOutcome::Ignored(Ignored::Stale));
+}
+
+#[test]
+fn a_newcomer_where_a_peer_lately_was_is_it_restarted() {
+    use members::PeerState::{Alive, Dead, Suspect};
+    let secs = Duration::from_secs;
+    let window = secs(10);
+    assert!(restarted(&entry(Alive, Some(5), None), secs(1), window));
+    assert!(restarted(&entry(Suspect, Some(5), None), secs(9), window));
+    assert!(!restarted(&entry(Alive, Some(5), None), secs(10), window));
+    assert!(!restarted(&entry(Dead, Some(5), None), secs(1), window));
+    assert!(!restarted(&entry(Dead, Some(5), Some(5)), secs(1), window));
+}
@@ -0,0 +1,296 @@
+use members::NodeId;
+use protocol::limits::{DEFAULT_MTU, MAX_PING_PAD, MIN_MTU, MTU_PRECISION};
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Whether and how to find out how large a datagram gets through to each
+// peer. Every `interval` a peer is sent a Ping padded out to the next size
+// to try, alongside its usual probe, and a binary search over sizes from
+// MIN_MTU to `max` narrows in on the largest one it answers: a handful of
+// probes, over some minutes. Until then, and whenever the answers are
+// ambiguous, `default` is used. Peers still limiting Ping padding to
+// what older versions did simply don't answer the larger probes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MtuConfig {
+    pub enabled: bool,
+    pub default: usize,
+    pub max: usize,
+    pub interval: Duration,
+}
+
+impl Default for MtuConfig {
+    fn default() -> MtuConfig {
+        MtuConfig {
+            enabled: false,
+            default: DEFAULT_MTU,
+            max: MAX_PING_PAD,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+// A padded Ping awaiting its Pong.
+struct Probe {
+    nonce: u64,
+    size: usize,
+    sent: Instant,
+}
+
+// How far the search for one peer has got.
+struct Search {
+    // The largest size known to get through, if any is yet, and the
+    // smallest known not to. The answer lies between.
+    good: Option<usize>,
+    bad: usize,
+    probe: Option<Probe>,
+    last_sent: Option<Instant>,
+    // Finished, with `good` as the answer; or abandoned, with None.
+    done: bool,
+}
+
+impl Search {
+    fn new(config: &MtuConfig) -> Search {
+        Search {
+            good: None,
+            bad: config.max + 1,
+            probe: None,
+            last_sent: None,
+            done: false,
+        }
+    }
+
+    // The next size worth trying: the default first, as the likeliest,
+    // then halfway between what's known. If nothing has got through by
+    // the time the search closes in on MIN_MTU, that's tried itself.
+    fn next_size(&self, config: &MtuConfig) -> Option<usize> {
+        if self.done {
+            return None;
+        }
+        let low = match self.good {
+            Some(good) => good,
+            // Only if it's not been tried and lost already.
+            None if self.bad > config.default && config.default >= MIN_MTU =>
+                return Some(config.default),
+            None if self.bad <= MIN_MTU + MTU_PRECISION =>
+                return if self.bad > MIN_MTU { Some(MIN_MTU) } else { None },
+            None => MIN_MTU,
+        };
+        if self.bad <= low + MTU_PRECISION {
+            None
+        } else {
+            Some((low + self.bad) / 2)
+        }
+    }
+}
+
+// The MTU search for every peer; see MtuConfig.
+pub struct MtuProber {
+    config: MtuConfig,
+    searches: HashMap<NodeId, Search>,
+}
+
+impl MtuProber {
+    pub fn new(config: MtuConfig) -> MtuProber {
+        MtuProber { config: config, searches: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &MtuConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: MtuConfig) {
+        self.config = config;
+    }
+
+    // The size of datagram to probe `id` with now, if one is due. A probe
+    // still unanswered when the next is due was too big, if we've heard
+    // from the peer since we sent it (`heard` being when we last did);
+    // if not, there's no telling, and the search is given up.
+    pub fn due(&mut self, id: NodeId, heard: Instant, now: Instant)
+            -> Option<usize> {
+        if !self.config.enabled {
+            return None;
+        }
+        let config = &self.config;
+        let search = self.searches.entry(id)
+            .or_insert_with(|| Search::new(config));
+        if search.done {
+            return None;
+        }
+        if let Some(last) = search.last_sent {
+            if now.duration_since(last) < config.interval {
+                return None;
+            }
+        }
+        if let Some(probe) = search.probe.take() {
+            if heard > probe.sent {
+                search.bad = probe.size;
+            } else {
+                search.good = None;
+                search.done = true;
+                return None;
+            }
+        }
+        let size = search.next_size(config);
+        if size.is_none() {
+            search.done = true;
+        }
+        size
+    }
+
+    // A probe of `size` bytes went to `id` as the Ping with `nonce`.
+    pub fn sent(&mut self, id: NodeId, nonce: u64, size: usize,
+                now: Instant) {
+        if let Some(search) = self.searches.get_mut(&id) {
+            search.probe = Some(Probe { nonce: nonce, size: size, sent: now });
+            search.last_sent = Some(now);
+        }
+    }
+
+    // The Pong for the Ping with `nonce` came back. If that was a probe,
+    // returns who it was to, whose MTU may now be known to be larger.
+    pub fn answered(&mut self, nonce: u64) -> Option<NodeId> {
+        for (id, search) in self.searches.iter_mut() {
+            if search.probe.as_ref().map_or(false, |p| p.nonce == nonce) {
+                let probe = search.probe.take().unwrap();
+                search.good = Some(probe.size);
+                return Some(*id);
+            }
+        }
+        None
+    }
+
+    // The OS refused to send `id` a datagram of `size` bytes, as too big
+    // for the path. Nothing that size or more is taken to get through
+    // from now on, and if nothing smaller is known to, MIN_MTU is
+    // assumed, every path carrying that much, unless that was refused.
+    pub fn refused(&mut self, id: NodeId, size: usize) {
+        let config = &self.config;
+        let search = self.searches.entry(id)
+            .or_insert_with(|| Search::new(config));
+        search.bad = cmp::min(search.bad, size);
+        if search.good.map_or(true, |good| good >= size) {
+            search.good = if size > MIN_MTU { Some(MIN_MTU) } else { None };
+        }
+    }
+
+    // The largest datagram known to get through to `id`, if any is.
+    pub fn mtu(&self, id: NodeId) -> Option<usize> {
+        self.searches.get(&id).and_then(|s| s.good)
+    }
+
+    // Whether the search for `id` has finished, one way or the other.
+    pub fn done(&self, id: NodeId) -> bool {
+        self.searches.get(&id).map_or(false, |s| s.done)
+    }
+
+    pub fn forget(&mut self, id: NodeId) {
+        self.searches.remove(&id);
+    }
+}
+
+#[cfg(test)]
+fn enabled() -> MtuConfig {
+    MtuConfig { enabled: true, .. MtuConfig::default() }
+}
+
+// Run the search for one peer to its end against a path that carries
+// datagrams of up to `limit` bytes, the peer answering everything else.
+// Returns the answer and how many probes it took.
+#[cfg(test)]
+fn search(config: MtuConfig, limit: usize) -> (Option<usize>, usize) {
+    let mut prober = MtuProber::new(config.clone());
+    let id = NodeId(1);
+    let mut now = Instant::now();
+    let mut probes = 0;
+    // Always having just heard from the peer, by way of its usual Pongs.
+    while let Some(size) = prober.due(id, now, now) {
+        prober.sent(id, probes as u64, size, now);
+        if size <= limit {
+            prober.answered(probes as u64);
+        }
+        probes += 1;
+        now += config.interval;
+    }
+    assert!(prober.done(id));
+    (prober.mtu(id), probes)
+}
+
+#[test]
+fn search_converges_just_below_the_limit() {
+    for &limit in &[MIN_MTU, 1000, 1400, 1472, 3000, 7000, MAX_PING_PAD] {
+        let (mtu, probes) = search(enabled(), limit);
+        let mtu = mtu.unwrap();
+        assert!(mtu <= limit && mtu + MTU_PRECISION >= limit,
+                "found {} for a limit of {}", mtu, limit);
+        assert!(probes <= 10, "{} probes for a limit of {}", probes, limit);
+    }
+}
+
+#[test]
+fn nothing_getting_through_leaves_the_default() {
+    assert_eq!(search(enabled(), MIN_MTU - 1), (None, 6));
+}
+
+#[test]
+fn probes_are_spaced_out() {
+    let mut prober = MtuProber::new(enabled());
+    let now = Instant::now();
+    assert_eq!(prober.due(NodeId(1), now, now), Some(1400));
+    prober.sent(NodeId(1), 1, 1400, now);
+    prober.answered(1);
+    let soon = now + Duration::from_secs(29);
+    assert_eq!(prober.due(NodeId(1), soon, soon), None);
+    let later = now + Duration::from_secs(30);
+    assert!(prober.due(NodeId(1), later, later).unwrap() > 1400);
+}
+
+#[test]
+fn silence_gives_up_on_the_search() {
+    let mut prober = MtuProber::new(enabled());
+    let start = Instant::now();
+    prober.due(NodeId(1), start, start);
+    prober.sent(NodeId(1), 1, 1400, start);
+    prober.answered(1);
+    let next = start + Duration::from_secs(30);
+    prober.due(NodeId(1), next, next);
+    prober.sent(NodeId(1), 2, 4700, next);
+
+    // Nothing at all since: the probe's loss says nothing about its size.
+    let later = next + Duration::from_secs(30);
+    assert_eq!(prober.due(NodeId(1), next, later), None);
+    assert!(prober.done(NodeId(1)));
+    assert_eq!(prober.mtu(NodeId(1)), None);
+}
+
+#[test]
+fn disabled_sends_no_probes() {
+    let mut prober = MtuProber::new(MtuConfig::default());
+    let now = Instant::now();
+    assert_eq!(prober.due(NodeId(1), now, now), None);
+}
+
+#[test]
+fn refusals_lower_the_mtu() {
+    let mut prober = MtuProber::new(enabled());
+    let now = Instant::now();
+    prober.due(NodeId(1), now, now);
+    prober.sent(NodeId(1), 1, 1400, now);
+    prober.answered(1);
+    prober.refused(NodeId(1), 2000);
+    assert_eq!(prober.mtu(NodeId(1)), Some(1400));
+    prober.refused(NodeId(1), 1000);
+    assert_eq!(prober.mtu(NodeId(1)), Some(MIN_MTU));
+    // The search carries on below the refusal.
+    let later = now + Duration::from_secs(30);
+    let next = prober.due(NodeId(1), later, later).unwrap();
+    assert!(next > MIN_MTU && next < 1000, "{}", next);
+
+    // Even without probing, a refusal says something.
+    let mut quiet = MtuProber::new(MtuConfig::default());
+    quiet.refused(NodeId(2), 9000);
+    assert_eq!(quiet.mtu(NodeId(2)), Some(MIN_MTU));
+    quiet.refused(NodeId(2), MIN_MTU);
+    assert_eq!(quiet.mtu(NodeId(2)), None);
+}
@@ -0,0 +1,459 @@
+use chaos::Chaos;
+use clock::{Clock, Nanos, SystemClock};
+use config::Config;
+use dispatch::Dispatcher;
+use histogram::Histogram;
+use members::{NodeId, Peer, PeerState};
+use rustc_serialize::json;
+use scheduler::Scheduler;
+use signals;
+use size::SizeEstimate;
+use sockopts::SocketInfo;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::time::Duration;
+use trace::Recorder;
+use transport::Transport;
+
+// A node on a real socket and the real clock. The socket misbehaves only
+// if Config::chaos says so, and what the node makes of it is written
+// down only if Config::dump_packets says where.
+pub type Node = Dispatcher<Recorder<Chaos<UdpSocket>>, SystemClock>;
+
+// Bind a socket as `config` says and start a dispatcher on it. Once this
+// returns, the node is receiving.
+pub fn start(config: &Config) -> io::Result<Node> {
+    let socket = try!(config.socket_opts().bind((&config.host[..], config.port)));
+    let socket = Recorder::new(Chaos::new(socket, config.chaos.clone()));
+    let node = Dispatcher::with_config(socket, SystemClock, config);
+    if let Some(ref path) = config.dump_packets {
+        // A line at a time, so that a node that dies leaves all it saw.
+        let file = LineWriter::new(try!(File::create(path)));
+        try!(node.transport().record_to(Box::new(file), node.node_id(),
+                                        node.seed()));
+    }
+    Ok(node)
+}
+
+// Switch a node started with `running` over to `new` as far as it can
+// be without a restart, warning about each field that can't, and return
+// the names of those changed.
+pub fn reload<T: Transport, C: Clock>(node: &mut Dispatcher<T, C>,
+                                      running: &Config, new: &Config)
+        -> Vec<&'static str> {
+    for field in running.restart_needed(new) {
+        println!("WARNING: {} changed; restart to apply it", field);
+    }
+    node.reload(new)
+}
+
+#[derive(RustcEncodable)]
+struct Ready<'a> {
+    event: &'a str,
+    addr: String,
+    id: String,
+    cluster: &'a str,
+}
+
+// The line announcing that a node is up, for supervisors and test
+// harnesses to wait for: `READY addr=<ip:port> id=<node id> cluster=<name>`,
+// or the same as a JSON object with "event": "ready".
+pub fn ready_line(addr: &SocketAddr, id: NodeId, config: &Config) -> String {
+    if config.json {
+        json::encode(&Ready {
+            event: "ready",
+            addr: addr.to_string(),
+            id: id.to_string(),
+            cluster: &config.cluster,
+        }).unwrap()
+    } else {
+        format!("READY addr={} id={} cluster={}", addr, id, config.cluster)
+    }
+}
+
+#[derive(RustcEncodable)]
+struct Socket<'a> {
+    event: &'a str,
+    addr: String,
+    family: String,
+    recv_buffer_bytes: usize,
+    send_buffer_bytes: usize,
+    reuse_addr: bool,
+    read_timeout_ms: Option<u64>,
+}
+
+// What a node's socket is bound to and how it's set up, as announced
+// after READY: `SOCKET addr=<ip:port> family=<ipv4|ipv6>
+// recv_buffer_bytes=<n> send_buffer_bytes=<n> reuse_addr=<bool>
+// read_timeout_ms=<ms>`, or the same as a JSON object with "event":
+// "socket". The read timeout is `-` (null in JSON) if there's none.
+pub fn socket_line(info: &SocketInfo, config: &Config) -> String {
+    let socket = Socket {
+        event: "socket",
+        addr: info.local_addr.to_string(),
+        family: info.family.to_string(),
+        recv_buffer_bytes: info.recv_buffer_bytes,
+        send_buffer_bytes: info.send_buffer_bytes,
+        reuse_addr: info.reuse_addr,
+        read_timeout_ms: info.read_timeout.map(|t| {
+            t.as_secs() * 1000 + t.subsec_nanos() as u64 / 1000000
+        }),
+    };
+    if config.json {
+        json::encode(&socket).unwrap()
+    } else {
+        format!("SOCKET addr={} family={} recv_buffer_bytes={} \
+                 send_buffer_bytes={} reuse_addr={} read_timeout_ms={}",
+                socket.addr, socket.family, socket.recv_buffer_bytes,
+                socket.send_buffer_bytes, socket.reuse_addr,
+                socket.read_timeout_ms.map_or("-".to_string(),
+                                              |ms| ms.to_string()))
+    }
+}
+
+#[derive(RustcEncodable)]
+struct Status<'a> {
+    event: &'a str,
+    peers: usize,
+    alive: usize,
+    suspect: usize,
+    dead: usize,
+    rtt_p50_us: Option<u64>,
+    rtt_p90_us: Option<u64>,
+    rtt_p99_us: Option<u64>,
+    overloaded: usize,
+    mesh_size: usize,
+    mesh_size_min: usize,
+    mesh_size_max: usize,
+    handling_p99_us: Option<u64>,
+}
+
+// A running node's periodic report: `STATUS peers=<n> alive=<n>
+// suspect=<n> dead=<n> rtt_p50_us=<us> rtt_p90_us=<us> rtt_p99_us=<us>
+// overloaded=<n> mesh_size=<n> mesh_size_min=<n> mesh_size_max=<n>
+// handling_p99_us=<us>`, or the same as a JSON object with "event":
+// "status". The rtt percentiles are of every round trip time measured to
+// the peers listed, and are `-` (null in JSON) before any has been.
+// Overloaded peers are those whose last Pong said so, by `config.health`.
+// The mesh's size is `size`, and how far apart the nodes' counts of it
+// are; see Dispatcher::estimated_size. The last is of how long messages
+// of every kind have taken to handle, `handling`, and is `-` likewise;
+// see timing::TimingConfig.
+pub fn status_line(peers: &[Peer], size: &SizeEstimate, handling: &Histogram,
+                   config: &Config) -> String {
+    let count = |state| peers.iter().filter(|p| p.state() == state).count();
+    let mut rtts = Histogram::new();
+    for p in peers {
+        rtts.merge(p.rtts());
+    }
+    let micros = |d: Option<Duration>| d.map(|d| {
+        d.as_secs() * 1000000 + d.subsec_nanos() as u64 / 1000
+    });
+    let status = Status {
+        event: "status",
+        peers: peers.len(),
+        alive: count(PeerState::Alive),
+        suspect: count(PeerState::Suspect),
+        dead: count(PeerState::Dead),
+        rtt_p50_us: micros(rtts.p50()),
+        rtt_p90_us: micros(rtts.p90()),
+        rtt_p99_us: micros(rtts.p99()),
+        overloaded: peers.iter().filter(|p| {
+            p.health().map_or(false, |h| config.health.overloaded(&h))
+        }).count(),
+        mesh_size: size.size,
+        mesh_size_min: size.min,
+        mesh_size_max: size.max,
+        handling_p99_us: micros(handling.p99()),
+    };
+    if config.json {
+        json::encode(&status).unwrap()
+    } else {
+        let show = |us: Option<u64>| us.map_or("-".to_string(),
+                                              |us| us.to_string());
+        format!("STATUS peers={} alive={} suspect={} dead={} rtt_p50_us={} \
+                 rtt_p90_us={} rtt_p99_us={} overloaded={} mesh_size={} \
+                 mesh_size_min={} mesh_size_max={} handling_p99_us={}",
+                status.peers, status.alive, status.suspect, status.dead,
+                show(status.rtt_p50_us), show(status.rtt_p90_us),
+                show(status.rtt_p99_us), status.overloaded, status.mesh_size,
+                status.mesh_size_min, status.mesh_size_max,
+                show(status.handling_p99_us))
+    }
+}
+
+// Run a node until it's shut down, or the process is told to terminate
+// (see signals::install): start it, announce it on `out`, join `target`
+// if given, and print a status line every `config.status_interval` as
+// `scheduler` times it. The node watches that the scheduler keeps time;
+// see Dispatcher::watch. Returns what the process should exit with, if
+// the node shut itself down; see Dispatcher::exit_code.
+pub fn run<W: Write>(config: &Config, target: Option<&str>,
+                     scheduler: &mut Scheduler, out: &mut W)
+        -> io::Result<Option<i32>> {
+    let mut node = try!(start(config));
+    let addr = try!(node.local_addr());
+    if !config.json {
+        try!(writeln!(out, "mesh node {} listening on {}", node.node_id(), addr));
+    }
+    // With the seed, so that the run can be repeated.
+    if let Some(chaos) = node.transport().inner().config() {
+        try!(writeln!(out, "WARNING: injecting failures: {}", chaos));
+    }
+    if let Some(ref path) = config.dump_packets {
+        try!(writeln!(out, "Dumping packets to {}", path));
+    }
+    try!(writeln!(out, "{}", ready_line(&addr, node.node_id(), config)));
+    // Where the platform can say.
+    if let Ok(info) = node.socket_info() {
+        try!(writeln!(out, "{}", socket_line(&info, config)));
+    }
+    try!(out.flush());
+
+    if let Some(target) = target {
+        let target = try!(resolve(target));
+        node.join(&target);
+        node.ping(&target);
+    }
+
+    // The node polls on this thread; the scheduler's only tells it when
+    // to report.
+    let interval = Nanos::from(config.status_interval);
+    let (tx, rx) = mpsc::sync_channel(1);
+    scheduler.delay_send(interval, tx.clone(), ());
+    node.watch(scheduler.handle());
+    let shutdown = node.shutdown_handle();
+    while !shutdown.load(Ordering::SeqCst) && !signals::terminated() {
+        node.poll();
+        if rx.try_recv().is_ok() {
+            let handling = node.stats.handling.total();
+            try!(writeln!(out, "{}", status_line(&node.peers(),
+                                                 &node.estimated_size(),
+                                                 &handling, config)));
+            try!(out.flush());
+            scheduler.delay_send(interval, tx.clone(), ());
+        }
+    }
+    Ok(node.exit_code())
+}
+
+// The address to send to for `target`, as given on the command line.
+pub fn resolve(target: &str) -> io::Result<SocketAddr> {
+    match try!(target.to_socket_addrs()).next() {
+        Some(addr) => {
+            try!(check_target(&addr));
+            Ok(addr)
+        },
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                   format!("{} has no addresses", target))),
+    }
+}
+
+// Refuse to send to `addr` if it isn't one node: the unspecified address
+// means nothing as a destination, and a broadcast or multicast one would
+// put our Join on the whole LAN. Anything a user names as somewhere to
+// send goes through here first.
+pub fn check_target(addr: &SocketAddr) -> io::Result<()> {
+    let ip = addr.ip();
+    let what = if ip.is_unspecified() {
+        "the unspecified address"
+    } else if ip.is_multicast() {
+        "a multicast address"
+    } else {
+        match ip {
+            IpAddr::V4(v4) if v4.is_broadcast() => "the broadcast address",
+            _ => return Ok(()),
+        }
+    };
+    Err(io::Error::new(io::ErrorKind::InvalidInput,
+                       format!("{} is {}, not a node", addr, what)))
+}
+
+#[test]
+fn start_reports_os_assigned_port() {
+    let node = start(&Config::default()).unwrap();
+    let addr = node.local_addr().unwrap();
+    assert!(addr.port() != 0);
+    assert_eq!(addr.ip().to_string(), "127.0.0.1");
+}
+
+#[test]
+fn ready_line_formats() {
+    let addr = "127.0.0.1:4000".parse().unwrap();
+    let mut config = Config { cluster: "prod".to_string(),
+                              .. Config::default() };
+    assert_eq!(ready_line(&addr, NodeId(0xab), &config),
+               "READY addr=127.0.0.1:4000 id=00000000000000ab cluster=prod");
+
+    config.json = true;
+    config.cluster = "prod \"eu\"".to_string();
+    assert_eq!(ready_line(&addr, NodeId(0xab), &config),
+               "{\"event\":\"ready\",\"addr\":\"127.0.0.1:4000\",\
+                \"id\":\"00000000000000ab\",\"cluster\":\"prod \\\"eu\\\"\"}");
+}
+
+#[test]
+fn socket_line_formats() {
+    use sockopts::Family;
+
+    let mut info = SocketInfo {
+        local_addr: "127.0.0.1:4000".parse().unwrap(),
+        family: Family::V4,
+        recv_buffer_bytes: 212992,
+        send_buffer_bytes: 65536,
+        reuse_addr: true,
+        read_timeout: Some(Duration::from_millis(50)),
+    };
+    let mut config = Config::default();
+    assert_eq!(socket_line(&info, &config),
+               "SOCKET addr=127.0.0.1:4000 family=ipv4 \
+                recv_buffer_bytes=212992 send_buffer_bytes=65536 \
+                reuse_addr=true read_timeout_ms=50");
+
+    config.json = true;
+    info.read_timeout = None;
+    assert_eq!(socket_line(&info, &config),
+               "{\"event\":\"socket\",\"addr\":\"127.0.0.1:4000\",\
+                \"family\":\"ipv4\",\"recv_buffer_bytes\":212992,\
+                \"send_buffer_bytes\":65536,\"reuse_addr\":true,\
+                \"read_timeout_ms\":null}");
+}
+
+#[test]
+fn status_line_counts_peers_by_state() {
+    use members::Members;
+    use message::Health;
+    use std::time::Instant;
+
+    let mut members = Members::new();
+    let now = Instant::now();
+    for n in 1..4 {
+        let addr = format!("127.0.0.1:{}", 9000 + n).parse().unwrap();
+        members.join(NodeId(n), addr, now);
+    }
+    members.set_state(NodeId(2), PeerState::Suspect);
+    members.set_state(NodeId(3), PeerState::Dead);
+    let mut config = Config::default();
+    let busy = Health { queued: config.health.queued, dropped: 0, alive: 2 };
+    members.set_health(NodeId(1), Some(busy));
+    members.set_health(NodeId(2), Some(Health { queued: 0, .. busy }));
+    let size = SizeEstimate { size: 3, min: 2, max: 4, reports: 3 };
+
+    let none = Histogram::new();
+
+    assert_eq!(status_line(&members.peers(), &size, &none, &config),
+               "STATUS peers=3 alive=1 suspect=1 dead=1 rtt_p50_us=- \
+                rtt_p90_us=- rtt_p99_us=- overloaded=1 mesh_size=3 \
+                mesh_size_min=2 mesh_size_max=4 handling_p99_us=-");
+    config.json = true;
+    let alone = SizeEstimate { size: 1, min: 1, max: 1, reports: 1 };
+    assert_eq!(status_line(&[], &alone, &none, &config),
+               "{\"event\":\"status\",\"peers\":0,\"alive\":0,\"suspect\":0,\
+                \"dead\":0,\"rtt_p50_us\":null,\"rtt_p90_us\":null,\
+                \"rtt_p99_us\":null,\"overloaded\":0,\"mesh_size\":1,\
+                \"mesh_size_min\":1,\"mesh_size_max\":1,\
+                \"handling_p99_us\":null}");
+}
+
+#[test]
+fn status_line_gives_rtt_percentiles_over_all_peers() {
+    use members::Members;
+    use std::time::Instant;
+
+    let mut members = Members::new();
+    let now = Instant::now();
+    for n in 1..11 {
+        let addr = format!("127.0.0.1:{}", 9000 + n).parse().unwrap();
+        members.join(NodeId(n), addr, now);
+        // Nine quick peers, and one slow.
+        let rtt = if n < 10 { 1 } else { 100 };
+        members.set_rtt(NodeId(n), Duration::from_millis(rtt));
+    }
+    // Each percentile is the top of the bucket it falls in.
+    let size = SizeEstimate { size: 11, min: 11, max: 11, reports: 1 };
+    let mut handling = Histogram::new();
+    handling.record(Duration::from_millis(30));
+    assert_eq!(status_line(&members.peers(), &size, &handling,
+                           &Config::default()),
+               "STATUS peers=10 alive=10 suspect=0 dead=0 rtt_p50_us=1100 \
+                rtt_p90_us=1100 rtt_p99_us=102400 overloaded=0 \
+                mesh_size=11 mesh_size_min=11 mesh_size_max=11 \
+                handling_p99_us=32000");
+}
+
+#[test]
+fn check_target_refuses_non_unicast() {
+    let cases = [
+        ("127.0.0.1:9000", true),
+        ("10.1.2.3:9000", true),
+        ("[::1]:9000", true),
+        ("[2001:db8::1]:9000", true),
+        ("0.0.0.0:9000", false),
+        ("[::]:9000", false),
+        ("255.255.255.255:9000", false),
+        ("224.0.0.1:9000", false),
+        ("239.255.255.250:1900", false),
+        ("[ff02::1]:9000", false),
+        ("[ff0e::1]:9000", false),
+    ];
+    for &(addr, ok) in cases.iter() {
+        let addr: SocketAddr = addr.parse().unwrap();
+        assert_eq!(check_target(&addr).is_ok(), ok, "{}", addr);
+    }
+    let err = check_target(&"255.255.255.255:9000".parse().unwrap())
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(err.to_string(),
+               "255.255.255.255:9000 is the broadcast address, not a node");
+}
+
+#[test]
+fn join_async_completes_against_a_running_node() {
+    use join::JoinStatus;
+    use std::sync::mpsc;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    let (tx, rx) = mpsc::channel();
+    let seed = thread::spawn(move || {
+        let mut node = start(&Config::default()).unwrap();
+        let shutdown = node.shutdown_handle();
+        tx.send((node.local_addr().unwrap(), shutdown)).unwrap();
+        node.run();
+    });
+    let (addr, shutdown) = rx.recv().unwrap();
+
+    let mut node = start(&Config::default()).unwrap();
+    let ticket = node.join_async(&addr);
+    match node.wait_join(&ticket, Duration::from_secs(5)) {
+        JoinStatus::Joined(peer) => assert_eq!(peer.addr(), addr),
+        other => panic!("expected Joined, got {:?}", other),
+    }
+    shutdown.store(true, Ordering::SeqCst);
+    seed.join().unwrap();
+}
+
+#[test]
+fn join_async_fails_against_a_dead_port() {
+    use config::RetransmitPolicy;
+    use error::MeshError;
+    use join::JoinStatus;
+    use std::time::Duration;
+
+    let dead = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config = Config {
+        join_retransmit: RetransmitPolicy {
+            attempts: 2,
+            budget: Duration::from_secs(10),
+        },
+        .. Config::default()
+    };
+    let mut node = start(&config).unwrap();
+    let ticket = node.join_async(&dead);
+    assert_eq!(node.wait_join(&ticket, Duration::from_secs(5)),
+               JoinStatus::Failed(MeshError::Timeout));
+}
@@ -0,0 +1,127 @@
+use protocol::limits::OBSERVERS;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+// When to believe what peers say our address is. Every Ack and Pong
+// carries the source address the answering node saw on what it answered,
+// and once the last `quorum` peers to tell us all agree on an address
+// other than the one we advertise, that's taken to be our address as the
+// rest of the mesh sees it. With `adopt` set we advertise it from then on.
+// A quorum of 0 turns detection off.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObservedConfig {
+    pub quorum: usize,
+    pub adopt: bool,
+}
+
+impl Default for ObservedConfig {
+    fn default() -> ObservedConfig {
+        ObservedConfig { quorum: 3, adopt: false }
+    }
+}
+
+// The latest address each of our most recent observers saw us at. A peer
+// only ever has one say, however often it answers us. Peers behind a
+// hairpin NAT, or reaching us on different interfaces, will see different
+// addresses; until the last `quorum` agree nothing is detected, so a mix
+// of them can't make the address flap.
+pub struct Observations {
+    config: ObservedConfig,
+    // Observer and what it saw, least recently heard first.
+    samples: VecDeque<(SocketAddr, SocketAddr)>,
+    // What we last detected, so it's only reported once.
+    detected: Option<SocketAddr>,
+}
+
+impl Observations {
+    pub fn new(config: ObservedConfig) -> Observations {
+        Observations {
+            config: config,
+            samples: VecDeque::new(),
+            detected: None,
+        }
+    }
+
+    pub fn config(&self) -> &ObservedConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: ObservedConfig) {
+        self.config = config;
+    }
+
+    // `observer` saw us at `addr`. Returns the address a quorum now
+    // agrees on, if that's news: neither `advertised` nor what we last
+    // detected.
+    pub fn observe(&mut self, observer: &SocketAddr, addr: &SocketAddr,
+                   advertised: Option<SocketAddr>) -> Option<SocketAddr> {
+        self.samples.retain(|&(o, _)| o != *observer);
+        if self.samples.len() == OBSERVERS {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((*observer, *addr));
+
+        let quorum = self.config.quorum;
+        if quorum == 0 || self.samples.len() < quorum {
+            return None;
+        }
+        let agreed = self.samples.iter().rev().take(quorum)
+            .all(|&(_, a)| a == *addr);
+        if !agreed || Some(*addr) == advertised
+                || Some(*addr) == self.detected {
+            return None;
+        }
+        self.detected = Some(*addr);
+        Some(*addr)
+    }
+}
+
+#[cfg(test)]
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[cfg(test)]
+fn observer(n: u16) -> SocketAddr {
+    SocketAddr::new("10.0.0.1".parse().unwrap(), n)
+}
+
+#[test]
+fn quorum_of_peers_detects_external_address() {
+    let mut o = Observations::new(ObservedConfig { quorum: 3, adopt: false });
+    let local = Some(addr("192.168.1.2:4000"));
+    let external = addr("203.0.113.7:61000");
+
+    assert_eq!(o.observe(&observer(1), &external, local), None);
+    // The same peer again is still only one say.
+    assert_eq!(o.observe(&observer(1), &external, local), None);
+    assert_eq!(o.observe(&observer(2), &external, local), None);
+    assert_eq!(o.observe(&observer(3), &external, local), Some(external));
+    // Only reported once.
+    assert_eq!(o.observe(&observer(4), &external, local), None);
+}
+
+#[test]
+fn agreeing_with_advertised_detects_nothing() {
+    let mut o = Observations::new(ObservedConfig { quorum: 2, adopt: false });
+    let local = addr("192.168.1.2:4000");
+    for n in 0..4 {
+        assert_eq!(o.observe(&observer(n), &local, Some(local)), None);
+    }
+}
+
+#[test]
+fn conflicting_observations_do_not_flap() {
+    let mut o = Observations::new(ObservedConfig { quorum: 3, adopt: false });
+    let local = Some(addr("192.168.1.2:4000"));
+    let a = addr("203.0.113.7:61000");
+    let b = addr("198.51.100.9:62000");
+    for n in 0..12 {
+        let seen = if n % 2 == 0 { a } else { b };
+        assert_eq!(o.observe(&observer(n), &seen, local), None);
+    }
+
+    // Once they settle on one, it's detected.
+    assert_eq!(o.observe(&observer(12), &b, local), None);
+    assert_eq!(o.observe(&observer(13), &b, local), Some(b));
+}
@@ -0,0 +1,279 @@
+use protocol::limits::{ORDERED_STREAMS, ORDERED_WINDOW, SACK_BITS};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// What came of an ordered message arriving.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arrival {
+    // It was next, and these are ready to hand over in order: it, and any
+    // held back waiting for it.
+    Deliver(Vec<Vec<u8>>),
+    // It's early, and is held until those before it arrive.
+    Held,
+    // We've had it before.
+    Duplicate,
+    // It's too early to hold: more than ORDERED_WINDOW past the next.
+    Refused,
+}
+
+// A gap in a stream given up on; see `Streams::skip_overdue`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Skipped {
+    pub from: SocketAddr,
+    // How many messages were given up on.
+    pub missing: u32,
+    // What could then be handed over, in order.
+    pub ready: Vec<Vec<u8>>,
+}
+
+struct Inbound {
+    next: u32,
+    held: BTreeMap<u32, Vec<u8>>,
+    // When we started waiting on `next` with later messages held.
+    waiting_since: Option<Instant>,
+    heard: Instant,
+}
+
+impl Inbound {
+    // Everything held from `next` on with no gaps, taken out.
+    fn ready(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+        while let Some(data) = self.held.remove(&self.next) {
+            ready.push(data);
+            self.next = self.next.wrapping_add(1);
+        }
+        self.waiting_since = if self.held.is_empty() { None } else { Some(now) };
+        ready
+    }
+}
+
+// Each peer's stream of ordered messages, both ways: the next stream
+// number for what we send it, and for what it sends us, which messages
+// have come early and wait for those before them. Streams start at zero
+// and are forgotten when a peer restarts, as a Join from a new node at
+// the same address shows.
+pub struct Streams {
+    outbound: HashMap<SocketAddr, u32>,
+    inbound: HashMap<SocketAddr, Inbound>,
+}
+
+impl Streams {
+    pub fn new() -> Streams {
+        Streams { outbound: HashMap::new(), inbound: HashMap::new() }
+    }
+
+    // The stream number for the next message to `to`, which is taken
+    // once `sent` says it has been.
+    pub fn next_seq(&self, to: &SocketAddr) -> u32 {
+        self.outbound.get(to).cloned().unwrap_or(0)
+    }
+
+    pub fn sent(&mut self, to: &SocketAddr) {
+        let next = self.outbound.entry(*to).or_insert(0);
+        *next = next.wrapping_add(1);
+    }
+
+    // Take in message `seq` of `from`'s stream.
+    pub fn receive(&mut self, from: &SocketAddr, seq: u32, data: Vec<u8>,
+                   now: Instant) -> Arrival {
+        self.make_room(from);
+        let s = self.inbound.entry(*from).or_insert(Inbound {
+            next: 0,
+            held: BTreeMap::new(),
+            waiting_since: None,
+            heard: now,
+        });
+        s.heard = now;
+        // How far ahead of the next it is, allowing for wrapping.
+        let ahead = seq.wrapping_sub(s.next);
+        if ahead > u32::max_value() / 2 || s.held.contains_key(&seq) {
+            return Arrival::Duplicate;
+        }
+        if ahead >= ORDERED_WINDOW {
+            return Arrival::Refused;
+        }
+        s.held.insert(seq, data);
+        if ahead > 0 {
+            if s.waiting_since.is_none() {
+                s.waiting_since = Some(now);
+            }
+            return Arrival::Held;
+        }
+        Arrival::Deliver(s.ready(now))
+    }
+
+    // Give up on the next message of every stream that has had later ones
+    // waiting on it for `deadline`, by which time its sender will have
+    // given up retransmitting it too, and hand over what follows.
+    pub fn skip_overdue(&mut self, now: Instant, deadline: Duration)
+            -> Vec<Skipped> {
+        let mut skipped = Vec::new();
+        for (from, s) in self.inbound.iter_mut() {
+            match s.waiting_since {
+                Some(since) if now - since >= deadline => (),
+                _ => continue,
+            }
+            let first = *s.held.keys().next().unwrap();
+            let missing = first.wrapping_sub(s.next);
+            s.next = first;
+            skipped.push(Skipped {
+                from: *from,
+                missing: missing,
+                ready: s.ready(now),
+            });
+        }
+        skipped
+    }
+
+    // What we have of `from`'s stream, as an AckSack says it: the next
+    // we're waiting on, and a bit for each of the SACK_BITS after it, set
+    // if it's held.
+    pub fn sack(&self, from: &SocketAddr) -> (u32, u64) {
+        let s = match self.inbound.get(from) {
+            Some(s) => s,
+            None => return (0, 0),
+        };
+        let mut bitmap = 0;
+        for &seq in s.held.keys() {
+            let bit = seq.wrapping_sub(s.next).wrapping_sub(1);
+            if (bit as usize) < SACK_BITS {
+                bitmap |= 1 << bit;
+            }
+        }
+        (s.next, bitmap)
+    }
+
+    // How many messages from `from` are held waiting for earlier ones.
+    pub fn held(&self, from: &SocketAddr) -> usize {
+        self.inbound.get(from).map_or(0, |s| s.held.len())
+    }
+
+    // Start both of `addr`'s streams afresh, dropping anything held.
+    pub fn forget(&mut self, addr: &SocketAddr) {
+        self.outbound.remove(addr);
+        self.inbound.remove(addr);
+    }
+
+    fn make_room(&mut self, from: &SocketAddr) {
+        if self.inbound.contains_key(from)
+                || self.inbound.len() < ORDERED_STREAMS {
+            return;
+        }
+        let oldest = self.inbound.iter().min_by_key(|&(_, s)| s.heard)
+            .map(|(addr, _)| *addr);
+        if let Some(oldest) = oldest {
+            self.inbound.remove(&oldest);
+        }
+    }
+}
+
+// Whether an AckSack of `next` and `bitmap` (see Streams::sack) says
+// message `seq` of the stream has arrived.
+pub fn sacked(seq: u32, next: u32, bitmap: u64) -> bool {
+    let ahead = seq.wrapping_sub(next);
+    if ahead > u32::max_value() / 2 {
+        return true;
+    }
+    ahead >= 1 && ahead as usize <= SACK_BITS
+        && bitmap & (1 << (ahead - 1)) != 0
+}
+
+#[cfg(test)]
+fn addr() -> SocketAddr {
+    "127.0.0.1:9000".parse().unwrap()
+}
+
+#[test]
+fn early_messages_wait_for_those_before_them() {
+    let mut streams = Streams::new();
+    let now = Instant::now();
+    assert_eq!(streams.receive(&addr(), 1, vec![1], now), Arrival::Held);
+    assert_eq!(streams.receive(&addr(), 2, vec![2], now), Arrival::Held);
+    assert_eq!(streams.receive(&addr(), 2, vec![2], now), Arrival::Duplicate);
+    assert_eq!(streams.held(&addr()), 2);
+    assert_eq!(streams.receive(&addr(), 0, vec![0], now),
+               Arrival::Deliver(vec![vec![0], vec![1], vec![2]]));
+    assert_eq!(streams.receive(&addr(), 1, vec![1], now), Arrival::Duplicate);
+    assert_eq!(streams.receive(&addr(), 3, vec![3], now),
+               Arrival::Deliver(vec![vec![3]]));
+
+    // Each peer's stream is its own, both ways.
+    let other = "127.0.0.1:9001".parse().unwrap();
+    assert_eq!(streams.receive(&other, 0, vec![0], now),
+               Arrival::Deliver(vec![vec![0]]));
+    assert_eq!(streams.next_seq(&addr()), 0);
+    streams.sent(&addr());
+    assert_eq!(streams.next_seq(&addr()), 1);
+    assert_eq!(streams.next_seq(&other), 0);
+    streams.forget(&addr());
+    assert_eq!(streams.next_seq(&addr()), 0);
+    assert_eq!(streams.receive(&addr(), 0, vec![0], now),
+               Arrival::Deliver(vec![vec![0]]));
+}
+
+#[test]
+fn the_window_bounds_what_is_held() {
+    let mut streams = Streams::new();
+    let now = Instant::now();
+    for seq in 1..ORDERED_WINDOW {
+        assert_eq!(streams.receive(&addr(), seq, vec![], now), Arrival::Held);
+    }
+    assert_eq!(streams.receive(&addr(), ORDERED_WINDOW, vec![], now),
+               Arrival::Refused);
+    assert_eq!(streams.held(&addr()), ORDERED_WINDOW as usize - 1);
+    match streams.receive(&addr(), 0, vec![], now) {
+        Arrival::Deliver(ready) =>
+            assert_eq!(ready.len(), ORDERED_WINDOW as usize),
+        other => panic!("expected Deliver, got {:?}", other),
+    }
+    // Refused, then resent once there's room.
+    assert_eq!(streams.receive(&addr(), ORDERED_WINDOW, vec![], now),
+               Arrival::Deliver(vec![vec![]]));
+}
+
+#[test]
+fn overdue_gaps_are_skipped() {
+    let mut streams = Streams::new();
+    let start = Instant::now();
+    let deadline = Duration::from_secs(5);
+    streams.receive(&addr(), 2, vec![2], start);
+    streams.receive(&addr(), 3, vec![3], start);
+    streams.receive(&addr(), 5, vec![5], start);
+    assert!(streams.skip_overdue(start + Duration::from_secs(4), deadline)
+            .is_empty());
+
+    let later = start + deadline;
+    assert_eq!(streams.skip_overdue(later, deadline), vec![Skipped {
+        from: addr(),
+        missing: 2,
+        ready: vec![vec![2], vec![3]],
+    }]);
+    // The next gap has its own deadline, from now.
+    assert!(streams.skip_overdue(later + Duration::from_secs(1), deadline)
+            .is_empty());
+    assert_eq!(streams.skip_overdue(later + deadline, deadline)[0].ready,
+               vec![vec![5]]);
+    // What was given up on is too late if it comes after all.
+    assert_eq!(streams.receive(&addr(), 0, vec![0], later), Arrival::Duplicate);
+}
+
+#[test]
+fn sacks_say_what_has_arrived() {
+    let mut streams = Streams::new();
+    let now = Instant::now();
+    assert_eq!(streams.sack(&addr()), (0, 0));
+    for &seq in &[0, 1, 3, 4, 6] {
+        streams.receive(&addr(), seq, vec![], now);
+    }
+    let (next, bitmap) = streams.sack(&addr());
+    assert_eq!((next, bitmap), (2, 0b1011));
+    let arrived: Vec<u32> = (0..10).filter(|&seq| sacked(seq, next, bitmap))
+        .collect();
+    assert_eq!(arrived, vec![0, 1, 3, 4, 6]);
+
+    // Across the wrap.
+    assert!(sacked(u32::max_value(), 1, 0));
+    assert!(sacked(1, u32::max_value(), 0b10));
+    assert!(!sacked(0, u32::max_value(), 0b10));
+}
@@ -0,0 +1,262 @@
+#[cfg(test)] use members::{MetaVersion, NodeId};
+#[cfg(test)] use message::{PingBody, RejectCode, Seq, WireAddr,
+                           PROTOCOL_VERSION};
+use message::{Message, AckedMessage};
+use protocol::limits::{BAND_CAPACITY, BULK_STARVATION_LIMIT};
+#[cfg(test)] use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+// Outbound traffic is split into priority bands so that protocol control
+// messages never wait behind bulk payloads when the socket backs up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Band {
+    // Acks, joins and anything else the protocol's correctness hinges on.
+    Control = 0,
+    // Liveness probing.
+    Probe = 1,
+    // User payloads: broadcasts, transfers and so on.
+    Bulk = 2,
+}
+
+pub const BANDS: [Band; 3] = [Band::Control, Band::Probe, Band::Bulk];
+
+impl Band {
+    pub fn of(msg: &Message) -> Band {
+        match *msg {
+            Message::Ack(..) | Message::AckMulti(..)
+                | Message::AckSack(..) => Band::Control,
+            Message::VersionMismatch(_) | Message::SyncRequest(_)
+                | Message::Rejected(_) | Message::JoinForward(..)
+                | Message::JoinRetryAfter(_)
+                | Message::JoinRejected(_) => Band::Control,
+            Message::Acked(_, AckedMessage::Join(..))
+                | Message::Acked(_, AckedMessage::Suspected(..))
+                | Message::Acked(_, AckedMessage::Tags(..)) => Band::Control,
+            Message::Ping(..) | Message::Pong(..)
+                | Message::Keepalive => Band::Probe,
+            Message::Acked(_, AckedMessage::Data(_))
+                | Message::Acked(_, AckedMessage::Ordered(..)) => Band::Bulk,
+        }
+    }
+}
+
+pub struct OutboundQueue {
+    bands: [VecDeque<(Vec<u8>, SocketAddr)>; 3],
+    dropped: [u64; 3],
+    since_bulk: u32,
+}
+
+impl OutboundQueue {
+    pub fn new() -> OutboundQueue {
+        OutboundQueue {
+            bands: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            dropped: [0; 3],
+            since_bulk: 0,
+        }
+    }
+
+    // Queue a message, choosing its band by type.
+    pub fn push(&mut self, msg: &Message, target: &SocketAddr) {
+        self.push_bytes(Band::of(msg), msg.encode(), target);
+    }
+
+    // Queue an already-encoded datagram in a particular band. Returns
+    // false (and counts a drop) if that band is full.
+    pub fn push_bytes(&mut self, band: Band, bytes: Vec<u8>,
+                      target: &SocketAddr) -> bool {
+        let queue = &mut self.bands[band as usize];
+        if queue.len() >= BAND_CAPACITY {
+            self.dropped[band as usize] += 1;
+            return false;
+        }
+        queue.push_back((bytes, *target));
+        true
+    }
+
+    // Take the next datagram to send: the head of the highest-priority
+    // non-empty band, unless bulk traffic has waited too long.
+    pub fn pop(&mut self) -> Option<(Band, Vec<u8>, SocketAddr)> {
+        let bulk_waiting = !self.bands[Band::Bulk as usize].is_empty();
+        let band = if bulk_waiting && self.since_bulk >= BULK_STARVATION_LIMIT {
+            Band::Bulk
+        } else {
+            match BANDS.iter().find(|&&b| !self.bands[b as usize].is_empty()) {
+                Some(&b) => b,
+                None => return None,
+            }
+        };
+
+        if band == Band::Bulk {
+            self.since_bulk = 0;
+        } else if bulk_waiting {
+            self.since_bulk += 1;
+        }
+
+        self.bands[band as usize].pop_front()
+            .map(|(bytes, target)| (band, bytes, target))
+    }
+
+    // Take the next control or probe datagram, leaving bulk traffic
+    // where it is, for when bulk can't go but the rest still may; see
+    // bandwidth::Bandwidth.
+    pub fn pop_urgent(&mut self) -> Option<(Band, Vec<u8>, SocketAddr)> {
+        let band = match BANDS.iter()
+                .find(|&&b| b != Band::Bulk
+                      && !self.bands[b as usize].is_empty()) {
+            Some(&b) => b,
+            None => return None,
+        };
+        self.bands[band as usize].pop_front()
+            .map(|(bytes, target)| (band, bytes, target))
+    }
+
+    // Put back a datagram that `pop` returned but which couldn't be sent,
+    // so it goes out first next time.
+    pub fn unpop(&mut self, band: Band, bytes: Vec<u8>, target: SocketAddr) {
+        self.bands[band as usize].push_front((bytes, target));
+    }
+
+    // Drop everything queued for `target` in `band`, returning how many
+    // datagrams that was. These count as dropped.
+    pub fn drop_to(&mut self, band: Band, target: &SocketAddr) -> usize {
+        let queue = &mut self.bands[band as usize];
+        let before = queue.len();
+        queue.retain(|&(_, t)| t != *target);
+        let dropped = before - queue.len();
+        self.dropped[band as usize] += dropped as u64;
+        dropped
+    }
+
+    pub fn depth(&self, band: Band) -> usize {
+        self.bands[band as usize].len()
+    }
+
+    pub fn dropped(&self, band: Band) -> u64 {
+        self.dropped[band as usize]
+    }
+}
+
+#[cfg(test)]
+fn peer() -> SocketAddr {
+    "127.0.0.1:9000".parse().unwrap()
+}
+
+#[cfg(test)]
+fn seq(n: u32) -> Seq {
+    Seq::new(1, n)
+}
+
+#[test]
+fn messages_are_banded_by_type() {
+    assert_eq!(Band::of(&Message::Ack(seq(1), WireAddr(peer()), None)),
+               Band::Control);
+    let join = AckedMessage::Join(NodeId(1), PROTOCOL_VERSION);
+    assert_eq!(Band::of(&Message::Acked(seq(1), join)), Band::Control);
+    let suspected = AckedMessage::Suspected(NodeId(1), 0);
+    assert_eq!(Band::of(&Message::Acked(seq(1), suspected)), Band::Control);
+    let tags = AckedMessage::Tags(NodeId(1), MetaVersion::new(0, 1),
+                                  BTreeMap::new());
+    assert_eq!(Band::of(&Message::Acked(seq(1), tags)), Band::Control);
+    assert_eq!(Band::of(&Message::VersionMismatch(PROTOCOL_VERSION)),
+               Band::Control);
+    assert_eq!(Band::of(&Message::SyncRequest(1)), Band::Control);
+    assert_eq!(Band::of(&Message::Rejected(RejectCode::Blocked)),
+               Band::Control);
+    let forward = Message::JoinForward(WireAddr(peer()), seq(1),
+                                       NodeId(2), PROTOCOL_VERSION);
+    assert_eq!(Band::of(&forward), Band::Control);
+    let body = PingBody { nonce: 0, sent_at_micros: 0, pad: vec![] };
+    assert_eq!(Band::of(&Message::Ping(body.clone())), Band::Probe);
+    assert_eq!(Band::of(&Message::Pong(body, WireAddr(peer()), None)),
+               Band::Probe);
+    assert_eq!(Band::of(&Message::AckMulti(vec![seq(1)])), Band::Control);
+    assert_eq!(Band::of(&Message::AckSack(1, 1, 2)), Band::Control);
+    let data = AckedMessage::Data(vec![]);
+    assert_eq!(Band::of(&Message::Acked(seq(1), data)),
+               Band::Bulk);
+    let ordered = AckedMessage::Ordered(0, vec![]);
+    assert_eq!(Band::of(&Message::Acked(seq(1), ordered)),
+               Band::Bulk);
+}
+
+#[test]
+fn ack_jumps_a_full_bulk_band() {
+    let mut q = OutboundQueue::new();
+    for _ in 0..BAND_CAPACITY {
+        assert!(q.push_bytes(Band::Bulk, vec![0], &peer()));
+    }
+    q.push(&Message::Ack(seq(7), WireAddr(peer()), None), &peer());
+
+    let (band, bytes, _) = q.pop().unwrap();
+    assert_eq!(band, Band::Control);
+    assert_eq!(bytes, Message::Ack(seq(7), WireAddr(peer()), None).encode());
+}
+
+#[test]
+fn full_band_drops_and_counts() {
+    let mut q = OutboundQueue::new();
+    for _ in 0..BAND_CAPACITY {
+        q.push_bytes(Band::Bulk, vec![0], &peer());
+    }
+    assert!(!q.push_bytes(Band::Bulk, vec![1], &peer()));
+    assert_eq!(q.depth(Band::Bulk), BAND_CAPACITY);
+    assert_eq!(q.dropped(Band::Bulk), 1);
+    assert_eq!(q.dropped(Band::Control), 0);
+}
+
+#[test]
+fn bulk_is_not_starved() {
+    let mut q = OutboundQueue::new();
+    q.push_bytes(Band::Bulk, vec![2], &peer());
+    for _ in 0..100 {
+        q.push_bytes(Band::Control, vec![0], &peer());
+    }
+
+    let bands: Vec<Band> = (0..BULK_STARVATION_LIMIT + 1)
+        .map(|_| q.pop().unwrap().0).collect();
+    assert_eq!(bands[BULK_STARVATION_LIMIT as usize], Band::Bulk);
+    assert!(bands[..BULK_STARVATION_LIMIT as usize].iter()
+            .all(|&b| b == Band::Control));
+}
+
+#[test]
+fn drop_to_only_drops_that_target_and_band() {
+    let mut q = OutboundQueue::new();
+    let other = "127.0.0.1:9001".parse().unwrap();
+    q.push_bytes(Band::Bulk, vec![1], &peer());
+    q.push_bytes(Band::Bulk, vec![2], &other);
+    q.push_bytes(Band::Bulk, vec![3], &peer());
+    q.push_bytes(Band::Control, vec![4], &peer());
+
+    assert_eq!(q.drop_to(Band::Bulk, &peer()), 2);
+    assert_eq!(q.dropped(Band::Bulk), 2);
+    assert_eq!(q.pop(), Some((Band::Control, vec![4], peer())));
+    assert_eq!(q.pop(), Some((Band::Bulk, vec![2], other)));
+    assert_eq!(q.pop(), None);
+}
+
+#[test]
+fn unpop_goes_out_first() {
+    let mut q = OutboundQueue::new();
+    q.push(&Message::Ack(seq(1), WireAddr(peer()), None), &peer());
+    q.push(&Message::Ack(seq(2), WireAddr(peer()), None), &peer());
+
+    let (band, bytes, target) = q.pop().unwrap();
+    q.unpop(band, bytes, target);
+    assert_eq!(q.pop().unwrap().1,
+               Message::Ack(seq(1), WireAddr(peer()), None).encode());
+}
+
+#[test]
+fn pop_urgent_leaves_bulk_alone() {
+    let mut q = OutboundQueue::new();
+    q.push_bytes(Band::Bulk, vec![1], &peer());
+    q.push_bytes(Band::Probe, vec![2], &peer());
+    q.push_bytes(Band::Control, vec![3], &peer());
+
+    assert_eq!(q.pop_urgent(), Some((Band::Control, vec![3], peer())));
+    assert_eq!(q.pop_urgent(), Some((Band::Probe, vec![2], peer())));
+    assert_eq!(q.pop_urgent(), None);
+    assert_eq!(q.depth(Band::Bulk), 1);
+}
@@ -0,0 +1,239 @@
+use clock::Nanos;
+use members::NodeId;
+use rand::Rng;
+use std::collections::HashSet;
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+// How often to probe a peer. Each node probes one peer per interval, so
+// scaling the interval with the number of members keeps the mesh-wide
+// probe traffic roughly constant: a 3-node mesh probes several times a
+// second, a 500-node one only every few seconds. While membership is
+// churning, the interval tightens to notice further changes sooner.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeConfig {
+    // Interval per known member, before clamping.
+    pub per_member: Duration,
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    // Joins and state changes within this long count as churn.
+    pub churn_window: Duration,
+    // Send each probe from a socket connected to its target, so that if
+    // nothing is listening there we may hear so at once; see `interpret`.
+    pub fast_fail: bool,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> ProbeConfig {
+        ProbeConfig {
+            per_member: Duration::from_millis(100),
+            min_interval: Duration::from_millis(200),
+            max_interval: Duration::from_secs(5),
+            churn_window: Duration::from_secs(10),
+            fast_fail: false,
+        }
+    }
+}
+
+// The probe interval for a mesh of `members` other nodes that has seen
+// `churn` membership events in the last churn window: `per_member` times
+// the number of members, divided by one more than the churn, and kept
+// within the configured bounds.
+pub fn probe_interval(config: &ProbeConfig, members: usize, churn: usize)
+        -> Duration {
+    let base = Nanos::from(config.per_member) * members as u64;
+    let interval = Duration::from(base / (churn as u64 + 1));
+    if interval < config.min_interval {
+        config.min_interval
+    } else if interval > config.max_interval {
+        config.max_interval
+    } else {
+        interval
+    }
+}
+
+// Who to probe next, SWIM style: every member once per cycle, in an
+// order shuffled afresh for each cycle, so that no peer can go unprobed
+// for long by bad luck. Members that join part way through a cycle take
+// their turn somewhere in what's left of it; those that leave are
+// skipped over without disturbing anyone else's turn.
+pub struct ProbeOrder {
+    // Still to be probed this cycle, next last.
+    remaining: Vec<NodeId>,
+    // Probed already this cycle.
+    probed: HashSet<NodeId>,
+}
+
+impl ProbeOrder {
+    pub fn new() -> ProbeOrder {
+        ProbeOrder { remaining: Vec::new(), probed: HashSet::new() }
+    }
+
+    // The next of `members` to probe, where `members` is everyone who
+    // should be probed at the moment, in any order.
+    pub fn next<R: Rng>(&mut self, rng: &mut R, members: &[NodeId])
+            -> Option<NodeId> {
+        let current: HashSet<NodeId> = members.iter().cloned().collect();
+        self.remaining.retain(|id| current.contains(id));
+        for &id in members {
+            if !self.probed.contains(&id) && !self.remaining.contains(&id) {
+                let at = rng.gen_range(0, self.remaining.len() + 1);
+                self.remaining.insert(at, id);
+            }
+        }
+
+        if self.remaining.is_empty() {
+            self.probed.clear();
+            self.remaining = members.to_vec();
+            rng.shuffle(&mut self.remaining);
+        }
+        let next = self.remaining.pop();
+        if let Some(id) = next {
+            self.probed.insert(id);
+        }
+        next
+    }
+}
+
+// What an error from a connected probe socket tells us about its peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feedback {
+    // An ICMP port unreachable came back: the host is up but nothing is
+    // listening, most likely because the process died.
+    Refused,
+    // Nothing we can read anything into. Most errors, and every OS that
+    // doesn't report ICMP errors on UDP sockets, end up here.
+    Unknown,
+}
+
+// Linux and the BSDs report a port unreachable as ECONNREFUSED; Windows
+// reports it as WSAECONNRESET.
+pub fn interpret(e: &io::Error) -> Feedback {
+    match e.kind() {
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset =>
+            Feedback::Refused,
+        _ => Feedback::Unknown,
+    }
+}
+
+#[test]
+fn probe_interval_table() {
+    let config = ProbeConfig::default();
+    let ms = Duration::from_millis;
+    let table = vec![
+        // (members, churn, interval)
+        (0, 0, ms(200)),
+        (1, 0, ms(200)),
+        (3, 0, ms(300)),
+        (10, 0, ms(1000)),
+        (50, 0, ms(5000)),
+        (500, 0, ms(5000)),
+        (10, 1, ms(500)),
+        (10, 4, ms(200)),
+        (10, 100, ms(200)),
+        (500, 9, ms(5000)),
+        (500, 99, ms(500)),
+    ];
+    for (members, churn, interval) in table {
+        assert_eq!(probe_interval(&config, members, churn), interval,
+                   "members={} churn={}", members, churn);
+    }
+}
+
+#[test]
+fn interpret_errors() {
+    let table = vec![
+        (ErrorKind::ConnectionRefused, Feedback::Refused),
+        (ErrorKind::ConnectionReset, Feedback::Refused),
+        (ErrorKind::WouldBlock, Feedback::Unknown),
+        (ErrorKind::TimedOut, Feedback::Unknown),
+        (ErrorKind::PermissionDenied, Feedback::Unknown),
+        (ErrorKind::Other, Feedback::Unknown),
+    ];
+    for (kind, feedback) in table {
+        assert_eq!(interpret(&io::Error::new(kind, "test")), feedback,
+                   "{:?}", kind);
+    }
+    if cfg!(target_os = "linux") {
+        // ECONNREFUSED, as a connected socket really reports it.
+        assert_eq!(interpret(&io::Error::from_raw_os_error(111)),
+                   Feedback::Refused);
+    }
+}
+
+#[cfg(test)]
+fn ids(range: ::std::ops::Range<u64>) -> Vec<NodeId> {
+    range.map(NodeId).collect()
+}
+
+// Take `n` probes from `order`, checking none is repeated.
+#[cfg(test)]
+fn take_probes<R: Rng>(order: &mut ProbeOrder, rng: &mut R,
+                       members: &[NodeId], n: usize) -> HashSet<NodeId> {
+    let mut seen = HashSet::new();
+    for _ in 0..n {
+        let id = order.next(rng, members).unwrap();
+        assert!(members.contains(&id), "{} isn't a member", id);
+        assert!(seen.insert(id), "{} probed twice in a cycle", id);
+    }
+    seen
+}
+
+#[cfg(test)]
+fn seeded_rng() -> ::rand::XorShiftRng {
+    ::rand::SeedableRng::from_seed([1, 2, 3, 4])
+}
+
+#[test]
+fn probe_order_covers_everyone_each_cycle() {
+    let mut rng = seeded_rng();
+    let mut order = ProbeOrder::new();
+    let members = ids(0..10);
+    for _ in 0..3 {
+        let seen = take_probes(&mut order, &mut rng, &members, 10);
+        assert_eq!(seen, members.iter().cloned().collect());
+    }
+    assert!(order.next(&mut rng, &[]).is_none());
+}
+
+#[test]
+fn probe_order_fits_in_joiners_mid_cycle() {
+    let mut rng = seeded_rng();
+    let mut order = ProbeOrder::new();
+    let mut members = ids(0..5);
+    let mut seen = take_probes(&mut order, &mut rng, &members, 2);
+
+    members.extend(ids(5..8));
+    seen.extend(take_probes(&mut order, &mut rng, &members, 6));
+    assert_eq!(seen.len(), 8);
+    assert!(order.remaining.is_empty());
+
+    // And the next cycle has everyone too.
+    let seen = take_probes(&mut order, &mut rng, &members, 8);
+    assert_eq!(seen, members.iter().cloned().collect());
+}
+
+#[test]
+fn probe_order_skips_leavers_mid_cycle() {
+    let mut rng = seeded_rng();
+    let mut order = ProbeOrder::new();
+    let mut members = ids(0..8);
+    let first = take_probes(&mut order, &mut rng, &members, 3);
+
+    // One that's been probed and one that hasn't leave.
+    let probed = *first.iter().next().unwrap();
+    let unprobed = *members.iter().find(|id| !first.contains(id)).unwrap();
+    members.retain(|id| *id != probed && *id != unprobed);
+    let mut rest = take_probes(&mut order, &mut rng, &members, 3);
+    assert!(rest.is_disjoint(&first));
+    assert!(!rest.contains(&unprobed));
+
+    // One that left after its turn and comes back before the cycle is
+    // over doesn't get another.
+    members.push(probed);
+    rest.extend(take_probes(&mut order, &mut rng, &members, 1));
+    assert!(!rest.contains(&probed));
+    assert!(order.remaining.is_empty());
+    let seen: HashSet<NodeId> = first.union(&rest).cloned().collect();
+    assert_eq!(seen, members.iter().cloned().collect());
+}
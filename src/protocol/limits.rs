@@ -0,0 +1,387 @@
+#[cfg(test)] use bincode;
+#[cfg(test)] use delta::{self, Digest, Entry};
+#[cfg(test)] use members::{MetaVersion, NodeId};
+#[cfg(test)] use message::{AckedMessage, Health, Message, PingBody,
+                           RejectCode, Responder, Seq, WireAddr,
+                           PROTOCOL_VERSION};
+#[cfg(test)] use ordered;
+#[cfg(test)] use rustc_serialize::Encodable;
+#[cfg(test)] use std::collections::BTreeMap;
+#[cfg(test)] use std::mem;
+use std::time::Duration;
+
+// Every size, count and timing the protocol is built around, in one
+// place, each with why it is what it is. Code names these rather than
+// spelling out their values, and the tests below check that the ones
+// that depend on each other still do: that the largest message of each
+// kind fits what it has to, and that timings come in the order the
+// dispatcher relies on. Operators' knobs are in `config` instead.
+
+// Sizes on the wire.
+
+// No message we send comes anywhere near this; anything bigger is
+// rejected without being looked at.
+pub const MAX_MESSAGE_SIZE: usize = 8192;
+
+// The most padding a Ping may carry: enough to take it up to a jumbo
+// frame's worth, short of MAX_MESSAGE_SIZE, for finding out what fits
+// (see mtu::MtuConfig), and no more.
+pub const MAX_PING_PAD: usize = 8000;
+
+// How many bytes of a frame aren't the message's contents: the version
+// and type bytes, then the checksum of a frame with any contents (see
+// message::frame_checksum). One without, a Keepalive, is just the two.
+pub const FRAME_OVERHEAD: usize = FRAME_HEADER_LEN + CHECKSUM_LEN;
+pub const FRAME_HEADER_LEN: usize = 2;
+pub const CHECKSUM_LEN: usize = 4;
+
+// How many bytes bincode gives an enum's variant, which Message::encode
+// trims to the type byte, making way for the version.
+pub const VARIANT_TAG_LEN: usize = 4;
+
+// The largest possible UDP payload. A datagram that fills the whole
+// buffer may have been truncated, so anything at or near this size is
+// suspect; see Dispatcher::poll.
+pub const RECV_BUFFER_SIZE: usize = 65535;
+
+// The smallest datagram every IPv4 path must carry whole (576 bytes less
+// the IP and UDP headers); we never look for anything below it, and
+// everything but Pings padded to probe the path, data and ordered
+// messages fits in it.
+pub const MIN_MTU: usize = 548;
+
+// What's assumed to get through to a peer until probing says otherwise:
+// a typical Ethernet path with room to spare for tunnels.
+pub const DEFAULT_MTU: usize = 1400;
+
+// Searching for a path's MTU stops once the largest size known to get
+// through and the smallest known not to are this close.
+pub const MTU_PRECISION: usize = 64;
+
+// Acks for the same peer are held back ACK_DELAY, or until there are
+// this many of them, and sent together as one AckMulti.
+pub const ACK_BATCH: usize = 16;
+
+// How many stream numbers past the one it starts from an AckSack's
+// bitmap covers: one per bit of a u64.
+pub const SACK_BITS: usize = 64;
+
+// How far past the next message we're waiting on a sender's stream may
+// run. Anything further is refused, unacked, to be resent once the gap
+// has filled. Everything held fits in an AckSack's bitmap.
+pub const ORDERED_WINDOW: u32 = 64;
+
+// The most a node's tags may take, encoded (see Dispatcher::set_tag), so
+// that the Tags message carrying them, and a sync page of its entry alone
+// (see delta::Page), each fit in MIN_MTU.
+pub const MAX_TAGS_SIZE: usize = 384;
+
+// The most bytes of reason a JoinRejected may give. A longer one is cut
+// short by its sender (see Dispatcher::resolve_join), and refused by its
+// receiver, so that it fits MIN_MTU.
+pub const MAX_REJECT_REASON: usize = 256;
+
+// How many buckets a delta::Digest sorts entries into, by the top bits of
+// their ids. A Digest of them all fits in MIN_MTU.
+pub const DIGEST_BUCKETS: usize = 64;
+
+// Timings.
+
+// How long recv_from may block before we wake up for maintenance.
+pub const TICK: Duration = Duration::from_millis(50);
+
+// Acked messages are resent this often until acknowledged, or until
+// their kind's RetransmitPolicy runs out. An ack held back for
+// ACK_DELAY, and then up to a tick more, still gets there first.
+pub const RETRANSMIT: Duration = Duration::from_millis(500);
+
+// How long acks are held back to be batched; see ACK_BATCH. (Timers only
+// fire once per poll, so when idle a lone ack may wait up to TICK.)
+pub const ACK_DELAY: Duration = Duration::from_millis(5);
+
+// A peer whose Ack shows it on a new incarnation is sent a SyncRequest
+// this long after, from the timer rather than while handling the Ack.
+pub const SYNC_DELAY: Duration = Duration::from_millis(5);
+
+// A tick this many times longer than the longest interval we'd ever
+// wait on (the probe interval at its most relaxed, or a retransmission
+// budget) means we weren't running, as across a suspend, rather than that
+// time went by unnoticed. Acting on it would fire every timer at once and
+// suspect every peer, so instead we carry on as if one tick had passed.
+pub const CLOCK_JUMP_FACTOR: u32 = 4;
+
+// A newcomer's Join that comes while we're letting in as many as we
+// will at once (see config::Limits::join_handshakes) is told to try
+// again after between this long and twice this long, at random, so that
+// those turned away together don't all come back together. Told to wait
+// longer than JOIN_RETRY_AFTER_MAX, a joiner waits only that long.
+pub const JOIN_RETRY_AFTER: Duration = Duration::from_millis(500);
+pub const JOIN_RETRY_AFTER_MAX: Duration = Duration::from_secs(10);
+
+// A newcomer's Join held for the embedder to decide on (see
+// admission::Admissions) is given up on, unacked, this long after it
+// came, by when the joiner has most likely given up too; and one turned
+// away is told so again for as long should it try again.
+pub const ADMISSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Drops from one address more than this far apart end a flood; see
+// throttle::ThrottleConfig.
+pub const FLOOD_GAP: Duration = Duration::from_secs(1);
+
+// Each source may be told why we dropped what it sent (see
+// Config::explain_rejects) REJECT_BURST times at once, and once more every
+// REJECT_REFILL after that. A source address is easily forged, so this
+// is kept small: what someone can have us send a victim is a trickle.
+pub const REJECT_BURST: u32 = 3;
+pub const REJECT_REFILL: Duration = Duration::from_secs(10);
+
+// The Health in our Pongs counts what we've dropped over about this long,
+// so that a peer probing us at any pace sees a burst in the next Pong or
+// two, and not one that's long over.
+pub const HEALTH_WINDOW: Duration = Duration::from_secs(1);
+
+// A peer's word on how big the mesh is, which its Pongs' Health carries,
+// counts for this many probe cycles after it last said so: long enough
+// for a live peer, probed once a cycle, to keep it fresh through a lost
+// Pong or two. See size::SizeReports.
+pub const SIZE_REPORT_CYCLES: u32 = 3;
+
+// How much state others can have us keep. Past each of these, the oldest
+// or least recently heard from makes way.
+
+// How many of our most recent Pings we remember, so as to only accept
+// the Pongs that answer them.
+pub const OUTSTANDING_PINGS: usize = 256;
+
+// How many senders' ordered streams we keep. A forgotten one starts
+// afresh; see ordered::Streams::forget.
+pub const ORDERED_STREAMS: usize = 1024;
+
+// How many addresses the throttle keeps buckets for. Forgetting an
+// unblocked one at worst gives it a full bucket.
+pub const THROTTLE_SOURCES: usize = 1024;
+
+// How many sources' reject allowances we keep. Forgetting one at worst
+// gives it a fresh burst.
+pub const REJECT_SOURCES: usize = 256;
+
+// How many peers' observations of our address we keep at once.
+pub const OBSERVERS: usize = 16;
+
+// How many of the latest malformed datagrams we keep for debug dumps,
+// each cut short at MAX_MESSAGE_SIZE; see dump::DebugDump.
+pub const MALFORMED_KEPT: usize = 8;
+
+// How many inter-arrival times the phi-accrual detector remembers per
+// peer.
+pub const PHI_HISTORY: usize = 100;
+
+// How many datagrams each outbound band may hold before new ones are
+// dropped.
+pub const BAND_CAPACITY: usize = 1024;
+
+// However busy the higher bands are, a waiting bulk datagram is sent at
+// least once per this many others.
+pub const BULK_STARVATION_LIMIT: u32 = 16;
+
+#[cfg(test)]
+fn ascending<T: PartialOrd>(xs: &[T]) -> bool {
+    xs.windows(2).all(|w| w[0] < w[1])
+}
+
+#[cfg(test)]
+fn v6() -> WireAddr {
+    WireAddr("[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535".parse()
+             .unwrap())
+}
+
+#[cfg(test)]
+fn framed_len<T: Encodable>(t: &T) -> usize {
+    FRAME_OVERHEAD + bincode::encode(t, bincode::SizeLimit::Infinite)
+        .unwrap().len()
+}
+
+#[cfg(test)]
+fn max_ping(pad: usize) -> PingBody {
+    PingBody {
+        nonce: u64::max_value(),
+        sent_at_micros: u64::max_value(),
+        pad: vec![0xff; pad],
+    }
+}
+
+// One tag, as long as tags may be.
+#[cfg(test)]
+fn max_tags() -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+    tags.insert("k".to_string(), String::new());
+    let room = MAX_TAGS_SIZE - framed_len(&tags) + FRAME_OVERHEAD;
+    tags.insert("k".to_string(), (0..room).map(|_| 'x').collect());
+    tags
+}
+
+#[cfg(test)]
+fn max_health() -> Health {
+    let max = u32::max_value();
+    Health { queued: max, dropped: max, alive: max }
+}
+
+#[test]
+fn sizes_are_in_order() {
+    assert!(ascending(&[MTU_PRECISION, MIN_MTU, DEFAULT_MTU, MAX_PING_PAD,
+                        MAX_MESSAGE_SIZE, RECV_BUFFER_SIZE]));
+}
+
+#[test]
+fn the_largest_pings_fit_in_a_message() {
+    let ping = Message::Ping(max_ping(MAX_PING_PAD));
+    assert!(ping.encode().len() <= MAX_MESSAGE_SIZE);
+    // A Pong echoes no padding, but what it may carry is no less bounded.
+    let pong = Message::Pong(max_ping(MAX_PING_PAD), v6(),
+                             Some(max_health()));
+    assert!(pong.encode().len() <= MAX_MESSAGE_SIZE);
+}
+
+#[test]
+fn control_messages_fit_the_smallest_path() {
+    let responder = Responder {
+        id: NodeId(u64::max_value()),
+        incarnation: u64::max_value(),
+    };
+    let seq = Seq::new(u32::max_value(), u32::max_value());
+    let messages = vec![
+        Message::Acked(seq, AckedMessage::Join(NodeId(u64::max_value()),
+                                               PROTOCOL_VERSION)),
+        Message::Ack(seq, v6(), Some(responder)),
+        Message::Ping(max_ping(0)),
+        Message::Pong(max_ping(0), v6(), Some(max_health())),
+        Message::AckMulti(vec![seq; ACK_BATCH]),
+        Message::VersionMismatch(PROTOCOL_VERSION),
+        Message::SyncRequest(u64::max_value()),
+        Message::Rejected(RejectCode::ClusterMismatch),
+        Message::AckSack(u32::max_value(), u32::max_value(),
+                         u64::max_value()),
+        Message::Acked(seq, AckedMessage::Suspected(NodeId(u64::max_value()),
+                                                    u64::max_value())),
+        Message::JoinForward(v6(), seq, NodeId(u64::max_value()),
+                             PROTOCOL_VERSION),
+        Message::Keepalive,
+        Message::JoinRetryAfter(u32::max_value()),
+        Message::JoinRejected(String::from_utf8(vec![b'x'; MAX_REJECT_REASON])
+                              .unwrap()),
+        Message::Acked(seq, AckedMessage::Tags(
+            NodeId(u64::max_value()),
+            MetaVersion::new(u64::max_value(), u64::max_value()),
+            max_tags())),
+    ];
+    for m in messages {
+        let len = m.encode().len();
+        assert!(len <= MIN_MTU, "{:?} is {} bytes", m, len);
+    }
+}
+
+#[test]
+fn frames_add_the_overhead_to_the_contents() {
+    let messages = vec![
+        Message::SyncRequest(u64::max_value()),
+        Message::Pong(max_ping(0), v6(), Some(max_health())),
+        Message::Acked(Seq::new(1, 1), AckedMessage::Ordered(2, vec![3; 100])),
+    ];
+    for m in messages {
+        let contents = bincode::encode(&m, bincode::SizeLimit::Infinite)
+            .unwrap().len() - VARIANT_TAG_LEN;
+        assert_eq!(m.encode().len(), FRAME_OVERHEAD + contents);
+    }
+    assert_eq!(Message::Keepalive.encode().len(), FRAME_HEADER_LEN);
+}
+
+#[test]
+fn sync_fits_the_smallest_path() {
+    assert!(framed_len(&Digest(vec![u64::max_value(); DIGEST_BUCKETS]))
+            <= MIN_MTU);
+    let max = u64::max_value();
+    let entry = Entry {
+        id: NodeId(max),
+        addr: v6(),
+        incarnation: max,
+        meta: MetaVersion::new(max, max),
+        tags: BTreeMap::new(),
+    };
+    let pages = delta::paginate(max, &vec![entry.clone(); 100], MIN_MTU);
+    assert!(pages.len() > 1);
+    for page in &pages {
+        assert!(framed_len(page) <= MIN_MTU, "{} bytes", framed_len(page));
+    }
+
+    let tagged = Entry { tags: max_tags(), .. entry };
+    assert_eq!(framed_len(&tagged.tags), FRAME_OVERHEAD + MAX_TAGS_SIZE);
+    for page in &delta::paginate(max, &[tagged.clone(), tagged], MIN_MTU) {
+        assert!(framed_len(page) <= MIN_MTU, "{} bytes", framed_len(page));
+    }
+}
+
+#[test]
+fn every_held_ordered_message_has_a_sack_bit() {
+    assert_eq!(SACK_BITS, 8 * mem::size_of::<u64>());
+    assert!(ascending(&[ORDERED_WINDOW as usize - 1, SACK_BITS + 1]));
+    let next = u32::max_value() - 3;
+    for ahead in 1..ORDERED_WINDOW {
+        assert!(ordered::sacked(next.wrapping_add(ahead), next,
+                                u64::max_value()));
+    }
+}
+
+#[test]
+fn acks_beat_retransmissions() {
+    assert!(ascending(&[ACK_DELAY, TICK, ACK_DELAY + TICK,
+                        RETRANSMIT]));
+    assert!(ascending(&[SYNC_DELAY, TICK]));
+}
+
+// Whether `literal` appears in `code` as a number of its own.
+#[cfg(test)]
+fn mentions(code: &str, literal: &str) -> bool {
+    let word = |c: Option<char>| c.map_or(false, |c| c.is_alphanumeric()
+                                                     || c == '_');
+    code.match_indices(literal).any(|(i, _)| {
+        !word(code[..i].chars().next_back())
+            && !word(code[i + literal.len()..].chars().next())
+    })
+}
+
+// `source` up to its tests.
+#[cfg(test)]
+fn code_of(source: &str) -> &str {
+    let end = ["\n#[test]", "\n#[cfg(test)]\n"].iter()
+        .filter_map(|marker| source.find(marker))
+        .min().unwrap_or(source.len());
+    &source[..end]
+}
+
+#[test]
+fn code_names_the_limits_rather_than_spelling_them_out() {
+    let sources = [
+        ("compat.rs", include_str!("../compat.rs")),
+        ("delta.rs", include_str!("../delta.rs")),
+        ("detector.rs", include_str!("../detector.rs")),
+        ("dispatch.rs", include_str!("../dispatch.rs")),
+        ("message.rs", include_str!("../message.rs")),
+        ("mtu.rs", include_str!("../mtu.rs")),
+        ("observed.rs", include_str!("../observed.rs")),
+        ("ordered.rs", include_str!("../ordered.rs")),
+        ("outbound.rs", include_str!("../outbound.rs")),
+        ("rejects.rs", include_str!("../rejects.rs")),
+        ("throttle.rs", include_str!("../throttle.rs")),
+    ];
+    let values: Vec<String> = vec![
+        MAX_MESSAGE_SIZE, MAX_PING_PAD, RECV_BUFFER_SIZE, MIN_MTU,
+        DEFAULT_MTU,
+    ].iter().map(|v| v.to_string()).collect();
+    for &(name, source) in &sources {
+        let code = code_of(source);
+        for value in &values {
+            assert!(!mentions(code, value),
+                    "{} spells out {} rather than naming it", name, value);
+        }
+    }
+}
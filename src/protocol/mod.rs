@@ -0,0 +1,5 @@
+// What the protocol itself fixes, as opposed to what a node is configured
+// with. `limits` holds every size, count and timing the wire format and
+// the dispatcher's loop are built around, with why each is what it is,
+// and tests that they still add up.
+pub mod limits;
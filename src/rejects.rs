@@ -0,0 +1,83 @@
+use protocol::limits::{REJECT_BURST, REJECT_REFILL, REJECT_SOURCES};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+struct Allowance {
+    replies: u32,
+    // When `replies` was last topped up, or the last reply sent.
+    refilled: Instant,
+}
+
+// Who may be sent a Rejected now.
+pub struct RejectLimiter {
+    sources: HashMap<SocketAddr, Allowance>,
+}
+
+impl RejectLimiter {
+    pub fn new() -> RejectLimiter {
+        RejectLimiter { sources: HashMap::new() }
+    }
+
+    // Whether `src` may be sent a Rejected now, counting it if so.
+    pub fn allow(&mut self, src: &SocketAddr, now: Instant) -> bool {
+        if !self.sources.contains_key(src)
+                && self.sources.len() >= REJECT_SOURCES {
+            let oldest = self.sources.iter()
+                .min_by_key(|&(_, a)| a.refilled).map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                self.sources.remove(&oldest);
+            }
+        }
+        let a = self.sources.entry(*src)
+            .or_insert(Allowance { replies: REJECT_BURST, refilled: now });
+        let intervals = (now - a.refilled).as_secs() / REJECT_REFILL.as_secs();
+        if intervals > 0 {
+            a.replies = (a.replies as u64 + intervals)
+                .min(REJECT_BURST as u64) as u32;
+            a.refilled = now;
+        }
+        if a.replies == 0 {
+            return false;
+        }
+        a.replies -= 1;
+        true
+    }
+}
+
+#[cfg(test)]
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+}
+
+#[test]
+fn each_source_gets_a_burst_then_a_trickle() {
+    let mut limiter = RejectLimiter::new();
+    let start = Instant::now();
+    for _ in 0..REJECT_BURST {
+        assert!(limiter.allow(&addr(9000), start));
+    }
+    assert!(!limiter.allow(&addr(9000), start));
+    // Others have allowances of their own.
+    assert!(limiter.allow(&addr(9001), start));
+
+    let later = start + REJECT_REFILL;
+    assert!(limiter.allow(&addr(9000), later));
+    assert!(!limiter.allow(&addr(9000), later));
+    // A long quiet spell earns no more than a burst.
+    let much_later = later + REJECT_REFILL * 100;
+    for _ in 0..REJECT_BURST {
+        assert!(limiter.allow(&addr(9000), much_later));
+    }
+    assert!(!limiter.allow(&addr(9000), much_later));
+}
+
+#[test]
+fn sources_are_capped() {
+    let mut limiter = RejectLimiter::new();
+    let now = Instant::now();
+    for port in 0..REJECT_SOURCES as u16 * 2 {
+        limiter.allow(&addr(port), now);
+    }
+    assert_eq!(limiter.sources.len(), REJECT_SOURCES);
+}
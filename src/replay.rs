@@ -0,0 +1,147 @@
+use clock::ManualClock;
+use config::Config;
+use dispatch::Dispatcher;
+use dump;
+use members::Peer;
+use message::parse_datagram;
+use protocol::limits::TICK;
+use std::cmp;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+use trace::{Direction, Trace};
+use transport::SimTransport;
+
+// A node fed a packet dump instead of a socket.
+type Replaying = Dispatcher<SimTransport, ManualClock>;
+
+// Play the packet dump at `path` (see trace) back through a fresh node
+// started with `config`, for `mesh replay`, and return the peers it ends
+// up with.
+pub fn run<W: Write>(config: &Config, path: &str, fast: bool, out: &mut W)
+        -> io::Result<Vec<Peer>> {
+    let trace = try!(Trace::read(path));
+    replay(config, &trace, fast, out)
+}
+
+// The node takes on the recorded one's address, id and seed, and is
+// polled a tick at a time by a clock that only moves as the dump says.
+// Each datagram the recorded node received arrives again, from where it
+// came from, when it did, and the node is polled whenever the recorded
+// one sent anything. So, given the options the recorded node was run
+// with, it does again what that node did, as far as what it received
+// decided. Timing is kept to in real time too, unless `fast`.
+//
+// Everything that happens is written to `out`, each line led by the
+// seconds since the dump began: each datagram received, as the message
+// it decodes to or why it doesn't decode; each event, joins and state
+// changes among them; and each datagram the node sends, which goes
+// nowhere. (The datagrams it sent when recorded are what we reproduce,
+// and are left out.) Then the peers it ends up with.
+pub fn replay<W: Write>(config: &Config, trace: &Trace, fast: bool,
+                        out: &mut W) -> io::Result<Vec<Peer>> {
+    let header = &trace.header;
+    let mut transport = SimTransport::new();
+    transport.addr = header.addr;
+    let mut node = Dispatcher::with_seed(transport, ManualClock::new(),
+                                         config, header.id, header.seed);
+    try!(writeln!(out, "replaying node {} on {}, seed {}", header.id,
+                  header.addr, header.seed));
+
+    let mut now = Duration::from_secs(0);
+    let mut polled = None;
+    for record in &trace.records {
+        // Whatever comes due meanwhile, a tick at a time.
+        while now < record.at {
+            let by = cmp::min(TICK, record.at - now);
+            if !fast {
+                thread::sleep(by);
+            }
+            node.clock().advance(by);
+            now = now + by;
+            if now < record.at {
+                try!(poll(&mut node, now, out));
+                polled = Some(now);
+            }
+        }
+        match record.direction {
+            Direction::In => {
+                try!(writeln!(out, "{} recv {} {}", secs(now), record.addr,
+                              describe(&record.bytes)));
+                node.transport().deliver(record.bytes.clone(), record.addr);
+            },
+            // The recorded node was polled then, to have sent it.
+            Direction::Out if polled == Some(now) => continue,
+            Direction::Out => (),
+        }
+        try!(poll(&mut node, now, out));
+        polled = Some(now);
+    }
+
+    let peers = node.peers();
+    for peer in &peers {
+        try!(writeln!(out, "{} peer {} {} {:?}", secs(now), peer.id(),
+                      peer.addr(), peer.state()));
+    }
+    Ok(peers)
+}
+
+// Poll the node at `now`, and write out the events that raised and what
+// it sent.
+fn poll<W: Write>(node: &mut Replaying, now: Duration, out: &mut W)
+        -> io::Result<()> {
+    node.poll();
+    while let Some(event) = node.next_event() {
+        try!(writeln!(out, "{} event {:?}", secs(now), event));
+    }
+    let sent: Vec<(Vec<u8>, _)> = node.transport().sent.borrow_mut()
+        .drain(..).collect();
+    for (bytes, to) in sent {
+        try!(writeln!(out, "{} send {} {}", secs(now), to, describe(&bytes)));
+    }
+    Ok(())
+}
+
+// What a datagram says, or why it says nothing, in its bytes.
+fn describe(bytes: &[u8]) -> String {
+    match parse_datagram(bytes) {
+        Ok(msg) => format!("{:?}", msg),
+        Err(e) => format!("undecodable ({}): {}", e, dump::hex(bytes)),
+    }
+}
+
+fn secs(d: Duration) -> String {
+    format!("{}.{:06}", d.as_secs(), d.subsec_nanos() / 1000)
+}
+
+#[test]
+fn a_checked_in_dump_replays_to_the_same_peers() {
+    use members::{NodeId, PeerState};
+
+    let path = concat!(env!("CARGO_MANIFEST_DIR"),
+                       "/tests/packets/one_silent_peer.packets");
+    let mut out = Vec::new();
+    let peers = run(&Config::default(), path, true, &mut out).unwrap();
+    let found: Vec<(NodeId, String, PeerState)> = peers.iter()
+        .map(|p| (p.id(), p.addr().to_string(), p.state())).collect();
+    assert_eq!(found, vec![
+        (NodeId(0xb1), "127.0.0.1:9001".to_string(), PeerState::Alive),
+        (NodeId(0xc2), "127.0.0.1:9002".to_string(), PeerState::Suspect),
+    ]);
+
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("replaying node 00000000000000aa on \
+                             127.0.0.1:7000, seed 1\n"));
+    assert!(out.contains("1.000000 recv 127.0.0.1:9003 undecodable"));
+    assert!(out.ends_with("peer 00000000000000c2 127.0.0.1:9002 Suspect\n"));
+    // It sent just what the recorded node did, when it did.
+    let sent: Vec<&str> = out.lines().filter(|l| l.contains(" send "))
+        .collect();
+    let recorded: Vec<String> = Trace::read(path).unwrap().records.iter()
+        .filter(|r| r.direction == Direction::Out)
+        .map(|r| format!("{} send {} {}", secs(r.at), r.addr,
+                         describe(&r.bytes)))
+        .collect();
+    assert_eq!(recorded.len(), 59);
+    assert_eq!(sent, recorded);
+}
@@ -1,2 +1,11 @@
-pub use self::scheduler::Scheduler;
+// Timers. A Timer is the bare data structure, driven by its owner as time
+// passes; the dispatcher embeds one in its own loop. A Scheduler wraps one
+// in a thread of its own, for work that should happen on a clock rather
+// than between datagrams, and a Watchdog notices when that thread stalls.
+pub use self::scheduler::{Scheduler, SchedulerError, SchedulerHandle};
+pub use self::timer::Timer;
+pub use self::watchdog::{Watchdog, WatchdogConfig};
+
 mod scheduler;
+mod timer;
+mod watchdog;
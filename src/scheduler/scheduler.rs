@@ -1,261 +1,540 @@
 extern crate time;
 
-use self::time::Duration;
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
-use std::sync::{Arc, Mutex};
+use clock::Nanos;
+use histogram::Histogram;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
 use std::thread;
-use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{channel, Receiver, SyncSender, TrySendError};
+use super::timer::Timer;
+
+// Runs functions after a delay. A thread of its own keeps time with a
+// Timer and hands each function back, when it's due, to whichever thread
+// calls `run` or `run_limit`; or, for `delay_send`, delivers a value down
+// a channel for its owner to drain however suits it.
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    // None once the thread has been joined.
+    timer_thread: Option<thread::JoinHandle<()>>,
+    receiver: Receiver<Box<TimerCB>>,
+}
 
-struct Event<F> {
-    time: u64,
-    cb: F,
+// How a Scheduler can fail.
+#[derive(Debug)]
+pub enum SchedulerError {
+    // The timer thread panicked, with this message if it had one.
+    TimerThreadPanicked(String),
 }
 
-impl<F> Event<F> {
-    fn new(time: u64, cb: F) -> Event<F> {
-        Event {
-            time: time,
-            cb: cb
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchedulerError::TimerThreadPanicked(ref msg) =>
+                write!(f, "timer thread panicked: {}", msg),
         }
     }
+}
 
-    fn fire(&self, actual: u64) {
-        let drift = actual - self.time;
-        println!("Event {} fired at {} => lag {}ns",
-                 &self.time, actual, drift);
-    }
+type TimerCB = Fn(&mut Scheduler) + Send + 'static;
+
+// What the timer thread does with an event once it's due.
+enum Expiry {
+    // Hand the function back to run on the Scheduler's own thread.
+    Call(Box<TimerCB>),
+    // Deliver a value to a channel; see `delay_send`.
+    Deliver(Box<FnMut() + Send>),
 }
 
-// Events are ordered in reverse according to their scheduled time,
-// hence we implement Ord and PartialOrd reversing the sense of cmp.
-impl<F> Ord for Event<F> {
-    fn cmp(&self, other: &Event<F>) -> Ordering {
-        other.time.cmp(&self.time)
-    }
+// State shared between the scheduling side and the timer thread. The
+// timer thread only ever waits on `wakeup` while holding `timer`'s lock,
+// and anyone adding an event signals `wakeup` under that same lock, so
+// there's no window in which a newly added, earlier deadline can go
+// unnoticed until the old one expires.
+//
+// The locks are taken in the order `timer`, then `lag`, and never the
+// other way round: `lag` is only held to record or copy, and nothing is
+// locked under it. It's a lock of its own so that reading it doesn't wait
+// on a timer thread that's stuck holding `timer` (see Watchdog). What's
+// done under `timer` is either quick or hands off without blocking: due
+// functions go down an unbounded channel, and `delay_send` values down
+// theirs with try_send.
+struct Shared {
+    timer: Mutex<Timed>,
+    wakeup: Condvar,
+    // Set (under the timer lock) to make the timer thread exit.
+    shutdown: AtomicBool,
+    // Values from `delay_send` dropped because their channel was full.
+    dropped_sends: AtomicUsize,
+    // How late each event has fired, past its deadline.
+    lag: Mutex<Histogram>,
+    // Lets tests make the timer thread panic.
+    #[cfg(test)]
+    crash: AtomicBool,
 }
 
-impl<F> PartialOrd for Event<F> {
-    fn partial_cmp(&self, other: &Event<F>) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
+// The timer and when the timer thread last advanced it. The timer counts
+// from then, so a delay asked for now is that much longer by its count.
+struct Timed {
+    timer: Timer<Expiry>,
+    advanced: Instant,
 }
 
-// We must also implement Eq, though this is strictly nonsense.
-impl<F> Eq for Event<F> { }
-impl<F> PartialEq for Event<F> {
-    fn eq(&self, other: &Event<F>) -> bool {
-        &self.time == &other.time
+impl Timed {
+    // `delay` from now, by the timer's count.
+    fn from_now(&self, delay: Nanos) -> Nanos {
+        delay + Nanos::from(Instant::now() - self.advanced)
     }
 }
 
-#[test]
-fn event_cmp() {
-    // Because we order events with earliest time first, time=1 is
-    // "bigger" than time=2. This is awkward, but made necessary by
-    // the fact that std::collections::BinaryHeap is exclusively a
-    // max-heap based on the contents' PartialOrd implementation.
-    // This seems unhelpfully rigid (what if we want both a max-heap
-    // AND a min-heap for the same type?), but c'est la vie.
-    assert!(Event::new(1, ()) > Event::new(2, ()));
-}
+impl Scheduler {
+    // A scheduler whose events may fire up to 5ms late.
+    pub fn new() -> Scheduler {
+        Scheduler::with_tolerance(Nanos::from_millis(5))
+    }
 
-// A timer controls the scheduling of events based on the passage of time.
-// Time here is a unitless 64-bit int, which it may be useful to interpret
-// as milliseconds or nanoseconds.
-struct Timer<F> {
-    events: BinaryHeap<Event<F>>,
-    elapsed: u64,
-}
+    // A scheduler whose events may fire up to `tolerance` late (but never
+    // early) so nearby deadlines share a wakeup. Zero disables
+    // coalescing.
+    pub fn with_tolerance(tolerance: Nanos) -> Scheduler {
+        let shared = Arc::new(Shared {
+            timer: Mutex::new(Timed {
+                timer: Timer::new(),
+                advanced: Instant::now(),
+            }),
+            wakeup: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            dropped_sends: AtomicUsize::new(0),
+            lag: Mutex::new(Histogram::new()),
+            #[cfg(test)]
+            crash: AtomicBool::new(false),
+        });
+
+        let (tx, rx) = channel::<Box<TimerCB>>();
+
+        let timer_thread = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let mut timed = shared.timer.lock().unwrap();
+
+                while !shared.shutdown.load(AtomicOrdering::SeqCst) {
+                    #[cfg(test)]
+                    assert!(!shared.crash.load(AtomicOrdering::SeqCst),
+                            "crashed on purpose");
+
+                    // Advance by however long it's really been, whether we
+                    // woke because of a deadline, a new event or nothing.
+                    let now = Instant::now();
+                    let elapsed = Nanos::from(now - timed.advanced);
+                    let cbs = timed.timer.advance_late(elapsed);
+                    timed.advanced = now;
+                    if !cbs.is_empty() {
+                        let mut lag = shared.lag.lock().unwrap();
+                        for &(_, late) in cbs.iter() {
+                            lag.record(Duration::from(late));
+                        }
+                    }
+                    for (expiry, _) in cbs {
+                        match expiry {
+                            Expiry::Call(f) => tx.send(f).unwrap(),
+                            Expiry::Deliver(mut deliver) => deliver(),
+                        }
+                    }
+
+                    // Wait for the next deadline, or until somebody
+                    // schedules an event.
+                    timed = match timed.timer.wakeup(tolerance) {
+                        Some(wait) => shared.wakeup
+                            .wait_timeout(timed, Duration::from(wait))
+                            .unwrap().0,
+                        None => shared.wakeup.wait(timed).unwrap(),
+                    };
+                }
+            })
+        };
+
+        Scheduler {
+            shared: shared,
+            timer_thread: Some(timer_thread),
+            receiver: rx,
+        }
+    }
+
+    // Schedule the execution of a function after `delay`.
+    pub fn delay<F>(&mut self, delay: Nanos, func: F)
+            where F: Fn(&mut Scheduler) + Send + 'static {
+        self.handle().delay(delay, func);
+    }
+
+    // As `delay`, labelling the event for `dump`.
+    pub fn delay_named<S, F>(&mut self, delay: Nanos, label: S, func: F)
+            where S: Into<String>, F: Fn(&mut Scheduler) + Send + 'static {
+        self.handle().delay_named(delay, label, func);
+    }
+
+    // As `delay_named`, for an event that must not be coalesced with
+    // others and so fires as close to on time as we can manage.
+    pub fn delay_exact<S, F>(&mut self, delay: Nanos, label: S, func: F)
+            where S: Into<String>, F: Fn(&mut Scheduler) + Send + 'static {
+        self.handle().delay_exact(delay, label, func);
+    }
+
+    // Send `value` down `tx` after `delay`, for owners that
+    // would rather drain expirations on their own thread than have
+    // closures handed back through `run`. The timer thread never blocks
+    // on the channel: if it's full (or disconnected) when the value is
+    // due, the value is dropped and counted in `dropped_sends`, so a slow
+    // receiver can't hold up other timers.
+    pub fn delay_send<T: Send + 'static>(&mut self, delay: Nanos,
+                                         tx: SyncSender<T>, value: T) {
+        self.handle().delay_send(delay, tx, value);
+    }
+
+    // Drop every pending event labelled `label`, returning how many there
+    // were; see Timer::cancel.
+    pub fn cancel(&self, label: &str) -> usize {
+        self.shared.timer.lock().unwrap().timer.cancel(label)
+    }
+
+    // How many events are pending.
+    pub fn len(&self) -> usize {
+        self.shared.timer.lock().unwrap().timer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // How many `delay_send` values have been dropped so far.
+    pub fn dropped_sends(&self) -> usize {
+        self.shared.dropped_sends.load(AtomicOrdering::SeqCst)
+    }
+
+    // How late events have fired so far, past their deadlines. Some
+    // lateness is by design (see `with_tolerance`); much more means the
+    // timer thread isn't getting to run.
+    pub fn lag(&self) -> Histogram {
+        self.handle().lag()
+    }
+
+    // The label and remaining time of each pending event, soonest first.
+    // Remaining times are as of the timer thread's last wakeup, so may run
+    // slightly long.
+    pub fn dump(&self) -> Vec<(String, Nanos)> {
+        self.shared.timer.lock().unwrap().timer.dump()
+    }
+
+    // Get a handle through which other threads can schedule functions.
+    pub fn handle(&self) -> SchedulerHandle {
+        SchedulerHandle {
+            shared: self.shared.clone(),
+        }
+    }
 
-impl<F> Timer<F> {
-    fn new() -> Timer<F> {
-        Timer {
-            events: BinaryHeap::new(),
-            elapsed: 0
+    // Run the event loop until the timer thread goes away, returning how
+    // it ended.
+    pub fn run(&mut self) -> Result<(), SchedulerError> {
+        loop {
+            match self.receiver.recv() {
+                Ok(f) => f(self),
+                Err(_) => return self.join(),
+            }
         }
     }
 
-    // Schedule an event in the timer.
-    fn add(&mut self, delay: u64, cb: F) {
-        self.events.push(Event::new(delay + self.elapsed, cb));
+    // Run at most n scheduled functions.
+    pub fn run_limit(&mut self, n: u32) -> Result<(), SchedulerError> {
+        for _ in 0..n {
+            match self.receiver.recv() {
+                Ok(f) => f(self),
+                Err(_) => return self.join(),
+            }
+        }
+        Ok(())
     }
 
-    // Get the time remaining to the earliest pending event,
-    // if there is one; None otherwise.
-    fn earliest(&self) -> Option<u64> {
-        self.events.peek().map(|e| e.time - self.elapsed)
+    // Stop the timer thread, dropping any pending events. Reports a
+    // panic on the timer thread even if it happened long before.
+    pub fn shutdown(mut self) -> Result<(), SchedulerError> {
+        {
+            // The lock is poisoned if the thread panicked holding it, but
+            // we still want to get through to the join.
+            let _timer = match self.shared.timer.lock() {
+                Ok(timer) => timer,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            self.shared.shutdown.store(true, AtomicOrdering::SeqCst);
+            self.shared.wakeup.notify_one();
+        }
+        self.join()
     }
 
-    // Advance time by a specified duration, expiring all scheduled
-    // events whose timeout period has now elapsed.
-    // Return a Vec containing the expired items.
-    fn advance(&mut self, elapsed: u64) -> Vec<F> {
-        self.elapsed += elapsed;
-        let mut result = Vec::new();
-        while self.events.peek().map_or(false, |e| e.time <= self.elapsed) {
-            result.push(self.events.pop().unwrap().cb);
+    fn join(&mut self) -> Result<(), SchedulerError> {
+        match self.timer_thread.take() {
+            None => Ok(()),
+            Some(t) => t.join().map_err(|e| {
+                let msg = match e.downcast_ref::<&'static str>() {
+                    Some(s) => s.to_string(),
+                    None => match e.downcast_ref::<String>() {
+                        Some(s) => s.clone(),
+                        None => "unknown panic".to_string(),
+                    },
+                };
+                SchedulerError::TimerThreadPanicked(msg)
+            }),
         }
-        result
     }
 }
 
-#[test]
-fn timer_earliest_no_events() {
-    let t = Timer::<()>::new();
-    assert_eq!(t.earliest(), None);
+// Schedules functions on a Scheduler from any thread. They still run on
+// whichever thread calls the Scheduler's run methods.
+#[derive(Clone)]
+pub struct SchedulerHandle {
+    shared: Arc<Shared>,
 }
 
-#[test]
-fn timer_earliest_one() {
-    let mut t = Timer::new();
-    t.add(100, ());
-    assert_eq!(t.earliest(), Some(100));
+// Each method is as the Scheduler's of the same name.
+impl SchedulerHandle {
+    pub fn delay<F>(&self, delay: Nanos, func: F)
+            where F: Fn(&mut Scheduler) + Send + 'static {
+        self.delay_named(delay, String::new(), func);
+    }
+
+    pub fn delay_named<S, F>(&self, delay: Nanos, label: S, func: F)
+            where S: Into<String>, F: Fn(&mut Scheduler) + Send + 'static {
+        let mut timed = self.shared.timer.lock().unwrap();
+        let delay = timed.from_now(delay);
+        timed.timer.add_named(delay, label, Expiry::Call(Box::new(func)));
+        self.shared.wakeup.notify_one();
+    }
+
+    pub fn delay_exact<S, F>(&self, delay: Nanos, label: S, func: F)
+            where S: Into<String>, F: Fn(&mut Scheduler) + Send + 'static {
+        let mut timed = self.shared.timer.lock().unwrap();
+        let delay = timed.from_now(delay);
+        timed.timer.add_exact(delay, label, Expiry::Call(Box::new(func)));
+        self.shared.wakeup.notify_one();
+    }
+
+    pub fn delay_send<T: Send + 'static>(&self, delay: Nanos,
+                                         tx: SyncSender<T>, value: T) {
+        let shared = self.shared.clone();
+        let mut value = Some(value);
+        let deliver = move || {
+            if let Some(value) = value.take() {
+                match tx.try_send(value) {
+                    Ok(()) => (),
+                    Err(TrySendError::Full(_)) |
+                    Err(TrySendError::Disconnected(_)) => {
+                        shared.dropped_sends.fetch_add(1, AtomicOrdering::SeqCst);
+                    },
+                }
+            }
+        };
+
+        let mut timed = self.shared.timer.lock().unwrap();
+        let delay = timed.from_now(delay);
+        timed.timer.add(delay, Expiry::Deliver(Box::new(deliver)));
+        self.shared.wakeup.notify_one();
+    }
+
+    pub fn lag(&self) -> Histogram {
+        self.shared.lag.lock().unwrap().clone()
+    }
+
+    // Have the timer thread sleep for `d` as soon as it can, holding up
+    // everything due meanwhile, as though it were stuck.
+    #[cfg(test)]
+    pub fn wedge(&self, d: Duration) {
+        let mut timed = self.shared.timer.lock().unwrap();
+        let now = timed.from_now(Nanos::zero());
+        let sleep = move || thread::sleep(d);
+        timed.timer.add(now, Expiry::Deliver(Box::new(sleep)));
+        self.shared.wakeup.notify_one();
+    }
+}
+
+#[cfg(test)]
+fn ms(n: u64) -> Nanos {
+    Nanos::from_millis(n)
 }
 
 #[test]
-fn timer_earliest_orders_correctly() {
-    let mut t = Timer::new();
-    t.add(100, ());
-    t.add(10, ());
-    t.add(50, ());
-    assert_eq!(t.earliest(), Some(10));
+fn crappy_threaded_scheduler_test() {
+    let mut s = Scheduler::new();
+
+    s.delay(ms(1000), |s| {
+        println!("Hello, world!!!");
+        s.delay(ms(1000), |s| {
+            println!("A second message!");
+            s.delay(ms(1000), |s| {
+                println!("A fifth message! ...Wait.");
+            });
+        });
+    });
+
+    s.run_limit(3).unwrap();
 }
 
 #[test]
-fn timer_earliest_updates_after_advance() {
-    let mut t = Timer::new();
-    t.add(100, ());
-    t.advance(58);
-    assert_eq!(t.earliest(), Some(42));
+fn earlier_event_interrupts_longer_wait() {
+    use std::time::Duration;
+
+    let mut s = Scheduler::new();
+    s.delay(ms(10000), |_| panic!("the 10s event fired first"));
+
+    // Give the timer thread time to settle into its 10s wait.
+    thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    s.delay(ms(10), |_| ());
+    s.run_limit(1).unwrap();
+    assert!(start.elapsed() < Duration::from_millis(500));
 }
 
 #[test]
-fn timer_advance_pops_events() {
-    let mut t = Timer::new();
-    t.add(2, "second");
-    t.add(3, "third");
-    t.add(1, "first");
-
-    for n in vec!["first", "second", "third"] {
-        // NB: delta to next earliest is 1 each time
-        assert_eq!(t.earliest(), Some(1));
-        assert_eq!(t.advance(1), vec![n]);
-    }
-    assert_eq!(t.earliest(), None);
+fn delays_count_from_when_they_are_asked_for() {
+    let mut s = Scheduler::new();
+    // The timer thread sleeps with nothing to do, its timer unadvanced.
+    thread::sleep(Duration::from_millis(100));
+    let start = Instant::now();
+    s.delay(ms(50), |_| ());
+    s.run_limit(1).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(s.lag().p99().unwrap() < Duration::from_millis(40));
 }
 
 #[test]
-fn timer_advance_pops_multiple() {
-    let mut t = Timer::new();
-    t.add(1, 1);
-    t.add(2, 2);
-    t.add(3, 3);
-    t.add(10, 5);
-    t.add(10, 5);
-    t.add(14, 6);
-    assert_eq!(t.advance(10), vec![1, 2, 3, 5, 5]);
-    assert_eq!(t.earliest(), Some(4));
+fn dump_shows_labelled_events() {
+    let mut s = Scheduler::new();
+    s.delay_named(ms(20000), "probe:10.0.0.3", |_| ());
+    s.delay_named(ms(10), "retransmit:seq=42", |_| ());
+    s.delay_named(ms(10000), "gossip", |_| ());
+
+    let labels: Vec<String> = s.dump().into_iter().map(|(l, _)| l).collect();
+    assert_eq!(labels, vec!["retransmit:seq=42", "gossip", "probe:10.0.0.3"]);
+
+    s.run_limit(1).unwrap();
+    let dump = s.dump();
+    assert_eq!(dump.len(), 2);
+    assert_eq!(dump[0].0, "gossip");
+    assert!(dump[0].1 <= ms(10000));
+    assert_eq!(dump[1].0, "probe:10.0.0.3");
 }
 
 #[test]
-fn timer_add_after_advance() {
-    let mut t = Timer::new();
-    t.advance(1000);
-    t.add(1, ());
-    assert_eq!(t.earliest(), Some(1));
+fn cancelled_event_never_fires() {
+    let mut s = Scheduler::new();
+    s.delay_named(ms(10), "doomed", |_| panic!("cancelled event fired"));
+    s.delay_named(ms(50), "kept", |_| ());
+    assert_eq!(s.len(), 2);
+    assert_eq!(s.cancel("doomed"), 1);
+    assert_eq!(s.len(), 1);
+    s.run_limit(1).unwrap();
+    assert_eq!(s.len(), 0);
 }
 
-pub struct Scheduler {
-    timer: Arc<Mutex<Timer<Box<TimerCB>>>>,
-    timer_thread: thread::JoinHandle<()>,
-    receiver: Receiver<Box<TimerCB>>,
+#[test]
+fn exact_event_is_not_coalesced() {
+    use std::time::Duration;
+
+    let mut s = Scheduler::with_tolerance(ms(1000));
+    let start = Instant::now();
+    s.delay_exact(ms(10), "retransmit:seq=1", |_| ());
+    s.run_limit(1).unwrap();
+    assert!(start.elapsed() < Duration::from_millis(500));
 }
 
-type TimerCB = Fn(&mut Scheduler) + Send + 'static;
+#[test]
+fn delay_send_delivers_in_deadline_order() {
+    use std::sync::mpsc::sync_channel;
+    use std::time::Duration;
 
-impl Scheduler {
-    fn new() -> Scheduler {
-        let timer: Arc<Mutex<Timer<Box<TimerCB>>>>
-            = Arc::new(Mutex::new(Timer::new()));
+    let mut s = Scheduler::new();
+    let (tx, rx) = sync_channel(16);
+    s.delay_send(ms(30), tx.clone(), 3);
+    s.delay_send(ms(10), tx.clone(), 1);
+    s.delay_send(ms(20), tx, 2);
+
+    let timeout = Duration::from_secs(5);
+    let got: Vec<u32> = (0..3).map(|_| rx.recv_timeout(timeout).unwrap())
+        .collect();
+    assert_eq!(got, vec![1, 2, 3]);
+}
 
-        let (tx, rx) = channel::<Box<TimerCB>>();
+#[test]
+fn slow_receiver_does_not_hold_up_timers() {
+    use std::sync::mpsc::sync_channel;
+    use std::time::Duration;
 
-        let timer_thread = {
-            let timer = timer.clone();
-            thread::spawn(move || {
-                // How long we plan to park the thread, in nanoseconds.
-                // None means "park until somebody schedules an event."
-                let mut wait = None;
-
-                loop {
-                    // Measure how long we actually spend parked
-                    let elapsed = Duration::span(|| {
-                        if let Some(ns) = wait {
-                            thread::park_timeout_ms((ns/1000000) as u32);
-                        } else {
-                            thread::park();
-                        }
-                    }).num_nanoseconds().unwrap() as u64;
+    let mut s = Scheduler::new();
+    let (tx, rx) = sync_channel(1);
+    for i in 0..3 {
+        s.delay_send(ms(i), tx.clone(), i);
+    }
 
-                    // Advance the timer and decide how long to wait again
-                    let mut timer = timer.lock().unwrap();
-                    let cbs = timer.advance(elapsed);
-                    for f in cbs {
-                        tx.send(f).unwrap();
-                    }
-                    wait = timer.earliest();
-                }
-            })
-        };
+    // Nobody reads rx, yet a later event still fires on time.
+    let start = Instant::now();
+    s.delay(ms(50), |_| ());
+    s.run_limit(1).unwrap();
+    assert!(start.elapsed() < Duration::from_millis(500));
 
-        Scheduler { 
-            timer: timer,
-            timer_thread: timer_thread,
-            receiver: rx,
-        }
-    }
+    assert_eq!(s.dropped_sends(), 2);
+    assert_eq!(rx.try_recv().unwrap(), 0);
+}
 
-    // Schedule the execution of a function after a specified
-    // period of time in milliseconds.
-    fn delay<F>(&mut self, millis: u64, func: F)
-            where F: Fn(&mut Scheduler) + Send + 'static {
-        let mut timer = self.timer.lock().unwrap();
-        timer.add(millis * 1000000, Box::new(func));
-        self.timer_thread.thread().unpark();
-    }
+#[test]
+fn lag_shows_a_wedged_timer_thread() {
+    let mut s = Scheduler::new();
+    s.delay(ms(0), |_| ());
+    s.run_limit(1).unwrap();
+    assert_eq!(s.lag().len(), 1);
+    assert!(s.lag().p99().unwrap() < Duration::from_millis(100));
+
+    // Added first, so that it doesn't wait on the wedged thread's lock.
+    s.delay(ms(10), |_| ());
+    s.handle().wedge(Duration::from_millis(300));
+    s.run_limit(1).unwrap();
+    // The wedge itself, and the event stuck behind it.
+    assert_eq!(s.lag().len(), 3);
+    assert!(s.lag().p99().unwrap() >= Duration::from_millis(250));
+}
 
-    // Run the event loop forever.
-    fn run(&mut self) {
-        loop {
-            let f = self.receiver.recv().unwrap();
-            f(self);
-        }
+#[test]
+fn shutdown_stops_timer_thread() {
+    let mut s = Scheduler::new();
+    s.delay(ms(10000), |_| ());
+    s.shutdown().unwrap();
+}
+
+#[test]
+fn run_reports_timer_thread_panic() {
+    let mut s = Scheduler::new();
+    {
+        let _timer = s.shared.timer.lock().unwrap();
+        s.shared.crash.store(true, AtomicOrdering::SeqCst);
+        s.shared.wakeup.notify_one();
     }
 
-    fn run_limit(&mut self, n: u32) {
-        for i in 0..n {
-            let f = self.receiver.recv().unwrap();
-            f(self);
-        }
+    match s.run() {
+        Err(SchedulerError::TimerThreadPanicked(msg)) =>
+            assert_eq!(msg, "crashed on purpose"),
+        Ok(()) => panic!("timer thread panic went unreported"),
     }
 }
 
 #[test]
-fn crappy_threaded_scheduler_test() {
-    let mut s = Scheduler::new();
-
-    s.delay(1000, |s| {
-        println!("Hello, world!!!");
-        s.delay(1000, |s| {
-            println!("A second message!");
-            s.delay(1000, |s| {
-                println!("A fifth message! ...Wait.");
-            });
-        });
-    });
+fn shutdown_reports_earlier_panic() {
+    let s = Scheduler::new();
+    {
+        let _timer = s.shared.timer.lock().unwrap();
+        s.shared.crash.store(true, AtomicOrdering::SeqCst);
+        s.shared.wakeup.notify_one();
+    }
+    thread::sleep(::std::time::Duration::from_millis(50));
 
-    s.run_limit(3);
+    assert!(s.shutdown().is_err());
 }
 
 #[test]
@@ -266,13 +545,13 @@ fn drift_test() {
     let start = PreciseTime::now();
 
     for i in 0..10 {
-        s.delay(i * 1000, move |_| {
+        s.delay(ms(i * 1000), move |_| {
             let t = start.to(PreciseTime::now()).num_nanoseconds().unwrap();
             println!("{}", t);
         });
     }
 
-    s.run_limit(10);
+    s.run_limit(10).unwrap();
 }
 
 #[test]
@@ -287,12 +566,12 @@ fn latency_test() {
         let result = delta.clone();
 
         let start = PreciseTime::now();
-        s.delay(n, move |_| {
+        s.delay(ms(n), move |_| {
             let t = start.to(PreciseTime::now()).
                 num_nanoseconds().unwrap() as u64;
             *delta.lock().unwrap() = t;
         });
-        s.run_limit(1);
+        s.run_limit(1).unwrap();
 
         let result = *result.lock().unwrap();
         result
@@ -303,3 +582,107 @@ fn latency_test() {
         println!("Scheduled function ran with latency {} ns", d);
     }
 }
+
+#[test]
+fn concurrent_delay_fires_each_event_once() {
+    use std::collections::HashMap;
+
+    const THREADS: u64 = 4;
+    const PER_THREAD: u64 = 250;
+    const TOTAL: u64 = THREADS * PER_THREAD;
+
+    let mut s = Scheduler::new();
+    let handles: Vec<SchedulerHandle> = (0..THREADS).map(|_| s.handle()).collect();
+    let fired = Arc::new(Mutex::new(HashMap::new()));
+
+    // Run callbacks concurrently with the scheduling below, on a thread
+    // of their own so that a lost event fails the test instead of hanging
+    // it. Once all are in, anything left over in the channel would be a
+    // double fire.
+    let (done_tx, done_rx) = channel();
+    thread::spawn(move || {
+        s.run_limit(TOTAL as u32).unwrap();
+        thread::sleep(::std::time::Duration::from_millis(100));
+        let drained = s.receiver.try_recv().is_err();
+        done_tx.send((drained, s)).unwrap();
+    });
+
+    let hammers: Vec<_> = handles.into_iter().enumerate().map(|(t, handle)| {
+        let fired = fired.clone();
+        thread::spawn(move || {
+            for i in 0..PER_THREAD {
+                let id = t as u64 * PER_THREAD + i;
+                let fired = fired.clone();
+                // Zero and near-zero delays land as close as possible to
+                // the timer thread's own advance.
+                handle.delay(ms(i % 3), move |_| {
+                    *fired.lock().unwrap().entry(id).or_insert(0) += 1;
+                });
+            }
+        })
+    }).collect();
+    for h in hammers {
+        h.join().unwrap();
+    }
+
+    let (drained, s) = done_rx
+        .recv_timeout(::std::time::Duration::from_secs(10))
+        .expect("scheduled events were lost");
+    assert!(drained, "an event fired more than once");
+
+    let fired = fired.lock().unwrap();
+    assert_eq!(fired.len() as u64, TOTAL);
+    assert!(fired.values().all(|&n| n == 1));
+
+    // Still the one timer thread, which stops when told to.
+    assert!(s.timer_thread.is_some());
+    let (stopped_tx, stopped_rx) = channel();
+    thread::spawn(move || stopped_tx.send(s.shutdown().is_ok()).unwrap());
+    let stopped = stopped_rx
+        .recv_timeout(::std::time::Duration::from_secs(10))
+        .expect("the timer thread never stopped");
+    assert!(stopped, "the timer thread panicked");
+}
+
+#[test]
+fn every_lock_path_runs_concurrently_without_blocking() {
+    use std::sync::mpsc::sync_channel;
+
+    const THREADS: usize = 4;
+    const PER_THREAD: u64 = 200;
+    const TOTAL: usize = THREADS * PER_THREAD as usize;
+
+    let s = Scheduler::new();
+    let (tx, rx) = sync_channel(TOTAL);
+    // Sends firing, events added that never will, and lag read, all
+    // while the timer thread advances and records lag.
+    let hammers: Vec<_> = (0..THREADS).map(|t| {
+        let handle = s.handle();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for i in 0..PER_THREAD {
+                handle.delay_send(ms(i % 3), tx.clone(), (t, i));
+                handle.delay_named(ms(60000), format!("never:{}", t), |_| ());
+                handle.lag();
+            }
+        })
+    }).collect();
+    for _ in 0..100 {
+        s.dump();
+        s.len();
+    }
+    for h in hammers {
+        h.join().unwrap();
+    }
+
+    let cancelled: usize = (0..THREADS)
+        .map(|t| s.cancel(&format!("never:{}", t))).sum();
+    assert_eq!(cancelled, TOTAL);
+    for _ in 0..TOTAL {
+        rx.recv_timeout(::std::time::Duration::from_secs(10))
+            .expect("a delay_send was lost");
+    }
+    assert_eq!(s.dropped_sends(), 0);
+    assert!(s.lag().len() >= TOTAL as u64);
+    s.shutdown().unwrap();
+}
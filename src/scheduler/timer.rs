@@ -0,0 +1,352 @@
+use clock::Nanos;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::mem;
+
+struct Event<F> {
+    time: Nanos,
+    // What the event is for, for debugging; may be empty.
+    label: String,
+    cb: F,
+}
+
+impl<F> Event<F> {
+    fn new(time: Nanos, cb: F) -> Event<F> {
+        Event::named(time, String::new(), cb)
+    }
+
+    fn named(time: Nanos, label: String, cb: F) -> Event<F> {
+        Event {
+            time: time,
+            label: label,
+            cb: cb
+        }
+    }
+}
+
+// Events are ordered in reverse according to their scheduled time,
+// hence we implement Ord and PartialOrd reversing the sense of cmp.
+impl<F> Ord for Event<F> {
+    fn cmp(&self, other: &Event<F>) -> Ordering {
+        other.time.cmp(&self.time)
+    }
+}
+
+impl<F> PartialOrd for Event<F> {
+    fn partial_cmp(&self, other: &Event<F>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// We must also implement Eq, though this is strictly nonsense.
+impl<F> Eq for Event<F> { }
+impl<F> PartialEq for Event<F> {
+    fn eq(&self, other: &Event<F>) -> bool {
+        &self.time == &other.time
+    }
+}
+
+#[cfg(test)]
+fn ns(n: u64) -> Nanos {
+    Nanos::from_nanos(n)
+}
+
+#[test]
+fn event_cmp() {
+    // Because we order events with earliest time first, time=1 is
+    // "bigger" than time=2. This is awkward, but made necessary by
+    // the fact that std::collections::BinaryHeap is exclusively a
+    // max-heap based on the contents' PartialOrd implementation.
+    // This seems unhelpfully rigid (what if we want both a max-heap
+    // AND a min-heap for the same type?), but c'est la vie.
+    assert!(Event::new(ns(1), ()) > Event::new(ns(2), ()));
+}
+
+// A timer controls the scheduling of events based on the passage of time,
+// which it counts in Nanos. Nothing happens by itself: whoever owns
+// the timer calls `advance` as time passes and acts on what comes back,
+// as the dispatcher does from its tick and the Scheduler from its thread.
+pub struct Timer<F> {
+    events: BinaryHeap<Event<F>>,
+    // The deadlines (and labels) of events added with `add_exact`, which
+    // `wakeup` won't coalesce.
+    exact: BinaryHeap<Event<()>>,
+    elapsed: Nanos,
+}
+
+impl<F> Timer<F> {
+    pub fn new() -> Timer<F> {
+        Timer {
+            events: BinaryHeap::new(),
+            exact: BinaryHeap::new(),
+            elapsed: Nanos::zero(),
+        }
+    }
+
+    // Schedule an event in the timer.
+    pub fn add(&mut self, delay: Nanos, cb: F) {
+        self.events.push(Event::new(delay + self.elapsed, cb));
+    }
+
+    // Schedule an event with a label saying what it's for, which shows up
+    // in `dump`.
+    pub fn add_named<S: Into<String>>(&mut self, delay: Nanos, label: S,
+                                      cb: F) {
+        self.events.push(Event::named(delay + self.elapsed, label.into(), cb));
+    }
+
+    // As `add_named`, but for an event that mustn't fire late just to
+    // save a wakeup.
+    pub fn add_exact<S: Into<String>>(&mut self, delay: Nanos, label: S,
+                                      cb: F) {
+        let label = label.into();
+        self.exact.push(Event::named(delay + self.elapsed, label.clone(), ()));
+        self.add_named(delay, label, cb);
+    }
+
+    // Drop every pending event labelled `label`, returning how many there
+    // were. Unlabelled events can't be cancelled.
+    pub fn cancel(&mut self, label: &str) -> usize {
+        if label.is_empty() {
+            return 0;
+        }
+        let before = self.events.len();
+        self.events = mem::replace(&mut self.events, BinaryHeap::new())
+            .into_vec().into_iter().filter(|e| e.label != label).collect();
+        self.exact = mem::replace(&mut self.exact, BinaryHeap::new())
+            .into_vec().into_iter().filter(|e| e.label != label).collect();
+        before - self.events.len()
+    }
+
+    // How many events are pending.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    // How long whoever drives the timer may sleep before advancing,
+    // allowing events to fire up to `tolerance` late so that a cluster of
+    // nearby deadlines is handled in one wakeup. Exact events are never
+    // kept waiting. None if nothing is pending.
+    pub fn wakeup(&self, tolerance: Nanos) -> Option<Nanos> {
+        self.earliest().map(|ns| {
+            let exact = self.exact.peek().map(|e| e.time - self.elapsed);
+            match exact {
+                Some(exact) if exact < ns + tolerance => exact,
+                _ => ns + tolerance,
+            }
+        })
+    }
+
+    // The label and remaining time of every pending event, soonest first.
+    // Only reads the heap, so scheduling is unaffected.
+    pub fn dump(&self) -> Vec<(String, Nanos)> {
+        let mut pending: Vec<(String, Nanos)> = self.events.iter()
+            .map(|e| (e.label.clone(), e.time - self.elapsed))
+            .collect();
+        pending.sort_by_key(|&(_, remaining)| remaining);
+        pending
+    }
+
+    // Get the time remaining to the earliest pending event,
+    // if there is one; None otherwise.
+    pub fn earliest(&self) -> Option<Nanos> {
+        self.events.peek().map(|e| e.time - self.elapsed)
+    }
+
+    // Advance time by a specified duration, expiring all scheduled
+    // events whose timeout period has now elapsed.
+    // Return a Vec containing the expired items.
+    pub fn advance(&mut self, elapsed: Nanos) -> Vec<F> {
+        self.advance_late(elapsed).into_iter().map(|(cb, _)| cb).collect()
+    }
+
+    // As `advance`, along with how long past its deadline each expired
+    // item is.
+    pub fn advance_late(&mut self, elapsed: Nanos) -> Vec<(F, Nanos)> {
+        self.elapsed = self.elapsed + elapsed;
+        let mut result = Vec::new();
+        while self.events.peek().map_or(false, |e| e.time <= self.elapsed) {
+            let e = self.events.pop().unwrap();
+            result.push((e.cb, self.elapsed - e.time));
+        }
+        while self.exact.peek().map_or(false, |e| e.time <= self.elapsed) {
+            self.exact.pop();
+        }
+        result
+    }
+}
+
+#[test]
+fn timer_earliest_no_events() {
+    let t = Timer::<()>::new();
+    assert_eq!(t.earliest(), None);
+}
+
+#[test]
+fn timer_earliest_one() {
+    let mut t = Timer::new();
+    t.add(ns(100), ());
+    assert_eq!(t.earliest(), Some(ns(100)));
+}
+
+#[test]
+fn timer_earliest_orders_correctly() {
+    let mut t = Timer::new();
+    t.add(ns(100), ());
+    t.add(ns(10), ());
+    t.add(ns(50), ());
+    assert_eq!(t.earliest(), Some(ns(10)));
+}
+
+#[test]
+fn timer_earliest_updates_after_advance() {
+    let mut t = Timer::new();
+    t.add(ns(100), ());
+    t.advance(ns(58));
+    assert_eq!(t.earliest(), Some(ns(42)));
+}
+
+#[test]
+fn timer_advance_pops_events() {
+    let mut t = Timer::new();
+    t.add(ns(2), "second");
+    t.add(ns(3), "third");
+    t.add(ns(1), "first");
+
+    for n in vec!["first", "second", "third"] {
+        // NB: delta to next earliest is 1 each time
+        assert_eq!(t.earliest(), Some(ns(1)));
+        assert_eq!(t.advance(ns(1)), vec![n]);
+    }
+    assert_eq!(t.earliest(), None);
+}
+
+#[test]
+fn timer_advance_pops_multiple() {
+    let mut t = Timer::new();
+    t.add(ns(1), 1);
+    t.add(ns(2), 2);
+    t.add(ns(3), 3);
+    t.add(ns(10), 5);
+    t.add(ns(10), 5);
+    t.add(ns(14), 6);
+    assert_eq!(t.advance(ns(10)), vec![1, 2, 3, 5, 5]);
+    assert_eq!(t.earliest(), Some(ns(4)));
+}
+
+#[test]
+fn timer_advance_late_says_how_late() {
+    let mut t = Timer::new();
+    t.add(ns(1), "first");
+    t.add(ns(10), "on time");
+    t.add(ns(20), "later");
+    assert_eq!(t.advance_late(ns(10)),
+               vec![("first", ns(9)), ("on time", ns(0))]);
+    assert_eq!(t.advance_late(ns(15)), vec![("later", ns(5))]);
+}
+
+#[test]
+fn timer_dump_lists_pending_by_deadline() {
+    let mut t = Timer::new();
+    t.add_named(ns(30), "probe:10.0.0.3", ());
+    t.add_named(ns(10), "retransmit:seq=42", ());
+    t.add_named(ns(20), "gossip", ());
+    assert_eq!(t.dump(), vec![("retransmit:seq=42".to_string(), ns(10)),
+                              ("gossip".to_string(), ns(20)),
+                              ("probe:10.0.0.3".to_string(), ns(30))]);
+
+    assert_eq!(t.advance(ns(15)).len(), 1);
+    assert_eq!(t.dump(), vec![("gossip".to_string(), ns(5)),
+                              ("probe:10.0.0.3".to_string(), ns(15))]);
+    assert_eq!(t.earliest(), Some(ns(5)));
+}
+
+#[test]
+fn timer_wakeup_coalesces_within_tolerance() {
+    let mut t = Timer::new();
+    assert_eq!(t.wakeup(ns(5)), None);
+
+    t.add(ns(10), ());
+    t.add(ns(12), ());
+    assert_eq!(t.wakeup(ns(0)), Some(ns(10)));
+    assert_eq!(t.wakeup(ns(5)), Some(ns(15)));
+    assert_eq!(t.advance(ns(15)).len(), 2);
+
+    // An exact event caps the oversleep.
+    t.add(ns(10), ());
+    t.add_exact(ns(12), "retransmit:seq=1", ());
+    assert_eq!(t.wakeup(ns(5)), Some(ns(12)));
+    t.advance(ns(12));
+    assert_eq!(t.wakeup(ns(5)), None);
+}
+
+#[test]
+fn coalesced_events_fire_within_tolerance() {
+    use clock::{Clock, ManualClock};
+    use std::time::Duration;
+
+    let tolerance = Nanos::from_millis(5);
+
+    let clock = ManualClock::new();
+    let start = clock.now();
+    let mut t = Timer::new();
+    // (deadline, exact) for a spread of events, every seventh exact.
+    for i in 0..1000u64 {
+        let deadline = Nanos::from_micros(i * 7919 % 1000000);
+        let exact = i % 7 == 0;
+        if exact {
+            t.add_exact(deadline, "", (deadline, true));
+        } else {
+            t.add(deadline, (deadline, false));
+        }
+    }
+
+    let mut fired = 0;
+    let mut last = Nanos::zero();
+    while let Some(wait) = t.wakeup(tolerance) {
+        clock.advance(Duration::from(wait));
+        let now = Nanos::from(clock.now() - start);
+        for (deadline, exact) in t.advance(now - last) {
+            assert!(now >= deadline, "fired early");
+            if exact {
+                assert_eq!(now, deadline);
+            } else {
+                assert!(now <= deadline + tolerance, "fired too late");
+            }
+            fired += 1;
+        }
+        last = now;
+    }
+    assert_eq!(fired, 1000);
+}
+
+#[test]
+fn timer_cancel_drops_labelled_events() {
+    let mut t = Timer::new();
+    t.add_named(ns(10), "probe", "probe");
+    t.add_exact(ns(20), "retransmit:seq=1", "first");
+    t.add_exact(ns(20), "retransmit:seq=1", "again");
+    t.add(ns(30), "unlabelled");
+    assert_eq!(t.len(), 4);
+
+    assert_eq!(t.cancel("retransmit:seq=1"), 2);
+    assert_eq!(t.cancel("retransmit:seq=1"), 0);
+    assert_eq!(t.cancel(""), 0);
+    assert_eq!(t.len(), 2);
+    // The exact deadline went with them.
+    assert_eq!(t.wakeup(ns(15)), Some(ns(25)));
+    assert_eq!(t.advance(ns(30)), vec!["probe", "unlabelled"]);
+    assert!(t.is_empty());
+}
+
+#[test]
+fn timer_add_after_advance() {
+    let mut t = Timer::new();
+    t.advance(ns(1000));
+    t.add(ns(1), ());
+    assert_eq!(t.earliest(), Some(ns(1)));
+}
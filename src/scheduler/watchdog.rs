@@ -0,0 +1,155 @@
+use clock::Nanos;
+use histogram::Histogram;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+use super::scheduler::SchedulerHandle;
+
+// How a Scheduler's timer thread is watched: a heartbeat is scheduled on
+// it every `interval`, and if one hasn't fired `tolerance` after it was
+// due, the thread is taken to be stalled. If `abort` is set, a stalled
+// timer thread aborts the process, for a supervisor to restart it, rather
+// than leave the node running without its timers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchdogConfig {
+    pub interval: Duration,
+    pub tolerance: Duration,
+    pub abort: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> WatchdogConfig {
+        WatchdogConfig {
+            interval: Duration::from_secs(1),
+            tolerance: Duration::from_secs(5),
+            abort: false,
+        }
+    }
+}
+
+// Watches a Scheduler from outside it. Its owner calls `check` from a
+// loop of its own, which mustn't depend on the scheduler: a stalled
+// timer thread can't be relied on to report itself.
+pub struct Watchdog {
+    config: WatchdogConfig,
+    scheduler: SchedulerHandle,
+    tx: SyncSender<()>,
+    rx: Receiver<()>,
+    // When the outstanding heartbeat is due, if one is.
+    due: Option<Instant>,
+    // Whether the outstanding heartbeat has been reported late already.
+    reported: bool,
+}
+
+impl Watchdog {
+    pub fn new(scheduler: SchedulerHandle, config: WatchdogConfig)
+            -> Watchdog {
+        let (tx, rx) = sync_channel(1);
+        Watchdog {
+            config: config,
+            scheduler: scheduler,
+            tx: tx,
+            rx: rx,
+            due: None,
+            reported: false,
+        }
+    }
+
+    pub fn config(&self) -> &WatchdogConfig {
+        &self.config
+    }
+
+    // Takes effect from the next heartbeat.
+    pub fn set_config(&mut self, config: WatchdogConfig) {
+        self.config = config;
+    }
+
+    // Schedule a heartbeat if none is outstanding, and see whether the one
+    // that is has fired. Returns how far behind the timer thread is, the
+    // first time it's found more than the tolerance late on a heartbeat;
+    // None otherwise, including while it stays stalled.
+    pub fn check(&mut self, now: Instant) -> Option<Duration> {
+        let due = match self.due {
+            Some(due) => due,
+            None => {
+                self.scheduler.delay_send(Nanos::from(self.config.interval),
+                                          self.tx.clone(), ());
+                self.due = Some(now + self.config.interval);
+                return None;
+            },
+        };
+        if self.rx.try_recv().is_ok() {
+            self.due = None;
+            self.reported = false;
+            return None;
+        }
+        if self.reported || now < due + self.config.tolerance {
+            return None;
+        }
+        self.reported = true;
+        Some(now - due)
+    }
+
+    // Whether a heartbeat is outstanding.
+    pub fn waiting(&self) -> bool {
+        self.due.is_some()
+    }
+
+    // Stop waiting on the outstanding heartbeat, for when our own clock
+    // can't be trusted to say how late it is. If it then arrives, it's
+    // taken for the next one.
+    pub fn reset(&mut self) {
+        self.due = None;
+        self.reported = false;
+    }
+
+    // How late the scheduler's events have fired; see Scheduler::lag.
+    pub fn lag(&self) -> Histogram {
+        self.scheduler.lag()
+    }
+}
+
+#[cfg(test)]
+fn test_config() -> WatchdogConfig {
+    WatchdogConfig {
+        interval: Duration::from_millis(10),
+        tolerance: Duration::from_millis(200),
+        abort: false,
+    }
+}
+
+#[test]
+fn heartbeats_keep_the_watchdog_quiet() {
+    use super::Scheduler;
+    use std::thread;
+
+    let s = Scheduler::new();
+    let mut watchdog = Watchdog::new(s.handle(), test_config());
+    let start = Instant::now();
+    assert_eq!(watchdog.check(start), None);
+    assert!(watchdog.waiting());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(watchdog.check(Instant::now()), None);
+    assert!(!watchdog.waiting());
+}
+
+#[test]
+fn a_wedged_timer_thread_is_reported_once() {
+    use super::Scheduler;
+    use std::thread;
+
+    let s = Scheduler::new();
+    let mut watchdog = Watchdog::new(s.handle(), test_config());
+    let start = Instant::now();
+    watchdog.check(start);
+    s.handle().wedge(Duration::from_millis(500));
+
+    // Our own idea of the time, so as not to race the wedge.
+    let late = start + Duration::from_millis(400);
+    assert_eq!(watchdog.check(late), Some(Duration::from_millis(390)));
+    assert_eq!(watchdog.check(late + Duration::from_millis(50)), None);
+
+    // Once it comes unstuck, the heartbeat arrives and all is well.
+    thread::sleep(Duration::from_millis(700));
+    assert_eq!(watchdog.check(Instant::now()), None);
+    assert!(!watchdog.waiting());
+}
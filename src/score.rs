@@ -0,0 +1,244 @@
+use clock::Nanos;
+use members::{NodeId, Peer};
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// How far away a peer has to be to score half what one next door would:
+// scores fall off as 1 / (1 + rtt / RTT_SCALE_MS), by the 90th
+// percentile of the peer's round trips. A peer we've no round trips for
+// yet counts as this far.
+pub const RTT_SCALE_MS: f64 = 50.0;
+
+// Likewise, how often a peer has to have been suspected in the last hour
+// to score half what a steady one would.
+pub const FLAP_SCALE: f64 = 4.0;
+
+// How much each probe counts toward a peer's loss ratio, which is a
+// moving average so that a peer that has stopped losing probes is soon
+// forgiven.
+pub const LOSS_WEIGHT: f64 = 0.2;
+
+// The least chance a weighted choice has of ignoring scores and picking
+// uniformly, so that a poor peer still gets picked now and then, and a
+// peer that has got better gets the chance to show it.
+pub const EXPLORATION: f64 = 0.1;
+
+// The suspicions remembered for any one peer. More than this an hour
+// score as this many.
+pub const FLAPS_KEPT: usize = 64;
+
+// How good a peer is to rely on, from 0 (useless) to 1 (next door,
+// never loses a probe, never suspected): its round trip time, the share
+// of probes it loses, and how many times an hour it's suspected.
+pub fn score(rtt: Option<Duration>, loss: f64, flaps_per_hour: usize)
+        -> f64 {
+    let rtt_ms = rtt.map_or(RTT_SCALE_MS, |rtt| {
+        Nanos::from(rtt).as_nanos() as f64 / 1e6
+    });
+    let near = 1.0 / (1.0 + rtt_ms / RTT_SCALE_MS);
+    let steady = 1.0 / (1.0 + flaps_per_hour as f64 / FLAP_SCALE);
+    near * (1.0 - loss.max(0.0).min(1.0)) * steady
+}
+
+// One of `candidates`, each with its score, picked at random in
+// proportion to its score, except that with chance `explore` (and if
+// none scores above 0) it's picked uniformly. None if there are none.
+pub fn choose<'a, R: Rng, T>(rng: &mut R, candidates: &'a [(T, f64)],
+                             explore: f64) -> Option<&'a T> {
+    let total = candidates.iter().map(|&(_, s)| s.max(0.0))
+        .fold(0.0, |sum, s| sum + s);
+    if total <= 0.0 || rng.gen::<f64>() < explore {
+        return rng.choose(candidates).map(|&(ref t, _)| t);
+    }
+    let mut at = rng.gen::<f64>() * total;
+    for &(ref t, s) in candidates {
+        let s = s.max(0.0);
+        if at < s {
+            return Some(t);
+        }
+        at -= s;
+    }
+    // Rounding can leave a sliver past the last.
+    candidates.last().map(|&(ref t, _)| t)
+}
+
+struct Record {
+    loss: f64,
+    // Whether the last probe is still unanswered.
+    outstanding: bool,
+    // When the peer was suspected, oldest first.
+    flaps: VecDeque<Instant>,
+}
+
+// What goes into each peer's score besides its round trips, which the
+// membership table keeps. Scores are worked out only when asked for.
+pub struct Scores {
+    records: HashMap<NodeId, Record>,
+}
+
+impl Scores {
+    pub fn new() -> Scores {
+        Scores { records: HashMap::new() }
+    }
+
+    fn record(&mut self, id: NodeId) -> &mut Record {
+        self.records.entry(id).or_insert(Record {
+            loss: 0.0,
+            outstanding: false,
+            flaps: VecDeque::new(),
+        })
+    }
+
+    // Note that we've probed `id`. If the last probe is still
+    // unanswered, it counts as lost.
+    pub fn probed(&mut self, id: NodeId) {
+        let r = self.record(id);
+        if r.outstanding {
+            r.loss += LOSS_WEIGHT * (1.0 - r.loss);
+        }
+        r.outstanding = true;
+    }
+
+    // Note that `id` has answered a probe.
+    pub fn answered(&mut self, id: NodeId) {
+        if let Some(r) = self.records.get_mut(&id) {
+            if r.outstanding {
+                r.loss -= LOSS_WEIGHT * r.loss;
+                r.outstanding = false;
+            }
+        }
+    }
+
+    // Note that we've come to suspect `id`.
+    pub fn flapped(&mut self, id: NodeId, now: Instant) {
+        let r = self.record(id);
+        if r.flaps.len() == FLAPS_KEPT {
+            r.flaps.pop_front();
+        }
+        r.flaps.push_back(now);
+    }
+
+    // The share of its probes `id` has lost, lately.
+    pub fn loss(&self, id: NodeId) -> f64 {
+        self.records.get(&id).map_or(0.0, |r| r.loss)
+    }
+
+    // How many times we've suspected `id` in the hour before `now`.
+    pub fn flaps(&self, id: NodeId, now: Instant) -> usize {
+        let hour = Duration::from_secs(3600);
+        self.records.get(&id).map_or(0, |r| {
+            r.flaps.iter().filter(|&&t| now - t < hour).count()
+        })
+    }
+
+    pub fn score(&self, peer: &Peer, now: Instant) -> f64 {
+        score(peer.rtts().p90(), self.loss(peer.id()),
+              self.flaps(peer.id(), now))
+    }
+}
+
+#[cfg(test)]
+fn seeded_rng() -> ::rand::XorShiftRng {
+    ::rand::SeedableRng::from_seed([1, 2, 3, 4])
+}
+
+#[test]
+fn scores_fall_with_distance_loss_and_flapping() {
+    let ms = Duration::from_millis;
+    assert_eq!(score(Some(ms(0)), 0.0, 0), 1.0);
+    assert_eq!(score(Some(ms(50)), 0.0, 0), 0.5);
+    assert_eq!(score(None, 0.0, 0), 0.5);
+    assert_eq!(score(Some(ms(0)), 0.25, 0), 0.75);
+    assert_eq!(score(Some(ms(0)), 0.0, 4), 0.5);
+    assert_eq!(score(Some(ms(50)), 0.5, 4), 0.125);
+    assert_eq!(score(Some(ms(0)), 2.0, 0), 0.0);
+    assert!(score(Some(ms(10)), 0.0, 0) > score(Some(ms(20)), 0.0, 0));
+}
+
+#[test]
+fn loss_is_a_moving_average_of_unanswered_probes() {
+    let mut scores = Scores::new();
+    let id = NodeId(1);
+    scores.probed(id);
+    scores.answered(id);
+    assert_eq!(scores.loss(id), 0.0);
+    // Probed again before answering: one lost.
+    scores.probed(id);
+    scores.probed(id);
+    assert_eq!(scores.loss(id), LOSS_WEIGHT);
+    // An answer nothing was waiting for counts for nothing.
+    scores.answered(id);
+    scores.answered(id);
+    assert!((scores.loss(id) - LOSS_WEIGHT * (1.0 - LOSS_WEIGHT)).abs()
+            < 1e-9);
+    for _ in 0..50 {
+        scores.probed(id);
+        scores.answered(id);
+    }
+    assert!(scores.loss(id) < 0.001);
+    assert_eq!(scores.loss(NodeId(2)), 0.0);
+}
+
+#[test]
+fn flaps_count_for_an_hour() {
+    let mut scores = Scores::new();
+    let start = Instant::now();
+    let id = NodeId(1);
+    for i in 0..FLAPS_KEPT as u64 + 10 {
+        scores.flapped(id, start + Duration::from_secs(i));
+    }
+    let now = start + Duration::from_secs(100);
+    assert_eq!(scores.flaps(id, now), FLAPS_KEPT);
+    assert_eq!(scores.flaps(id, start + Duration::from_secs(3650)), 23);
+    assert_eq!(scores.flaps(NodeId(2), now), 0);
+}
+
+#[test]
+fn choices_lean_toward_high_scores_but_explore() {
+    let mut rng = seeded_rng();
+    let candidates = vec![("good", 1.0), ("bad", 0.0)];
+    let bad = (0..10000)
+        .filter(|_| choose(&mut rng, &candidates, 0.1) == Some(&"bad"))
+        .count();
+    // Half the one in ten that explore.
+    assert!(bad > 300 && bad < 700, "{}", bad);
+
+    let none: Vec<(&str, f64)> = Vec::new();
+    assert_eq!(choose(&mut rng, &none, 0.1), None);
+    let zeroes = vec![("a", 0.0), ("b", 0.0)];
+    assert!(choose(&mut rng, &zeroes, 0.0).is_some());
+}
+
+#[test]
+fn a_lossy_peer_is_chosen_less_but_still_chosen() {
+    let mut rng = seeded_rng();
+    let mut scores = Scores::new();
+    let ids: Vec<NodeId> = (0..5).map(NodeId).collect();
+    // Probe everyone in turn; NodeId(0) answers only two in ten.
+    for _ in 0..200 {
+        for &id in &ids {
+            scores.probed(id);
+            if id != NodeId(0) || rng.gen::<f64>() < 0.2 {
+                scores.answered(id);
+            }
+        }
+    }
+    let now = Instant::now();
+    let candidates: Vec<(NodeId, f64)> = ids.iter()
+        .map(|&id| (id, score(None, scores.loss(id), scores.flaps(id, now))))
+        .collect();
+
+    let mut chosen = HashMap::new();
+    for _ in 0..10000 {
+        let id = *choose(&mut rng, &candidates, EXPLORATION).unwrap();
+        *chosen.entry(id).or_insert(0) += 1;
+    }
+    // An even share would be 2000.
+    let lossy = chosen[&NodeId(0)];
+    assert!(lossy < 1400, "{:?}", chosen);
+    assert!(lossy > 100, "{:?}", chosen);
+    for id in &ids[1..] {
+        assert!(chosen[id] > 2000, "{:?}", chosen);
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set once the process has been asked to terminate.
+static TERMINATED: AtomicBool = AtomicBool::new(false);
+
+// Have SIGTERM and SIGINT ask the process to stop rather than kill it, so
+// that a running node (see node::run) can finish its current tick and
+// return normally. The platform-specific part is in `imp` below.
+pub fn install() {
+    imp::install();
+}
+
+// Whether we've been asked to terminate since `install`.
+pub fn terminated() -> bool {
+    TERMINATED.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+mod imp {
+    extern crate libc;
+
+    use std::sync::atomic::Ordering;
+    use super::TERMINATED;
+
+    // Only async-signal-safe things may happen in here: an atomic store
+    // is, where printing or taking a lock would not be.
+    extern "C" fn terminate(_: libc::c_int) {
+        TERMINATED.store(true, Ordering::SeqCst);
+    }
+
+    pub fn install() {
+        let handler = terminate as extern "C" fn(libc::c_int);
+        unsafe {
+            libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    // The default handling, killing the process, is all there is.
+    pub fn install() {}
+}
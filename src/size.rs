@@ -0,0 +1,108 @@
+use members::NodeId;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// How big the mesh is by every node's own count, not just ours: during
+// churn our table and our peers' disagree, and how far apart they are
+// says how far from converged the mesh is.
+#[derive(Clone, Copy, Debug, PartialEq, RustcEncodable)]
+pub struct SizeEstimate {
+    // The median of the sizes counted, ours and every peer's lately
+    // reported: the lower of the middle two if there's no one middle.
+    pub size: usize,
+    // The smallest and largest of them.
+    pub min: usize,
+    pub max: usize,
+    // How many were counted, ours included.
+    pub reports: usize,
+}
+
+// The size of the mesh as each peer last reported it, by the count of
+// peers alive in the Health of its Pongs (see message::Health), with
+// itself. Reports grow stale as a peer goes unprobed or stops answering,
+// as a dead one does, and past `max_age` count for nothing.
+pub struct SizeReports {
+    reports: HashMap<NodeId, (usize, Instant)>,
+}
+
+impl SizeReports {
+    pub fn new() -> SizeReports {
+        SizeReports { reports: HashMap::new() }
+    }
+
+    // Peer `id` said at `now` that the mesh is `size` nodes, itself among
+    // them.
+    pub fn report(&mut self, id: NodeId, size: usize, now: Instant) {
+        self.reports.insert(id, (size, now));
+    }
+
+    // Peer `id` is dead, and its say with it.
+    pub fn forget(&mut self, id: NodeId) {
+        self.reports.remove(&id);
+    }
+
+    // The estimate at `now`, by `ours`, our own count of the mesh, and
+    // every report no older than `max_age`.
+    pub fn estimate(&self, ours: usize, now: Instant, max_age: Duration)
+            -> SizeEstimate {
+        let mut sizes: Vec<usize> = self.reports.values()
+            .filter(|&&(_, at)| now.duration_since(at) <= max_age)
+            .map(|&(size, _)| size)
+            .collect();
+        sizes.push(ours);
+        sizes.sort();
+        SizeEstimate {
+            size: sizes[(sizes.len() - 1) / 2],
+            min: sizes[0],
+            max: sizes[sizes.len() - 1],
+            reports: sizes.len(),
+        }
+    }
+}
+
+#[test]
+fn the_median_report_stands_against_outliers() {
+    let now = Instant::now();
+    let max_age = Duration::from_secs(30);
+    let mut r = SizeReports::new();
+    assert_eq!(r.estimate(1, now, max_age),
+               SizeEstimate { size: 1, min: 1, max: 1, reports: 1 });
+
+    for (n, &size) in [10, 10, 11, 9, 10, 500, 2].iter().enumerate() {
+        r.report(NodeId(n as u64), size, now);
+    }
+    assert_eq!(r.estimate(10, now, max_age),
+               SizeEstimate { size: 10, min: 2, max: 500, reports: 8 });
+    r.forget(NodeId(5));
+    assert_eq!(r.estimate(12, now, max_age),
+               SizeEstimate { size: 10, min: 2, max: 12, reports: 7 });
+    // A peer's latest report replaces its last.
+    r.report(NodeId(6), 10, now);
+    assert_eq!(r.estimate(12, now, max_age).min, 9);
+
+    // With no one middle, the lower.
+    let mut r = SizeReports::new();
+    r.report(NodeId(1), 4, now);
+    assert_eq!(r.estimate(6, now, max_age),
+               SizeEstimate { size: 4, min: 4, max: 6, reports: 2 });
+}
+
+#[test]
+fn reports_age_out() {
+    let start = Instant::now();
+    let secs = |n| start + Duration::from_secs(n);
+    let max_age = Duration::from_secs(30);
+    let mut r = SizeReports::new();
+    r.report(NodeId(1), 3, start);
+    r.report(NodeId(2), 3, secs(20));
+    assert_eq!(r.estimate(5, secs(30), max_age),
+               SizeEstimate { size: 3, min: 3, max: 5, reports: 3 });
+    // The first has said nothing since, as a dead peer wouldn't.
+    assert_eq!(r.estimate(5, secs(31), max_age),
+               SizeEstimate { size: 3, min: 3, max: 5, reports: 2 });
+    assert_eq!(r.estimate(5, secs(51), max_age),
+               SizeEstimate { size: 5, min: 5, max: 5, reports: 1 });
+    // Until it says something again.
+    r.report(NodeId(1), 4, secs(60));
+    assert_eq!(r.estimate(5, secs(60), max_age).reports, 2);
+}
@@ -0,0 +1,360 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+// Options applied to the UDP socket at bind time. std::net doesn't let us
+// touch a socket before it is bound (which SO_REUSEADDR requires) or set
+// buffer sizes at all, so the actual setsockopt calls live in the
+// platform-specific `imp` module below and everything else just sees this.
+pub struct SocketOpts {
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    pub reuse_addr: bool,
+    // Have the kernel set Don't Fragment on what we send, where it can.
+    pub dont_fragment: bool,
+}
+
+impl SocketOpts {
+    pub fn new() -> SocketOpts {
+        SocketOpts {
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            reuse_addr: false,
+            dont_fragment: false,
+        }
+    }
+
+    // Bind a UDP socket to the first address `addr` resolves to, applying
+    // the configured options. The kernel is free to clamp (or, on Linux,
+    // double) requested buffer sizes, so we log what we actually got.
+    pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<UdpSocket> {
+        let addr = match try!(addr.to_socket_addrs()).next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "no address to bind to")),
+        };
+
+        let socket = try!(imp::bind(&addr, self.reuse_addr));
+
+        if let Some(size) = self.recv_buffer_size {
+            try!(imp::set_recv_buffer_size(&socket, size));
+            println!("Requested {} byte receive buffer, got {}",
+                     size, try!(recv_buffer_size(&socket)));
+        }
+        if let Some(size) = self.send_buffer_size {
+            try!(imp::set_send_buffer_size(&socket, size));
+            println!("Requested {} byte send buffer, got {}",
+                     size, try!(send_buffer_size(&socket)));
+        }
+
+        if self.dont_fragment {
+            try!(imp::set_dont_fragment(&socket, &addr));
+        }
+
+        Ok(socket)
+    }
+}
+
+// The receive buffer size the kernel reports for a socket.
+pub fn recv_buffer_size(socket: &UdpSocket) -> io::Result<usize> {
+    imp::recv_buffer_size(socket)
+}
+
+// The send buffer size the kernel reports for a socket.
+pub fn send_buffer_size(socket: &UdpSocket) -> io::Result<usize> {
+    imp::send_buffer_size(socket)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Family::V4 => write!(f, "ipv4"),
+            Family::V6 => write!(f, "ipv6"),
+        }
+    }
+}
+
+// What a socket is bound to and how it's set up, as the kernel has it
+// now rather than as we asked for it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SocketInfo {
+    pub local_addr: SocketAddr,
+    pub family: Family,
+    pub recv_buffer_bytes: usize,
+    pub send_buffer_bytes: usize,
+    pub reuse_addr: bool,
+    pub read_timeout: Option<Duration>,
+}
+
+// `127.0.0.1:4000 (ipv4, 212992 byte receive buffer, 212992 byte send
+// buffer, SO_REUSEADDR off, read timeout 50ms)`.
+impl fmt::Display for SocketInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{} ({}, {} byte receive buffer, {} byte send buffer, \
+                        SO_REUSEADDR {}, ", self.local_addr, self.family,
+                    self.recv_buffer_bytes, self.send_buffer_bytes,
+                    if self.reuse_addr { "on" } else { "off" }));
+        match self.read_timeout {
+            Some(t) => write!(f, "read timeout {}ms)",
+                              t.as_secs() * 1000
+                              + t.subsec_nanos() as u64 / 1000000),
+            None => write!(f, "no read timeout)"),
+        }
+    }
+}
+
+pub fn socket_info(socket: &UdpSocket) -> io::Result<SocketInfo> {
+    let local_addr = try!(socket.local_addr());
+    Ok(SocketInfo {
+        local_addr: local_addr,
+        family: match local_addr {
+            SocketAddr::V4(..) => Family::V4,
+            SocketAddr::V6(..) => Family::V6,
+        },
+        recv_buffer_bytes: try!(recv_buffer_size(socket)),
+        send_buffer_bytes: try!(send_buffer_size(socket)),
+        reuse_addr: try!(imp::reuse_addr(socket)),
+        read_timeout: try!(socket.read_timeout()),
+    })
+}
+
+#[cfg(unix)]
+mod imp {
+    extern crate libc;
+
+    use std::io;
+    use std::mem;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+    pub fn bind(addr: &SocketAddr, reuse_addr: bool) -> io::Result<UdpSocket> {
+        let family = match *addr {
+            SocketAddr::V4(..) => libc::AF_INET,
+            SocketAddr::V6(..) => libc::AF_INET6,
+        };
+
+        let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Take ownership right away so the fd is closed on any error below.
+        let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+        if reuse_addr {
+            try!(setsockopt(fd, libc::SO_REUSEADDR, 1));
+        }
+
+        let ret = match *addr {
+            SocketAddr::V4(ref a) => {
+                let mut sin: libc::sockaddr_in = unsafe { mem::zeroed() };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = a.port().to_be();
+                sin.sin_addr = libc::in_addr {
+                    s_addr: u32::from(*a.ip()).to_be()
+                };
+                unsafe {
+                    libc::bind(fd, &sin as *const _ as *const libc::sockaddr,
+                               mem::size_of_val(&sin) as libc::socklen_t)
+                }
+            },
+            SocketAddr::V6(ref a) => {
+                let mut sin6: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = a.port().to_be();
+                sin6.sin6_flowinfo = a.flowinfo();
+                sin6.sin6_scope_id = a.scope_id();
+                sin6.sin6_addr = libc::in6_addr { s6_addr: a.ip().octets() };
+                unsafe {
+                    libc::bind(fd, &sin6 as *const _ as *const libc::sockaddr,
+                               mem::size_of_val(&sin6) as libc::socklen_t)
+                }
+            },
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+
+    pub fn set_recv_buffer_size(socket: &UdpSocket, size: usize)
+            -> io::Result<()> {
+        setsockopt(socket.as_raw_fd(), libc::SO_RCVBUF, size as libc::c_int)
+    }
+
+    pub fn set_send_buffer_size(socket: &UdpSocket, size: usize)
+            -> io::Result<()> {
+        setsockopt(socket.as_raw_fd(), libc::SO_SNDBUF, size as libc::c_int)
+    }
+
+    pub fn recv_buffer_size(socket: &UdpSocket) -> io::Result<usize> {
+        getsockopt(socket.as_raw_fd(), libc::SO_RCVBUF).map(|n| n as usize)
+    }
+
+    // Linux lets us ask for Don't Fragment outright, by having path MTU
+    // discovery done on every datagram.
+    #[cfg(target_os = "linux")]
+    pub fn set_dont_fragment(socket: &UdpSocket, addr: &SocketAddr)
+            -> io::Result<()> {
+        let (level, opt, val) = match *addr {
+            SocketAddr::V4(..) => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER,
+                                   libc::IP_PMTUDISC_DO),
+            SocketAddr::V6(..) => (libc::IPPROTO_IPV6,
+                                   libc::IPV6_MTU_DISCOVER,
+                                   libc::IPV6_PMTUDISC_DO),
+        };
+        setsockopt_at(socket.as_raw_fd(), level, opt, val)
+    }
+
+    // Elsewhere it's whatever the kernel does by default.
+    #[cfg(not(target_os = "linux"))]
+    pub fn set_dont_fragment(_: &UdpSocket, _: &SocketAddr)
+            -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn send_buffer_size(socket: &UdpSocket) -> io::Result<usize> {
+        getsockopt(socket.as_raw_fd(), libc::SO_SNDBUF).map(|n| n as usize)
+    }
+
+    pub fn reuse_addr(socket: &UdpSocket) -> io::Result<bool> {
+        getsockopt(socket.as_raw_fd(), libc::SO_REUSEADDR).map(|n| n != 0)
+    }
+
+    fn setsockopt(fd: RawFd, opt: libc::c_int, val: libc::c_int)
+            -> io::Result<()> {
+        setsockopt_at(fd, libc::SOL_SOCKET, opt, val)
+    }
+
+    fn setsockopt_at(fd: RawFd, level: libc::c_int, opt: libc::c_int,
+                     val: libc::c_int) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(fd, level, opt,
+                             &val as *const _ as *const libc::c_void,
+                             mem::size_of_val(&val) as libc::socklen_t)
+        };
+        if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+    }
+
+    fn getsockopt(fd: RawFd, opt: libc::c_int) -> io::Result<libc::c_int> {
+        let mut val: libc::c_int = 0;
+        let mut len = mem::size_of_val(&val) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(fd, libc::SOL_SOCKET, opt,
+                             &mut val as *mut _ as *mut libc::c_void,
+                             &mut len)
+        };
+        if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(val) }
+    }
+}
+
+// Elsewhere we fall back to a plain bind and don't pretend to have
+// applied anything.
+#[cfg(not(unix))]
+mod imp {
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other,
+                       "socket options not supported on this platform")
+    }
+
+    pub fn bind(addr: &SocketAddr, _reuse_addr: bool) -> io::Result<UdpSocket> {
+        UdpSocket::bind(addr)
+    }
+
+    pub fn set_recv_buffer_size(_: &UdpSocket, _: usize) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn set_send_buffer_size(_: &UdpSocket, _: usize) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn recv_buffer_size(_: &UdpSocket) -> io::Result<usize> {
+        Err(unsupported())
+    }
+
+    pub fn send_buffer_size(_: &UdpSocket) -> io::Result<usize> {
+        Err(unsupported())
+    }
+
+    pub fn reuse_addr(_: &UdpSocket) -> io::Result<bool> {
+        Err(unsupported())
+    }
+
+    pub fn set_dont_fragment(_: &UdpSocket, _: &SocketAddr)
+            -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn larger_recv_buffer_is_granted() {
+    let default = SocketOpts::new().bind("127.0.0.1:0").unwrap();
+    let default = recv_buffer_size(&default).unwrap();
+
+    let mut opts = SocketOpts::new();
+    opts.recv_buffer_size = Some(default * 2);
+    let socket = opts.bind("127.0.0.1:0").unwrap();
+    assert!(recv_buffer_size(&socket).unwrap() >= default);
+}
+
+#[test]
+fn larger_send_buffer_is_granted() {
+    let default = SocketOpts::new().bind("127.0.0.1:0").unwrap();
+    let default = send_buffer_size(&default).unwrap();
+
+    let mut opts = SocketOpts::new();
+    opts.send_buffer_size = Some(default * 2);
+    let socket = opts.bind("127.0.0.1:0").unwrap();
+    assert!(send_buffer_size(&socket).unwrap() >= default);
+}
+
+#[test]
+fn socket_info_reads_back_what_was_set() {
+    let mut opts = SocketOpts::new();
+    opts.recv_buffer_size = Some(65536);
+    opts.send_buffer_size = Some(65536);
+    opts.reuse_addr = true;
+    let socket = opts.bind("127.0.0.1:0").unwrap();
+    let timeout = Some(Duration::from_millis(250));
+    socket.set_read_timeout(timeout).unwrap();
+
+    let info = socket_info(&socket).unwrap();
+    assert_eq!(info.local_addr, socket.local_addr().unwrap());
+    assert_eq!(info.family, Family::V4);
+    // The kernel may clamp what we asked for, but Linux never gives less.
+    if cfg!(target_os = "linux") {
+        assert!(info.recv_buffer_bytes >= 65536);
+        assert!(info.send_buffer_bytes >= 65536);
+    }
+    assert!(info.reuse_addr);
+    // The kernel keeps it in its own ticks, so it may round it up a little.
+    let read_timeout = info.read_timeout.unwrap();
+    assert!(read_timeout >= timeout.unwrap());
+    assert!(read_timeout < timeout.unwrap() + Duration::from_millis(20));
+    let described = info.to_string();
+    assert!(described.contains("SO_REUSEADDR on, read timeout 25"));
+    assert!(described.ends_with("ms)"));
+
+    let plain = socket_info(&SocketOpts::new().bind("127.0.0.1:0").unwrap());
+    assert!(!plain.unwrap().reuse_addr);
+}
+
+#[test]
+fn dont_fragment_still_sends() {
+    let mut opts = SocketOpts::new();
+    opts.dont_fragment = true;
+    let socket = opts.bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    assert_eq!(socket.send_to(&[0; 1200], addr).unwrap(), 1200);
+}
@@ -0,0 +1,263 @@
+use histogram::Histogram;
+use timing::HandlingTimes;
+
+// Counters for things worth knowing about but not worth stopping for.
+#[derive(Clone, RustcEncodable)]
+pub struct Stats {
+    // Datagrams that filled the whole receive buffer and so may have been
+    // cut short by the kernel.
+    pub truncated: u64,
+
+    // Datagrams that didn't parse as a message.
+    pub malformed: u64,
+
+    // Of those, frames whose lengths didn't add up, by how: a length or
+    // count claiming more than the frame holds, one over its limit, and
+    // bytes left after the message. See message::LengthError.
+    pub length_mismatches: u64,
+    pub counts_too_large: u64,
+    pub trailing_bytes: u64,
+
+    // Frames damaged on the way, their contents not matching their
+    // checksum (see message::frame_checksum), and so dropped unread.
+    // Not counted as malformed: the sender's encoding isn't at fault.
+    pub corrupt: u64,
+
+    // Datagrams too short to hold a frame's header, empty ones among
+    // them, such as port scanners and health checks send; see
+    // message::DecodeError::Runt. Counted here alone, and never logged,
+    // there being so many.
+    pub runts: u64,
+
+    // Acks and Pongs read by fastpath rather than the general decoder.
+    pub fast_path: u64,
+
+    // Frames of a message type we don't know; see
+    // MeshEvent::UnknownMessageType.
+    pub unknown_types: u64,
+
+    // Frames of a protocol version we can't read; see
+    // message::MIN_PROTOCOL_VERSION.
+    pub unsupported_versions: u64,
+
+    // Messages an inbound or outbound hook told us to drop.
+    pub hook_dropped_inbound: u64,
+    pub hook_dropped_outbound: u64,
+
+    // Datagrams waiting in, and dropped from, each outbound band, indexed
+    // by `outbound::Band`. Refreshed every time the queue is flushed.
+    pub outbound_depth: [usize; 3],
+    pub outbound_dropped: [u64; 3],
+
+    // How often, currently, we probe a peer; see `probe::probe_interval`.
+    pub probe_interval_ms: u64,
+
+    // Probes whose target's host told us nothing was listening.
+    pub probes_refused: u64,
+
+    // Messages we sent ourselves, which never touch the socket.
+    pub self_delivered: u64,
+
+    // Times the clock jumped too far between ticks to be believed; see
+    // `protocol::limits::CLOCK_JUMP_FACTOR`.
+    pub clock_jumps: u64,
+
+    // Acked messages and joins refused for want of room; see
+    // config::Limits.
+    pub pending_rejected: u64,
+    pub joins_rejected: u64,
+
+    // Seqs the dedup cache forgot to make room for others, a peer's
+    // oldest or all of a peer's at once; see dedup::DedupCache.
+    // Refreshed every poll.
+    pub dedup_evicted: u64,
+
+    // Messages and probes held back from peers that weren't answering;
+    // see backoff::Backoff.
+    pub suppressed_sends: u64,
+
+    // Pongs and Acks dropped for answering nothing we sent, or for coming
+    // from somewhere other than where we sent it.
+    pub unsolicited: u64,
+
+    // Of those, Acks of what was sent in a session of ours long gone, or
+    // never ours: most likely from before we restarted. See
+    // message::Seq.
+    pub stale_acks: u64,
+
+    // Pings from addresses that aren't peers, dropped unanswered; see
+    // Config::ping_strangers.
+    pub stranger_pings: u64,
+
+    // Peers whose Acks showed them on a new incarnation, and so were
+    // asked to sync; see message::Responder.
+    pub syncs_requested: u64,
+
+    // Rejecteds we sent to explain a dropped frame, and got for one of
+    // ours; see Config::explain_rejects.
+    pub rejects_sent: u64,
+    pub rejects_received: u64,
+
+    // Datagrams dropped for coming too fast, and the addresses blocked
+    // for it; see throttle::Throttle.
+    pub throttled: u64,
+    pub sources_blocked: u64,
+
+    // How much of the bandwidth cap the last whole second used, in
+    // percent; the datagrams the cap has held back to a later poll; and
+    // how often it has held them back long enough to warn. See
+    // bandwidth::Bandwidth.
+    pub bandwidth_utilization: u32,
+    pub bandwidth_held: u64,
+    pub bandwidth_warnings: u64,
+
+    // Ordered messages refused, unacked, for being too far ahead of the
+    // next in their stream, and those given up on for never arriving; see
+    // ordered::Streams.
+    pub ordered_refused: u64,
+    pub ordered_skipped: u64,
+
+    // Datagrams the OS refused to send as too big for the path to their
+    // target, and acked messages refused since without trying, being no
+    // smaller; see Dispatcher::sent_too_big.
+    pub too_big: u64,
+
+    // Joins and Acks from elsewhere that claimed to come from us, by
+    // carrying our NodeId; see Dispatcher::impersonated.
+    pub impersonations: u64,
+
+    // Suspected messages sent to peers we came to suspect, those never
+    // acked, and those we got that we refuted; see
+    // Dispatcher::tell_suspected.
+    pub suspicions_sent: u64,
+    pub suspicions_unanswered: u64,
+    pub suspicions_refuted: u64,
+
+    // Peers an operator evicted; see Dispatcher::evict.
+    pub evictions: u64,
+
+    // Peers replaced by a node Joining from their address, taken for them
+    // restarted; see merge::restarted.
+    pub restarts: u64,
+
+    // Joins we handed on to another peer to answer, and those handed to
+    // us; see Dispatcher::forward_join.
+    pub joins_forwarded: u64,
+    pub joins_taken_over: u64,
+
+    // Newcomers' Joins we turned away for letting in too many at once,
+    // and Joins of ours we were told to send again later; see
+    // Dispatcher::defer_join.
+    pub joins_deferred: u64,
+    pub joins_retried: u64,
+
+    // Newcomers' Joins held for the embedder to decide on, those it
+    // turned away, and those it never decided on in time; see
+    // Dispatcher::require_admission.
+    pub joins_held: u64,
+    pub joins_refused: u64,
+    pub joins_expired: u64,
+
+    // Keepalives we sent peers we'd otherwise have sent nothing lately,
+    // and those we got; see keepalive::Keepalives.
+    pub keepalives_sent: u64,
+    pub keepalives_received: u64,
+
+    // Times every peer has been found dead; see isolation::Isolation.
+    pub isolations: u64,
+
+    // Polls that woke to nothing to read, the socket's read timeout
+    // having run out first (EAGAIN, or ETIMEDOUT where that's what the
+    // OS says).
+    pub read_timeouts: u64,
+
+    // How late the watched Scheduler's events have fired, refreshed with
+    // each heartbeat, and how often its timer thread has been found
+    // stalled; see scheduler::Watchdog.
+    pub scheduler_lag: Histogram,
+    pub scheduler_stalls: u64,
+
+    // How long each kind of message has taken to handle, and how many
+    // took too long; see timing::TimingConfig.
+    pub handling: HandlingTimes,
+    pub slow_handlings: u64,
+}
+
+// How much of each bounded structure is in use; see config::Limits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryStats {
+    pub pending: usize,
+    pub dedup_peers: usize,
+    pub dedup_entries: usize,
+    // Peers we owe acks to, and how many.
+    pub acks_owed_peers: usize,
+    pub acks_owed: usize,
+    pub joins: usize,
+    pub probe_sockets: usize,
+    // Datagrams waiting to be sent, over all bands.
+    pub outbound: usize,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            truncated: 0,
+            malformed: 0,
+            length_mismatches: 0,
+            counts_too_large: 0,
+            trailing_bytes: 0,
+            corrupt: 0,
+            runts: 0,
+            fast_path: 0,
+            unknown_types: 0,
+            unsupported_versions: 0,
+            hook_dropped_inbound: 0,
+            hook_dropped_outbound: 0,
+            outbound_depth: [0; 3],
+            outbound_dropped: [0; 3],
+            probe_interval_ms: 0,
+            probes_refused: 0,
+            self_delivered: 0,
+            clock_jumps: 0,
+            pending_rejected: 0,
+            joins_rejected: 0,
+            dedup_evicted: 0,
+            suppressed_sends: 0,
+            unsolicited: 0,
+            stale_acks: 0,
+            stranger_pings: 0,
+            syncs_requested: 0,
+            rejects_sent: 0,
+            rejects_received: 0,
+            throttled: 0,
+            sources_blocked: 0,
+            bandwidth_utilization: 0,
+            bandwidth_held: 0,
+            bandwidth_warnings: 0,
+            ordered_refused: 0,
+            ordered_skipped: 0,
+            too_big: 0,
+            impersonations: 0,
+            suspicions_sent: 0,
+            suspicions_unanswered: 0,
+            suspicions_refuted: 0,
+            evictions: 0,
+            restarts: 0,
+            joins_forwarded: 0,
+            joins_taken_over: 0,
+            joins_deferred: 0,
+            joins_retried: 0,
+            joins_held: 0,
+            joins_refused: 0,
+            joins_expired: 0,
+            keepalives_sent: 0,
+            keepalives_received: 0,
+            isolations: 0,
+            read_timeouts: 0,
+            scheduler_lag: Histogram::new(),
+            scheduler_stalls: 0,
+            handling: HandlingTimes::new(),
+            slow_handlings: 0,
+        }
+    }
+}
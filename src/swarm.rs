@@ -0,0 +1,509 @@
+use builder::{Mesh, MeshBuilder};
+use cli;
+use config::Config;
+use error::MeshError;
+use event::MeshEvent;
+use graph;
+use members::{NodeId, PeerState, Suspicion};
+use node;
+use signals;
+use std::fs::File;
+use std::io::{self, Write};
+use std::iter;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::Duration;
+
+// How long `run` waits for an event before looking for a command.
+const WAIT_MS: u64 = 100;
+
+// How long `graph` waits for the nodes to answer.
+const GRAPH_TIMEOUT_MS: u64 = 1000;
+
+// Several nodes in one process, for trying things out on one host
+// without a terminal and a port apiece: every node is on loopback, on a
+// port of the OS's choosing, and each but the first joins the first.
+pub struct Swarm {
+    meshes: Vec<Mesh>,
+    // Every node's events, and which node had them.
+    events: Receiver<(usize, MeshEvent)>,
+}
+
+impl Swarm {
+    // Start `n` nodes, otherwise as `config` says, each joining the first
+    // once it's running. If any fails to start, those already started are
+    // shut down again.
+    pub fn start(n: usize, config: &Config) -> Result<Swarm, MeshError> {
+        let (tx, rx) = mpsc::channel();
+        let mut meshes: Vec<Mesh> = Vec::new();
+        for i in 0..n {
+            let config = Config {
+                host: "127.0.0.1".to_string(),
+                port: 0,
+                .. config.clone()
+            };
+            let tx = tx.clone();
+            let mut builder = MeshBuilder::new()
+                .config(config)
+                .on_event(move |event| { let _ = tx.send((i, event)); });
+            if let Some(seed) = meshes.first() {
+                builder = builder.join(&seed.local_addr().to_string());
+            }
+            meshes.push(try!(builder.build()));
+        }
+        Ok(Swarm { meshes: meshes, events: rx })
+    }
+
+    pub fn len(&self) -> usize {
+        self.meshes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meshes.is_empty()
+    }
+
+    // Node `i`, counting from the first, which the others join.
+    pub fn mesh(&self, i: usize) -> Option<&Mesh> {
+        self.meshes.get(i)
+    }
+
+    // The next event from any node, and which node had it, waiting up to
+    // `timeout` for one.
+    pub fn next_event(&self, timeout: Duration) -> Option<(usize, MeshEvent)> {
+        self.events.recv_timeout(timeout).ok()
+    }
+
+    // Whether the first node has every other as alive, and every other
+    // the first. Nodes only learn of each other by joining or being
+    // joined, so that's as joined up as a swarm gets.
+    pub fn joined(&self) -> bool {
+        let seed = match self.meshes.first() {
+            Some(seed) => seed,
+            None => return true,
+        };
+        let alive = |mesh: &Mesh| -> Vec<_> {
+            mesh.peers().peers.iter()
+                .filter(|p| p.state() == PeerState::Alive)
+                .map(|p| p.id()).collect()
+        };
+        let seen = alive(seed);
+        self.meshes[1..].iter().all(|m| {
+            seen.contains(&m.node_id()) && alive(m).contains(&seed.node_id())
+        })
+    }
+
+    // Carry out one line typed at the swarm's console: `@N command` for
+    // node N, or just `command` for every node. The commands are `addr`,
+    // `peers`, `history`, `timers` (see Mesh::timers), `evict ID` and
+    // `probe ID`, where ID is as much of a peer's id as tells it apart
+    // (see Mesh::evict and Mesh::probe_now), `reload [OPTIONS]`, where
+    // OPTIONS are as the command line takes them, any not given going
+    // back to their defaults (see Mesh::reload), `dump [FILE]` and
+    // `graph [FILE]`. Returns what to print, each line prefixed with the
+    // node it's about, like the events, but for a dump: a JSON array of
+    // each node's Mesh::debug_dump, unprefixed, or written to FILE if
+    // given; and a graph likewise, in DOT, of who the nodes know and how
+    // well (see graph::Graph), the others drawn as those nodes see them.
+    pub fn command(&self, line: &str) -> String {
+        let line = line.trim();
+        let (nodes, command): (Vec<usize>, &str) = if line.starts_with('@') {
+            let mut words = line[1..].splitn(2, ' ');
+            let which = words.next().unwrap();
+            match which.parse::<usize>() {
+                Ok(i) if i < self.meshes.len() =>
+                    (vec![i], words.next().unwrap_or("").trim()),
+                _ => return format!("no node {}; there are {}", which,
+                                    self.meshes.len()),
+            }
+        } else {
+            ((0..self.meshes.len()).collect(), line)
+        };
+
+        let mut words = command.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+        let arg = args.first().cloned().unwrap_or("");
+        if command == "graph" {
+            let meshes: Vec<&Mesh> = nodes.iter().map(|&i| &self.meshes[i])
+                .collect();
+            let timeout = Duration::from_millis(GRAPH_TIMEOUT_MS);
+            let graph = graph::collect(&meshes, timeout);
+            return write_out(graph.to_dot(), arg, "graph");
+        }
+        let reload = if command == "reload" {
+            match options(&args) {
+                Ok(config) => Some(config),
+                Err(why) => return format!("can't reload: {}", why),
+            }
+        } else {
+            None
+        };
+        let mut out = Vec::new();
+        let mut dumps = Vec::new();
+        for i in nodes {
+            let mesh = &self.meshes[i];
+            match command {
+                "addr" =>
+                    out.push(format!("[{}] {} {}", i, mesh.node_id(),
+                                     mesh.local_addr())),
+                "peers" => for p in &mesh.peers().peers {
+                    out.push(format!("[{}] {} {:?}{}", i, p, p.state(),
+                                     suspected(p.suspicion())));
+                },
+                "history" => for c in mesh.history(None) {
+                    out.push(format!("[{}] {} {} {:?} -> {:?} ({:?}){}", i,
+                                     c.peer, c.addr, c.from, c.to, c.cause,
+                                     suspected(c.suspicion)));
+                },
+                "timers" => for (label, due_in) in
+                        mesh.timers().unwrap_or_default() {
+                    out.push(format!("[{}] {} in {}ms", i, label,
+                                     due_in.as_millis()));
+                },
+                "reload" => if let Some(ref config) = reload {
+                    out.push(match mesh.reload(config) {
+                        Ok(()) => format!("[{}] reloaded", i),
+                        Err(e) => format!("[{}] can't reload: {}", i, e),
+                    });
+                },
+                "dump" => if let Some(dump) = mesh.debug_dump() {
+                    dumps.push(dump.to_json());
+                },
+                "evict" | "probe" => {
+                    let id = match resolve(mesh, arg) {
+                        Ok(id) => id,
+                        Err(why) => {
+                            out.push(format!("[{}] {}", i, why));
+                            continue;
+                        },
+                    };
+                    let result = if command == "evict" {
+                        mesh.evict(id)
+                    } else {
+                        mesh.probe_now(id)
+                    };
+                    out.push(match result {
+                        Ok(()) => format!("[{}] {} {}", i, command, id),
+                        Err(e) => format!("[{}] can't {} {}: {}", i, command,
+                                          id, e),
+                    });
+                },
+                _ => return format!("unknown command {:?}; try addr, peers, \
+                                     history, timers, evict, probe, reload, \
+                                     dump or graph", command),
+            }
+        }
+        if command == "dump" {
+            return write_out(format!("[{}]", dumps.join(",\n")), arg, "dump");
+        }
+        out.join("\n")
+    }
+}
+
+// `text` to print, or if `path` is given, what came of writing it there
+// instead, as the `what`.
+fn write_out(text: String, path: &str, what: &str) -> String {
+    if path.is_empty() {
+        return text;
+    }
+    match File::create(path).and_then(|mut f| f.write_all(text.as_bytes())) {
+        Ok(()) => format!("wrote the {} to {}", what, path),
+        Err(e) => format!("can't write {}: {}", path, e),
+    }
+}
+
+// The settings a swarm's node runs on with command line `options`, and
+// no TARGET or subcommand.
+fn options(options: &[&str]) -> Result<Config, String> {
+    let args = try!(cli::parse(iter::once("mesh").chain(options.iter()
+                                                        .cloned()))
+        .map_err(|e| e.to_string()));
+    if args.target().is_some() || args.cmd_doctor || args.cmd_swarm
+            || args.cmd_replay {
+        return Err("give it options only".to_string());
+    }
+    Ok(Config {
+        host: "127.0.0.1".to_string(),
+        port: 0,
+        .. args.config()
+    })
+}
+
+// What a peer is, or was, suspected of and by whom, if anything, to
+// follow its state.
+fn suspected(suspicion: Option<Suspicion>) -> String {
+    match suspicion {
+        Some(s) => format!(" [{:?}, by {}]", s.reason, s.by),
+        None => String::new(),
+    }
+}
+
+// The one node `mesh` knows of, itself included, whose id starts with
+// `prefix`.
+fn resolve(mesh: &Mesh, prefix: &str) -> Result<NodeId, String> {
+    if prefix.is_empty() {
+        return Err("which peer? give its id".to_string());
+    }
+    let mut ids: Vec<NodeId> = mesh.peers().peers.iter()
+        .map(|p| p.id()).collect();
+    ids.push(mesh.node_id());
+    let matching: Vec<NodeId> = ids.into_iter()
+        .filter(|id| id.to_string().starts_with(prefix)).collect();
+    match matching.len() {
+        1 => Ok(matching[0]),
+        0 => Err(format!("no peer {}", prefix)),
+        _ => Err(format!("{} could be any of {} peers", prefix,
+                         matching.len())),
+    }
+}
+
+// Run `swarm` as `mesh swarm` does until the process is told to
+// terminate (see signals::install) or a line says `quit`: announce each
+// node as node::run would, print each node's events on `out` as they
+// happen, prefixed with which node had them, and carry out every other
+// line from `lines` as Swarm::command. Once `lines` runs out there's
+// nothing more to do but watch. Dropping the swarm afterwards shuts each
+// node down in turn.
+pub fn run<W: Write>(swarm: &Swarm, config: &Config, lines: Receiver<String>,
+                     out: &mut W) -> io::Result<()> {
+    for (i, mesh) in swarm.meshes.iter().enumerate() {
+        try!(writeln!(out, "[{}] {}", i, node::ready_line(&mesh.local_addr(),
+                                                           mesh.node_id(),
+                                                           config)));
+    }
+    try!(out.flush());
+
+    let mut lines = Some(lines);
+    while !signals::terminated() {
+        if let Some((i, event)) = swarm.next_event(
+                Duration::from_millis(WAIT_MS)) {
+            try!(writeln!(out, "[{}] {:?}", i, event));
+        }
+        let line = match lines.as_ref().map(|l| l.try_recv()) {
+            Some(Ok(line)) => line,
+            Some(Err(TryRecvError::Disconnected)) => {
+                lines = None;
+                continue;
+            },
+            Some(Err(TryRecvError::Empty)) | None => continue,
+        };
+        if line.trim() == "quit" {
+            break;
+        }
+        let reply = swarm.command(&line);
+        if !reply.is_empty() {
+            try!(writeln!(out, "{}", reply));
+        }
+        try!(out.flush());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn quick_config() -> Config {
+    let mut config = Config::default();
+    config.probe.min_interval = Duration::from_millis(50);
+    config.probe.max_interval = Duration::from_millis(200);
+    config
+}
+
+#[test]
+fn a_swarm_of_five_joins_up_and_shuts_down() {
+    use std::thread;
+    use std::time::Instant;
+
+    let swarm = Swarm::start(5, &quick_config()).unwrap();
+    assert_eq!(swarm.len(), 5);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !swarm.joined() {
+        assert!(Instant::now() < deadline, "not joined up within 5s:\n{}",
+                swarm.command("peers"));
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let seed = swarm.mesh(0).unwrap().node_id();
+    let peers = swarm.command("@3 peers");
+    assert_eq!(peers.lines().count(), 1, "{}", peers);
+    assert!(peers.starts_with("[3] "), "{}", peers);
+    assert!(peers.contains(&format!("{:08x}", seed.0 >> 32)), "{}", peers);
+    assert_eq!(swarm.command("@0 peers").lines().count(), 4);
+    assert_eq!(swarm.command("addr").lines().count(), 5);
+    let timers = swarm.command("@2 timers");
+    assert!(timers.lines().any(|l| l.starts_with("[2] probe in ")),
+            "{}", timers);
+    assert!(timers.lines().all(|l| l.starts_with("[2] ") &&
+                                   l.ends_with("ms")), "{}", timers);
+    assert!(swarm.command("@5 peers").starts_with("no node 5"));
+    assert!(swarm.command("@1 leave").starts_with("unknown command"));
+
+    let handles: Vec<_> = (0..5)
+        .map(|i| swarm.mesh(i).unwrap().shutdown_handle()).collect();
+    drop(swarm);
+    for handle in handles {
+        assert_eq!(::std::sync::Arc::strong_count(&handle), 1);
+    }
+}
+
+#[test]
+fn run_announces_each_node_and_takes_commands() {
+    let swarm = Swarm::start(2, &quick_config()).unwrap();
+    let (tx, rx) = mpsc::channel();
+    tx.send("@1 addr".to_string()).unwrap();
+    tx.send("quit".to_string()).unwrap();
+    let mut out = Vec::new();
+    run(&swarm, &Config::default(), rx, &mut out).unwrap();
+
+    let out = String::from_utf8(out).unwrap();
+    let mesh = swarm.mesh(1).unwrap();
+    assert!(out.starts_with("[0] READY addr=127.0.0.1:"), "{}", out);
+    assert!(out.contains(&format!("[1] READY addr={}", mesh.local_addr())),
+            "{}", out);
+    assert!(out.contains(&format!("[1] {} {}", mesh.node_id(),
+                                  mesh.local_addr())), "{}", out);
+}
+
+#[test]
+fn peers_can_be_evicted_and_probed_back_from_the_console() {
+    use std::thread;
+    use std::time::Instant;
+
+    let swarm = Swarm::start(2, &quick_config()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !swarm.joined() {
+        assert!(Instant::now() < deadline, "not joined up within 5s");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let id = swarm.mesh(1).unwrap().node_id();
+    let short = format!("{:08x}", id.0 >> 32);
+    let state = || swarm.mesh(0).unwrap().peers().peers[0].state();
+
+    assert_eq!(swarm.command(&format!("@0 evict {}", short)),
+               format!("[0] evict {}", id));
+    assert_eq!(state(), PeerState::Dead);
+    assert_eq!(swarm.command(&format!("@1 evict {}", short)),
+               format!("[1] can't evict {}: that's us, not a peer", id));
+    assert_eq!(swarm.command("@0 probe ffffffffffffffff1"),
+               "[0] no peer ffffffffffffffff1");
+    assert_eq!(swarm.command("@0 probe"), "[0] which peer? give its id");
+
+    assert_eq!(swarm.command(&format!("@0 probe {}", short)),
+               format!("[0] probe {}", id));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while state() != PeerState::Alive {
+        assert!(Instant::now() < deadline, "not revived within 5s");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn a_swarm_dumps_its_state_as_json() {
+    use rustc_serialize::json::Json;
+    use std::fs;
+    use std::thread;
+    use std::time::Instant;
+
+    let swarm = Swarm::start(2, &quick_config()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !swarm.joined() {
+        assert!(Instant::now() < deadline, "not joined up within 5s");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let (seed, joiner) = (swarm.mesh(0).unwrap(), swarm.mesh(1).unwrap());
+
+    let dumps = Json::from_str(&swarm.command("dump")).unwrap();
+    let dumps = dumps.as_array().unwrap();
+    assert_eq!(dumps.len(), 2);
+    assert_eq!(dumps[0]["id"].as_string(),
+               Some(&seed.node_id().to_string()[..]));
+    let peers = dumps[0]["peers"].as_array().unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0]["id"].as_string(),
+               Some(&joiner.node_id().to_string()[..]));
+    assert_eq!(peers[0]["state"].as_string(), Some("Alive"));
+    assert!(dumps[1]["history"].as_array().unwrap().len() >= 1);
+
+    let path = ::std::env::temp_dir()
+        .join(format!("mesh-dump-{}.json", joiner.local_addr().port()));
+    let path = path.to_str().unwrap();
+    assert_eq!(swarm.command(&format!("@1 dump {}", path)),
+               format!("wrote the dump to {}", path));
+    let written = fs::read_to_string(path).unwrap();
+    fs::remove_file(path).unwrap();
+    let dumps = Json::from_str(&written).unwrap();
+    assert_eq!(dumps[0]["id"].as_string(),
+               Some(&joiner.node_id().to_string()[..]));
+}
+
+#[test]
+fn a_swarm_graphs_who_knows_whom() {
+    use graph::parse_dot;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Instant;
+
+    let swarm = Swarm::start(3, &quick_config()).unwrap();
+    let ids: Vec<String> = (0..3)
+        .map(|i| swarm.mesh(i).unwrap().node_id().to_string()).collect();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !swarm.joined() {
+        assert!(Instant::now() < deadline, "not joined up within 5s");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let edge = |from: usize, to: usize| (ids[from].clone(), ids[to].clone());
+    fn sorted<T: Ord>(mut v: Vec<T>) -> Vec<T> {
+        v.sort();
+        v
+    }
+
+    let dot = swarm.command("graph");
+    assert!(dot.starts_with("digraph mesh {") && dot.ends_with("}"), "{}",
+            dot);
+    let (nodes, edges) = parse_dot(&dot);
+    assert_eq!(sorted(nodes),
+               sorted(ids.iter().map(|id| (id.clone(), false)).collect()));
+    assert_eq!(sorted(edges),
+               sorted(vec![edge(0, 1), edge(0, 2), edge(1, 0), edge(2, 0)]));
+    assert!(dot.contains(&format!("\"{}\" [label=\"{}\\n{}\\nAlive\"]",
+                                  ids[1], &ids[1][..8],
+                                  swarm.mesh(1).unwrap().local_addr())),
+            "{}", dot);
+
+    // Asking node 0 alone, the others are drawn as it sees them.
+    let (nodes, edges) = parse_dot(&swarm.command("@0 graph"));
+    assert_eq!(sorted(nodes), sorted(vec![(ids[0].clone(), false),
+                                          (ids[1].clone(), true),
+                                          (ids[2].clone(), true)]));
+    assert_eq!(sorted(edges), sorted(vec![edge(0, 1), edge(0, 2)]));
+
+    // A node that has stopped is drawn from what the rest know.
+    let stopped = swarm.mesh(2).unwrap();
+    stopped.shutdown_handle().store(true, Ordering::SeqCst);
+    while stopped.debug_dump().is_some() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let (nodes, edges) = parse_dot(&swarm.command("graph"));
+    assert_eq!(sorted(nodes), sorted(vec![(ids[0].clone(), false),
+                                          (ids[1].clone(), false),
+                                          (ids[2].clone(), true)]));
+    assert_eq!(sorted(edges), sorted(vec![edge(0, 1), edge(0, 2), edge(1, 0)]));
+}
+
+#[test]
+fn nodes_can_be_reloaded_from_the_console() {
+    let swarm = Swarm::start(2, &quick_config()).unwrap();
+    let cap = |i| swarm.mesh(i).unwrap().debug_dump().unwrap().config
+        .contains("cap: 5000");
+
+    assert_eq!(swarm.command("@1 reload --bandwidth-cap 5000"),
+               "[1] reloaded");
+    assert!(cap(1));
+    assert!(!cap(0));
+    assert_eq!(swarm.command("reload --bandwidth-cap 5000"),
+               "[0] reloaded\n[1] reloaded");
+    assert!(cap(0));
+
+    assert_eq!(swarm.command("reload 127.0.0.1:9000"),
+               "can't reload: give it options only");
+    assert!(swarm.command("reload --no-such-option")
+            .starts_with("can't reload: "));
+    assert!(cap(0) && cap(1));
+}
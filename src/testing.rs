@@ -0,0 +1,155 @@
+use chaos::seeded_rng;
+use clock::Nanos;
+use rand::{Rng, XorShiftRng};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use transport::Delays;
+
+// Aids for testing what's built on the library, only there with the
+// `testing` feature (and in the library's own tests).
+
+// How long a datagram takes over one link, one way.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Latency {
+    // Always the same.
+    Fixed(Duration),
+    // Anything from the first to the second, inclusive, each as likely.
+    Uniform(Duration, Duration),
+    // A step function of the time: each latency holds from the time
+    // given on, counted from when the model began, until the next; before
+    // the first, datagrams take no time at all. In order of time.
+    Steps(Vec<(Duration, Latency)>),
+}
+
+impl Latency {
+    fn sample<R: Rng>(&self, rng: &mut R, now: Duration) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform(low, high) => {
+                let (low, high) = (Nanos::from(low).as_nanos(),
+                                   Nanos::from(high).as_nanos());
+                Duration::from(Nanos::from_nanos(rng.gen_range(low, high + 1)))
+            },
+            Latency::Steps(ref steps) => {
+                match steps.iter().rev().find(|&&(from, _)| now >= from) {
+                    Some(&(_, ref latency)) => latency.sample(rng, now),
+                    None => Duration::from_millis(0),
+                }
+            },
+        }
+    }
+}
+
+// The latency between every pair of addresses, for SimTransports to hold
+// back what they send by (see SimTransport::delays); one model is
+// shared by every transport in a test, by way of an Rc. Each link, one
+// way, has its own Latency if it's been given one, and `default`
+// otherwise, so the way there and the way back may differ. Time is
+// made up, only passing when `advance` says, and jitter is drawn from a
+// generator that follows from `seed`: the same seed, and the same
+// datagrams sent in the same order, give the same delays every run.
+pub struct LatencyModel {
+    default: Latency,
+    links: RefCell<HashMap<(SocketAddr, SocketAddr), Latency>>,
+    elapsed: Cell<Duration>,
+    rng: RefCell<XorShiftRng>,
+}
+
+impl LatencyModel {
+    pub fn new(seed: u64, default: Latency) -> LatencyModel {
+        LatencyModel {
+            default: default,
+            links: RefCell::new(HashMap::new()),
+            elapsed: Cell::new(Duration::from_millis(0)),
+            rng: RefCell::new(seeded_rng(seed)),
+        }
+    }
+
+    // Datagrams from `from` to `to` take `latency`, whatever they take
+    // the other way.
+    pub fn link(&self, from: SocketAddr, to: SocketAddr, latency: Latency) {
+        self.links.borrow_mut().insert((from, to), latency);
+    }
+
+    // Those from `a` to `b` take `forward`, and those back `reverse`.
+    pub fn between(&self, a: SocketAddr, b: SocketAddr, forward: Latency,
+                   reverse: Latency) {
+        self.link(a, b, forward);
+        self.link(b, a, reverse);
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.elapsed.set(self.elapsed.get() + by);
+    }
+}
+
+impl Delays for LatencyModel {
+    fn delay(&self, from: &SocketAddr, to: &SocketAddr) -> Duration {
+        let links = self.links.borrow();
+        let latency = links.get(&(*from, *to)).unwrap_or(&self.default);
+        latency.sample(&mut *self.rng.borrow_mut(), self.elapsed.get())
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.elapsed.get()
+    }
+}
+
+#[cfg(test)]
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+}
+
+#[cfg(test)]
+fn ms(n: u64) -> Duration {
+    Duration::from_millis(n)
+}
+
+#[test]
+fn links_each_way_have_their_own_latency() {
+    let model = LatencyModel::new(1, Latency::Fixed(ms(5)));
+    model.between(addr(1), addr(2), Latency::Fixed(ms(10)),
+                  Latency::Fixed(ms(30)));
+    assert_eq!(model.delay(&addr(1), &addr(2)), ms(10));
+    assert_eq!(model.delay(&addr(2), &addr(1)), ms(30));
+    assert_eq!(model.delay(&addr(1), &addr(3)), ms(5));
+    model.link(addr(1), addr(2), Latency::Fixed(ms(1)));
+    assert_eq!(model.delay(&addr(1), &addr(2)), ms(1));
+    assert_eq!(model.delay(&addr(2), &addr(1)), ms(30));
+}
+
+#[test]
+fn steps_take_effect_as_time_passes() {
+    let model = LatencyModel::new(1, Latency::Steps(vec![
+        (ms(100), Latency::Fixed(ms(10))),
+        (ms(200), Latency::Uniform(ms(40), ms(60))),
+    ]));
+    let delay = || model.delay(&addr(1), &addr(2));
+    assert_eq!(delay(), ms(0));
+    model.advance(ms(100));
+    assert_eq!(delay(), ms(10));
+    model.advance(ms(150));
+    assert_eq!(model.elapsed(), ms(250));
+    for _ in 0..100 {
+        let d = delay();
+        assert!(d >= ms(40) && d <= ms(60), "{:?}", d);
+    }
+}
+
+#[test]
+fn the_same_seed_gives_the_same_jitter() {
+    let jitter = Latency::Uniform(ms(1), ms(100));
+    let delays = |seed| {
+        let model = LatencyModel::new(seed, jitter.clone());
+        (0..50).map(|_| model.delay(&addr(1), &addr(2)))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(delays(7), delays(7));
+    assert!(delays(7) != delays(8));
+    // Spread over the range rather than stuck at one end.
+    let some = delays(7);
+    assert!(some.iter().any(|&d| d < ms(50)));
+    assert!(some.iter().any(|&d| d > ms(50)));
+}
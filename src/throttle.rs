@@ -0,0 +1,220 @@
+use protocol::limits::{FLOOD_GAP, THROTTLE_SOURCES};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// How much any one address may send us. Each has a bucket of `burst`
+// datagrams, refilled at `rate` a second; datagrams past it are dropped.
+// An address that has kept on being dropped for `block_after` is blocked
+// outright for `block_for`. Either way, Pings, Pongs and Acks keep
+// getting through at `liveness_rate` a second, so that a peer we're
+// throttling isn't also judged dead for want of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThrottleConfig {
+    pub rate: u32,
+    pub burst: u32,
+    pub liveness_rate: u32,
+    pub block_after: Duration,
+    pub block_for: Duration,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> ThrottleConfig {
+        ThrottleConfig {
+            rate: 1000,
+            burst: 1000,
+            liveness_rate: 10,
+            block_after: Duration::from_secs(10),
+            block_for: Duration::from_secs(60),
+        }
+    }
+}
+
+// What to do with a datagram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Admit,
+    Drop,
+    // Drop it, and block its sender from now on: it has been flooding us
+    // for ThrottleConfig::block_after. Until `unblock` is called, only
+    // liveness traffic from it is admitted.
+    Block,
+}
+
+struct Source {
+    tokens: f64,
+    liveness: f64,
+    refilled: Instant,
+    // When the current flood started, and the last datagram dropped.
+    flooding: Option<(Instant, Instant)>,
+    blocked: bool,
+    dropped: u64,
+}
+
+// Every address's bucket.
+pub struct Throttle {
+    config: ThrottleConfig,
+    sources: HashMap<SocketAddr, Source>,
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Throttle {
+        Throttle { config: config, sources: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &ThrottleConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: ThrottleConfig) {
+        self.config = config;
+    }
+
+    // Whether to take in a datagram from `src`, which is liveness
+    // traffic (see message::is_liveness_type) if `liveness` is set.
+    pub fn check(&mut self, src: &SocketAddr, liveness: bool, now: Instant)
+            -> Verdict {
+        self.make_room(src);
+        let config = &self.config;
+        let s = self.sources.entry(*src).or_insert(Source {
+            tokens: config.burst as f64,
+            liveness: config.liveness_rate as f64,
+            refilled: now,
+            flooding: None,
+            blocked: false,
+            dropped: 0,
+        });
+        let elapsed = secs(now - s.refilled);
+        s.tokens = (s.tokens + elapsed * config.rate as f64)
+            .min(config.burst as f64);
+        s.liveness = (s.liveness + elapsed * config.liveness_rate as f64)
+            .min(config.liveness_rate as f64);
+        s.refilled = now;
+
+        if !s.blocked && s.tokens >= 1.0 {
+            s.tokens -= 1.0;
+            return Verdict::Admit;
+        }
+        if liveness && s.liveness >= 1.0 {
+            s.liveness -= 1.0;
+            return Verdict::Admit;
+        }
+        s.dropped += 1;
+        if s.blocked {
+            return Verdict::Drop;
+        }
+        let started = match s.flooding {
+            Some((started, last)) if now - last <= FLOOD_GAP => started,
+            _ => now,
+        };
+        if now - started >= config.block_after {
+            s.flooding = None;
+            s.blocked = true;
+            return Verdict::Block;
+        }
+        s.flooding = Some((started, now));
+        Verdict::Drop
+    }
+
+    // Lift the block on `src`, if any, with a full bucket.
+    pub fn unblock(&mut self, src: &SocketAddr) {
+        if let Some(s) = self.sources.get_mut(src) {
+            s.blocked = false;
+            s.tokens = self.config.burst as f64;
+        }
+    }
+
+    pub fn blocked(&self, src: &SocketAddr) -> bool {
+        self.sources.get(src).map_or(false, |s| s.blocked)
+    }
+
+    // How many datagrams from `src` we've dropped, for as long as we've
+    // kept its bucket.
+    pub fn dropped(&self, src: &SocketAddr) -> u64 {
+        self.sources.get(src).map_or(0, |s| s.dropped)
+    }
+
+    fn make_room(&mut self, src: &SocketAddr) {
+        if self.sources.contains_key(src)
+                || self.sources.len() < THROTTLE_SOURCES {
+            return;
+        }
+        let oldest = self.sources.iter().filter(|&(_, s)| !s.blocked)
+            .min_by_key(|&(_, s)| s.refilled).map(|(addr, _)| *addr);
+        if let Some(oldest) = oldest {
+            self.sources.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+fn addr() -> SocketAddr {
+    "127.0.0.1:9000".parse().unwrap()
+}
+
+#[cfg(test)]
+fn test_config() -> ThrottleConfig {
+    ThrottleConfig {
+        rate: 10,
+        burst: 5,
+        liveness_rate: 2,
+        block_after: Duration::from_secs(2),
+        block_for: Duration::from_secs(10),
+    }
+}
+
+#[test]
+fn a_burst_then_the_rate() {
+    let mut throttle = Throttle::new(test_config());
+    let now = Instant::now();
+    let verdicts: Vec<Verdict> = (0..7)
+        .map(|_| throttle.check(&addr(), false, now)).collect();
+    assert_eq!(verdicts.iter().filter(|&&v| v == Verdict::Admit).count(), 5);
+    assert_eq!(throttle.dropped(&addr()), 2);
+
+    // A tenth of a second buys one more.
+    let later = now + Duration::from_millis(100);
+    assert_eq!(throttle.check(&addr(), false, later), Verdict::Admit);
+    assert_eq!(throttle.check(&addr(), false, later), Verdict::Drop);
+    // Liveness traffic still has its own trickle.
+    assert_eq!(throttle.check(&addr(), true, later), Verdict::Admit);
+    assert_eq!(throttle.check(&addr(), true, later), Verdict::Admit);
+    assert_eq!(throttle.check(&addr(), true, later), Verdict::Drop);
+}
+
+#[test]
+fn sustained_floods_are_blocked_and_pauses_forgiven() {
+    let mut throttle = Throttle::new(test_config());
+    let start = Instant::now();
+    // 20 a second, twice the rate, for a while.
+    let mut blocked_at = None;
+    for i in 0..100 {
+        let now = start + Duration::from_millis(50 * i);
+        if throttle.check(&addr(), false, now) == Verdict::Block {
+            blocked_at = Some(now - start);
+            break;
+        }
+    }
+    let blocked_at = blocked_at.expect("never blocked");
+    assert!(blocked_at >= Duration::from_secs(2)
+            && blocked_at < Duration::from_secs(3), "{:?}", blocked_at);
+    assert!(throttle.blocked(&addr()));
+    let now = start + blocked_at + Duration::from_secs(5);
+    assert_eq!(throttle.check(&addr(), false, now), Verdict::Drop);
+
+    throttle.unblock(&addr());
+    assert_eq!(throttle.check(&addr(), false, now), Verdict::Admit);
+
+    // Dropping now and then, with pauses, is no flood.
+    let mut other = Throttle::new(test_config());
+    for i in 0..20 {
+        let now = start + Duration::from_millis(1500 * i);
+        for _ in 0..10 {
+            assert!(other.check(&addr(), false, now) != Verdict::Block);
+        }
+    }
+}
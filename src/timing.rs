@@ -0,0 +1,98 @@
+use histogram::Histogram;
+use message::MessageKind;
+use rustc_serialize::{Encodable, Encoder};
+use std::time::Duration;
+
+// Whether to time how long each kind of message takes us to handle, from
+// recv_from returning to its handler being done (see Dispatcher::handle),
+// and what's too long. Without `enabled` nothing is timed: handling a
+// message doesn't so much as read the clock. A message taking `slow` or
+// longer is counted, and logged with a warning, if `slow` is given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimingConfig {
+    pub enabled: bool,
+    pub slow: Option<Duration>,
+}
+
+impl Default for TimingConfig {
+    fn default() -> TimingConfig {
+        TimingConfig {
+            enabled: true,
+            slow: Some(Duration::from_millis(50)),
+        }
+    }
+}
+
+// How long handling each kind of message has taken, a Histogram for each
+// kind that has been timed, in the order they were first seen.
+#[derive(Clone, Debug)]
+pub struct HandlingTimes {
+    kinds: Vec<(MessageKind, Histogram)>,
+}
+
+impl HandlingTimes {
+    pub fn new() -> HandlingTimes {
+        HandlingTimes { kinds: Vec::new() }
+    }
+
+    pub fn record(&mut self, kind: MessageKind, took: Duration) {
+        if let Some(&mut (_, ref mut times)) = self.kinds.iter_mut()
+                .find(|&&mut (k, _)| k == kind) {
+            times.record(took);
+            return;
+        }
+        let mut times = Histogram::new();
+        times.record(took);
+        self.kinds.push((kind, times));
+    }
+
+    // Those of `kind`, if any have been timed.
+    pub fn get(&self, kind: MessageKind) -> Option<&Histogram> {
+        self.kinds.iter().find(|&&(k, _)| k == kind).map(|&(_, ref h)| h)
+    }
+
+    // Every kind's together.
+    pub fn total(&self) -> Histogram {
+        let mut total = Histogram::new();
+        for &(_, ref times) in self.kinds.iter() {
+            total.merge(times);
+        }
+        total
+    }
+}
+
+// As an object from each kind's name to its Histogram.
+impl Encodable for HandlingTimes {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_map(self.kinds.len(), |s| {
+            for (i, &(kind, ref times)) in self.kinds.iter().enumerate() {
+                try!(s.emit_map_elt_key(i, |s| {
+                    format!("{:?}", kind).encode(s)
+                }));
+                try!(s.emit_map_elt_val(i, |s| times.encode(s)));
+            }
+            Ok(())
+        })
+    }
+}
+
+#[test]
+fn each_kind_has_its_own_times() {
+    let ms = Duration::from_millis;
+    let mut times = HandlingTimes::new();
+    times.record(MessageKind::Ping, ms(1));
+    times.record(MessageKind::Join, ms(30));
+    times.record(MessageKind::Ping, ms(1));
+    assert_eq!(times.get(MessageKind::Ping).unwrap().len(), 2);
+    assert_eq!(times.get(MessageKind::Join).unwrap().len(), 1);
+    assert!(times.get(MessageKind::Data).is_none());
+    let total = times.total();
+    assert_eq!(total.len(), 3);
+    // 30ms is in the bucket from 28.8ms to 32ms.
+    assert_eq!(total.p99(), Some(ms(32)));
+
+    let json = ::rustc_serialize::json::encode(&times).unwrap();
+    assert_eq!(json, "{\"Ping\":{\"len\":2,\"p50_us\":1100,\"p90_us\":1100,\
+                      \"p99_us\":1100},\"Join\":{\"len\":1,\"p50_us\":32000,\
+                      \"p90_us\":32000,\"p99_us\":32000}}");
+}
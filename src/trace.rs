@@ -0,0 +1,373 @@
+use dump;
+use members::NodeId;
+use sockopts::SocketInfo;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use transport::{Connected, Transport};
+
+// A record of the datagrams a node sent and received, for playing back
+// later (see replay), as `mesh --dump-packets FILE` writes it. It's text,
+// a line at a time, so that it can be read, cut down and compared by
+// hand. The first line says what it is and whose it is:
+//
+//     mesh-packets 1 addr=127.0.0.1:7000 id=00000000000000aa seed=42
+//
+// the format's version, where the node was bound, its id, and the seed
+// its choices were drawn from (see Dispatcher::with_seed). Then a line
+// for each datagram, in the order the node saw them:
+//
+//     1500 in 127.0.0.1:9001 89000000000100000000000000000000000109
+//
+// the microseconds since the first line was written, `in` or `out`, who
+// it came from or went to, and its bytes in hex. Blank lines and those
+// starting with # are skipped, so that notes can be added.
+
+// The format's version. Dumps of any other are refused.
+pub const VERSION: u32 = 1;
+
+const MAGIC: &'static str = "mesh-packets";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub addr: SocketAddr,
+    pub id: NodeId,
+    pub seed: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub at: Duration,
+    pub direction: Direction,
+    pub addr: SocketAddr,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trace {
+    pub header: Header,
+    pub records: Vec<Record>,
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} addr={} id={} seed={}", MAGIC, VERSION, self.addr,
+               self.id, self.seed)
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+        };
+        let micros = self.at.as_secs() * 1000000
+            + self.at.subsec_nanos() as u64 / 1000;
+        write!(f, "{} {} {} {}", micros, direction, self.addr,
+               dump::hex(&self.bytes))
+    }
+}
+
+impl Header {
+    pub fn parse(line: &str) -> Result<Header, String> {
+        let mut fields = line.split(' ');
+        if fields.next() != Some(MAGIC) {
+            return Err(format!("not a packet dump: {:?}", line));
+        }
+        match fields.next().and_then(|v| v.parse::<u32>().ok()) {
+            Some(VERSION) => (),
+            Some(v) => return Err(format!("packet dump version {} isn't {}, \
+                                           the one we read", v, VERSION)),
+            None => return Err(format!("packet dump {:?} has no version",
+                                       line)),
+        }
+        let (mut addr, mut id, mut seed) = (None, None, None);
+        for field in fields {
+            let mut kv = field.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("addr"), Some(v)) => addr = v.parse().ok(),
+                (Some("id"), Some(v)) =>
+                    id = u64::from_str_radix(v, 16).ok().map(NodeId),
+                (Some("seed"), Some(v)) => seed = v.parse().ok(),
+                _ => return Err(format!("unknown field {:?}", field)),
+            }
+        }
+        match (addr, id, seed) {
+            (Some(addr), Some(id), Some(seed)) =>
+                Ok(Header { addr: addr, id: id, seed: seed }),
+            _ => Err(format!("packet dump {:?} needs an addr, id and seed",
+                             line)),
+        }
+    }
+}
+
+impl Record {
+    pub fn parse(line: &str) -> Result<Record, String> {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() != 4 {
+            return Err(format!("expected `MICROS in|out ADDR HEX`, got {:?}",
+                               line));
+        }
+        let micros = try!(fields[0].parse::<u64>().map_err(|_| {
+            format!("{:?} isn't a time in microseconds", fields[0])
+        }));
+        let direction = match fields[1] {
+            "in" => Direction::In,
+            "out" => Direction::Out,
+            other => return Err(format!("{:?} isn't in or out", other)),
+        };
+        let addr = try!(fields[2].parse().map_err(|_| {
+            format!("{:?} isn't an address", fields[2])
+        }));
+        let bytes = try!(unhex(fields[3]).ok_or_else(|| {
+            format!("{:?} isn't hex", fields[3])
+        }));
+        Ok(Record {
+            at: Duration::new(micros / 1000000,
+                              (micros % 1000000) as u32 * 1000),
+            direction: direction,
+            addr: addr,
+            bytes: bytes,
+        })
+    }
+}
+
+impl Trace {
+    // Errors say which line is at fault.
+    pub fn parse(text: &str) -> Result<Trace, String> {
+        let mut header = None;
+        let mut records = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at_line = |e| format!("line {}: {}", n + 1, e);
+            if header.is_none() {
+                header = Some(try!(Header::parse(line).map_err(at_line)));
+            } else {
+                records.push(try!(Record::parse(line).map_err(at_line)));
+            }
+        }
+        match header {
+            Some(header) => Ok(Trace { header: header, records: records }),
+            None => Err("empty packet dump".to_string()),
+        }
+    }
+
+    pub fn read(path: &str) -> io::Result<Trace> {
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        Trace::parse(&text).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("{}: {}", path, e))
+        })
+    }
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+// A transport that writes down what goes through `inner` once it's told
+// where to (see `record_to`), as a packet dump. Sockets from `connect`
+// aren't recorded. If writing fails, it warns and stops recording, and
+// the node carries on without.
+pub struct Recorder<T> {
+    inner: T,
+    out: RefCell<Option<Box<Write>>>,
+    // When the header was written.
+    started: Cell<Instant>,
+}
+
+impl<T: Transport> Recorder<T> {
+    // Wrap `inner`, recording nothing yet.
+    pub fn new(inner: T) -> Recorder<T> {
+        Recorder {
+            inner: inner,
+            out: RefCell::new(None),
+            started: Cell::new(Instant::now()),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    // Start writing a dump to `out`, for node `id` drawing from `seed`.
+    pub fn record_to(&self, mut out: Box<Write>, id: NodeId, seed: u64)
+            -> io::Result<()> {
+        let header = Header {
+            addr: try!(self.inner.local_addr()),
+            id: id,
+            seed: seed,
+        };
+        try!(writeln!(out, "{}", header));
+        self.started.set(Instant::now());
+        *self.out.borrow_mut() = Some(out);
+        Ok(())
+    }
+
+    fn record(&self, direction: Direction, addr: &SocketAddr, bytes: &[u8]) {
+        let mut out = self.out.borrow_mut();
+        let result = match *out {
+            Some(ref mut out) => writeln!(out, "{}", Record {
+                at: Instant::now() - self.started.get(),
+                direction: direction,
+                addr: *addr,
+                bytes: bytes.to_vec(),
+            }),
+            None => return,
+        };
+        if let Err(e) = result {
+            println!("WARNING: stopped dumping packets: {}", e);
+            *out = None;
+        }
+    }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        let amt = try!(self.inner.send_to(buf, addr));
+        self.record(Direction::Out, addr, buf);
+        Ok(amt)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (amt, from) = try!(self.inner.recv_from(buf));
+        self.record(Direction::In, &from, &buf[..amt]);
+        Ok((amt, from))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Connected>> {
+        self.inner.connect(addr)
+    }
+
+    fn socket_info(&self) -> io::Result<SocketInfo> {
+        self.inner.socket_info()
+    }
+}
+
+#[test]
+fn dumps_print_and_parse_back() {
+    let trace = Trace {
+        header: Header {
+            addr: "127.0.0.1:7000".parse().unwrap(),
+            id: NodeId(0xaa),
+            seed: 42,
+        },
+        records: vec![
+            Record {
+                at: Duration::from_millis(1500),
+                direction: Direction::In,
+                addr: "127.0.0.1:9001".parse().unwrap(),
+                bytes: vec![0x89, 0, 0xff],
+            },
+            Record {
+                at: Duration::new(2, 1000),
+                direction: Direction::Out,
+                addr: "[::1]:9002".parse().unwrap(),
+                bytes: vec![],
+            },
+        ],
+    };
+    let text = format!("{}\n# a note\n\n{}\n{}\n", trace.header,
+                       trace.records[0], trace.records[1]);
+    assert_eq!(text, "mesh-packets 1 addr=127.0.0.1:7000 \
+                      id=00000000000000aa seed=42\n\
+                      # a note\n\
+                      \n\
+                      1500000 in 127.0.0.1:9001 8900ff\n\
+                      2000001 out [::1]:9002 \n");
+    assert_eq!(Trace::parse(&text), Ok(trace));
+}
+
+#[test]
+fn bad_dumps_are_refused() {
+    let header = "mesh-packets 1 addr=127.0.0.1:7000 id=aa seed=1";
+    assert!(Trace::parse(header).is_ok());
+    for text in &["",
+                  "mesh-packets 2 addr=127.0.0.1:7000 id=aa seed=1",
+                  "mesh-packets 1 addr=127.0.0.1:7000 id=aa",
+                  "mesh-packets 1 addr=127.0.0.1:7000 id=aa seed=1 x=2",
+                  "mesh-pockets 1 addr=127.0.0.1:7000 id=aa seed=1"] {
+        assert!(Trace::parse(text).is_err(), "{:?} was accepted", text);
+    }
+    for record in &["1500 in 127.0.0.1:9001", "later in 127.0.0.1:9001 89",
+                    "1500 sideways 127.0.0.1:9001 89", "1500 in nowhere 89",
+                    "1500 in 127.0.0.1:9001 890", "1500 in 127.0.0.1:9001 zz",
+                    "1500 in 127.0.0.1:9001 éé"] {
+        let text = format!("{}\n{}", header, record);
+        assert!(Trace::parse(&text).is_err(), "{:?} was accepted", record);
+    }
+    assert_eq!(Trace::parse(&format!("{}\n\nbad", header)).unwrap_err(),
+               "line 3: expected `MICROS in|out ADDR HEX`, got \"bad\"");
+}
+
+#[test]
+fn recorder_dumps_what_it_sends_and_receives() {
+    use std::rc::Rc;
+    use transport::SimTransport;
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let peer: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    let recorder = Recorder::new(SimTransport::new());
+    // Before recording starts, nothing is written down.
+    recorder.send_to(&[1], &peer).unwrap();
+
+    let written = Rc::new(RefCell::new(Vec::new()));
+    recorder.record_to(Box::new(Shared(written.clone())), NodeId(0xaa), 7)
+        .unwrap();
+    recorder.inner().deliver(vec![2, 3], peer);
+    let mut buf = [0; 16];
+    assert_eq!(recorder.recv_from(&mut buf).unwrap(), (2, peer));
+    assert!(recorder.recv_from(&mut buf).is_err());
+    recorder.send_to(&[4], &peer).unwrap();
+    assert_eq!(recorder.inner().sent.borrow().len(), 2);
+
+    let trace = Trace::parse(&String::from_utf8(written.borrow().clone())
+                             .unwrap()).unwrap();
+    assert_eq!(trace.header, Header {
+        addr: recorder.local_addr().unwrap(),
+        id: NodeId(0xaa),
+        seed: 7,
+    });
+    let records: Vec<(Direction, Vec<u8>)> = trace.records.iter()
+        .map(|r| (r.direction, r.bytes.clone())).collect();
+    assert_eq!(records, vec![(Direction::In, vec![2, 3]),
+                             (Direction::Out, vec![4])]);
+    assert!(trace.records.iter().all(|r| r.addr == peer));
+}
@@ -0,0 +1,307 @@
+use sockopts::{self, SocketInfo};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+use std::time::Duration;
+
+// How long datagrams take between addresses, for a SimTransport to hold
+// back what it sends until they'd have arrived; see SimTransport::delays
+// and testing::LatencyModel.
+pub trait Delays {
+    // How long one sent from `from` to `to` now takes to get there.
+    fn delay(&self, from: &SocketAddr, to: &SocketAddr) -> Duration;
+    // How long it's been, in the made-up time the delays are counted in.
+    fn elapsed(&self) -> Duration;
+}
+
+// The datagram operations the dispatcher needs from its socket. The real
+// thing is a UdpSocket; tests substitute a SimTransport.
+pub trait Transport {
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize>;
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    // A new socket of our own, connected to `addr`. The OS may then report
+    // ICMP errors from `addr` to us; see probe::interpret.
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Connected>>;
+    // What the socket underneath is bound to and how it's set up.
+    fn socket_info(&self) -> io::Result<SocketInfo>;
+}
+
+// A socket that only talks to one address. recv never blocks, failing
+// with WouldBlock when nothing has arrived.
+pub trait Connected {
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+// Whether a send failed for being larger than the OS will send to its
+// target in one datagram: EMSGSIZE, as a socket with Don't Fragment set
+// reports for anything over the path MTU it knows of (see
+// sockopts::SocketOpts::dont_fragment), and any socket for anything over
+// 64k.
+pub fn too_big(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(imp::EMSGSIZE)
+}
+
+#[cfg(unix)]
+mod imp {
+    extern crate libc;
+
+    pub const EMSGSIZE: i32 = libc::EMSGSIZE;
+}
+
+#[cfg(not(unix))]
+mod imp {
+    // WSAEMSGSIZE.
+    pub const EMSGSIZE: i32 = 10040;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Connected>> {
+        let local = try!(UdpSocket::local_addr(self));
+        let socket = try!(UdpSocket::bind((local.ip(), 0)));
+        try!(socket.connect(addr));
+        try!(socket.set_nonblocking(true));
+        Ok(Box::new(socket))
+    }
+
+    fn socket_info(&self) -> io::Result<SocketInfo> {
+        sockopts::socket_info(self)
+    }
+}
+
+impl Connected for UdpSocket {
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        UdpSocket::send(self, buf)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        UdpSocket::recv(self, buf)
+    }
+}
+
+// An in-memory transport. Datagrams pushed with `deliver` are handed out
+// by recv_from in order; when none are queued, recv_from fails with
+// WouldBlock immediately, just as a real socket would once its read
+// timeout expired. Everything sent is recorded for inspection, unless
+// `blocked` is set, in which case send_to fails with WouldBlock as if the
+// socket's buffer were full. Sending to an address in `unreachable`
+// fails as if there were no route there, and isn't recorded. Datagrams
+// larger than `max_datagram`, if set, seem to be sent but are lost on
+// the way, as if too big for the path, and aren't recorded either; those
+// larger than `path_mtu`, if set, fail with EMSGSIZE, as if Don't
+// Fragment were set and the OS knew the path. It claims to be bound to
+// `addr`.
+//
+// Connected sockets record what they send alongside everything else, and
+// never receive anything: those connected to an address in `refusing`
+// fail with ConnectionRefused, as if an ICMP port unreachable had come
+// back.
+//
+// With `delays` set, what it sends is held back as long as they say it
+// takes to get from `addr` to its target, and recorded only after that,
+// from the next send or receive (or `release`) once the time has come;
+// so datagrams on a link that varies may overtake one another. Connected
+// sockets' datagrams are never held back.
+pub struct SimTransport {
+    pub addr: SocketAddr,
+    inbox: RefCell<VecDeque<(Vec<u8>, SocketAddr)>>,
+    pub sent: Rc<RefCell<Vec<(Vec<u8>, SocketAddr)>>>,
+    pub blocked: Cell<bool>,
+    pub refusing: RefCell<HashSet<SocketAddr>>,
+    pub unreachable: RefCell<HashSet<SocketAddr>>,
+    pub max_datagram: Cell<Option<usize>>,
+    pub path_mtu: Cell<Option<usize>>,
+    pub delays: RefCell<Option<Rc<Delays>>>,
+    // Sent datagrams still on their way, with when they arrive, in the
+    // order they were sent.
+    held: RefCell<Vec<(Duration, Vec<u8>, SocketAddr)>>,
+}
+
+impl SimTransport {
+    pub fn new() -> SimTransport {
+        SimTransport {
+            addr: "127.0.0.1:7000".parse().unwrap(),
+            inbox: RefCell::new(VecDeque::new()),
+            sent: Rc::new(RefCell::new(Vec::new())),
+            blocked: Cell::new(false),
+            refusing: RefCell::new(HashSet::new()),
+            unreachable: RefCell::new(HashSet::new()),
+            max_datagram: Cell::new(None),
+            path_mtu: Cell::new(None),
+            delays: RefCell::new(None),
+            held: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn deliver(&self, buf: Vec<u8>, from: SocketAddr) {
+        self.inbox.borrow_mut().push_back((buf, from));
+    }
+
+    // How many datagrams delivered are still to be received.
+    pub fn queued(&self) -> usize {
+        self.inbox.borrow().len()
+    }
+
+    // How many datagrams sent are still on their way.
+    pub fn in_flight(&self) -> usize {
+        self.held.borrow().len()
+    }
+
+    // Record as sent whatever has been held back long enough, those due
+    // first going first.
+    pub fn release(&self) {
+        let now = match *self.delays.borrow() {
+            Some(ref delays) => delays.elapsed(),
+            None => return,
+        };
+        let mut held = self.held.borrow_mut();
+        let (mut due, waiting): (Vec<_>, Vec<_>) = held.drain(..)
+            .partition(|&(at, _, _)| at <= now);
+        *held = waiting;
+        due.sort_by_key(|&(at, _, _)| at);
+        self.sent.borrow_mut().extend(due.into_iter()
+                                      .map(|(_, bytes, to)| (bytes, to)));
+    }
+}
+
+impl Transport for SimTransport {
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        if self.blocked.get() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                      "send buffer full"));
+        }
+        if self.unreachable.borrow().contains(addr) {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "no route to host"));
+        }
+        if self.path_mtu.get().map_or(false, |mtu| buf.len() > mtu) {
+            return Err(io::Error::from_raw_os_error(imp::EMSGSIZE));
+        }
+        if self.max_datagram.get().map_or(false, |max| buf.len() > max) {
+            return Ok(buf.len());
+        }
+        self.release();
+        let delay = self.delays.borrow().as_ref()
+            .map(|delays| (delays.elapsed(), delays.delay(&self.addr, addr)));
+        match delay {
+            Some((now, delay)) if delay > Duration::from_millis(0) =>
+                self.held.borrow_mut().push((now + delay, buf.to_vec(),
+                                             *addr)),
+            _ => self.sent.borrow_mut().push((buf.to_vec(), *addr)),
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.release();
+        match self.inbox.borrow_mut().pop_front() {
+            Some((packet, from)) => {
+                let amt = cmp::min(buf.len(), packet.len());
+                buf[..amt].clone_from_slice(&packet[..amt]);
+                Ok((amt, from))
+            },
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                       "no datagrams queued")),
+        }
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Box<Connected>> {
+        Ok(Box::new(SimConnected {
+            addr: *addr,
+            sent: self.sent.clone(),
+            refused: self.refusing.borrow().contains(addr),
+        }))
+    }
+
+    fn socket_info(&self) -> io::Result<SocketInfo> {
+        Err(io::Error::new(io::ErrorKind::Other, "not a real socket"))
+    }
+}
+
+struct SimConnected {
+    addr: SocketAddr,
+    sent: Rc<RefCell<Vec<(Vec<u8>, SocketAddr)>>>,
+    refused: bool,
+}
+
+impl Connected for SimConnected {
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.borrow_mut().push((buf.to_vec(), self.addr));
+        Ok(buf.len())
+    }
+
+    fn recv(&self, _: &mut [u8]) -> io::Result<usize> {
+        if self.refused {
+            Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                               "port unreachable"))
+        } else {
+            Err(io::Error::new(io::ErrorKind::WouldBlock,
+                               "no datagrams queued"))
+        }
+    }
+}
+
+#[test]
+fn sends_over_the_path_mtu_fail_as_too_big() {
+    let t = SimTransport::new();
+    let to = "127.0.0.1:9000".parse().unwrap();
+    t.path_mtu.set(Some(100));
+    assert!(t.send_to(&[0; 100], &to).is_ok());
+    assert!(too_big(&t.send_to(&[0; 101], &to).unwrap_err()));
+    assert!(!too_big(&io::Error::new(io::ErrorKind::Other, "no route")));
+    assert_eq!(t.sent.borrow().len(), 1);
+}
+
+#[test]
+fn delayed_sends_are_recorded_once_they_arrive() {
+    use testing::{Latency, LatencyModel};
+
+    let ms = Duration::from_millis;
+    let (near, far) = ("127.0.0.1:9001".parse().unwrap(),
+                       "127.0.0.1:9002".parse().unwrap());
+    let model = Rc::new(LatencyModel::new(1, Latency::Fixed(ms(0))));
+    let t = SimTransport::new();
+    model.link(t.addr, far, Latency::Fixed(ms(20)));
+    *t.delays.borrow_mut() = Some(model.clone());
+    t.send_to(&[1], &far).unwrap();
+    t.send_to(&[2], &near).unwrap();
+    assert_eq!(*t.sent.borrow(), vec![(vec![2], near)]);
+    assert_eq!(t.in_flight(), 1);
+
+    model.advance(ms(19));
+    assert!(t.recv_from(&mut [0; 16]).is_err());
+    assert_eq!(t.in_flight(), 1);
+    model.advance(ms(1));
+    t.release();
+    assert_eq!(*t.sent.borrow(), vec![(vec![2], near), (vec![1], far)]);
+    assert_eq!(t.in_flight(), 0);
+}
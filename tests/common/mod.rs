@@ -0,0 +1,154 @@
+// Running the compiled binary as child processes on loopback, and
+// learning everything from their output, as a supervisor would: READY
+// lines for where they are, STATUS lines for who they know. Shared by the
+// tests that do this; not every one uses all of it.
+#![allow(dead_code)]
+
+extern crate libc;
+
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Generous, so that a slow or loaded machine doesn't fail a test.
+pub const STARTUP: Duration = Duration::from_secs(30);
+pub const CONVERGE: Duration = Duration::from_secs(60);
+pub const EXIT: Duration = Duration::from_secs(30);
+
+// Our children, killed if they're still running when we're done with
+// them, however that happens; a panicking test mustn't leave nodes behind.
+pub struct Nodes {
+    children: Vec<Child>,
+    // Each line any of them prints, on stdout or stderr, with which one
+    // printed it.
+    tx: Sender<(usize, String)>,
+    pub lines: Receiver<(usize, String)>,
+    // Each node's address, from its READY line.
+    pub addrs: Vec<String>,
+}
+
+impl Nodes {
+    pub fn new() -> Nodes {
+        let (tx, lines) = mpsc::channel();
+        Nodes { children: Vec::new(), tx: tx, lines: lines, addrs: Vec::new() }
+    }
+
+    // Start another node with `args`, joining `target` if given, and wait
+    // until it says it's ready.
+    pub fn spawn(&mut self, target: Option<&str>, args: &[&str]) {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_mesh"));
+        command.args(&["--port", "0", "--status-interval", "200"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(target) = target {
+            command.arg(target);
+        }
+        let mut child = command.spawn().unwrap();
+        let n = self.children.len();
+        forward(n, child.stdout.take().unwrap(), self.tx.clone());
+        forward(n, child.stderr.take().unwrap(), self.tx.clone());
+        self.children.push(child);
+
+        // Other nodes' lines go by meanwhile, but they'll say it all again
+        // in their next STATUS.
+        let addr = wait_for(&self.lines, STARTUP, |from, line| {
+            if from != n || !line.starts_with("READY ") {
+                return None;
+            }
+            line.split(' ').find(|f| f.starts_with("addr="))
+                .map(|f| f["addr=".len()..].to_string())
+        });
+        self.addrs.push(addr.expect("node never said it was READY"));
+    }
+
+    pub fn terminate(&self, n: usize) {
+        let pid = self.children[n].id() as libc::pid_t;
+        assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+    }
+
+    // How node `n` exited, if it does within `timeout`.
+    pub fn wait(&mut self, n: usize, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.children[n].try_wait().unwrap() {
+                return Some(status);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        None
+    }
+
+    // Terminate every node and check each exits cleanly.
+    pub fn terminate_all(&mut self) {
+        for n in 0..self.children.len() {
+            self.terminate(n);
+        }
+        for n in 0..self.children.len() {
+            match self.wait(n, EXIT) {
+                Some(status) => assert!(status.success(),
+                                        "node {} exited with {}", n, status),
+                None => panic!("node {} didn't exit within {:?}", n, EXIT),
+            }
+        }
+    }
+}
+
+impl Drop for Nodes {
+    fn drop(&mut self) {
+        for child in self.children.iter_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// Send each line read from `from` down `tx` as node `n`'s.
+fn forward<R: Read + Send + 'static>(n: usize, from: R,
+                                     tx: Sender<(usize, String)>) {
+    thread::spawn(move || {
+        for line in BufReader::new(from).lines() {
+            match line {
+                Ok(line) => if tx.send((n, line)).is_err() { return },
+                Err(_) => return,
+            }
+        }
+    });
+}
+
+// The first thing `f` finds in a line from `lines` within `timeout`.
+pub fn wait_for<T, F>(lines: &Receiver<(usize, String)>, timeout: Duration,
+                      mut f: F) -> Option<T>
+        where F: FnMut(usize, &str) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        match lines.recv_timeout(deadline - now) {
+            Ok((n, line)) => {
+                if let Some(found) = f(n, &line) {
+                    return Some(found);
+                }
+            },
+            Err(_) => return None,
+        }
+    }
+}
+
+// The peers= and alive= counts from a STATUS line.
+pub fn peer_counts(line: &str) -> Option<(usize, usize)> {
+    if !line.starts_with("STATUS ") {
+        return None;
+    }
+    let field = |name: &str| line.split(' ')
+        .find(|f| f.starts_with(name))
+        .and_then(|f| f[name.len()..].parse::<usize>().ok());
+    match (field("peers="), field("alive=")) {
+        (Some(peers), Some(alive)) => Some((peers, alive)),
+        _ => None,
+    }
+}
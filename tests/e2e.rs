@@ -0,0 +1,44 @@
+// The compiled binary, end to end: three nodes as child processes on
+// loopback, joined up and then told to terminate, and the doctor.
+//
+// Nodes don't yet tell each other about the peers they know, so the two
+// that join through the first only ever know the first, which knows both.
+mod common;
+
+use common::{peer_counts, wait_for, Nodes, CONVERGE};
+use std::process::Command;
+
+#[test]
+fn three_nodes_join_up_and_exit_cleanly_on_sigterm() {
+    let mut nodes = Nodes::new();
+    nodes.spawn(None, &[]);
+    let seed = nodes.addrs[0].clone();
+    nodes.spawn(Some(&seed), &[]);
+    nodes.spawn(Some(&seed), &[]);
+
+    // Each node's latest counts, until they're what a joined mesh has.
+    let expected = vec![Some((2, 2)), Some((1, 1)), Some((1, 1))];
+    let mut latest = vec![None; 3];
+    let converged = wait_for(&nodes.lines, CONVERGE, |n, line| {
+        if let Some(counts) = peer_counts(line) {
+            latest[n] = Some(counts);
+        }
+        if latest == expected { Some(()) } else { None }
+    });
+    assert!(converged.is_some(), "never converged; last counts {:?}", latest);
+
+    nodes.terminate_all();
+}
+
+#[test]
+fn the_doctor_reports_a_bad_cluster_name_as_a_failed_check() {
+    let output = Command::new(env!("CARGO_BIN_EXE_mesh"))
+        .args(&["doctor", "--cluster", ""])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.lines().any(|l| l.starts_with("FAIL cluster: ")),
+            "{}", stdout);
+    assert!(stdout.lines().any(|l| l.starts_with("PASS bind: ")),
+            "{}", stdout);
+    assert_eq!(output.status.code(), Some(1));
+}
@@ -0,0 +1,70 @@
+// The binary under injected failures (see --chaos): three nodes losing,
+// duplicating, delaying and corrupting their traffic for a while, which
+// should neither split the mesh up for good nor panic anything. Slow, so
+// only run when asked for: `cargo test --test soak -- --ignored`.
+mod common;
+
+use common::{peer_counts, wait_for, Nodes, CONVERGE};
+use std::time::Duration;
+
+const SOAK: Duration = Duration::from_secs(30);
+
+// Enough to exercise every failure often, not so much that the failure
+// detector can't tell a lossy peer from a dead one.
+const CHAOS: &'static str = "drop=0.05,dup=0.02,delay=1..20ms,corrupt=0.001";
+
+#[test]
+#[ignore]
+fn three_chaotic_nodes_stay_converged() {
+    let mut nodes = Nodes::new();
+    nodes.spawn(None, &["--chaos", CHAOS]);
+    let seed = nodes.addrs[0].clone();
+    nodes.spawn(Some(&seed), &["--chaos", CHAOS]);
+    nodes.spawn(Some(&seed), &["--chaos", CHAOS]);
+
+    // As in the e2e test, a star. A peer may be suspected for a while
+    // when too much of what it says goes missing, but none may be
+    // forgotten, and all must be back to alive by the end.
+    let expected = [2, 1, 1];
+    let mut latest: Vec<Option<(usize, usize)>> = vec![None; 3];
+    let mut panics = Vec::new();
+    let mut lost = Vec::new();
+    let mut watch = |n: usize, line: &str,
+                     latest: &mut Vec<Option<(usize, usize)>>| {
+        if line.contains("panicked") {
+            panics.push((n, line.to_string()));
+        }
+        if let Some((peers, alive)) = peer_counts(line) {
+            let had = latest[n].map_or(false, |(had, _)| had >= expected[n]);
+            if had && peers < expected[n] {
+                lost.push((n, line.to_string()));
+            }
+            latest[n] = Some((peers, alive));
+        }
+    };
+    let converged = |latest: &Vec<Option<(usize, usize)>>| {
+        latest.iter().zip(expected.iter())
+            .all(|(counts, &e)| *counts == Some((e, e)))
+    };
+
+    let up = wait_for(&nodes.lines, CONVERGE, |n, line| {
+        watch(n, line, &mut latest);
+        if converged(&latest) { Some(()) } else { None }
+    });
+    assert!(up.is_some(), "never converged; last counts {:?}", latest);
+
+    wait_for(&nodes.lines, SOAK, |n, line| -> Option<()> {
+        watch(n, line, &mut latest);
+        None
+    });
+
+    let settled = wait_for(&nodes.lines, CONVERGE, |n, line| {
+        watch(n, line, &mut latest);
+        if converged(&latest) { Some(()) } else { None }
+    });
+    assert!(panics.is_empty(), "panicked: {:?}", panics);
+    assert!(lost.is_empty(), "peers forgotten: {:?}", lost);
+    assert!(settled.is_some(), "didn't reconverge; last counts {:?}", latest);
+
+    nodes.terminate_all();
+}
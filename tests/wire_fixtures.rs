@@ -0,0 +1,416 @@
+// The wire format as a contract. Every fixture is the exact bytes of a
+// frame and the message they stand for: current code must decode each to
+// its message, and encode each message to exactly its bytes. A new message
+// type needs fixtures of its own; changing the bytes of any needs
+// message::PROTOCOL_VERSION bumped, along with FIXTURES_VERSION and
+// FIXTURES_HASH below.
+//
+// Frames lead with the protocol version, flagged with
+// message::VERSION_FLAG, then the message type, then for all but
+// Keepalives the checksum of the rest (see message::frame_checksum), the
+// three on a line of their own. They're all plain, there being no keyed,
+// fragmented or batched ones yet.
+//
+// Near misses are frames as a buggy encoder might make them: checksummed,
+// of known types, and off only in their lengths. Each must be rejected
+// for how its lengths don't add up (see message::LengthError).
+extern crate mesh;
+
+use mesh::fastpath;
+use mesh::members::{MetaVersion, NodeId};
+use mesh::message::{self, AckedMessage, Health, LengthError, Message,
+                    MessageKind, PingBody, RejectCode, Responder, Seq,
+                    WireAddr};
+use mesh::{parse_datagram, DecodeError};
+use std::collections::BTreeMap;
+
+// The protocol version these fixtures are of, and their `fixtures_hash`.
+const FIXTURES_VERSION: u8 = 15;
+const FIXTURES_HASH: u64 = 0xd910c18fb6d7558a;
+
+fn ping_body(nonce: u64, sent_at_micros: u64, pad: usize) -> PingBody {
+    PingBody { nonce: nonce, sent_at_micros: sent_at_micros, pad: vec![0; pad] }
+}
+
+fn addr(s: &str) -> WireAddr {
+    WireAddr(s.parse().unwrap())
+}
+
+fn tags(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+// Our sequence numbers are in session 0xabcd.
+fn seq(n: u32) -> Seq {
+    Seq::new(0xabcd, n)
+}
+
+// Name, bytes in hex, and message.
+fn fixtures() -> Vec<(&'static str, &'static str, Message)> {
+    vec![
+        ("join",
+         "8f0086d163b0\
+          0000abcd00000001000000000123456789abcdef0f",
+         Message::Acked(seq(1), AckedMessage::Join(
+             NodeId(0x0123456789abcdef), 15))),
+        ("data",
+         "8f00a5ad30dd\
+          0000abcd00000002000000010000000000000002cafe",
+         Message::Acked(seq(2), AckedMessage::Data(vec![0xca, 0xfe]))),
+        ("ordered",
+         "8f00666df738\
+          0000abcd0000000500000002000000070000000000000002cafe",
+         Message::Acked(seq(5),
+                        AckedMessage::Ordered(7, vec![0xca, 0xfe]))),
+        ("suspected",
+         "8f0066b0f15e\
+          0000abcd00000006000000030123456789abcdef0000000000000005",
+         Message::Acked(seq(6), AckedMessage::Suspected(
+             NodeId(0x0123456789abcdef), 5))),
+        ("tags",
+         "8f007cf27504\
+          0000abcd00000007000000040123456789abcdef\
+          00000000000000050000000000000002\
+          00000000000000010000000000000004726f6c65\
+          0000000000000006616374697665",
+         Message::Acked(seq(7), AckedMessage::Tags(
+             NodeId(0x0123456789abcdef), MetaVersion::new(5, 2),
+             tags(&[("role", "active")])))),
+        ("ack_v4",
+         "8f01301204fc\
+          0000abcd0000000300000000000000047f0000012328\
+          010123456789abcdef0000000000000005",
+         Message::Ack(seq(3), addr("127.0.0.1:9000"), Some(Responder {
+             id: NodeId(0x0123456789abcdef),
+             incarnation: 5,
+         }))),
+        ("ack_v6",
+         "8f0122b86e2e\
+          0000abcd00000004000000000000001020010db80000\
+          00000000000000000001232800",
+         Message::Ack(seq(4), addr("[2001:db8::1]:9000"), None)),
+        ("ping",
+         "8f0267852ba7\
+          0123456789abcdef00000000000f42400000000000000000",
+         Message::Ping(ping_body(0x0123456789abcdef, 1000000, 0))),
+        ("ping_padded",
+         "8f02453ce86c\
+          00000000000000050000000000000006000000000000000400000000",
+         Message::Ping(ping_body(5, 6, 4))),
+        ("pong",
+         "8f032c2768e2\
+          0123456789abcdef00000000000f4240000000000000000000000000\
+          000000040a0000010fa000",
+         Message::Pong(ping_body(0x0123456789abcdef, 1000000, 0),
+                       addr("10.0.0.1:4000"), None)),
+        ("pong_health",
+         "8f03479df08f\
+          00000000000000050000000000000006000000000000000000000000\
+          000000040a0000010fa001000000030000000100000004",
+         Message::Pong(ping_body(5, 6, 0), addr("10.0.0.1:4000"),
+                       Some(Health { queued: 3, dropped: 1, alive: 4 }))),
+        ("ack_multi",
+         "8f0464effccf\
+          00000000000000030000abcd000000010000abcd00000002\
+          0000abcd00000003",
+         Message::AckMulti(vec![seq(1), seq(2), seq(3)])),
+        ("version_mismatch",
+         "8f05a4d94b1a\
+          0f",
+         Message::VersionMismatch(15)),
+        ("sync_request",
+         "8f06a2ccf396\
+          0000000000000005",
+         Message::SyncRequest(5)),
+        ("rejected",
+         "8f07164bd25e\
+          00000003",
+         Message::Rejected(RejectCode::ClusterMismatch)),
+        ("ack_sack",
+         "8f084a88b136\
+          0000abcd00000003000000000000000b",
+         Message::AckSack(0xabcd, 3, 0b1011)),
+        ("join_forward",
+         "8f090d1d0848\
+          0000000000000004\
+          7f00000123280000abcd000000010123456789abcdef0f",
+         Message::JoinForward(addr("127.0.0.1:9000"), seq(1),
+                              NodeId(0x0123456789abcdef), 15)),
+        ("keepalive",
+         "8f0a",
+         Message::Keepalive),
+        ("join_retry_after",
+         "8f0b076ff496\
+          000001f4",
+         Message::JoinRetryAfter(500)),
+        ("join_rejected",
+         "8f0c30095e7d\
+          0000000000000014\
+          6e6f7420696e2074686520696e76656e746f7279",
+         Message::JoinRejected("not in the inventory".to_string())),
+    ]
+}
+
+// Frames of the previous protocol version, which we still read but no
+// longer send: each must decode to its message as of today.
+fn v14_fixtures() -> Vec<(&'static str, &'static str, Message)> {
+    vec![
+        ("join",
+         "8e000169108b\
+          0000abcd00000001000000000123456789abcdef0e",
+         Message::Acked(seq(1), AckedMessage::Join(
+             NodeId(0x0123456789abcdef), 14))),
+        ("data",
+         "8e001fe2f253\
+          0000abcd00000002000000010000000000000002cafe",
+         Message::Acked(seq(2), AckedMessage::Data(vec![0xca, 0xfe]))),
+        ("ordered",
+         "8e00c4787c0c\
+          0000abcd0000000500000002000000070000000000000002cafe",
+         Message::Acked(seq(5),
+                        AckedMessage::Ordered(7, vec![0xca, 0xfe]))),
+        ("suspected",
+         "8e00730bb057\
+          0000abcd00000006000000030123456789abcdef0000000000000005",
+         Message::Acked(seq(6), AckedMessage::Suspected(
+             NodeId(0x0123456789abcdef), 5))),
+        ("tags",
+         "8e004521c792\
+          0000abcd00000007000000040123456789abcdef\
+          00000000000000050000000000000002\
+          00000000000000010000000000000004726f6c65\
+          0000000000000006616374697665",
+         Message::Acked(seq(7), AckedMessage::Tags(
+             NodeId(0x0123456789abcdef), MetaVersion::new(5, 2),
+             tags(&[("role", "active")])))),
+        ("ack_v4",
+         "8e0189fbe10c\
+          0000abcd0000000300000000000000047f0000012328\
+          010123456789abcdef0000000000000005",
+         Message::Ack(seq(3), addr("127.0.0.1:9000"), Some(Responder {
+             id: NodeId(0x0123456789abcdef),
+             incarnation: 5,
+         }))),
+        ("ack_v6",
+         "8e01dddd1f8c\
+          0000abcd00000004000000000000001020010db80000\
+          00000000000000000001232800",
+         Message::Ack(seq(4), addr("[2001:db8::1]:9000"), None)),
+        ("ping",
+         "8e024a2bafa8\
+          0123456789abcdef00000000000f42400000000000000000",
+         Message::Ping(ping_body(0x0123456789abcdef, 1000000, 0))),
+        ("ping_padded",
+         "8e025087a965\
+          00000000000000050000000000000006000000000000000400000000",
+         Message::Ping(ping_body(5, 6, 4))),
+        ("pong",
+         "8e0395ce8d12\
+          0123456789abcdef00000000000f4240000000000000000000000000\
+          000000040a0000010fa000",
+         Message::Pong(ping_body(0x0123456789abcdef, 1000000, 0),
+                       addr("10.0.0.1:4000"), None)),
+        ("pong_health",
+         "8e038e9003e5\
+          00000000000000050000000000000006000000000000000000000000\
+          000000040a0000010fa001000000030000000100000004",
+         Message::Pong(ping_body(5, 6, 0), addr("10.0.0.1:4000"),
+                       Some(Health { queued: 3, dropped: 1, alive: 4 }))),
+        ("ack_multi",
+         "8e041a1b771e\
+          00000000000000030000abcd000000010000abcd00000002\
+          0000abcd00000003",
+         Message::AckMulti(vec![seq(1), seq(2), seq(3)])),
+        ("version_mismatch",
+         "8e05f3f35a67\
+          0e",
+         Message::VersionMismatch(14)),
+        ("sync_request",
+         "8e06699a8833\
+          0000000000000005",
+         Message::SyncRequest(5)),
+        ("rejected",
+         "8e07f97bb947\
+          00000003",
+         Message::Rejected(RejectCode::ClusterMismatch)),
+        ("ack_sack",
+         "8e0845e1b31d\
+          0000abcd00000003000000000000000b",
+         Message::AckSack(0xabcd, 3, 0b1011)),
+        ("join_forward",
+         "8e09a54fa064\
+          0000000000000004\
+          7f00000123280000abcd000000010123456789abcdef0e",
+         Message::JoinForward(addr("127.0.0.1:9000"), seq(1),
+                              NodeId(0x0123456789abcdef), 14)),
+        ("keepalive",
+         "8e0a",
+         Message::Keepalive),
+        ("join_retry_after",
+         "8e0be85f9f8f\
+          000001f4",
+         Message::JoinRetryAfter(500)),
+    ]
+}
+
+// Name, bytes in hex, and what's wrong with them.
+fn near_misses() -> Vec<(&'static str, &'static str, LengthError)> {
+    vec![
+        ("data_claims_one_more",
+         "8f0000eca2a3\
+          0000abcd00000002000000010000000000000003cafe",
+         LengthError::LengthMismatch),
+        ("data_claims_one_fewer",
+         "8f004f83f0ae\
+          0000abcd00000002000000010000000000000001cafe",
+         LengthError::TrailingBytes),
+        ("ack_multi_claims_one_more",
+         "8f04ae618e1c\
+          00000000000000040000abcd000000010000abcd00000002\
+          0000abcd00000003",
+         LengthError::LengthMismatch),
+        ("ack_multi_over_the_batch",
+         "8f04b81e1ae2\
+          0000000000000011\
+          0000abcd000000010000abcd000000020000abcd00000003\
+          0000abcd000000040000abcd000000050000abcd00000006\
+          0000abcd000000070000abcd000000080000abcd00000009\
+          0000abcd0000000a0000abcd0000000b0000abcd0000000c\
+          0000abcd0000000d0000abcd0000000e0000abcd0000000f\
+          0000abcd000000100000abcd00000011",
+         LengthError::CountTooLarge),
+        ("sync_request_run_on",
+         "8f06e6d6845d\
+          000000000000000500",
+         LengthError::TrailingBytes),
+        ("keepalive_with_contents",
+         "8f0a1db37dc3\
+          00",
+         LengthError::TrailingBytes),
+        ("pong_run_on",
+         "8f03505aacd4\
+          0123456789abcdef00000000000f4240000000000000000000000000\
+          000000040a0000010fa00000",
+         LengthError::TrailingBytes),
+    ]
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    assert!(hex.len() % 2 == 0, "odd length hex: {}", hex);
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap())
+        .collect()
+}
+
+// FNV-1a over every fixture's name and bytes, each followed by a zero.
+fn fixtures_hash() -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for (name, hex, _) in fixtures() {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+        bytes.extend(unhex(hex));
+        bytes.push(0);
+        for b in bytes {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+#[test]
+fn every_fixture_decodes_to_its_message() {
+    for (name, hex, msg) in fixtures() {
+        assert_eq!(parse_datagram(&unhex(hex)), Ok(msg), "{}", name);
+    }
+}
+
+#[test]
+fn every_v14_fixture_decodes_to_its_message() {
+    for (name, hex, msg) in v14_fixtures() {
+        assert_eq!(parse_datagram(&unhex(hex)), Ok(msg), "v14 {}", name);
+    }
+}
+
+#[test]
+fn every_near_miss_is_rejected_for_its_lengths() {
+    for (name, hex, why) in near_misses() {
+        let bytes = unhex(hex);
+        assert_eq!(message::checked_contents(&bytes).map(|_| ()), Ok(()),
+                   "{} has a bad checksum", name);
+        assert_eq!(parse_datagram(&bytes), Err(DecodeError::BadLength(why)),
+                   "{}", name);
+        assert_eq!(fastpath::parse(&bytes), None, "{}", name);
+    }
+}
+
+// Those read by mesh::fastpath, which must read them exactly as the
+// general decoder does, and leave the rest to it.
+#[test]
+fn the_fast_path_reads_acks_and_pongs_as_parse_datagram_does() {
+    let mut fast = Vec::new();
+    for (name, hex, _) in fixtures() {
+        let bytes = unhex(hex);
+        if let Some(msg) = fastpath::parse(&bytes) {
+            assert_eq!(parse_datagram(&bytes), Ok(msg.clone()), "{}", name);
+            assert_eq!(msg.encode(), bytes, "{}", name);
+            fast.push(name);
+        }
+    }
+    assert_eq!(fast, vec!["ack_v4", "ack_v6", "pong", "pong_health"]);
+    for (name, hex, _) in v14_fixtures() {
+        assert_eq!(fastpath::parse(&unhex(hex)), None, "v14 {}", name);
+    }
+}
+
+// None gets through with a byte of it damaged: the checksum, or for a
+// Keepalive its being two bytes of known type, catches it.
+#[test]
+fn every_fixture_damaged_fails_to_decode() {
+    for (name, hex, _) in fixtures() {
+        let bytes = unhex(hex);
+        for at in 0..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[at] ^= 0xff;
+            assert!(parse_datagram(&damaged).is_err(), "{} at {}", name, at);
+            assert_eq!(fastpath::parse(&damaged), None, "{} at {}", name, at);
+        }
+    }
+}
+
+#[test]
+fn every_message_encodes_to_its_fixture() {
+    for (name, hex, msg) in fixtures() {
+        assert_eq!(msg.encode(), unhex(hex), "{}", name);
+    }
+}
+
+#[test]
+fn every_message_type_has_a_fixture() {
+    let fixtures = fixtures();
+    for t in 0..message::MESSAGE_TYPES {
+        assert!(fixtures.iter().any(|&(_, hex, _)| unhex(hex)[1] == t),
+                "no fixture of message type {}", t);
+    }
+    for kind in &[MessageKind::Join, MessageKind::Data,
+                  MessageKind::Ordered, MessageKind::Suspected,
+                  MessageKind::Tags,
+                  MessageKind::Ack, MessageKind::Ping, MessageKind::Pong,
+                  MessageKind::VersionMismatch, MessageKind::SyncRequest,
+                  MessageKind::Rejected, MessageKind::JoinForward,
+                  MessageKind::Keepalive, MessageKind::JoinRetryAfter,
+                  MessageKind::JoinRejected] {
+        assert!(fixtures.iter().any(|&(_, _, ref msg)| msg.kind() == *kind),
+                "no fixture of {:?}", kind);
+    }
+}
+
+#[test]
+fn fixtures_change_only_with_the_protocol_version() {
+    assert_eq!(message::PROTOCOL_VERSION, FIXTURES_VERSION,
+               "PROTOCOL_VERSION changed: update the fixtures to the new \
+                format, then FIXTURES_VERSION and FIXTURES_HASH");
+    assert_eq!(fixtures_hash(), FIXTURES_HASH,
+               "fixture bytes changed: bump PROTOCOL_VERSION, then \
+                FIXTURES_VERSION and FIXTURES_HASH");
+}